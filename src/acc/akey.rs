@@ -1,50 +1,253 @@
 use openssl::ec::EcKey;
-use openssl::pkey;
+use openssl::ecdsa::EcdsaSig;
+use openssl::hash::{hash, MessageDigest};
+use openssl::nid::Nid;
+use openssl::pkey::{self, Id, PKey};
+use openssl::rsa::Rsa;
+use openssl::sign::Signer;
 
-use crate::cert::EC_GROUP_P256;
+use crate::cert::{EC_GROUP_P256, EC_GROUP_P384};
+use crate::jwt::Jwk;
+use crate::signer::AccountSigner;
 use crate::Result;
 
+/// Algorithm of the account key used to sign requests to the ACME API.
+///
+/// The names are the JWS algorithms used for signing. The account key algorithm does
+/// not affect which key algorithms that can be used for the issued certificates.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AccountKeyAlgorithm {
+    /// ECDSA using curve P-256 and SHA-256. This is the default.
+    ES256,
+    /// ECDSA using curve P-384 and SHA-384.
+    ES384,
+    /// RSASSA-PKCS1-v1_5 using SHA-256, with the given number of bits for new keys.
+    /// New keys must have at least 2048 bits.
+    RS256(u32),
+    /// EdDSA using curve Ed25519.
+    EdDSA,
+}
+
+// not derived, since `#[default]` on enum variants needs Rust 1.62.
+#[allow(clippy::derivable_impls)]
+impl Default for AccountKeyAlgorithm {
+    fn default() -> Self {
+        AccountKeyAlgorithm::ES256
+    }
+}
+
+impl AccountKeyAlgorithm {
+    /// The JWS `alg` name.
+    pub fn jws_alg(self) -> &'static str {
+        match self {
+            AccountKeyAlgorithm::ES256 => "ES256",
+            AccountKeyAlgorithm::ES384 => "ES384",
+            AccountKeyAlgorithm::RS256(_) => "RS256",
+            AccountKeyAlgorithm::EdDSA => "EdDSA",
+        }
+    }
+}
+
+/// Account private key held in memory.
+///
+/// This is the [signer] used unless another is provided. The key can be saved
+/// as PEM, which is how it's kept in the [persistence].
+///
+/// [signer]: trait.AccountSigner.html
+/// [persistence]: ../persist/index.html
 #[derive(Clone, Debug)]
-pub(crate) struct AcmeKey {
-    private_key: EcKey<pkey::Private>,
-    /// set once we contacted the ACME API to figure out the key id
-    key_id: Option<String>,
+pub struct AcmeKey {
+    private_key: PKey<pkey::Private>,
+    algorithm: AccountKeyAlgorithm,
+    jwk: Jwk,
 }
 
 impl AcmeKey {
-    pub(crate) fn new() -> AcmeKey {
-        let pri_key = EcKey::generate(&*EC_GROUP_P256).expect("EcKey");
-        Self::from_key(pri_key)
+    /// Generate a new key for the algorithm.
+    pub fn generate(algorithm: AccountKeyAlgorithm) -> Result<AcmeKey> {
+        if let AccountKeyAlgorithm::RS256(bits) = algorithm {
+            if bits < 2048 {
+                return Err(format!("RSA account key too small: {} bits", bits).into());
+            }
+        }
+        let private_key = match algorithm {
+            AccountKeyAlgorithm::ES256 => {
+                EcKey::generate(&EC_GROUP_P256).and_then(PKey::from_ec_key)
+            }
+            AccountKeyAlgorithm::ES384 => {
+                EcKey::generate(&EC_GROUP_P384).and_then(PKey::from_ec_key)
+            }
+            AccountKeyAlgorithm::RS256(bits) => Rsa::generate(bits).and_then(PKey::from_rsa),
+            AccountKeyAlgorithm::EdDSA => PKey::generate_ed25519(),
+        }
+        .map_err(|e| format!("Failed to generate {:?} key: {}", algorithm, e))?;
+        Self::from_key(private_key, algorithm)
     }
 
-    pub(crate) fn from_pem(pem: &[u8]) -> Result<AcmeKey> {
-        let pri_key =
-            EcKey::private_key_from_pem(pem).map_err(|e| format!("Failed to read PEM: {}", e))?;
-        Ok(Self::from_key(pri_key))
+    /// Read a PEM encoded private key. The algorithm follows from the key type.
+    pub fn from_pem(pem: &[u8]) -> Result<AcmeKey> {
+        let private_key =
+            PKey::private_key_from_pem(pem).map_err(|e| format!("Failed to read PEM: {}", e))?;
+        let algorithm = algorithm_of(&private_key)?;
+        Self::from_key(private_key, algorithm)
     }
 
-    fn from_key(private_key: EcKey<pkey::Private>) -> AcmeKey {
-        AcmeKey {
+    fn from_key(
+        private_key: PKey<pkey::Private>,
+        algorithm: AccountKeyAlgorithm,
+    ) -> Result<AcmeKey> {
+        let jwk = Jwk::from_public_key(algorithm, &private_key)?;
+        Ok(AcmeKey {
             private_key,
-            key_id: None,
+            algorithm,
+            jwk,
+        })
+    }
+
+    /// The private key as PEM.
+    pub fn to_pem(&self) -> Vec<u8> {
+        match self.private_key.ec_key() {
+            // EC keys are kept in the traditional format of earlier versions.
+            Ok(ec_key) => ec_key.private_key_to_pem(),
+            Err(_) => self.private_key.private_key_to_pem_pkcs8(),
         }
+        .expect("private_key_to_pem")
     }
 
-    pub(crate) fn to_pem(&self) -> Vec<u8> {
-        self.private_key
-            .private_key_to_pem()
-            .expect("private_key_to_pem")
+    fn do_sign(&self, data: &[u8]) -> std::result::Result<Vec<u8>, openssl::error::ErrorStack> {
+        match self.algorithm {
+            AccountKeyAlgorithm::ES256 | AccountKeyAlgorithm::ES384 => {
+                // JWS wants the raw r and s, each padded to the size of the curve.
+                let size = ec_size(self.algorithm);
+                let md = if size == 32 {
+                    MessageDigest::sha256()
+                } else {
+                    MessageDigest::sha384()
+                };
+                let digest = hash(md, data)?;
+                let sig = EcdsaSig::sign(&digest, &*self.private_key.ec_key()?)?;
+                let mut v = sig.r().to_vec_padded(size)?;
+                v.extend_from_slice(&sig.s().to_vec_padded(size)?);
+                Ok(v)
+            }
+            AccountKeyAlgorithm::RS256(_) => {
+                let mut signer = Signer::new(MessageDigest::sha256(), &self.private_key)?;
+                signer.update(data)?;
+                signer.sign_to_vec()
+            }
+            AccountKeyAlgorithm::EdDSA => {
+                let mut signer = Signer::new_without_digest(&self.private_key)?;
+                signer.sign_oneshot_to_vec(data)
+            }
+        }
+    }
+}
+
+impl AccountSigner for AcmeKey {
+    fn algorithm(&self) -> AccountKeyAlgorithm {
+        self.algorithm
     }
 
-    pub(crate) fn private_key(&self) -> &EcKey<pkey::Private> {
-        &self.private_key
+    fn jwk(&self) -> Jwk {
+        self.jwk.clone()
     }
 
-    pub(crate) fn key_id(&self) -> &str {
-        self.key_id.as_ref().unwrap()
+    fn sign(&self, data: &[u8]) -> Result<Vec<u8>> {
+        self.do_sign(data)
+            .map_err(|e| format!("Failed to sign using {:?}: {}", self.algorithm, e).into())
+    }
+
+    fn private_key_pem(&self) -> Option<Vec<u8>> {
+        Some(self.to_pem())
+    }
+}
+
+/// Coordinate size in bytes.
+fn ec_size(algorithm: AccountKeyAlgorithm) -> i32 {
+    match algorithm {
+        AccountKeyAlgorithm::ES384 => 48,
+        _ => 32,
+    }
+}
+
+fn algorithm_of(private_key: &PKey<pkey::Private>) -> Result<AccountKeyAlgorithm> {
+    let algorithm = match private_key.id() {
+        Id::EC => {
+            let ec_key = private_key.ec_key().map_err(|e| e.to_string())?;
+            match ec_key.group().curve_name() {
+                Some(Nid::X9_62_PRIME256V1) => AccountKeyAlgorithm::ES256,
+                Some(Nid::SECP384R1) => AccountKeyAlgorithm::ES384,
+                c => return Err(format!("Unsupported account key curve: {:?}", c).into()),
+            }
+        }
+        Id::RSA => AccountKeyAlgorithm::RS256(private_key.bits()),
+        Id::ED25519 => AccountKeyAlgorithm::EdDSA,
+        id => return Err(format!("Unsupported account key type: {:?}", id).into()),
+    };
+    Ok(algorithm)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use openssl::bn::BigNum;
+    use openssl::sign::Verifier;
+
+    fn verify(key: &AcmeKey, data: &[u8], sig: &[u8]) -> bool {
+        let pkey = &key.private_key;
+        match key.algorithm {
+            AccountKeyAlgorithm::ES256 | AccountKeyAlgorithm::ES384 => {
+                let size = ec_size(key.algorithm);
+                assert_eq!(sig.len(), 2 * size as usize);
+                let (r, s) = sig.split_at(size as usize);
+                let r = BigNum::from_slice(r).unwrap();
+                let s = BigNum::from_slice(s).unwrap();
+                let sig = EcdsaSig::from_private_components(r, s).unwrap();
+                let md = if size == 32 {
+                    MessageDigest::sha256()
+                } else {
+                    MessageDigest::sha384()
+                };
+                let digest = hash(md, data).unwrap();
+                sig.verify(&digest, &*pkey.ec_key().unwrap()).unwrap()
+            }
+            AccountKeyAlgorithm::RS256(_) => {
+                let mut verifier = Verifier::new(MessageDigest::sha256(), pkey).unwrap();
+                verifier.verify_oneshot(sig, data).unwrap()
+            }
+            AccountKeyAlgorithm::EdDSA => {
+                let mut verifier = Verifier::new_without_digest(pkey).unwrap();
+                verifier.verify_oneshot(sig, data).unwrap()
+            }
+        }
+    }
+
+    #[test]
+    fn test_algorithms() -> Result<()> {
+        let algs = [
+            AccountKeyAlgorithm::ES256,
+            AccountKeyAlgorithm::ES384,
+            AccountKeyAlgorithm::RS256(2048),
+            AccountKeyAlgorithm::EdDSA,
+        ];
+        for alg in &algs {
+            let key = AcmeKey::generate(*alg)?;
+            let sig = key.sign(b"foo.bar")?;
+            assert!(verify(&key, b"foo.bar", &sig), "verify {:?}", alg);
+
+            // the algorithm is recovered from the PEM
+            let key2 = AcmeKey::from_pem(&key.to_pem())?;
+            assert_eq!(key2.algorithm(), *alg);
+
+            let jwk = serde_json::to_value(key.jwk())?;
+            assert_eq!(key.algorithm(), *alg);
+            assert_eq!(jwk["alg"], alg.jws_alg());
+        }
+        Ok(())
     }
 
-    pub(crate) fn set_key_id(&mut self, kid: String) {
-        self.key_id = Some(kid)
+    #[test]
+    fn test_small_rsa_key() {
+        assert!(AcmeKey::generate(AccountKeyAlgorithm::RS256(1024)).is_err());
     }
 }
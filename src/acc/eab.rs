@@ -0,0 +1,168 @@
+use openssl::hash::MessageDigest;
+use openssl::pkey::PKey;
+use openssl::sign::Signer;
+
+use crate::api::ApiJws;
+use crate::jwt::JwsProtected;
+use crate::signer::AccountSigner;
+use crate::util::base64url;
+use crate::Result;
+
+/// MAC algorithm used to sign the external account binding.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum EabAlgorithm {
+    /// HMAC using SHA-256. This is what most CAs hand out.
+    HS256,
+    /// HMAC using SHA-384.
+    HS384,
+    /// HMAC using SHA-512.
+    HS512,
+}
+
+impl EabAlgorithm {
+    fn name(self) -> &'static str {
+        match self {
+            EabAlgorithm::HS256 => "HS256",
+            EabAlgorithm::HS384 => "HS384",
+            EabAlgorithm::HS512 => "HS512",
+        }
+    }
+
+    fn digest(self) -> MessageDigest {
+        match self {
+            EabAlgorithm::HS256 => MessageDigest::sha256(),
+            EabAlgorithm::HS384 => MessageDigest::sha384(),
+            EabAlgorithm::HS512 => MessageDigest::sha512(),
+        }
+    }
+}
+
+/// Credentials binding a new ACME account to an existing account with the CA.
+///
+/// Some ACME API providers (ZeroSSL, Google Trust Services, Sectigo, …) require
+/// new accounts to be bound to an account in a non-ACME system. The CA hands out a
+/// key identifier and a MAC key, which are used to sign the new account key.
+///
+/// See [`Directory::account_with_eab`] and [RFC 8555 §7.3.4].
+///
+/// [`Directory::account_with_eab`]: struct.Directory.html#method.account_with_eab
+/// [RFC 8555 §7.3.4]: https://tools.ietf.org/html/rfc8555#section-7.3.4
+#[derive(Clone)]
+pub struct ExternalAccountBinding {
+    key_id: String,
+    hmac_key: Vec<u8>,
+    algorithm: EabAlgorithm,
+}
+
+impl ExternalAccountBinding {
+    /// Create a binding from the key id and the base64url encoded MAC key,
+    /// which is the form CAs normally provide them in.
+    ///
+    /// The binding is signed using `HS256` unless changed with [`with_algorithm`].
+    ///
+    /// [`with_algorithm`]: struct.ExternalAccountBinding.html#method.with_algorithm
+    pub fn new(key_id: &str, hmac_key_base64url: &str) -> Result<ExternalAccountBinding> {
+        // tolerate padded keys, the spec says unpadded base64url.
+        let trimmed = hmac_key_base64url.trim().trim_end_matches('=');
+        let hmac_key = base64::decode_config(trimmed, base64::URL_SAFE_NO_PAD)
+            .map_err(crate::Error::Base64Decode)?;
+        Ok(Self::from_raw_key(key_id, &hmac_key))
+    }
+
+    /// Create a binding from the key id and the raw (decoded) MAC key.
+    pub fn from_raw_key(key_id: &str, hmac_key: &[u8]) -> ExternalAccountBinding {
+        ExternalAccountBinding {
+            key_id: key_id.to_string(),
+            hmac_key: hmac_key.to_vec(),
+            algorithm: EabAlgorithm::HS256,
+        }
+    }
+
+    /// Change the MAC algorithm used to sign the binding.
+    pub fn with_algorithm(mut self, algorithm: EabAlgorithm) -> Self {
+        self.algorithm = algorithm;
+        self
+    }
+
+    /// The key identifier given by the CA.
+    pub fn key_id(&self) -> &str {
+        &self.key_id
+    }
+
+    /// The MAC algorithm used to sign the binding.
+    pub fn algorithm(&self) -> EabAlgorithm {
+        self.algorithm
+    }
+
+    /// Produce the inner JWS for the `externalAccountBinding` field of a
+    /// `newAccount` request. The payload is the account public key.
+    pub(crate) fn to_jws(&self, url: &str, signer: &dyn AccountSigner) -> Result<ApiJws> {
+        let protected = {
+            let protected = JwsProtected::new_eab(self.algorithm.name(), &self.key_id, url);
+            let pro_json = serde_json::to_string(&protected)?;
+            base64url(pro_json.as_bytes())
+        };
+        let payload = {
+            let jwk_json = serde_json::to_string(&signer.jwk())?;
+            base64url(jwk_json.as_bytes())
+        };
+
+        let to_sign = format!("{}.{}", protected, payload);
+        let signature = self
+            .sign(to_sign.as_bytes())
+            .map_err(|e| format!("Failed to sign external account binding: {}", e))?;
+
+        Ok(ApiJws {
+            protected,
+            payload,
+            signature: base64url(&signature),
+        })
+    }
+
+    fn sign(&self, data: &[u8]) -> std::result::Result<Vec<u8>, openssl::error::ErrorStack> {
+        let pkey = PKey::hmac(&self.hmac_key)?;
+        let mut signer = Signer::new(self.algorithm.digest(), &pkey)?;
+        signer.update(data)?;
+        signer.sign_to_vec()
+    }
+}
+
+impl std::fmt::Debug for ExternalAccountBinding {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        // never print the MAC key.
+        f.debug_struct("ExternalAccountBinding")
+            .field("key_id", &self.key_id)
+            .field("algorithm", &self.algorithm)
+            .finish()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::acc::AcmeKey;
+
+    #[test]
+    fn test_eab_jws() -> Result<()> {
+        let key = base64url(b"a very secret mac key");
+        let eab = ExternalAccountBinding::new("kid-1", &key)?.with_algorithm(EabAlgorithm::HS384);
+        let acme_key = AcmeKey::generate(crate::AccountKeyAlgorithm::ES256)?;
+        let jws = eab.to_jws("https://example.com/acme/new-acct", &acme_key)?;
+
+        let decode = |s: &str| base64::decode_config(s, base64::URL_SAFE_NO_PAD).unwrap();
+        let protected: serde_json::Value = serde_json::from_slice(&decode(&jws.protected))?;
+        assert_eq!(protected["alg"], "HS384");
+        assert_eq!(protected["kid"], "kid-1");
+        assert_eq!(protected["url"], "https://example.com/acme/new-acct");
+        assert!(protected.get("nonce").is_none());
+
+        assert_eq!(decode(&jws.payload), serde_json::to_vec(&acme_key.jwk())?);
+
+        let expect = ExternalAccountBinding::from_raw_key("kid-1", b"a very secret mac key")
+            .with_algorithm(EabAlgorithm::HS384)
+            .sign(format!("{}.{}", jws.protected, jws.payload).as_bytes())
+            .unwrap();
+        assert_eq!(decode(&jws.signature), expect);
+        Ok(())
+    }
+}
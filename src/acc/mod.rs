@@ -1,26 +1,47 @@
 //
+use serde::Serialize;
 use std::sync::Arc;
 
-use crate::api::{ApiAccount, ApiDirectory, ApiIdentifier, ApiOrder, ApiRevocation};
+use crate::api::{
+    ApiAccount, ApiContactUpdate, ApiDirectory, ApiEmptyString, ApiIdentifier, ApiOrder,
+    ApiRevocation,
+};
 use crate::cert::Certificate;
 use crate::order::{NewOrder, Order};
 use crate::persist::{Persist, PersistKey, PersistKind};
 use crate::req::req_expect_header;
+use crate::signer::AccountSigner;
 use crate::trans::Transport;
 use crate::util::{base64url, read_json};
-use crate::Result;
+use crate::{OrderLedger, Result};
 
 mod akey;
+mod eab;
 
-pub(crate) use self::akey::AcmeKey;
+pub use self::akey::AccountKeyAlgorithm;
+pub use self::akey::AcmeKey;
+pub use self::eab::{EabAlgorithm, ExternalAccountBinding};
 
-#[derive(Clone, Debug)]
+/// Persistence key for the account private key in a realm.
+pub(crate) fn account_key_persist_key(realm: &str) -> PersistKey<'static> {
+    PersistKey::new(realm, PersistKind::AccountPrivateKey, "acme_account")
+}
+
+/// The private key of a signer as PEM, for signers exposing it.
+pub(crate) fn signer_private_key_pem(signer: &dyn AccountSigner) -> Result<String> {
+    let pem = signer
+        .private_key_pem()
+        .ok_or("Account signer doesn't expose its private key")?;
+    Ok(String::from_utf8(pem).map_err(|e| e.to_string())?)
+}
+
+#[derive(Debug)]
 pub(crate) struct AccountInner<P: Persist> {
     pub persist: P,
     pub transport: Transport,
     pub realm: String,
-    pub api_account: ApiAccount,
     pub api_directory: ApiDirectory,
+    pub ledger: Option<OrderLedger>,
 }
 
 /// Account with an ACME provider.
@@ -28,18 +49,21 @@ pub(crate) struct AccountInner<P: Persist> {
 /// Accounts are created using [`Directory::account`] and consist of a contact
 /// email address and a private key for signing requests to the ACME API.
 ///
-/// acme-lib uses elliptic curve P-256 for accessing the account. This
-/// does not affect which key algorithms that can be used for the
-/// issued certificates.
+/// acme-lib uses elliptic curve P-256 for accessing the account by default. Other
+/// [algorithms] can be chosen when the account is created. This does not affect which
+/// key algorithms that can be used for the issued certificates.
 ///
 /// The advantage of using elliptic curve cryptography is that the signed
 /// requests against the ACME lib are kept small and that the public key
 /// can be derived from the private.
 ///
+/// [algorithms]: enum.AccountKeyAlgorithm.html
+///
 /// [`Directory::account`]: struct.Directory.html#method.account
 #[derive(Clone)]
 pub struct Account<P: Persist> {
     inner: Arc<AccountInner<P>>,
+    api_account: ApiAccount,
 }
 
 impl<P: Persist> Account<P> {
@@ -49,23 +73,156 @@ impl<P: Persist> Account<P> {
         realm: &str,
         api_account: ApiAccount,
         api_directory: ApiDirectory,
+        ledger: Option<OrderLedger>,
     ) -> Self {
         Account {
             inner: Arc::new(AccountInner {
                 persist,
                 transport,
                 realm: realm.to_string(),
-                api_account,
                 api_directory,
+                ledger,
             }),
+            api_account,
         }
     }
 
     /// Private key for this account.
     ///
-    /// The key is an elliptic curve private key.
+    /// The key is an elliptic curve private key, unless another [algorithm] was
+    /// chosen when creating the account.
+    ///
+    /// Panics if the account uses a [signer] that doesn't expose its private key,
+    /// see [`try_acme_private_key_pem`].
+    ///
+    /// [algorithm]: enum.AccountKeyAlgorithm.html
+    /// [signer]: signer/trait.AccountSigner.html
+    /// [`try_acme_private_key_pem`]: struct.Account.html#method.try_acme_private_key_pem
     pub fn acme_private_key_pem(&self) -> String {
-        String::from_utf8(self.inner.transport.acme_key().to_pem()).expect("from_utf8")
+        self.try_acme_private_key_pem()
+            .expect("acme_private_key_pem")
+    }
+
+    /// Private key for this account, or an error if the account uses a [signer]
+    /// that doesn't expose it.
+    ///
+    /// [signer]: signer/trait.AccountSigner.html
+    pub fn try_acme_private_key_pem(&self) -> Result<String> {
+        signer_private_key_pem(&*self.inner.transport.signer())
+    }
+
+    /// The account URL. This is also the key id used to sign requests to the ACME API.
+    pub fn account_url(&self) -> String {
+        self.inner.transport.key_id().to_string()
+    }
+
+    /// Fetch the current state of the account from the ACME API.
+    ///
+    /// The state is available via [`api_account`] afterwards. An account can be
+    /// deactivated by the user, or revoked by the ACME API provider.
+    ///
+    /// [`api_account`]: struct.Account.html#method.api_account
+    pub fn refresh(&mut self) -> Result<()> {
+        self.update_account(&ApiEmptyString)
+    }
+
+    /// Replace the contacts of the account.
+    ///
+    /// Contacts are URLs such as `mailto:foo@bar.com`. An empty list removes all contacts.
+    pub fn update_contacts(&mut self, contact: Vec<String>) -> Result<()> {
+        let acc = ApiContactUpdate { contact };
+        self.update_account(&acc)
+    }
+
+    /// Deactivate the account.
+    ///
+    /// This can not be undone. The ACME API provider will reject any further requests
+    /// signed by the account key, which means the persisted account key is of no more use.
+    pub fn deactivate(&mut self) -> Result<()> {
+        let acc = ApiAccount {
+            status: Some("deactivated".into()),
+            ..Default::default()
+        };
+        self.update_account(&acc)
+    }
+
+    /// URL of the terms of service of the ACME API provider, if it has any.
+    pub fn terms_of_service(&self) -> Option<&str> {
+        self.inner.api_directory.termsOfService()
+    }
+
+    /// Agree to the current [terms of service].
+    ///
+    /// When the ACME API provider changes its terms, requests fail with
+    /// [`Error::UserActionRequired`] until the user has agreed to the new terms.
+    ///
+    /// [terms of service]: struct.Account.html#method.terms_of_service
+    /// [`Error::UserActionRequired`]: enum.Error.html#variant.UserActionRequired
+    pub fn agree_to_terms_of_service(&mut self) -> Result<()> {
+        let acc = ApiAccount {
+            termsOfServiceAgreed: Some(true),
+            ..Default::default()
+        };
+        self.update_account(&acc)
+    }
+
+    /// POST to the account URL and keep the returned account state.
+    fn update_account<T: Serialize + ?Sized>(&mut self, body: &T) -> Result<()> {
+        let url = self.account_url();
+        let res = self.inner.transport.call(&url, body)?;
+        let api_account: ApiAccount = read_json(res)?;
+        self.api_account = api_account;
+        Ok(())
+    }
+
+    /// Roll over the account to a new private key.
+    ///
+    /// A new key, using the same algorithm as the current, is created and the ACME
+    /// API `keyChange` endpoint is called to replace the account key. The account
+    /// (and its URL) stays the same, so existing orders and authorizations remain
+    /// accessible.
+    ///
+    /// Once the ACME API provider has accepted the new key, it is used for all
+    /// subsequent calls by this account (and the orders created from it), and the
+    /// new key is written to the persistence replacing the old one. If saving
+    /// to the persistence fails, the new key can still be obtained from
+    /// [`acme_private_key_pem`].
+    ///
+    /// [`acme_private_key_pem`]: struct.Account.html#method.acme_private_key_pem
+    pub fn change_key(&self) -> Result<()> {
+        let algorithm = self.inner.transport.signer().algorithm();
+        self.change_key_algorithm(algorithm)
+    }
+
+    /// Roll over the account to a new private key using the given algorithm.
+    ///
+    /// See [`change_key`].
+    ///
+    /// [`change_key`]: struct.Account.html#method.change_key
+    pub fn change_key_algorithm(&self, algorithm: AccountKeyAlgorithm) -> Result<()> {
+        let new_key = AcmeKey::generate(algorithm)?;
+        self.change_signer(Arc::new(new_key))
+    }
+
+    /// Roll over the account to the key of the given [signer].
+    ///
+    /// As for [`change_key`], the new key is written to the persistence if the signer
+    /// exposes its private key. Otherwise the persisted key, if any, is left as is and
+    /// is of no more use.
+    ///
+    /// [signer]: signer/trait.AccountSigner.html
+    /// [`change_key`]: struct.Account.html#method.change_key
+    pub fn change_signer(&self, signer: Arc<dyn AccountSigner>) -> Result<()> {
+        let url = &self.inner.api_directory.keyChange;
+        self.inner.transport.change_key(url, signer.clone())?;
+
+        if let Some(pem) = signer.private_key_pem() {
+            debug!("Persist changed acme account key");
+            let pem_key = account_key_persist_key(&self.inner.realm);
+            self.inner.persist.put(&pem_key, &pem)?;
+        }
+
+        Ok(())
     }
 
     /// Get an already issued and [downloaded] certificate.
@@ -80,28 +237,7 @@ impl<P: Persist> Account<P> {
     /// [downloaded]: order/struct.CertOrder.html#method.download_and_save_cert
     /// [valid days left]: struct.Certificate.html#method.valid_days_left
     pub fn certificate(&self, primary_name: &str) -> Result<Option<Certificate>> {
-        // details needed for persistence
-        let realm = &self.inner.realm;
-        let persist = &self.inner.persist;
-
-        // read primary key
-        let pk_key = PersistKey::new(realm, PersistKind::PrivateKey, primary_name);
-        debug!("Read private key: {}", pk_key);
-        let private_key = persist
-            .get(&pk_key)?
-            .and_then(|s| String::from_utf8(s).ok());
-
-        // read certificate
-        let pk_crt = PersistKey::new(realm, PersistKind::Certificate, primary_name);
-        debug!("Read certificate: {}", pk_crt);
-        let certificate = persist
-            .get(&pk_crt)?
-            .and_then(|s| String::from_utf8(s).ok());
-
-        Ok(match (private_key, certificate) {
-            (Some(k), Some(c)) => Some(Certificate::new(k, c)),
-            _ => None,
-        })
+        read_certificate(&self.inner.persist, &self.inner.realm, primary_name)
     }
 
     /// Create a new order to issue a certificate for this account.
@@ -115,24 +251,29 @@ impl<P: Persist> Account<P> {
     /// Every call creates a new order with the ACME API provider, even when the domain
     /// names supplied are exactly the same.
     ///
+    /// Rate limits fail with [`Error::RateLimited`]. With an [order ledger] set on the
+    /// directory, orders that would obviously be rate limited fail without calling the
+    /// ACME API.
+    ///
     /// [100 names]: https://letsencrypt.org/docs/rate-limits/
+    /// [`Error::RateLimited`]: enum.Error.html#variant.RateLimited
+    /// [order ledger]: struct.Directory.html#method.with_order_ledger
     pub fn new_order(&self, primary_name: &str, alt_names: &[&str]) -> Result<NewOrder<P>> {
-        // construct the identifiers
-        let prim_arr = [primary_name];
-        let domains = prim_arr.iter().chain(alt_names);
-        let order = ApiOrder {
-            identifiers: domains
-                .map(|s| ApiIdentifier {
-                    _type: "dns".into(),
-                    value: s.to_string(),
-                })
-                .collect(),
-            ..Default::default()
-        };
+        let order = new_order_request(primary_name, alt_names);
 
         let new_order_url = &self.inner.api_directory.newOrder;
 
-        let res = self.inner.transport.call(new_order_url, &order)?;
+        // refuse orders the ledger knows would be rate limited.
+        let names: Vec<_> = [primary_name].iter().chain(alt_names).copied().collect();
+        if let Some(ledger) = &self.inner.ledger {
+            ledger.check(&names)?;
+        }
+
+        let res = self.inner.transport.call(new_order_url, &order);
+        if let Some(ledger) = &self.inner.ledger {
+            ledger.record(&names, &res);
+        }
+        let res = res?;
         let order_url = req_expect_header(&res, "location")?;
         let api_order: ApiOrder = read_json(res)?;
 
@@ -147,13 +288,7 @@ impl<P: Persist> Account<P> {
     ///
     /// [`certificate`]: struct.Account.html#method.certificate
     pub fn revoke_certificate(&self, cert: &Certificate, reason: RevocationReason) -> Result<()> {
-        // convert to base64url of the DER (which is not PEM).
-        let certificate = base64url(&cert.certificate_der());
-
-        let revoc = ApiRevocation {
-            certificate,
-            reason: reason as usize,
-        };
+        let revoc = revocation_request(cert, reason)?;
 
         let url = &self.inner.api_directory.revokeCert;
         self.inner.transport.call(url, &revoc)?;
@@ -162,11 +297,70 @@ impl<P: Persist> Account<P> {
     }
 
     /// Access the underlying JSON object for debugging.
+    ///
+    /// This is the account state as of the last call to the account URL, see [`refresh`].
+    ///
+    /// [`refresh`]: struct.Account.html#method.refresh
     pub fn api_account(&self) -> &ApiAccount {
-        &self.inner.api_account
+        &self.api_account
+    }
+}
+
+/// Read an already downloaded certificate and its private key from the persistence.
+pub(crate) fn read_certificate<P: Persist>(
+    persist: &P,
+    realm: &str,
+    primary_name: &str,
+) -> Result<Option<Certificate>> {
+    // read primary key
+    let pk_key = PersistKey::new(realm, PersistKind::PrivateKey, primary_name);
+    debug!("Read private key: {}", pk_key);
+    let private_key = persist
+        .get(&pk_key)?
+        .and_then(|s| String::from_utf8(s).ok());
+
+    // read certificate
+    let pk_crt = PersistKey::new(realm, PersistKind::Certificate, primary_name);
+    debug!("Read certificate: {}", pk_crt);
+    let certificate = persist
+        .get(&pk_crt)?
+        .and_then(|s| String::from_utf8(s).ok());
+
+    Ok(match (private_key, certificate) {
+        (Some(k), Some(c)) => Some(Certificate::new(k, c)),
+        _ => None,
+    })
+}
+
+/// The `newOrder` request for the domains.
+pub(crate) fn new_order_request(primary_name: &str, alt_names: &[&str]) -> ApiOrder {
+    // construct the identifiers
+    let prim_arr = [primary_name];
+    let domains = prim_arr.iter().chain(alt_names);
+    ApiOrder {
+        identifiers: domains
+            .map(|s| ApiIdentifier {
+                _type: "dns".into(),
+                value: s.to_string(),
+            })
+            .collect(),
+        ..Default::default()
     }
 }
 
+/// The `revokeCert` request for the certificate.
+pub(crate) fn revocation_request(
+    cert: &Certificate,
+    reason: RevocationReason,
+) -> Result<ApiRevocation> {
+    // convert to base64url of the DER (which is not PEM).
+    let certificate = base64url(&cert.certificate_der()?);
+    Ok(ApiRevocation {
+        certificate,
+        reason: reason as usize,
+    })
+}
+
 /// Enumeration of reasons for revocation.
 ///
 /// The reason codes are taken from [rfc5280](https://tools.ietf.org/html/rfc5280#section-5.3.1).
@@ -199,4 +393,117 @@ mod test {
         let _ = acc.new_order("acmetest.example.com", &[])?;
         Ok(())
     }
+
+    #[test]
+    fn test_change_key() -> Result<()> {
+        let server = crate::test::with_directory_server();
+        let url = DirectoryUrl::Other(&server.dir_url);
+        let persist = MemoryPersist::new();
+        let dir = Directory::from_url(persist, url)?;
+        let acc = dir.account("foo@bar.com")?;
+        let old_pem = acc.acme_private_key_pem();
+        acc.change_key()?;
+        let new_pem = acc.acme_private_key_pem();
+        assert!(old_pem != new_pem);
+        // the persisted key is the new one
+        let acc2 = dir.account("foo@bar.com")?;
+        assert_eq!(new_pem, acc2.acme_private_key_pem());
+        // and the account keeps working
+        let _ = acc.new_order("acmetest.example.com", &[])?;
+        // also when changing algorithm
+        acc.change_key_algorithm(AccountKeyAlgorithm::EdDSA)?;
+        let _ = acc.new_order("acmetest.example.com", &[])?;
+        Ok(())
+    }
+
+    #[test]
+    fn test_update_and_deactivate() -> Result<()> {
+        let server = crate::test::with_directory_server();
+        let url = DirectoryUrl::Other(&server.dir_url);
+        let persist = MemoryPersist::new();
+        let dir = Directory::from_url(persist, url)?;
+        let mut acc = dir.account("foo@bar.com")?;
+        assert!(acc.account_url().ends_with("/acme/acct/7728515"));
+
+        acc.update_contacts(vec!["mailto:karl@bar.com".into()])?;
+        assert_eq!(acc.api_account().contact, ["mailto:karl@bar.com"]);
+
+        acc.refresh()?;
+        assert!(acc.api_account().is_status_valid());
+
+        acc.deactivate()?;
+        assert!(acc.api_account().is_status_deactivated());
+        assert!(!acc.api_account().is_status_revoked());
+        Ok(())
+    }
+
+    #[test]
+    fn test_user_action_required() -> Result<()> {
+        let server = crate::test::with_directory_server();
+        // a directory without terms, so the builder doesn't insist on agreeing.
+        let url = format!("{}/eab", server.dir_url);
+        let persist = MemoryPersist::new();
+        let dir = Directory::from_url(persist, DirectoryUrl::Other(&url))?;
+        let eab = ExternalAccountBinding::new("kid-1", "c2VjcmV0")?;
+        let mut acc = dir
+            .account_builder("foo@bar.com")
+            .external_account_binding(eab)
+            .build()?;
+        match acc.new_order("acmetest.example.com", &[]) {
+            Err(Error::UserActionRequired(p)) => assert!(p.instance.is_some()),
+            x => panic!("Expected UserActionRequired: {:?}", x.err()),
+        }
+        acc.agree_to_terms_of_service()?;
+        assert!(acc.api_account().termsOfServiceAgreed());
+        let _ = acc.new_order("acmetest.example.com", &[])?;
+        Ok(())
+    }
+
+    #[test]
+    fn test_rate_limited() -> Result<()> {
+        use crate::http::HttpResponse;
+        use std::sync::Arc;
+        use std::time::{Duration, SystemTime};
+        let client = crate::test::memory_client(|req| match &req.url[..] {
+            "mem:/new-order" => Some(
+                HttpResponse::new(429)
+                    .with_header("Content-Type", "application/problem+json")
+                    .with_header("Retry-After", "3600")
+                    .with_body(
+                        r#"{"type":"urn:ietf:params:acme:error:rateLimited",
+                        "detail":"too many certificates already issued"}"#,
+                    ),
+            ),
+            _ => None,
+        });
+        let url = DirectoryUrl::Other("mem:/directory");
+        let ledger = OrderLedger::lets_encrypt();
+        let dir =
+            Directory::from_url_with_client(MemoryPersist::new(), url, Arc::new(client.clone()))?
+                .with_order_ledger(ledger);
+        let acc = dir.account("foo@bar.com")?;
+
+        let soon = SystemTime::now() + Duration::from_secs(3500);
+        match acc.new_order("www.example.com", &[]) {
+            Err(Error::RateLimited {
+                problem,
+                retry_at: Some(t),
+            }) => {
+                assert!(problem.is_rate_limited());
+                assert!(t > soon);
+            }
+            x => panic!("Expected RateLimited: {:?}", x.err()),
+        }
+
+        // the ledger refuses the next order for the domain without asking the API
+        let calls = client.requests().len();
+        match acc.new_order("example.com", &["foo.example.com"]) {
+            Err(Error::RateLimited {
+                retry_at: Some(t), ..
+            }) => assert!(t > soon),
+            x => panic!("Expected RateLimited: {:?}", x.err()),
+        }
+        assert_eq!(client.requests().len(), calls);
+        Ok(())
+    }
 }
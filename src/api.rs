@@ -9,6 +9,8 @@ use serde::{
     Deserialize, Serialize,
 };
 
+use crate::ProblemKind;
+
 /// Serializes to `""`
 pub struct ApiEmptyString;
 impl Serialize for ApiEmptyString {
@@ -38,17 +40,33 @@ pub struct ApiProblem {
     pub _type: String,
     #[serde(skip_serializing_if = "Option::is_none")]
     pub detail: Option<String>,
+    /// HTTP status of the response with the problem.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub status: Option<u16>,
     #[serde(skip_serializing_if = "Option::is_none")]
     pub subproblems: Option<Vec<ApiSubproblem>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub instance: Option<String>,
 }
 
 impl ApiProblem {
+    pub fn kind(&self) -> ProblemKind {
+        ProblemKind::from_type(&self._type)
+    }
     pub fn is_bad_nonce(&self) -> bool {
-        self._type == "badNonce"
+        self.kind() == ProblemKind::BadNonce
+    }
+    pub fn is_account_does_not_exist(&self) -> bool {
+        self.kind() == ProblemKind::AccountDoesNotExist
+    }
+    pub fn is_user_action_required(&self) -> bool {
+        self.kind() == ProblemKind::UserActionRequired
+    }
+    pub fn is_rate_limited(&self) -> bool {
+        self.kind() == ProblemKind::RateLimited
     }
     pub fn is_jwt_verification_error(&self) -> bool {
-        (self._type == "urn:acme:error:malformed"
-            || self._type == "urn:ietf:params:acme:error:malformed")
+        self.kind() == ProblemKind::Malformed
             && self
                 .detail
                 .as_ref()
@@ -75,6 +93,12 @@ pub struct ApiSubproblem {
     pub identifier: Option<ApiIdentifier>,
 }
 
+impl ApiSubproblem {
+    pub fn kind(&self) -> ProblemKind {
+        ProblemKind::from_type(&self._type)
+    }
+}
+
 #[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize, Default)]
 pub struct ApiDirectory {
     pub newNonce: String,
@@ -100,6 +124,18 @@ pub struct ApiDirectoryMeta {
     pub externalAccountRequired: Option<bool>,
 }
 
+impl ApiDirectory {
+    pub fn termsOfService(&self) -> Option<&str> {
+        self.meta.as_ref().and_then(|m| m.termsOfService.as_deref())
+    }
+    pub fn externalAccountRequired(&self) -> bool {
+        self.meta
+            .as_ref()
+            .map(|m| m.externalAccountRequired())
+            .unwrap_or(false)
+    }
+}
+
 impl ApiDirectoryMeta {
     pub fn externalAccountRequired(&self) -> bool {
         self.externalAccountRequired.unwrap_or(false)
@@ -119,11 +155,16 @@ impl ApiDirectoryMeta {
 pub struct ApiAccount {
     #[serde(skip_serializing_if = "Option::is_none")]
     pub status: Option<String>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
     pub contact: Vec<String>,
     #[serde(skip_serializing_if = "Option::is_none")]
     pub termsOfServiceAgreed: Option<bool>,
     #[serde(skip_serializing_if = "Option::is_none")]
     pub orders: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub externalAccountBinding: Option<ApiJws>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub onlyReturnExisting: Option<bool>,
 }
 
 impl ApiAccount {
@@ -139,6 +180,30 @@ impl ApiAccount {
     pub fn termsOfServiceAgreed(&self) -> bool {
         self.termsOfServiceAgreed.unwrap_or(false)
     }
+    pub fn onlyReturnExisting(&self) -> bool {
+        self.onlyReturnExisting.unwrap_or(false)
+    }
+}
+
+/// Replaces the contacts of an account. Unlike [`ApiAccount`], an empty list is
+/// sent, which removes all contacts.
+///
+/// [`ApiAccount`]: struct.ApiAccount.html
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize, Default)]
+pub struct ApiContactUpdate {
+    pub contact: Vec<String>,
+}
+
+// {
+//   "protected": "eyJhbGciOiJIUzI1NiIsImtpZCI6ImtpZC0xIiwidXJsIjoi...",
+//   "payload": "eyJjcnYiOiJQLTI1NiIsImt0eSI6IkVDIiwieCI6...",
+//   "signature": "cVwGL2xhQ7c8QnmyPTCTwnvyMkJ1CsOaHp3oaVkYZ1I"
+// }
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize, Default)]
+pub struct ApiJws {
+    pub protected: String,
+    pub payload: String,
+    pub signature: String,
 }
 
 // {
@@ -355,4 +420,32 @@ mod test {
         let x = serde_json::to_string(&ApiEmptyObject).unwrap();
         assert_eq!("{}", x);
     }
+
+    #[test]
+    fn test_api_problem_subproblems() {
+        let x: ApiProblem = serde_json::from_str(
+            r#"{
+            "type": "urn:ietf:params:acme:error:malformed",
+            "detail": "Some of the identifiers requested were rejected",
+            "subproblems": [
+                {
+                    "type": "urn:ietf:params:acme:error:malformed",
+                    "detail": "Invalid underscore in DNS name \"_example.org\"",
+                    "identifier": {"type": "dns", "value": "_example.org"}
+                },
+                {
+                    "type": "urn:ietf:params:acme:error:rejectedIdentifier",
+                    "detail": "This CA will not issue for \"example.net\"",
+                    "identifier": {"type": "dns", "value": "example.net"}
+                }
+            ]
+        }"#,
+        )
+        .unwrap();
+        assert_eq!(x.kind(), ProblemKind::Malformed);
+        assert!(!x.is_jwt_verification_error());
+        let subs = x.subproblems.unwrap();
+        assert_eq!(subs[1].kind(), ProblemKind::RejectedIdentifier);
+        assert_eq!(subs[1].identifier.as_ref().unwrap().value, "example.net");
+    }
 }
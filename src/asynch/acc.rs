@@ -0,0 +1,234 @@
+use serde::Serialize;
+use std::sync::Arc;
+
+use crate::acc::{
+    account_key_persist_key, new_order_request, read_certificate, revocation_request,
+    signer_private_key_pem, AccountKeyAlgorithm, AcmeKey, RevocationReason,
+};
+use crate::api::{ApiAccount, ApiContactUpdate, ApiDirectory, ApiEmptyString, ApiOrder};
+use crate::asynch::order::{NewOrder, Order};
+use crate::asynch::trans::Transport;
+use crate::cert::Certificate;
+use crate::persist::Persist;
+use crate::req::req_expect_header;
+use crate::signer::AccountSigner;
+use crate::util::read_json;
+use crate::{OrderLedger, Result};
+
+#[derive(Debug)]
+pub(crate) struct AccountInner<P: Persist> {
+    pub persist: P,
+    pub transport: Transport,
+    pub realm: String,
+    pub api_directory: ApiDirectory,
+    pub ledger: Option<OrderLedger>,
+}
+
+/// Async account with an ACME provider.
+///
+/// See the blocking [`Account`] for details.
+///
+/// [`Account`]: ../struct.Account.html
+#[derive(Clone)]
+pub struct Account<P: Persist> {
+    inner: Arc<AccountInner<P>>,
+    api_account: ApiAccount,
+}
+
+impl<P: Persist> Account<P> {
+    pub(crate) fn new(
+        persist: P,
+        transport: Transport,
+        realm: &str,
+        api_account: ApiAccount,
+        api_directory: ApiDirectory,
+        ledger: Option<OrderLedger>,
+    ) -> Self {
+        Account {
+            inner: Arc::new(AccountInner {
+                persist,
+                transport,
+                realm: realm.to_string(),
+                api_directory,
+                ledger,
+            }),
+            api_account,
+        }
+    }
+
+    /// Private key for this account.
+    ///
+    /// See [`Account::acme_private_key_pem`].
+    ///
+    /// [`Account::acme_private_key_pem`]: ../struct.Account.html#method.acme_private_key_pem
+    pub fn acme_private_key_pem(&self) -> String {
+        self.try_acme_private_key_pem()
+            .expect("acme_private_key_pem")
+    }
+
+    /// Private key for this account, or an error if the [signer] doesn't expose it.
+    ///
+    /// [signer]: ../signer/trait.AccountSigner.html
+    pub fn try_acme_private_key_pem(&self) -> Result<String> {
+        signer_private_key_pem(&*self.inner.transport.signer())
+    }
+
+    /// The account URL. This is also the key id used to sign requests to the ACME API.
+    pub fn account_url(&self) -> String {
+        self.inner.transport.key_id().to_string()
+    }
+
+    /// Fetch the current state of the account from the ACME API.
+    pub async fn refresh(&mut self) -> Result<()> {
+        self.update_account(&ApiEmptyString).await
+    }
+
+    /// Replace the contacts of the account.
+    pub async fn update_contacts(&mut self, contact: Vec<String>) -> Result<()> {
+        let acc = ApiContactUpdate { contact };
+        self.update_account(&acc).await
+    }
+
+    /// Deactivate the account. This can not be undone.
+    pub async fn deactivate(&mut self) -> Result<()> {
+        let acc = ApiAccount {
+            status: Some("deactivated".into()),
+            ..Default::default()
+        };
+        self.update_account(&acc).await
+    }
+
+    /// URL of the terms of service of the ACME API provider, if it has any.
+    pub fn terms_of_service(&self) -> Option<&str> {
+        self.inner.api_directory.termsOfService()
+    }
+
+    /// Agree to the current terms of service.
+    pub async fn agree_to_terms_of_service(&mut self) -> Result<()> {
+        let acc = ApiAccount {
+            termsOfServiceAgreed: Some(true),
+            ..Default::default()
+        };
+        self.update_account(&acc).await
+    }
+
+    async fn update_account<T: Serialize + Sync + ?Sized>(&mut self, body: &T) -> Result<()> {
+        let url = self.account_url();
+        let res = self.inner.transport.call(&url, body).await?;
+        let api_account: ApiAccount = read_json(res)?;
+        self.api_account = api_account;
+        Ok(())
+    }
+
+    /// Roll over the account to a new private key using the same algorithm.
+    ///
+    /// See [`Account::change_key`].
+    ///
+    /// [`Account::change_key`]: ../struct.Account.html#method.change_key
+    pub async fn change_key(&self) -> Result<()> {
+        let algorithm = self.inner.transport.signer().algorithm();
+        self.change_key_algorithm(algorithm).await
+    }
+
+    /// Roll over the account to a new private key using the given algorithm.
+    pub async fn change_key_algorithm(&self, algorithm: AccountKeyAlgorithm) -> Result<()> {
+        let new_key = AcmeKey::generate(algorithm)?;
+        self.change_signer(Arc::new(new_key)).await
+    }
+
+    /// Roll over the account to the key of the given [signer].
+    ///
+    /// [signer]: ../signer/trait.AccountSigner.html
+    pub async fn change_signer(&self, signer: Arc<dyn AccountSigner>) -> Result<()> {
+        let url = &self.inner.api_directory.keyChange;
+        self.inner.transport.change_key(url, signer.clone()).await?;
+
+        if let Some(pem) = signer.private_key_pem() {
+            debug!("Persist changed acme account key");
+            let pem_key = account_key_persist_key(&self.inner.realm);
+            self.inner.persist.put(&pem_key, &pem)?;
+        }
+
+        Ok(())
+    }
+
+    /// Get an already issued and downloaded certificate from the persistence.
+    pub fn certificate(&self, primary_name: &str) -> Result<Option<Certificate>> {
+        read_certificate(&self.inner.persist, &self.inner.realm, primary_name)
+    }
+
+    /// Create a new order to issue a certificate for this account.
+    ///
+    /// See [`Account::new_order`].
+    ///
+    /// [`Account::new_order`]: ../struct.Account.html#method.new_order
+    pub async fn new_order(&self, primary_name: &str, alt_names: &[&str]) -> Result<NewOrder<P>> {
+        let order = new_order_request(primary_name, alt_names);
+
+        let new_order_url = &self.inner.api_directory.newOrder;
+
+        // refuse orders the ledger knows would be rate limited.
+        let names: Vec<_> = [primary_name].iter().chain(alt_names).copied().collect();
+        if let Some(ledger) = &self.inner.ledger {
+            ledger.check(&names)?;
+        }
+
+        let res = self.inner.transport.call(new_order_url, &order).await;
+        if let Some(ledger) = &self.inner.ledger {
+            ledger.record(&names, &res);
+        }
+        let res = res?;
+        let order_url = req_expect_header(&res, "location")?;
+        let api_order: ApiOrder = read_json(res)?;
+
+        let order = Order::new(&self.inner, api_order, order_url);
+        Ok(NewOrder { order })
+    }
+
+    /// Revoke a certificate for the reason given.
+    pub async fn revoke_certificate(
+        &self,
+        cert: &Certificate,
+        reason: RevocationReason,
+    ) -> Result<()> {
+        let revoc = revocation_request(cert, reason)?;
+
+        let url = &self.inner.api_directory.revokeCert;
+        self.inner.transport.call(url, &revoc).await?;
+
+        Ok(())
+    }
+
+    /// Access the underlying JSON object for debugging.
+    pub fn api_account(&self) -> &ApiAccount {
+        &self.api_account
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::asynch::{block_on, BlockingClient, Directory};
+    use crate::persist::*;
+    use crate::*;
+    use std::sync::Arc;
+
+    #[test]
+    fn test_change_key() -> Result<()> {
+        let server = crate::test::with_directory_server();
+        let url = DirectoryUrl::Other(&server.dir_url);
+        let client = Arc::new(BlockingClient::default());
+        block_on(async {
+            let dir = Directory::from_url(MemoryPersist::new(), url, client).await?;
+            let acc = dir.account("foo@bar.com").await?;
+            let old_pem = acc.acme_private_key_pem();
+            acc.change_key().await?;
+            assert!(old_pem != acc.acme_private_key_pem());
+            // the persisted key is the new one
+            let acc2 = dir.account("foo@bar.com").await?;
+            assert_eq!(acc.acme_private_key_pem(), acc2.acme_private_key_pem());
+            // and the account keeps working
+            let _ = acc.new_order("acmetest.example.com", &[]).await?;
+            Ok(())
+        })
+    }
+}
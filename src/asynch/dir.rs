@@ -0,0 +1,363 @@
+use std::sync::Arc;
+
+use crate::acc::{AccountKeyAlgorithm, AcmeKey, ExternalAccountBinding};
+use crate::api::{ApiAccount, ApiDirectory};
+use crate::asynch::trans::Transport;
+use crate::asynch::{Account, AsyncHttpClient};
+use crate::dir::{
+    account_signer, existing_account, new_account, new_account_binding, save_account_key,
+    AccountOpts, DirectoryUrl, KeySource,
+};
+use crate::http::HttpRequest;
+use crate::persist::Persist;
+use crate::req::{req_expect_header, req_handle_error};
+use crate::signer::AccountSigner;
+use crate::trans::NoncePool;
+use crate::util::read_json;
+use crate::{Error, OrderLedger, Result, RetryPolicy};
+
+/// Async entry point for accessing an ACME API.
+///
+/// See the blocking [`Directory`] for details on accounts and persistence.
+///
+/// [`Directory`]: ../struct.Directory.html
+#[derive(Clone)]
+pub struct Directory<P: Persist> {
+    persist: P,
+    client: Arc<dyn AsyncHttpClient>,
+    nonce_pool: Arc<NoncePool>,
+    api_directory: ApiDirectory,
+    ledger: Option<OrderLedger>,
+    retry_policy: RetryPolicy,
+}
+
+impl<P: Persist> Directory<P> {
+    /// Create a directory over a persistence implementation and directory url, using
+    /// the client for all requests to the ACME API.
+    pub async fn from_url(
+        persist: P,
+        url: DirectoryUrl<'_>,
+        client: Arc<dyn AsyncHttpClient>,
+    ) -> Result<Directory<P>> {
+        let req = HttpRequest::get(url.to_url());
+        let res = req_handle_error(client.request(&req).await?)?;
+        let api_directory: ApiDirectory = read_json(res)?;
+        let nonce_pool = Arc::new(NoncePool::new(&api_directory.newNonce));
+        Ok(Directory {
+            persist,
+            client,
+            nonce_pool,
+            api_directory,
+            ledger: None,
+            retry_policy: RetryPolicy::default(),
+        })
+    }
+
+    /// Access an account identified by a contact email.
+    ///
+    /// See [`Directory::account`].
+    ///
+    /// [`Directory::account`]: ../struct.Directory.html#method.account
+    pub async fn account(&self, contact_email: &str) -> Result<Account<P>> {
+        // Contact email is the persistence realm when using this method.
+        let contact = vec![format!("mailto:{}", contact_email)];
+        self.account_with_realm(contact_email, contact).await
+    }
+
+    /// Access an account using a lower level method.
+    ///
+    /// See [`Directory::account_with_realm`].
+    ///
+    /// [`Directory::account_with_realm`]: ../struct.Directory.html#method.account_with_realm
+    pub async fn account_with_realm(
+        &self,
+        realm: &str,
+        contact: Vec<String>,
+    ) -> Result<Account<P>> {
+        self.do_account(realm, new_account(contact), AccountOpts::default())
+            .await
+    }
+
+    /// Access an account, binding it to an account with the ACME API provider
+    /// when it is created.
+    ///
+    /// See [`Directory::account_with_eab`].
+    ///
+    /// [`Directory::account_with_eab`]: ../struct.Directory.html#method.account_with_eab
+    pub async fn account_with_eab(
+        &self,
+        realm: &str,
+        contact: Vec<String>,
+        eab: &ExternalAccountBinding,
+    ) -> Result<Account<P>> {
+        let opts = AccountOpts {
+            eab: Some(eab),
+            ..Default::default()
+        };
+        self.do_account(realm, new_account(contact), opts).await
+    }
+
+    /// Build access to an account under the persistence `realm`, with explicit
+    /// agreement to the terms of service.
+    ///
+    /// See [`Directory::account_builder`].
+    ///
+    /// [`Directory::account_builder`]: ../struct.Directory.html#method.account_builder
+    pub fn account_builder(&self, realm: &str) -> AccountBuilder<'_, P> {
+        AccountBuilder {
+            dir: self,
+            realm: realm.to_string(),
+            contact: vec![],
+            terms_of_service_agreed: false,
+            eab: None,
+            key_algorithm: AccountKeyAlgorithm::default(),
+            signer: None,
+        }
+    }
+
+    /// Access an existing account using the private key persisted for the `realm`.
+    ///
+    /// See [`Directory::existing_account`].
+    ///
+    /// [`Directory::existing_account`]: ../struct.Directory.html#method.existing_account
+    pub async fn existing_account(&self, realm: &str) -> Result<Account<P>> {
+        self.do_account(realm, existing_account(), AccountOpts::default())
+            .await
+    }
+
+    /// Recover an existing account from a PEM encoded private key.
+    ///
+    /// See [`Directory::existing_account_from_pem`].
+    ///
+    /// [`Directory::existing_account_from_pem`]: ../struct.Directory.html#method.existing_account_from_pem
+    pub async fn existing_account_from_pem(
+        &self,
+        realm: &str,
+        private_key_pem: &str,
+    ) -> Result<Account<P>> {
+        let acme_key = AcmeKey::from_pem(private_key_pem.as_bytes())?;
+        let opts = AccountOpts {
+            signer: Some(Arc::new(acme_key)),
+            ..Default::default()
+        };
+        self.do_account(realm, existing_account(), opts).await
+    }
+
+    async fn do_account(
+        &self,
+        realm: &str,
+        mut acc: ApiAccount,
+        opts: AccountOpts<'_>,
+    ) -> Result<Account<P>> {
+        let (signer, source) = account_signer(&self.persist, realm, &acc, &opts)?;
+
+        let mut transport =
+            Transport::new(&self.client, &self.nonce_pool, signer, &self.retry_policy);
+        let new_account_url = &self.api_directory.newAccount;
+
+        // a provided key might have an account already, see the blocking Directory.
+        let mut res = None;
+        if source == KeySource::Provided && !acc.onlyReturnExisting() {
+            match transport
+                .call_jwk(new_account_url, &existing_account())
+                .await
+            {
+                Ok(r) => res = Some(r),
+                Err(Error::AccountDoesNotExist) => {}
+                Err(e) => return Err(e),
+            }
+        }
+
+        let res = match res {
+            Some(res) => res,
+            None => {
+                if source.needs_binding(&acc) {
+                    let signer = transport.signer();
+                    new_account_binding(&self.api_directory, &mut acc, opts.eab, &*signer)?;
+                }
+                transport.call_jwk(new_account_url, &acc).await?
+            }
+        };
+        let kid = req_expect_header(&res, "location")?;
+        debug!("Key id is: {}", kid);
+        let api_account: ApiAccount = read_json(res)?;
+
+        transport.set_key_id(kid);
+
+        if source.save_key() {
+            save_account_key(&self.persist, realm, &*transport.signer())?;
+        }
+
+        Ok(Account::new(
+            self.persist.clone(),
+            transport,
+            realm,
+            api_account,
+            self.api_directory.clone(),
+            self.ledger.clone(),
+        ))
+    }
+
+    /// Keep track of new orders in the [ledger], refusing orders that would hit a
+    /// rate limit of the ACME API provider.
+    ///
+    /// Applies to accounts accessed after this call.
+    ///
+    /// [ledger]: ../struct.OrderLedger.html
+    pub fn with_order_ledger(mut self, ledger: OrderLedger) -> Self {
+        self.ledger = Some(ledger);
+        self
+    }
+
+    /// Retry calls failing with transient errors according to the [policy].
+    ///
+    /// Applies to accounts accessed after this call.
+    ///
+    /// [policy]: ../struct.RetryPolicy.html
+    pub fn with_retry_policy(mut self, retry_policy: RetryPolicy) -> Self {
+        self.retry_policy = retry_policy;
+        self
+    }
+
+    /// URL of the terms of service of the ACME API provider, if it has any.
+    pub fn terms_of_service(&self) -> Option<&str> {
+        self.api_directory.termsOfService()
+    }
+
+    /// Whether the ACME API provider requires an [external account binding]
+    /// to create new accounts.
+    ///
+    /// [external account binding]: ../struct.ExternalAccountBinding.html
+    pub fn external_account_required(&self) -> bool {
+        self.api_directory.externalAccountRequired()
+    }
+
+    /// Access the underlying JSON object for debugging.
+    pub fn api_directory(&self) -> &ApiDirectory {
+        &self.api_directory
+    }
+}
+
+/// Async builder for account access that requires explicit agreement to the terms
+/// of service.
+///
+/// See the blocking [`AccountBuilder`] for details.
+///
+/// [`AccountBuilder`]: ../struct.AccountBuilder.html
+pub struct AccountBuilder<'a, P: Persist> {
+    dir: &'a Directory<P>,
+    realm: String,
+    contact: Vec<String>,
+    terms_of_service_agreed: bool,
+    eab: Option<ExternalAccountBinding>,
+    key_algorithm: AccountKeyAlgorithm,
+    signer: Option<Arc<dyn AccountSigner>>,
+}
+
+impl<'a, P: Persist> AccountBuilder<'a, P> {
+    /// URL of the terms of service that must be agreed to, if the ACME API
+    /// provider has any.
+    pub fn terms_of_service(&self) -> Option<&str> {
+        self.dir.terms_of_service()
+    }
+
+    /// Set the contacts of the account, such as `mailto:foo@bar.com`.
+    pub fn contact(mut self, contact: Vec<String>) -> Self {
+        self.contact = contact;
+        self
+    }
+
+    /// Record whether the user agreed to the terms of service.
+    pub fn agree_to_terms_of_service(mut self, agreed: bool) -> Self {
+        self.terms_of_service_agreed = agreed;
+        self
+    }
+
+    /// Bind a new account to an account with the ACME API provider.
+    pub fn external_account_binding(mut self, eab: ExternalAccountBinding) -> Self {
+        self.eab = Some(eab);
+        self
+    }
+
+    /// Algorithm of the account key, used if a new key is created. Defaults to `ES256`.
+    pub fn key_algorithm(mut self, key_algorithm: AccountKeyAlgorithm) -> Self {
+        self.key_algorithm = key_algorithm;
+        self
+    }
+
+    /// Sign requests using the given [signer] instead of a key from the persistence.
+    ///
+    /// [signer]: ../signer/trait.AccountSigner.html
+    pub fn signer(mut self, signer: Arc<dyn AccountSigner>) -> Self {
+        self.signer = Some(signer);
+        self
+    }
+
+    /// Access the account, creating it if there is no persisted key for the realm.
+    ///
+    /// See [`AccountBuilder::build`].
+    ///
+    /// [`AccountBuilder::build`]: ../struct.AccountBuilder.html#method.build
+    pub async fn build(self) -> Result<Account<P>> {
+        let acc = ApiAccount {
+            contact: self.contact,
+            termsOfServiceAgreed: Some(self.terms_of_service_agreed).filter(|a| *a),
+            ..Default::default()
+        };
+        let opts = AccountOpts {
+            eab: self.eab.as_ref(),
+            key_algorithm: self.key_algorithm,
+            signer: self.signer,
+        };
+        self.dir.do_account(&self.realm, acc, opts).await
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::asynch::{block_on, BlockingClient};
+    use crate::persist::*;
+    use crate::Error;
+
+    #[test]
+    fn test_existing_account() -> Result<()> {
+        let server = crate::test::with_directory_server();
+        let url = DirectoryUrl::Other(&server.dir_url);
+        let client = Arc::new(BlockingClient::default());
+        block_on(async {
+            let dir = Directory::from_url(MemoryPersist::new(), url, client).await?;
+            match dir.existing_account("foo@bar.com").await {
+                Err(Error::AccountDoesNotExist) => {}
+                x => panic!("Expected AccountDoesNotExist: {:?}", x.err()),
+            }
+            let acc1 = dir.account("foo@bar.com").await?;
+            let acc2 = dir.existing_account("foo@bar.com").await?;
+            assert_eq!(acc1.account_url(), acc2.account_url());
+            Ok(())
+        })
+    }
+
+    #[test]
+    fn test_account_builder() -> Result<()> {
+        let server = crate::test::with_directory_server();
+        let url = DirectoryUrl::Other(&server.dir_url);
+        let client = Arc::new(BlockingClient::default());
+        block_on(async {
+            let dir = Directory::from_url(MemoryPersist::new(), url, client).await?;
+            assert!(!dir.external_account_required());
+            match dir.account_builder("foo@bar.com").build().await {
+                Err(Error::TermsOfServiceNotAgreed(_)) => {}
+                x => panic!("Expected TermsOfServiceNotAgreed: {:?}", x.err()),
+            }
+            let acc = dir
+                .account_builder("foo@bar.com")
+                .agree_to_terms_of_service(true)
+                .key_algorithm(AccountKeyAlgorithm::ES384)
+                .build()
+                .await?;
+            assert!(acc.api_account().is_status_valid());
+            Ok(())
+        })
+    }
+}
@@ -0,0 +1,450 @@
+//! Async API, enabled with the `async` cargo feature.
+//!
+//! This mirrors the blocking API, with the same façades and life cycle:
+//! [`Directory`] -> [`Account`] -> [`NewOrder`] -> [`CsrOrder`] -> [`CertOrder`], where
+//! ownership is proven through [`Auth`] and [`Challenge`]. The JSON types in [`api`]
+//! and the persistence are shared with the blocking API.
+//!
+//! The library doesn't depend on any particular async runtime. All I/O, and the waiting
+//! between polls of the ACME API, goes through an [`AsyncHttpClient`]. Implement it
+//! using the HTTP client and timer of your runtime, or use [`BlockingClient`], which
+//! runs a blocking [`HttpClient`] on a few threads of its own.
+//!
+//! ```no_run
+//! use acme_lib::asynch::{BlockingClient, Directory};
+//! use acme_lib::persist::FilePersist;
+//! use acme_lib::{DirectoryUrl, Error};
+//! use std::sync::Arc;
+//!
+//! async fn request_cert() -> Result<(), Error> {
+//!   let client = Arc::new(BlockingClient::default());
+//!   let url = DirectoryUrl::LetsEncrypt;
+//!   let dir = Directory::from_url(FilePersist::new("."), url, client).await?;
+//!   let acc = dir.account("foo@bar.com").await?;
+//!   let mut ord_new = acc.new_order("mydomain.io", &[]).await?;
+//!   let ord_csr = loop {
+//!     if let Some(ord_csr) = ord_new.confirm_validations() {
+//!       break ord_csr;
+//!     }
+//!     let auths = ord_new.authorizations().await?;
+//!     let chall = auths[0].http_challenge().ok_or("No http challenge")?;
+//!     // place chall.http_proof() under chall.http_token()
+//!     chall.validate(5000).await?;
+//!     ord_new.refresh().await?;
+//!   };
+//!   let pkey_pri = acme_lib::create_p384_key();
+//!   let ord_cert = ord_csr.finalize_pkey(pkey_pri, 5000).await?;
+//!   let cert = ord_cert.download_and_save_cert().await?;
+//!   Ok(())
+//! }
+//! ```
+//!
+//! [`Directory`]: struct.Directory.html
+//! [`Account`]: struct.Account.html
+//! [`NewOrder`]: struct.NewOrder.html
+//! [`CsrOrder`]: struct.CsrOrder.html
+//! [`CertOrder`]: struct.CertOrder.html
+//! [`Auth`]: struct.Auth.html
+//! [`Challenge`]: struct.Challenge.html
+//! [`api`]: ../api/index.html
+//! [`AsyncHttpClient`]: trait.AsyncHttpClient.html
+//! [`BlockingClient`]: struct.BlockingClient.html
+//! [`HttpClient`]: ../http/trait.HttpClient.html
+use std::future::Future;
+use std::panic::{self, AssertUnwindSafe};
+use std::pin::Pin;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::mpsc::{self, Receiver, RecvTimeoutError, Sender};
+use std::sync::{Arc, Mutex};
+use std::task::{Context, Poll, Waker};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use crate::http::{HttpClient, HttpRequest, HttpResponse, UreqClient};
+use crate::Result;
+
+mod acc;
+mod dir;
+mod order;
+mod trans;
+
+pub use self::acc::Account;
+pub use self::dir::{AccountBuilder, Directory};
+pub use self::order::{AnyChallenge, Auth, CertOrder, Challenge, CsrOrder, NewOrder};
+
+/// Boxed future returned by [`AsyncHttpClient`].
+///
+/// [`AsyncHttpClient`]: trait.AsyncHttpClient.html
+pub type BoxFuture<'a, T> = Pin<Box<dyn Future<Output = T> + Send + 'a>>;
+
+/// Async client making the HTTP requests to the ACME API.
+///
+/// Besides the requests, the client provides the timer used to wait between polls
+/// of the ACME API, which keeps the library independent of the async runtime.
+pub trait AsyncHttpClient: std::fmt::Debug + Send + Sync {
+    /// Make the request and read the whole response.
+    ///
+    /// As for [`HttpClient::request`], error statuses are returned as `Ok`, and `Err`
+    /// is only for failing to get a response at all.
+    ///
+    /// [`HttpClient::request`]: ../http/trait.HttpClient.html#tymethod.request
+    fn request<'a>(&'a self, req: &'a HttpRequest) -> BoxFuture<'a, Result<HttpResponse>>;
+
+    /// Complete after the duration.
+    fn sleep(&self, duration: Duration) -> BoxFuture<'static, ()>;
+}
+
+/// Async client running a blocking [`HttpClient`] on threads.
+///
+/// Requests run on a small pool of worker threads, at most [`MAX_WORKERS`] at a time,
+/// and sleeps are timed by a single timer thread, which makes it work with any async
+/// runtime. The threads are started when first needed and shared by the clones of the
+/// client. Defaults to wrapping a [`UreqClient`].
+///
+/// A request can't be cancelled: dropping the future leaves it running on its worker
+/// until it completes or times out, and more requests than workers wait their turn.
+/// This suits tools and tests. Services making many calls should rather implement
+/// [`AsyncHttpClient`] on the HTTP client and timer of their runtime.
+///
+/// [`HttpClient`]: ../http/trait.HttpClient.html
+/// [`MAX_WORKERS`]: constant.MAX_WORKERS.html
+/// [`UreqClient`]: ../http/struct.UreqClient.html
+/// [`AsyncHttpClient`]: trait.AsyncHttpClient.html
+#[derive(Debug, Clone)]
+pub struct BlockingClient {
+    client: Arc<dyn HttpClient>,
+    threads: Arc<Threads>,
+}
+
+/// Worker threads a [`BlockingClient`] runs requests on.
+///
+/// [`BlockingClient`]: struct.BlockingClient.html
+pub const MAX_WORKERS: usize = 4;
+
+impl BlockingClient {
+    /// Wrap the blocking client.
+    pub fn new(client: Arc<dyn HttpClient>) -> Self {
+        BlockingClient {
+            client,
+            threads: Arc::new(Threads::default()),
+        }
+    }
+}
+
+impl Default for BlockingClient {
+    fn default() -> Self {
+        BlockingClient::new(Arc::new(UreqClient::new()))
+    }
+}
+
+impl AsyncHttpClient for BlockingClient {
+    fn request<'a>(&'a self, req: &'a HttpRequest) -> BoxFuture<'a, Result<HttpResponse>> {
+        let client = self.client.clone();
+        let req = req.clone();
+        let (state, fut) = thread_future();
+        self.threads
+            .run(Box::new(move || complete(&state, client.request(&req))));
+        Box::pin(fut)
+    }
+
+    fn sleep(&self, duration: Duration) -> BoxFuture<'static, ()> {
+        let (state, fut) = thread_future();
+        self.threads.wake_at(Instant::now() + duration, state);
+        Box::pin(fut)
+    }
+}
+
+type Job = Box<dyn FnOnce() + Send>;
+
+/// A sleep for the timer thread to complete.
+type Wakeup = (Instant, Arc<Mutex<ThreadState<()>>>);
+
+/// The threads of a [`BlockingClient`]. Dropping the senders lets the workers exit
+/// once the queued requests are done, and the timer once the pending sleeps are.
+///
+/// [`BlockingClient`]: struct.BlockingClient.html
+#[derive(Debug, Default)]
+struct Threads {
+    workers: Mutex<Workers>,
+    timer: Mutex<Option<Sender<Wakeup>>>,
+}
+
+#[derive(Debug, Default)]
+struct Workers {
+    jobs: Option<Sender<Job>>,
+    receiver: Option<Arc<Mutex<Receiver<Job>>>>,
+    started: usize,
+    idle: Arc<AtomicUsize>,
+}
+
+impl Threads {
+    /// Queue the job, starting another worker if none is idle and there is room.
+    fn run(&self, job: Job) {
+        let mut workers = self.workers.lock().unwrap();
+        if workers.jobs.is_none() {
+            let (tx, rx) = mpsc::channel();
+            workers.jobs = Some(tx);
+            workers.receiver = Some(Arc::new(Mutex::new(rx)));
+        }
+        if workers.idle.load(Ordering::SeqCst) == 0 && workers.started < MAX_WORKERS {
+            workers.started += 1;
+            let receiver = workers.receiver.clone().expect("receiver");
+            let idle = workers.idle.clone();
+            thread::spawn(move || work(&receiver, &idle));
+        }
+        // the receiver is held by the workers, so it can't be gone.
+        let _ = workers.jobs.as_ref().expect("jobs").send(job);
+    }
+
+    /// Complete the future of the state at the instant.
+    fn wake_at(&self, at: Instant, state: Arc<Mutex<ThreadState<()>>>) {
+        let mut timer = self.timer.lock().unwrap();
+        let tx = timer.get_or_insert_with(|| {
+            let (tx, rx) = mpsc::channel();
+            thread::spawn(move || time(&rx));
+            tx
+        });
+        // the timer only exits once the sender is dropped.
+        let _ = tx.send((at, state));
+    }
+}
+
+fn work(receiver: &Mutex<Receiver<Job>>, idle: &AtomicUsize) {
+    loop {
+        idle.fetch_add(1, Ordering::SeqCst);
+        let job = receiver.lock().unwrap().recv();
+        idle.fetch_sub(1, Ordering::SeqCst);
+        match job {
+            // a panicking client mustn't take the worker with it.
+            Ok(job) => drop(panic::catch_unwind(AssertUnwindSafe(job))),
+            Err(_) => return,
+        }
+    }
+}
+
+fn time(receiver: &Receiver<Wakeup>) {
+    let mut pending: Vec<Wakeup> = vec![];
+    let mut open = true;
+    while open || !pending.is_empty() {
+        let now = Instant::now();
+        let (due, later) = pending.into_iter().partition(|(at, _)| *at <= now);
+        pending = later;
+        for (_, state) in due {
+            complete(&state, ());
+        }
+        let next = pending.iter().map(|(at, _)| *at).min();
+        let received = match next {
+            Some(next) => receiver.recv_timeout(next.saturating_duration_since(now)),
+            None if open => receiver.recv().map_err(|_| RecvTimeoutError::Disconnected),
+            None => break,
+        };
+        match received {
+            Ok(wakeup) => pending.push(wakeup),
+            Err(RecvTimeoutError::Timeout) => {}
+            Err(RecvTimeoutError::Disconnected) => {
+                open = false;
+                // wait out the pending sleeps without a sender to wake us.
+                if let Some(next) = next {
+                    thread::sleep(next.saturating_duration_since(Instant::now()));
+                }
+            }
+        }
+    }
+}
+
+/// Run the function on a new thread, completing the future with its result.
+fn on_thread<T, F>(f: F) -> ThreadFuture<T>
+where
+    T: Send + 'static,
+    F: FnOnce() -> T + Send + 'static,
+{
+    let (state, fut) = thread_future();
+    thread::spawn(move || complete(&state, f()));
+    fut
+}
+
+/// A future, and the state that completes it from another thread.
+fn thread_future<T>() -> (Arc<Mutex<ThreadState<T>>>, ThreadFuture<T>) {
+    let state = Arc::new(Mutex::new(ThreadState {
+        result: None,
+        waker: None,
+    }));
+    (state.clone(), ThreadFuture { state })
+}
+
+fn complete<T>(state: &Mutex<ThreadState<T>>, result: T) {
+    let mut lock = state.lock().unwrap();
+    lock.result = Some(result);
+    if let Some(waker) = lock.waker.take() {
+        waker.wake();
+    }
+}
+
+struct ThreadFuture<T> {
+    state: Arc<Mutex<ThreadState<T>>>,
+}
+
+struct ThreadState<T> {
+    result: Option<T>,
+    waker: Option<Waker>,
+}
+
+impl<T> Future for ThreadFuture<T> {
+    type Output = T;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<T> {
+        let mut lock = self.state.lock().unwrap();
+        match lock.result.take() {
+            Some(result) => Poll::Ready(result),
+            None => {
+                lock.waker = Some(cx.waker().clone());
+                Poll::Pending
+            }
+        }
+    }
+}
+
+/// Minimal executor for the tests, which mustn't depend on a runtime either.
+#[cfg(test)]
+fn block_on<F: Future>(fut: F) -> F::Output {
+    use std::task::Wake;
+
+    struct ThreadWaker(thread::Thread);
+
+    impl Wake for ThreadWaker {
+        fn wake(self: Arc<Self>) {
+            self.0.unpark();
+        }
+    }
+
+    let waker = Waker::from(Arc::new(ThreadWaker(thread::current())));
+    let mut cx = Context::from_waker(&waker);
+    let mut fut = Box::pin(fut);
+    loop {
+        match fut.as_mut().poll(&mut cx) {
+            Poll::Ready(v) => return v,
+            Poll::Pending => thread::park(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::persist::MemoryPersist;
+    use crate::DirectoryUrl;
+
+    // futures must be Send to be spawned on multi-threaded runtimes.
+    fn assert_send<T: Send>(t: T) -> T {
+        t
+    }
+
+    #[test]
+    fn test_the_async_order() -> Result<()> {
+        let server = crate::test::with_directory_server();
+        let url = DirectoryUrl::Other(&server.dir_url);
+        let client = Arc::new(BlockingClient::default());
+        block_on(async {
+            let dir = assert_send(Directory::from_url(MemoryPersist::new(), url, client)).await?;
+            let acc = assert_send(dir.account("foo@bar.com")).await?;
+            let mut ord = assert_send(acc.new_order("acmetest.example.com", &[])).await?;
+            let auths = ord.authorizations().await?;
+            assert_eq!(auths.len(), 1);
+            let chall = auths[0].http_challenge().unwrap();
+            assert!(chall.need_validate());
+            assert_send(chall.validate(1)).await?;
+            assert_send(ord.refresh()).await?;
+            let ord = ord.confirm_validations().unwrap();
+            let pkey = crate::create_p256_key();
+            let ord = assert_send(ord.finalize_pkey(pkey, 1)).await?;
+            let cert = ord.download_and_save_cert().await?;
+            assert_eq!(cert.valid_days_left()?, 89);
+            assert_eq!(
+                Some(cert.certificate()),
+                acc.certificate("acmetest.example.com")?
+                    .as_ref()
+                    .map(|c| c.certificate())
+            );
+            Ok(())
+        })
+    }
+
+    #[test]
+    fn test_dns_provider_off_executor() -> Result<()> {
+        use crate::solver::DnsProvider;
+
+        // records the threads the provider is called on.
+        #[derive(Default)]
+        struct Provider(Mutex<Vec<(&'static str, thread::ThreadId)>>);
+
+        impl DnsProvider for Provider {
+            fn present(&self, _name: &str, _value: &str) -> Result<()> {
+                let id = thread::current().id();
+                self.0.lock().unwrap().push(("present", id));
+                Ok(())
+            }
+            fn cleanup(&self, _name: &str, _value: &str) -> Result<()> {
+                let id = thread::current().id();
+                self.0.lock().unwrap().push(("cleanup", id));
+                Ok(())
+            }
+        }
+
+        let server = crate::test::with_directory_server();
+        let url = DirectoryUrl::Other(&server.dir_url);
+        let client = Arc::new(BlockingClient::default());
+        let provider = Arc::new(Provider::default());
+        block_on(async {
+            let dir = Directory::from_url(MemoryPersist::new(), url, client).await?;
+            let acc = dir.account("foo@bar.com").await?;
+            let ord = acc.new_order("acmetest.example.com", &[]).await?;
+            let auths = ord.authorizations().await?;
+            let chall = auths[0].dns_challenge().unwrap();
+            let policy = crate::PollPolicy::from_millis(1);
+            // the test server only validates http challenges, the provider is called anyway.
+            let _ = assert_send(chall.validate_with_solver(provider.clone(), &policy)).await;
+            Ok::<_, crate::Error>(())
+        })?;
+        let calls = provider.0.lock().unwrap();
+        let names: Vec<_> = calls.iter().map(|(n, _)| *n).collect();
+        assert_eq!(names, ["present", "cleanup"]);
+        assert!(calls.iter().all(|(_, id)| *id != thread::current().id()));
+        Ok(())
+    }
+
+    #[test]
+    fn test_blocking_client_threads() -> Result<()> {
+        use std::collections::HashSet;
+
+        // records the threads the requests are made on.
+        let threads = Arc::new(Mutex::new(HashSet::new()));
+        let threads2 = threads.clone();
+        let http = crate::http::MemoryClient::new(move |_req| {
+            threads2.lock().unwrap().insert(thread::current().id());
+            thread::sleep(Duration::from_millis(10));
+            Ok(HttpResponse::new(200))
+        });
+        let client = BlockingClient::new(Arc::new(http));
+        let req = HttpRequest::get("mem:/directory");
+        let start = Instant::now();
+        block_on(async {
+            // all queued before the first is awaited.
+            let requests: Vec<_> = (0..3 * MAX_WORKERS).map(|_| client.request(&req)).collect();
+            let sleeps: Vec<_> = (1..=20)
+                .map(|i| client.sleep(Duration::from_millis(i)))
+                .collect();
+            for res in requests {
+                assert_eq!(res.await?.status, 200);
+            }
+            for sleep in sleeps {
+                sleep.await;
+            }
+            Ok::<_, crate::Error>(())
+        })?;
+        assert!(start.elapsed() >= Duration::from_millis(20));
+        let threads = threads.lock().unwrap();
+        assert!(threads.len() <= MAX_WORKERS);
+        assert!(!threads.contains(&thread::current().id()));
+        Ok(())
+    }
+}
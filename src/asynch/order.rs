@@ -0,0 +1,568 @@
+use openssl::pkey::{self, PKey};
+use openssl::sha::sha256;
+use std::sync::Arc;
+use std::time::Duration;
+
+use crate::api::{ApiAuth, ApiChallenge, ApiEmptyObject, ApiEmptyString, ApiOrder};
+use crate::asynch::acc::AccountInner;
+use crate::asynch::on_thread;
+use crate::cert::{create_tls_alpn_certificate, Certificate};
+use crate::order::{
+    any_challenge, auth_error, check_order_valid, finalize_request, key_authorization,
+    primary_domain, private_key_pem, save_certificate, select_api_challenge, select_chain,
+    ChallengeType, Dns, Http, TlsAlpn, Unknown,
+};
+use crate::persist::Persist;
+use crate::poll::{timeout_error, Poller};
+use crate::solver::{cleanup_after, DnsProvider, HttpSolver, TlsAlpnSolver};
+use crate::util::read_json;
+use crate::{PollPolicy, Result};
+
+/// The order wrapped with an outer façade.
+pub(crate) struct Order<P: Persist> {
+    inner: Arc<AccountInner<P>>,
+    api_order: ApiOrder,
+    url: String,
+}
+
+impl<P: Persist> Order<P> {
+    pub(crate) fn new(inner: &Arc<AccountInner<P>>, api_order: ApiOrder, url: String) -> Self {
+        Order {
+            inner: inner.clone(),
+            api_order,
+            url,
+        }
+    }
+}
+
+/// Helper to refresh an order status (POST-as-GET).
+async fn refresh_order<P: Persist>(inner: &Arc<AccountInner<P>>, url: String) -> Result<Order<P>> {
+    let (order, _) = poll_order(inner, url).await?;
+    Ok(order)
+}
+
+/// Refresh the order, also giving the `Retry-After` of the response.
+async fn poll_order<P: Persist>(
+    inner: &Arc<AccountInner<P>>,
+    url: String,
+) -> Result<(Order<P>, Option<Duration>)> {
+    let res = inner.transport.call(&url, &ApiEmptyString).await?;
+    let retry_after = res.retry_after();
+
+    let api_order: ApiOrder = read_json(res)?;
+
+    let order = Order {
+        inner: inner.clone(),
+        api_order,
+        url,
+    };
+    Ok((order, retry_after))
+}
+
+/// A new order created by [`Account::new_order`].
+///
+/// See the blocking [`NewOrder`].
+///
+/// [`Account::new_order`]: struct.Account.html#method.new_order
+/// [`NewOrder`]: ../order/struct.NewOrder.html
+pub struct NewOrder<P: Persist> {
+    pub(crate) order: Order<P>,
+}
+
+impl<P: Persist> NewOrder<P> {
+    /// Tell if the domains in this order have been authorized.
+    ///
+    /// This doesn't do any calls against the API. You must manually call [`refresh`].
+    ///
+    /// [`refresh`]: struct.NewOrder.html#method.refresh
+    pub fn is_validated(&self) -> bool {
+        self.order.api_order.is_status_ready() || self.order.api_order.is_status_valid()
+    }
+
+    /// If the order [`is_validated`] progress it to a [`CsrOrder`].
+    ///
+    /// [`is_validated`]: struct.NewOrder.html#method.is_validated
+    /// [`CsrOrder`]: struct.CsrOrder.html
+    pub fn confirm_validations(&self) -> Option<CsrOrder<P>> {
+        if self.is_validated() {
+            Some(CsrOrder {
+                order: Order::new(
+                    &self.order.inner,
+                    self.order.api_order.clone(),
+                    self.order.url.clone(),
+                ),
+            })
+        } else {
+            None
+        }
+    }
+
+    /// Refresh the order state against the ACME API.
+    pub async fn refresh(&mut self) -> Result<()> {
+        let order = refresh_order(&self.order.inner, self.order.url.clone()).await?;
+        self.order = order;
+        Ok(())
+    }
+
+    /// Provide the authorizations, one per domain in the order.
+    pub async fn authorizations(&self) -> Result<Vec<Auth<P>>> {
+        let mut result = vec![];
+        if let Some(authorizations) = &self.order.api_order.authorizations {
+            for auth_url in authorizations {
+                let transport = &self.order.inner.transport;
+                let res = transport.call(auth_url, &ApiEmptyString).await?;
+                let api_auth: ApiAuth = read_json(res)?;
+                result.push(Auth::new(&self.order.inner, api_auth, auth_url));
+            }
+        }
+        Ok(result)
+    }
+
+    /// Access the underlying JSON object for debugging.
+    pub fn api_order(&self) -> &ApiOrder {
+        &self.order.api_order
+    }
+}
+
+/// An order that is ready for a CSR submission.
+///
+/// See the blocking [`CsrOrder`].
+///
+/// [`CsrOrder`]: ../order/struct.CsrOrder.html
+pub struct CsrOrder<P: Persist> {
+    pub(crate) order: Order<P>,
+}
+
+impl<P: Persist> CsrOrder<P> {
+    /// Finalize the order by providing a private key as PEM.
+    ///
+    /// The `delay_millis` is the amount of time to wait between each poll of the
+    /// order status while it's processing.
+    pub async fn finalize(self, private_key_pem: &str, delay_millis: u64) -> Result<CertOrder<P>> {
+        let pkey_pri = PKey::private_key_from_pem(private_key_pem.as_bytes())
+            .map_err(|e| format!("Error reading private key PEM: {}", e))?;
+        self.finalize_pkey(pkey_pri, delay_millis).await
+    }
+
+    /// Lower level finalize call that works directly with the openssl crate structures.
+    pub async fn finalize_pkey(
+        self,
+        private_key: PKey<pkey::Private>,
+        delay_millis: u64,
+    ) -> Result<CertOrder<P>> {
+        let policy = PollPolicy::from_millis(delay_millis);
+        self.finalize_pkey_with_policy(private_key, &policy).await
+    }
+
+    /// Like [`finalize_pkey`], polling the `processing` order according to the policy.
+    ///
+    /// [`finalize_pkey`]: struct.CsrOrder.html#method.finalize_pkey
+    pub async fn finalize_pkey_with_policy(
+        self,
+        private_key: PKey<pkey::Private>,
+        policy: &PollPolicy,
+    ) -> Result<CertOrder<P>> {
+        let finalize = finalize_request(&self.order.api_order, &private_key)?;
+
+        let inner = self.order.inner;
+        let order_url = self.order.url;
+        let finalize_url = &self.order.api_order.finalize;
+
+        inner.transport.call(finalize_url, &finalize).await?;
+
+        let order = wait_for_order_status(&inner, &order_url, policy).await?;
+        check_order_valid(&order.api_order)?;
+
+        Ok(CertOrder { private_key, order })
+    }
+
+    /// Access the underlying JSON object for debugging.
+    pub fn api_order(&self) -> &ApiOrder {
+        &self.order.api_order
+    }
+}
+
+async fn wait_for_order_status<P: Persist>(
+    inner: &Arc<AccountInner<P>>,
+    url: &str,
+    policy: &PollPolicy,
+) -> Result<Order<P>> {
+    let mut poller = Poller::new(policy);
+    loop {
+        let (order, retry_after) = poll_order(inner, url.to_string()).await?;
+        if !order.api_order.is_status_processing() {
+            return Ok(order);
+        }
+        let status = order.api_order.status.as_deref();
+        let delay = poller
+            .next_delay(retry_after)
+            .ok_or_else(|| timeout_error(url, status, poller.elapsed()))?;
+        inner.transport.client().sleep(delay).await;
+    }
+}
+
+/// Order for an issued certificate that is ready to download.
+pub struct CertOrder<P: Persist> {
+    private_key: PKey<pkey::Private>,
+    order: Order<P>,
+}
+
+impl<P: Persist> CertOrder<P> {
+    /// Request download of the issued certificate, and save it in the persistence.
+    pub async fn download_and_save_cert(self) -> Result<Certificate> {
+        let url = self.certificate_url()?;
+        let res = self
+            .order
+            .inner
+            .transport
+            .call(url, &ApiEmptyString)
+            .await?;
+        self.save(res.body_str())
+    }
+
+    /// Download the certificate with each of the offered chains, the default first.
+    ///
+    /// See the blocking [`download_chains`].
+    ///
+    /// [`download_chains`]: ../order/struct.CertOrder.html#method.download_chains
+    pub async fn download_chains(&self) -> Result<Vec<Certificate>> {
+        let private_key = private_key_pem(&self.private_key)?;
+        let chains = self.chains().await?;
+        let certs = chains
+            .into_iter()
+            .map(|chain| Certificate::new(private_key.clone(), chain))
+            .collect();
+        Ok(certs)
+    }
+
+    /// Save the chain whose topmost certificate is issued by the common name, or
+    /// else the default chain.
+    ///
+    /// See the blocking [`download_and_save_preferred_chain`].
+    ///
+    /// [`download_and_save_preferred_chain`]: ../order/struct.CertOrder.html#method.download_and_save_preferred_chain
+    pub async fn download_and_save_preferred_chain(self, issuer_cn: &str) -> Result<Certificate> {
+        let chains = self.chains().await?;
+        self.save(select_chain(chains, issuer_cn))
+    }
+
+    fn certificate_url(&self) -> Result<&str> {
+        let url = self.order.api_order.certificate.as_deref();
+        Ok(url.ok_or("Order is valid, but has no certificate url")?)
+    }
+
+    async fn chains(&self) -> Result<Vec<String>> {
+        let transport = &self.order.inner.transport;
+        let res = transport
+            .call(self.certificate_url()?, &ApiEmptyString)
+            .await?;
+        let mut chains = vec![res.body_str()];
+        for url in res.links("alternate") {
+            let res = transport.call(&url, &ApiEmptyString).await?;
+            chains.push(res.body_str());
+        }
+        Ok(chains)
+    }
+
+    fn save(self, chain: String) -> Result<Certificate> {
+        let primary_name = primary_domain(&self.order.api_order)?;
+        let inner = &self.order.inner;
+        save_certificate(
+            &inner.persist,
+            &inner.realm,
+            primary_name,
+            &self.private_key,
+            chain,
+        )
+    }
+
+    /// Access the underlying JSON object for debugging.
+    pub fn api_order(&self) -> &ApiOrder {
+        &self.order.api_order
+    }
+}
+
+/// An authorization (ownership proof) for a domain name.
+///
+/// See the blocking [`Auth`].
+///
+/// [`Auth`]: ../order/struct.Auth.html
+pub struct Auth<P: Persist> {
+    inner: Arc<AccountInner<P>>,
+    api_auth: ApiAuth,
+    auth_url: String,
+}
+
+impl<P: Persist> Auth<P> {
+    fn new(inner: &Arc<AccountInner<P>>, api_auth: ApiAuth, auth_url: &str) -> Self {
+        Auth {
+            inner: inner.clone(),
+            api_auth,
+            auth_url: auth_url.into(),
+        }
+    }
+
+    /// Domain name for this authorization.
+    pub fn domain_name(&self) -> &str {
+        &self.api_auth.identifier.value
+    }
+
+    /// Whether we actually need to do the authorization.
+    pub fn need_challenge(&self) -> bool {
+        !self.api_auth.is_status_valid()
+    }
+
+    /// Get the http challenge, if the ACME API offers one for this authorization.
+    pub fn http_challenge(&self) -> Option<Challenge<P, Http>> {
+        self.api_auth
+            .http_challenge()
+            .map(|c| Challenge::new(&self.inner, c.clone(), self.domain_name(), &self.auth_url))
+    }
+
+    /// Get the dns challenge, if the ACME API offers one for this authorization.
+    pub fn dns_challenge(&self) -> Option<Challenge<P, Dns>> {
+        self.api_auth
+            .dns_challenge()
+            .map(|c| Challenge::new(&self.inner, c.clone(), self.domain_name(), &self.auth_url))
+    }
+
+    /// Get the TLS ALPN challenge, if the ACME API offers one for this authorization.
+    pub fn tls_alpn_challenge(&self) -> Option<Challenge<P, TlsAlpn>> {
+        self.api_auth
+            .tls_alpn_challenge()
+            .map(|c| Challenge::new(&self.inner, c.clone(), self.domain_name(), &self.auth_url))
+    }
+
+    /// All challenges the ACME API offers for this authorization.
+    pub fn challenges(&self) -> Vec<AnyChallenge<P>> {
+        self.api_auth
+            .challenges
+            .iter()
+            .map(|c| AnyChallenge::new(&self.inner, c.clone(), self.domain_name(), &self.auth_url))
+            .collect()
+    }
+
+    /// The first challenge type in `preference` that the ACME API offers for this
+    /// authorization. Invalid challenges are skipped.
+    pub fn select_challenge(&self, preference: &[ChallengeType]) -> Option<AnyChallenge<P>> {
+        select_api_challenge(&self.api_auth, preference)
+            .map(|c| AnyChallenge::new(&self.inner, c.clone(), self.domain_name(), &self.auth_url))
+    }
+
+    /// Access the underlying JSON object for debugging.
+    pub fn api_auth(&self) -> &ApiAuth {
+        &self.api_auth
+    }
+}
+
+/// A DNS, HTTP, or TLS-ALPN challenge as obtained from the [`Auth`].
+///
+/// [`Auth`]: struct.Auth.html
+pub struct Challenge<P: Persist, A> {
+    inner: Arc<AccountInner<P>>,
+    api_challenge: ApiChallenge,
+    domain: String,
+    auth_url: String,
+    _ph: std::marker::PhantomData<A>,
+}
+
+any_challenge! {
+    /// Any of the challenges of an [`Auth`], by type.
+    ///
+    /// See the blocking [`AnyChallenge`].
+    ///
+    /// [`Auth`]: struct.Auth.html
+    /// [`AnyChallenge`]: ../order/enum.AnyChallenge.html
+}
+
+impl<P: Persist> Challenge<P, Http> {
+    /// The `token` is the file name in the http challenge.
+    pub fn http_token(&self) -> &str {
+        &self.api_challenge.token
+    }
+
+    /// The `proof` is some text content that is placed in the file named by `token`.
+    pub fn http_proof(&self) -> String {
+        let signer = self.inner.transport.signer();
+        key_authorization(&self.api_challenge.token, &*signer, false)
+    }
+
+    /// Like [`validate_with_policy`], with the solver putting the proof in place
+    /// around the validation.
+    ///
+    /// [`validate_with_policy`]: struct.Challenge.html#method.validate_with_policy
+    pub async fn validate_with_solver<S>(self, solver: &S, policy: &PollPolicy) -> Result<()>
+    where
+        S: HttpSolver + ?Sized,
+    {
+        let domain = self.domain.clone();
+        let token = self.api_challenge.token.clone();
+        solver.present(&domain, &token, &self.http_proof())?;
+        let res = self.validate_with_policy(policy).await;
+        cleanup_after(res, solver.cleanup(&domain, &token))
+    }
+}
+
+impl<P: Persist> Challenge<P, Dns> {
+    /// The `proof` is the `TXT` record placed under `_acme-challenge.<domain>`.
+    pub fn dns_proof(&self) -> String {
+        let signer = self.inner.transport.signer();
+        key_authorization(&self.api_challenge.token, &*signer, true)
+    }
+
+    /// Name of the `TXT` record, `_acme-challenge.<domain-to-be-proven>`.
+    pub fn dns_name(&self) -> String {
+        format!("_acme-challenge.{}", self.domain.trim_start_matches("*."))
+    }
+
+    /// Like [`validate_with_policy`], with the provider publishing the `TXT` record
+    /// before the validation, and removing it after, also when the validation fails.
+    ///
+    /// Providers block while talking to name servers, or waiting for the record to
+    /// propagate, so they are run on a thread of their own rather than the executor.
+    ///
+    /// Wrap the provider in a [`PropagationCheck`] to only validate once the record is
+    /// visible at the authoritative name servers, and in a [`CnameDelegation`] when the
+    /// name is delegated to another zone with a CNAME.
+    ///
+    /// [`validate_with_policy`]: struct.Challenge.html#method.validate_with_policy
+    /// [`PropagationCheck`]: ../solver/struct.PropagationCheck.html
+    /// [`CnameDelegation`]: ../solver/struct.CnameDelegation.html
+    pub async fn validate_with_solver<S>(self, provider: Arc<S>, policy: &PollPolicy) -> Result<()>
+    where
+        S: DnsProvider + ?Sized + 'static,
+    {
+        let name = self.dns_name();
+        let proof = self.dns_proof();
+        let present = {
+            let (provider, name, proof) = (provider.clone(), name.clone(), proof.clone());
+            on_thread(move || provider.present(&name, &proof))
+        };
+        present.await?;
+        let res = self.validate_with_policy(policy).await;
+        let cleanup = on_thread(move || provider.cleanup(&name, &proof)).await;
+        cleanup_after(res, cleanup)
+    }
+}
+
+impl<P: Persist> Challenge<P, TlsAlpn> {
+    /// The `proof` is the contents of the ACME extension to be placed in the
+    /// certificate used for validation.
+    pub fn tls_alpn_proof(&self) -> [u8; 32] {
+        let signer = self.inner.transport.signer();
+        sha256(key_authorization(&self.api_challenge.token, &*signer, false).as_bytes())
+    }
+
+    /// The self-signed certificate with the proof, to serve for the domain.
+    ///
+    /// See [`create_tls_alpn_certificate`].
+    ///
+    /// [`create_tls_alpn_certificate`]: ../fn.create_tls_alpn_certificate.html
+    pub fn tls_alpn_certificate(&self) -> Result<Certificate> {
+        create_tls_alpn_certificate(&self.domain, &self.tls_alpn_proof())
+    }
+
+    /// Like [`validate_with_policy`], with the solver serving the validation certificate
+    /// during the validation, and removing it after, also when the validation fails.
+    ///
+    /// [`validate_with_policy`]: struct.Challenge.html#method.validate_with_policy
+    pub async fn validate_with_solver<S>(self, solver: &S, policy: &PollPolicy) -> Result<()>
+    where
+        S: TlsAlpnSolver + ?Sized,
+    {
+        let domain = self.domain.clone();
+        solver.present(&domain, &self.tls_alpn_proof())?;
+        let res = self.validate_with_policy(policy).await;
+        cleanup_after(res, solver.cleanup(&domain))
+    }
+}
+
+impl<P: Persist, A> Challenge<P, A> {
+    fn new(
+        inner: &Arc<AccountInner<P>>,
+        api_challenge: ApiChallenge,
+        domain: &str,
+        auth_url: &str,
+    ) -> Self {
+        Challenge {
+            inner: inner.clone(),
+            api_challenge,
+            domain: domain.into(),
+            auth_url: auth_url.into(),
+            _ph: std::marker::PhantomData,
+        }
+    }
+
+    /// Domain name of the authorization this challenge is for.
+    pub fn domain_name(&self) -> &str {
+        &self.domain
+    }
+
+    /// Type of the challenge.
+    pub fn challenge_type(&self) -> ChallengeType {
+        ChallengeType::from_type(&self.api_challenge._type)
+    }
+
+    /// Status of the challenge, `pending`, `processing`, `valid` or `invalid`.
+    pub fn status(&self) -> &str {
+        &self.api_challenge.status
+    }
+
+    /// Check whether this challenge still needs validation.
+    pub fn need_validate(&self) -> bool {
+        self.api_challenge.is_status_pending()
+    }
+
+    /// Tell the ACME API to attempt validating the proof of this challenge, and
+    /// wait `delay_millis` between each poll of the authorization status.
+    pub async fn validate(self, delay_millis: u64) -> Result<()> {
+        let policy = PollPolicy::from_millis(delay_millis);
+        self.validate_with_policy(&policy).await
+    }
+
+    /// Like [`validate`], polling the pending authorization according to the policy.
+    ///
+    /// [`validate`]: struct.Challenge.html#method.validate
+    pub async fn validate_with_policy(self, policy: &PollPolicy) -> Result<()> {
+        let url_chall = &self.api_challenge.url;
+        let res = self
+            .inner
+            .transport
+            .call(url_chall, &ApiEmptyObject)
+            .await?;
+        let _: ApiChallenge = read_json(res)?;
+
+        let auth = wait_for_auth_status(&self.inner, &self.auth_url, policy).await?;
+
+        match auth_error(&auth) {
+            Some(err) => Err(err),
+            None => Ok(()),
+        }
+    }
+
+    /// Access the underlying JSON object for debugging.
+    pub fn api_challenge(&self) -> &ApiChallenge {
+        &self.api_challenge
+    }
+}
+
+async fn wait_for_auth_status<P: Persist>(
+    inner: &Arc<AccountInner<P>>,
+    auth_url: &str,
+    policy: &PollPolicy,
+) -> Result<ApiAuth> {
+    let mut poller = Poller::new(policy);
+    loop {
+        let res = inner.transport.call(auth_url, &ApiEmptyString).await?;
+        let retry_after = res.retry_after();
+        let auth: ApiAuth = read_json(res)?;
+        if !auth.is_status_pending() {
+            return Ok(auth);
+        }
+        let status = auth.status.as_deref();
+        let delay = poller
+            .next_delay(retry_after)
+            .ok_or_else(|| timeout_error(auth_url, status, poller.elapsed()))?;
+        inner.transport.client().sleep(delay).await;
+    }
+}
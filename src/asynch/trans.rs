@@ -0,0 +1,130 @@
+use serde::Serialize;
+use std::sync::Arc;
+
+use crate::asynch::AsyncHttpClient;
+use crate::http::{HttpRequest, HttpResponse};
+use crate::retry::{Attempt, Failed, Retries};
+use crate::signer::AccountSigner;
+use crate::trans::{is_post_as_get, read_response, JwsSigner, NoncePool};
+use crate::{Result, RetryPolicy};
+
+/// Async counterpart of the blocking transport. The signing of the JWS payloads
+/// and the nonce pool are the same.
+#[derive(Debug)]
+pub(crate) struct Transport {
+    client: Arc<dyn AsyncHttpClient>,
+    nonce_pool: Arc<NoncePool>,
+    jws: JwsSigner,
+    retry_policy: RetryPolicy,
+}
+
+impl Transport {
+    pub fn new(
+        client: &Arc<dyn AsyncHttpClient>,
+        nonce_pool: &Arc<NoncePool>,
+        signer: Arc<dyn AccountSigner>,
+        retry_policy: &RetryPolicy,
+    ) -> Self {
+        Transport {
+            client: client.clone(),
+            nonce_pool: nonce_pool.clone(),
+            jws: JwsSigner::new(signer),
+            retry_policy: retry_policy.clone(),
+        }
+    }
+
+    /// Update the key id once it is known (part of setting up the transport).
+    pub fn set_key_id(&mut self, kid: String) {
+        self.jws.set_key_id(kid);
+    }
+
+    /// The key id, which is the account url.
+    pub fn key_id(&self) -> &str {
+        self.jws.key_id()
+    }
+
+    /// The signer used in the transport
+    pub fn signer(&self) -> Arc<dyn AccountSigner> {
+        self.jws.signer()
+    }
+
+    /// The client, which also provides the timer for polling.
+    pub fn client(&self) -> &dyn AsyncHttpClient {
+        &*self.client
+    }
+
+    /// Make call using the full jwk. Only for the first newAccount request.
+    pub async fn call_jwk<T: Serialize + Sync + ?Sized>(
+        &self,
+        url: &str,
+        body: &T,
+    ) -> Result<HttpResponse> {
+        // newAccount for a key that already has an account gives back that account.
+        self.do_call(url, body, JwsSigner::jwk_body, true).await
+    }
+
+    /// Make call using the key id
+    pub async fn call<T: Serialize + Sync + ?Sized>(
+        &self,
+        url: &str,
+        body: &T,
+    ) -> Result<HttpResponse> {
+        self.do_call(url, body, JwsSigner::kid_body, is_post_as_get(body))
+            .await
+    }
+
+    /// Roll over to a new key against the keyChange url. On success, the new signer
+    /// replaces the current for all subsequent calls.
+    pub async fn change_key(&self, url: &str, new_signer: Arc<dyn AccountSigner>) -> Result<()> {
+        let inner = self.jws.key_change(url, &*new_signer)?;
+        self.call(url, &inner).await?;
+        self.jws.replace_signer(new_signer);
+        Ok(())
+    }
+
+    async fn do_call<T: Serialize + Sync + ?Sized>(
+        &self,
+        url: &str,
+        body: &T,
+        make_body: fn(&JwsSigner, &str, String, &T) -> Result<String>,
+        idempotent: bool,
+    ) -> Result<HttpResponse> {
+        // The ACME API may at any point invalidate all nonces, and there might be
+        // server or connection trouble. Such calls are retried according to the policy,
+        // though server and connection trouble only for idempotent calls, since
+        // the server might have acted on a request we got no answer for.
+        let mut retries = Retries::new(&self.retry_policy);
+        loop {
+            match self.attempt(url, body, make_body, idempotent).await {
+                Ok(response) => return Ok(response),
+                Err(failed) => self.client.sleep(retries.failed(failed)?).await,
+            }
+        }
+    }
+
+    async fn attempt<T: Serialize + Sync + ?Sized>(
+        &self,
+        url: &str,
+        body: &T,
+        make_body: fn(&JwsSigner, &str, String, &T) -> Result<String>,
+        idempotent: bool,
+    ) -> Attempt<HttpResponse> {
+        // Either get a new nonce, or reuse one from a previous request.
+        let nonce = match self.nonce_pool.pop_nonce() {
+            Some(nonce) => nonce,
+            None => {
+                let req = self.nonce_pool.new_nonce_request();
+                NoncePool::read_new_nonce(self.client.request(&req).await)?
+            }
+        };
+
+        let body = make_body(&self.jws, url, nonce, body).map_err(Failed::fatal)?;
+
+        debug!("Call endpoint {}", url);
+
+        let req = HttpRequest::post(url, &body);
+        let response = self.client.request(&req).await;
+
+        read_response(&self.nonce_pool, response, idempotent)
+    }
+}
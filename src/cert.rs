@@ -1,12 +1,21 @@
 use lazy_static::lazy_static;
+use openssl::asn1::{Asn1Object, Asn1OctetString, Asn1Time, Asn1TimeRef};
+use openssl::bn::{BigNum, MsbOption};
 use openssl::ec::{Asn1Flag, EcGroup, EcKey};
+use openssl::error::ErrorStack;
 use openssl::hash::MessageDigest;
 use openssl::nid::Nid;
-use openssl::pkey::{self, PKey};
+use openssl::pkey::{self, Id, PKey};
 use openssl::rsa::Rsa;
 use openssl::stack::Stack;
 use openssl::x509::extension::SubjectAlternativeName;
-use openssl::x509::{X509Req, X509ReqBuilder, X509};
+use openssl::x509::{
+    X509Builder, X509Extension, X509NameBuilder, X509NameRef, X509Req, X509ReqBuilder,
+    X509VerifyResult, X509,
+};
+use std::convert::TryFrom;
+use std::net::IpAddr;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
 
 use crate::Result;
 
@@ -33,48 +42,128 @@ pub fn create_rsa_key(bits: u32) -> PKey<pkey::Private> {
 
 /// Make a P-256 private key (from which we can derive a public key).
 pub fn create_p256_key() -> PKey<pkey::Private> {
-    let pri_key_ec = EcKey::generate(&*EC_GROUP_P256).expect("EcKey");
+    let pri_key_ec = EcKey::generate(&EC_GROUP_P256).expect("EcKey");
     PKey::from_ec_key(pri_key_ec).expect("from_ec_key")
 }
 
 /// Make a P-384 private key pair (from which we can derive a public key).
 pub fn create_p384_key() -> PKey<pkey::Private> {
-    let pri_key_ec = EcKey::generate(&*EC_GROUP_P384).expect("EcKey");
+    let pri_key_ec = EcKey::generate(&EC_GROUP_P384).expect("EcKey");
     PKey::from_ec_key(pri_key_ec).expect("from_ec_key")
 }
 
 pub(crate) fn create_csr(pkey: &PKey<pkey::Private>, domains: &[&str]) -> Result<X509Req> {
-    //
+    if domains.is_empty() {
+        return Err("No domains to create a CSR for".into());
+    }
+    let err = |e: ErrorStack| format!("Failed to create CSR: {}", e);
+
     // the csr builder
-    let mut req_bld = X509ReqBuilder::new().expect("X509ReqBuilder");
+    let mut req_bld = X509ReqBuilder::new().map_err(err)?;
 
     // set private/public key in builder
-    req_bld.set_pubkey(&pkey).expect("set_pubkey");
+    req_bld.set_pubkey(pkey).map_err(err)?;
 
     // set all domains as alt names
-    let mut stack = Stack::new().expect("Stack::new");
+    let mut stack = Stack::new().map_err(err)?;
     let ctx = req_bld.x509v3_context(None);
-    let as_lst = domains
-        .iter()
-        .map(|&e| format!("DNS:{}", e))
-        .collect::<Vec<_>>()
-        .join(", ");
-    let as_lst = as_lst[4..].to_string();
     let mut an = SubjectAlternativeName::new();
-    an.dns(&as_lst);
-    let ext = an.build(&ctx).expect("SubjectAlternativeName::build");
-    stack.push(ext).expect("Stack::push");
-    req_bld.add_extensions(&stack).expect("add_extensions");
+    for domain in domains {
+        an.dns(domain);
+    }
+    let ext = an.build(&ctx).map_err(err)?;
+    stack.push(ext).map_err(err)?;
+    req_bld.add_extensions(&stack).map_err(err)?;
 
     // sign it
-    req_bld
-        .sign(pkey, MessageDigest::sha256())
-        .expect("csr_sign");
+    req_bld.sign(pkey, MessageDigest::sha256()).map_err(err)?;
 
     // the csr
     Ok(req_bld.build())
 }
 
+/// OID of the `id-pe-acmeIdentifier` extension of RFC 8737.
+const ACME_IDENTIFIER_OID: &str = "1.3.6.1.5.5.7.1.31";
+
+/// Make the self-signed certificate to answer a TLS ALPN challenge with.
+///
+/// The certificate is for a new P-256 key, has the domain as its only `dNSName` SAN and
+/// the proof in a critical `id-pe-acmeIdentifier` extension, as [RFC 8737] requires. It
+/// must be served in TLS handshakes for the domain negotiating the `acme-tls/1`
+/// ALPN protocol. See [`tls_alpn_proof`].
+///
+/// [RFC 8737]: https://tools.ietf.org/html/rfc8737
+/// [`tls_alpn_proof`]: order/struct.Challenge.html#method.tls_alpn_proof
+pub fn create_tls_alpn_certificate(domain: &str, proof: &[u8; 32]) -> Result<Certificate> {
+    let err = |e: ErrorStack| format!("Failed to create TLS ALPN certificate: {}", e);
+    let pkey = create_p256_key();
+
+    let mut name = X509NameBuilder::new().map_err(err)?;
+    // a common name can't be longer than 64 characters, and isn't needed anyway.
+    if domain.len() <= 64 {
+        name.append_entry_by_nid(Nid::COMMONNAME, domain)
+            .map_err(err)?;
+    }
+    let name = name.build();
+
+    let mut serial = BigNum::new().map_err(err)?;
+    serial
+        .rand(128, MsbOption::MAYBE_ZERO, false)
+        .map_err(err)?;
+    let serial = serial.to_asn1_integer().map_err(err)?;
+
+    let mut bld = X509Builder::new().map_err(err)?;
+    bld.set_version(2).map_err(err)?;
+    bld.set_serial_number(&serial).map_err(err)?;
+    bld.set_subject_name(&name).map_err(err)?;
+    bld.set_issuer_name(&name).map_err(err)?;
+    bld.set_pubkey(&pkey).map_err(err)?;
+    let not_before = Asn1Time::days_from_now(0).map_err(err)?;
+    let not_after = Asn1Time::days_from_now(7).map_err(err)?;
+    bld.set_not_before(&not_before).map_err(err)?;
+    bld.set_not_after(&not_after).map_err(err)?;
+
+    let san = SubjectAlternativeName::new()
+        .dns(domain)
+        .build(&bld.x509v3_context(None, None))
+        .map_err(err)?;
+    bld.append_extension(san).map_err(err)?;
+
+    // the extension value is the DER of an OCTET STRING holding the proof.
+    let mut value = vec![0x04, 0x20];
+    value.extend_from_slice(proof);
+    let oid = Asn1Object::from_str(ACME_IDENTIFIER_OID).map_err(err)?;
+    let value = Asn1OctetString::new_from_bytes(&value).map_err(err)?;
+    let acme_id = X509Extension::new_from_der(&oid, true, &value).map_err(err)?;
+    bld.append_extension(acme_id).map_err(err)?;
+
+    bld.sign(&pkey, MessageDigest::sha256()).map_err(err)?;
+    let x509 = bld.build();
+
+    let pem = |p: std::result::Result<Vec<u8>, ErrorStack>| -> Result<String> {
+        let p = p.map_err(err)?;
+        String::from_utf8(p).map_err(|e| format!("PEM is not UTF-8: {}", e).into())
+    };
+    let private_key = pem(pkey.private_key_to_pem_pkcs8())?;
+    let certificate = pem(x509.to_pem())?;
+    Ok(Certificate::new(private_key, certificate))
+}
+
+/// Type of the key of a [`Certificate`].
+///
+/// [`Certificate`]: struct.Certificate.html
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum KeyType {
+    /// An RSA key.
+    Rsa,
+    /// An ECDSA key, such as P-256 or P-384.
+    Ec,
+    /// An Ed25519 key.
+    Ed25519,
+    /// Some other type of key.
+    Other,
+}
+
 /// Encapsulated certificate and private key.
 #[derive(Debug, Clone, PartialEq, Eq)]
 pub struct Certificate {
@@ -96,9 +185,13 @@ impl Certificate {
     }
 
     /// The private key as DER.
-    pub fn private_key_der(&self) -> Vec<u8> {
-        let pkey = PKey::private_key_from_pem(self.private_key.as_bytes()).expect("from_pem");
-        pkey.private_key_to_der().expect("private_key_to_der")
+    pub fn private_key_der(&self) -> Result<Vec<u8>> {
+        let pkey = PKey::private_key_from_pem(self.private_key.as_bytes())
+            .map_err(|e| format!("Error reading private key PEM: {}", e))?;
+        let der = pkey
+            .private_key_to_der()
+            .map_err(|e| format!("Error converting private key to DER: {}", e))?;
+        Ok(der)
     }
 
     /// The PEM encoded issued certificate.
@@ -107,9 +200,12 @@ impl Certificate {
     }
 
     /// The issued certificate as DER.
-    pub fn certificate_der(&self) -> Vec<u8> {
-        let x509 = X509::from_pem(self.certificate.as_bytes()).expect("from_pem");
-        x509.to_der().expect("to_der")
+    pub fn certificate_der(&self) -> Result<Vec<u8>> {
+        let der = self
+            .x509()?
+            .to_der()
+            .map_err(|e| format!("Error converting certificate to DER: {}", e))?;
+        Ok(der)
     }
 
     /// Inspect the certificate to count the number of (whole) valid days left.
@@ -119,29 +215,246 @@ impl Certificate {
     /// issued cert, since it counts _whole_ days.
     ///
     /// It is possible to get negative days for an expired certificate.
-    pub fn valid_days_left(&self) -> i64 {
-        // the cert used in the tests is not valid to load as x509
-        if cfg!(test) {
-            return 89;
-        }
-
+    pub fn valid_days_left(&self) -> Result<i64> {
         // load as x509
-        let x509 = X509::from_pem(self.certificate.as_bytes()).expect("from_pem");
+        let x509 = self.x509()?;
 
         // convert asn1 time to Tm
         let not_after = format!("{}", x509.not_after());
         // Display trait produces this format, which is kinda dumb.
         // Apr 19 08:48:46 2019 GMT
-        let expires = parse_date(&not_after);
+        let expires = parse_date(&not_after)?;
         let dur = expires - time::now();
 
-        dur.num_days()
+        Ok(dur.num_days())
+    }
+
+    /// The PEM of the leaf certificate, the one issued for the domains, without the
+    /// rest of the chain.
+    pub fn leaf_certificate(&self) -> Result<String> {
+        let chain = self.chain()?;
+        pem(&chain[0])
+    }
+
+    /// The PEMs of the intermediate certificates of the chain, from the one that
+    /// issued the leaf upwards.
+    pub fn intermediate_certificates(&self) -> Result<Vec<String>> {
+        let chain = self.chain()?;
+        let end = if is_self_signed(&chain) {
+            chain.len() - 1
+        } else {
+            chain.len()
+        };
+        chain[1..end].iter().map(pem).collect()
+    }
+
+    /// The PEM of the root certificate, if the chain includes it.
+    ///
+    /// ACME APIs usually leave out the root, which clients are expected to have already.
+    pub fn root_certificate(&self) -> Result<Option<String>> {
+        let chain = self.chain()?;
+        if is_self_signed(&chain) {
+            pem(&chain[chain.len() - 1]).map(Some)
+        } else {
+            Ok(None)
+        }
+    }
+
+    /// The common name of the issuer of the topmost certificate in the chain.
+    ///
+    /// This identifies the chain, e.g. `ISRG Root X1` for a Let's Encrypt chain up to
+    /// that root. See [`download_and_save_preferred_chain`].
+    ///
+    /// [`download_and_save_preferred_chain`]: order/struct.CertOrder.html#method.download_and_save_preferred_chain
+    pub fn issuer_common_name(&self) -> Result<Option<String>> {
+        let chain = self.chain()?;
+        let top = &chain[chain.len() - 1];
+        let cn = top
+            .issuer_name()
+            .entries_by_nid(Nid::COMMONNAME)
+            .next()
+            .map(|e| String::from_utf8_lossy(e.data().as_slice()).into_owned());
+        Ok(cn)
+    }
+
+    /// The start of the validity period of the (leaf) certificate.
+    pub fn not_before(&self) -> Result<SystemTime> {
+        system_time(self.x509()?.not_before())
+    }
+
+    /// The end of the validity period of the (leaf) certificate.
+    pub fn not_after(&self) -> Result<SystemTime> {
+        system_time(self.x509()?.not_after())
+    }
+
+    /// The serial number of the certificate, as uppercase hex.
+    pub fn serial_number(&self) -> Result<String> {
+        let serial = self
+            .x509()?
+            .serial_number()
+            .to_bn()
+            .and_then(|bn| bn.to_hex_str().map(|h| h.to_string()))
+            .map_err(|e| format!("Error reading serial number: {}", e))?;
+        Ok(serial)
+    }
+
+    /// The distinguished name of the issuer of the certificate, such as
+    /// `C=US, O=Let's Encrypt, CN=R3`.
+    pub fn issuer(&self) -> Result<String> {
+        Ok(name_to_string(self.x509()?.issuer_name()))
+    }
+
+    /// The distinguished name of the subject of the certificate, such as
+    /// `CN=example.com`.
+    pub fn subject(&self) -> Result<String> {
+        Ok(name_to_string(self.x509()?.subject_name()))
+    }
+
+    /// The `dNSName` subject alternative names of the certificate.
+    pub fn dns_names(&self) -> Result<Vec<String>> {
+        let names = self.x509()?.subject_alt_names();
+        let dns_names = names
+            .iter()
+            .flat_map(|names| names.iter())
+            .filter_map(|n| n.dnsname().map(|d| d.to_string()))
+            .collect();
+        Ok(dns_names)
+    }
+
+    /// The `iPAddress` subject alternative names of the certificate.
+    pub fn ip_addresses(&self) -> Result<Vec<IpAddr>> {
+        let names = self.x509()?.subject_alt_names();
+        let mut ips = vec![];
+        for ip in names.iter().flat_map(|names| names.iter()) {
+            let ip = match ip.ipaddress() {
+                Some(ip) => ip,
+                None => continue,
+            };
+            let ip = match ip.len() {
+                4 => IpAddr::from(<[u8; 4]>::try_from(ip).unwrap()),
+                16 => IpAddr::from(<[u8; 16]>::try_from(ip).unwrap()),
+                _ => return Err(format!("Bad IP address in certificate: {:?}", ip).into()),
+            };
+            ips.push(ip);
+        }
+        Ok(ips)
+    }
+
+    /// The type of the key of the certificate.
+    pub fn key_type(&self) -> Result<KeyType> {
+        let key_type = match self.public_key()?.id() {
+            Id::RSA => KeyType::Rsa,
+            Id::EC => KeyType::Ec,
+            Id::ED25519 => KeyType::Ed25519,
+            _ => KeyType::Other,
+        };
+        Ok(key_type)
+    }
+
+    /// The size of the key of the certificate in bits, such as 2048 for an RSA key,
+    /// or 256 for a P-256 key.
+    pub fn key_bits(&self) -> Result<u32> {
+        Ok(self.public_key()?.bits())
+    }
+
+    /// The SHA-256 fingerprint of the certificate, the hash of its DER, as uppercase
+    /// hex with colons between the bytes.
+    pub fn sha256_fingerprint(&self) -> Result<String> {
+        let digest = self
+            .x509()?
+            .digest(MessageDigest::sha256())
+            .map_err(|e| format!("Error hashing certificate: {}", e))?;
+        let hex: Vec<_> = digest.iter().map(|b| format!("{:02X}", b)).collect();
+        Ok(hex.join(":"))
+    }
+
+    /// Whether the certificate is for the PEM encoded private key, such as
+    /// [`private_key`].
+    ///
+    /// [`private_key`]: struct.Certificate.html#method.private_key
+    pub fn matches_private_key(&self, private_key_pem: &str) -> Result<bool> {
+        let pkey = PKey::private_key_from_pem(private_key_pem.as_bytes())
+            .map_err(|e| format!("Error reading private key PEM: {}", e))?;
+        Ok(self.public_key()?.public_eq(&pkey))
+    }
+
+    fn public_key(&self) -> Result<PKey<pkey::Public>> {
+        let key = self
+            .x509()?
+            .public_key()
+            .map_err(|e| format!("Error reading certificate public key: {}", e))?;
+        Ok(key)
+    }
+
+    /// The certificates of the chain, starting with the leaf.
+    fn chain(&self) -> Result<Vec<X509>> {
+        let chain = X509::stack_from_pem(self.certificate.as_bytes())
+            .map_err(|e| format!("Error reading certificate PEM: {}", e))?;
+        if chain.is_empty() {
+            return Err("No certificate in PEM".into());
+        }
+        Ok(chain)
+    }
+
+    fn x509(&self) -> Result<X509> {
+        let x509 = X509::from_pem(self.certificate.as_bytes())
+            .map_err(|e| format!("Error reading certificate PEM: {}", e))?;
+        Ok(x509)
     }
 }
 
-fn parse_date(s: &str) -> time::Tm {
+fn system_time(time: &Asn1TimeRef) -> Result<SystemTime> {
+    let err = |e: ErrorStack| format!("Error reading certificate time: {}", e);
+    let epoch = Asn1Time::from_unix(0).map_err(err)?;
+    let diff = epoch.diff(time).map_err(err)?;
+    let secs = i64::from(diff.days) * 86400 + i64::from(diff.secs);
+    let time = if secs >= 0 {
+        UNIX_EPOCH + Duration::from_secs(secs as u64)
+    } else {
+        UNIX_EPOCH - Duration::from_secs(secs.unsigned_abs())
+    };
+    Ok(time)
+}
+
+/// The name as `short name=value` of each entry, comma separated.
+fn name_to_string(name: &X509NameRef) -> String {
+    let mut entries = vec![];
+    for entry in name.entries() {
+        let key = entry
+            .object()
+            .nid()
+            .short_name()
+            .map(|n| n.to_string())
+            .unwrap_or_else(|_| entry.object().to_string());
+        let value = String::from_utf8_lossy(entry.data().as_slice());
+        entries.push(format!("{}={}", key, value));
+    }
+    entries.join(", ")
+}
+
+/// Whether the chain ends with a self-signed root, above the leaf.
+fn is_self_signed(chain: &[X509]) -> bool {
+    let top = &chain[chain.len() - 1];
+    chain.len() > 1
+        && top.issued(top) == X509VerifyResult::OK
+        && top
+            .public_key()
+            .and_then(|k| top.verify(&k))
+            .unwrap_or(false)
+}
+
+fn pem(x509: &X509) -> Result<String> {
+    let pem = x509
+        .to_pem()
+        .map_err(|e| format!("Error converting certificate to PEM: {}", e))?;
+    Ok(String::from_utf8_lossy(&pem).into_owned())
+}
+
+fn parse_date(s: &str) -> Result<time::Tm> {
     debug!("Parse date/time: {}", s);
-    time::strptime(s, "%h %e %H:%M:%S %Y %Z").expect("strptime")
+    let tm = time::strptime(s, "%h %e %H:%M:%S %Y %Z")
+        .map_err(|e| format!("Error parsing date {:?}: {}", s, e))?;
+    Ok(tm)
 }
 
 #[cfg(test)]
@@ -150,7 +463,175 @@ mod test {
 
     #[test]
     fn test_parse_date() {
-        let x = parse_date("May  3 07:40:15 2019 GMT");
+        let x = parse_date("May  3 07:40:15 2019 GMT").unwrap();
         assert_eq!(time::strftime("%F %T", &x).unwrap(), "2019-05-03 07:40:15");
+        assert!(parse_date("3rd of May 2019").is_err());
+        assert!(parse_date("").is_err());
+    }
+
+    #[test]
+    fn test_malformed_certificate() {
+        for pem in &["", "CERT HERE", "-----BEGIN CERTIFICATE-----\nAAAA\n"] {
+            let cert = Certificate::new(pem.to_string(), pem.to_string());
+            assert!(cert.private_key_der().is_err());
+            assert!(cert.certificate_der().is_err());
+            assert!(cert.leaf_certificate().is_err());
+        }
+    }
+
+    #[test]
+    fn test_create_tls_alpn_certificate() {
+        let proof = [7_u8; 32];
+        let cert = create_tls_alpn_certificate("example.com", &proof).unwrap();
+        let x509 = cert.x509().unwrap();
+        let sans: Vec<_> = x509
+            .subject_alt_names()
+            .unwrap()
+            .iter()
+            .filter_map(|n| n.dnsname().map(|s| s.to_string()))
+            .collect();
+        assert_eq!(sans, ["example.com"]);
+        // the critical acmeIdentifier extension, with the proof as OCTET STRING
+        let der = cert.certificate_der().unwrap();
+        let mut ext = vec![0x06, 0x08, 0x2b, 0x06, 0x01, 0x05, 0x05, 0x07, 0x01, 0x1f];
+        ext.extend_from_slice(&[0x01, 0x01, 0xff, 0x04, 0x22, 0x04, 0x20]);
+        ext.extend_from_slice(&proof);
+        assert!(der.windows(ext.len()).any(|w| w == &ext[..]));
+        let pkey = PKey::private_key_from_pem(cert.private_key().as_bytes()).unwrap();
+        assert!(x509.verify(&pkey).unwrap());
+
+        // too long for a common name
+        let long = format!("{}.example.com", "a".repeat(60));
+        assert!(create_tls_alpn_certificate(&long, &proof).is_ok());
+    }
+
+    #[test]
+    fn test_chain() -> Result<()> {
+        let ca = crate::testing::ca::Ca::new()?;
+        let csr = create_csr(&create_p256_key(), &["example.com"])?;
+        let leaf = ca.issue(&csr, &["example.com".to_string()], 90)?;
+        let pem = |x: &X509| String::from_utf8(x.to_pem().unwrap()).unwrap();
+        let (leaf, intermediate, root) = (pem(&leaf), pem(ca.intermediate()), pem(ca.root()));
+
+        let cert = Certificate::new(String::new(), format!("{}{}", leaf, intermediate));
+        assert_eq!(cert.leaf_certificate()?, leaf);
+        assert_eq!(
+            cert.intermediate_certificates()?,
+            vec![intermediate.clone()]
+        );
+        assert_eq!(cert.root_certificate()?, None);
+        assert_eq!(
+            cert.issuer_common_name()?.as_deref(),
+            Some("acme-lib test root")
+        );
+
+        let chain = format!("{}{}{}", leaf, intermediate, root);
+        let cert = Certificate::new(String::new(), chain);
+        assert_eq!(cert.intermediate_certificates()?, [intermediate]);
+        assert_eq!(cert.root_certificate()?, Some(root));
+        assert_eq!(
+            cert.issuer_common_name()?.as_deref(),
+            Some("acme-lib test root")
+        );
+
+        // just the leaf
+        let cert = Certificate::new(String::new(), leaf.clone());
+        assert_eq!(cert.leaf_certificate()?, leaf);
+        assert!(cert.intermediate_certificates()?.is_empty());
+        assert_eq!(cert.root_certificate()?, None);
+        assert_eq!(
+            cert.issuer_common_name()?.as_deref(),
+            Some("acme-lib test intermediate")
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_inspect() -> Result<()> {
+        let ca = crate::testing::ca::Ca::new()?;
+        let pkey = create_p256_key();
+        let names = ["example.com".to_string(), "www.example.com".to_string()];
+        let csr = create_csr(&pkey, &["example.com", "www.example.com"])?;
+        let leaf = ca.issue(&csr, &names, 90)?;
+        let private_key = String::from_utf8(pkey.private_key_to_pem_pkcs8().unwrap()).unwrap();
+        let cert = Certificate::new(
+            private_key,
+            String::from_utf8(leaf.to_pem().unwrap()).unwrap(),
+        );
+
+        let now = SystemTime::now();
+        let (not_before, not_after) = (cert.not_before()?, cert.not_after()?);
+        assert!(not_before <= now && now < not_after);
+        let validity = not_after.duration_since(not_before).unwrap();
+        assert_eq!(validity.as_secs(), 90 * 86400);
+        let serial = cert.serial_number()?;
+        assert!(!serial.is_empty() && serial.chars().all(|c| c.is_ascii_hexdigit()));
+        assert_eq!(cert.issuer()?, "CN=acme-lib test intermediate");
+        assert_eq!(cert.subject()?, "CN=example.com");
+        assert_eq!(cert.dns_names()?, names);
+        assert!(cert.ip_addresses()?.is_empty());
+        assert_eq!(cert.key_type()?, KeyType::Ec);
+        assert_eq!(cert.key_bits()?, 256);
+        let digest = openssl::sha::sha256(&cert.certificate_der()?);
+        let fingerprint = cert.sha256_fingerprint()?;
+        assert_eq!(fingerprint.len(), 32 * 3 - 1);
+        assert!(fingerprint.starts_with(&format!("{:02X}:", digest[0])));
+        assert!(cert.matches_private_key(cert.private_key())?);
+        let other = create_p256_key().private_key_to_pem_pkcs8().unwrap();
+        assert!(!cert.matches_private_key(&String::from_utf8(other).unwrap())?);
+        assert!(cert.matches_private_key("not a key").is_err());
+
+        // rsa
+        let csr = create_csr(&create_rsa_key(2048), &["example.com"])?;
+        let leaf = ca.issue(&csr, &names[..1], 90)?;
+        let cert = Certificate::new(
+            String::new(),
+            String::from_utf8(leaf.to_pem().unwrap()).unwrap(),
+        );
+        assert_eq!(cert.key_type()?, KeyType::Rsa);
+        assert_eq!(cert.key_bits()?, 2048);
+
+        // ip addresses, in a self-signed certificate without names
+        let mut bld = X509Builder::new().unwrap();
+        bld.set_pubkey(&pkey).unwrap();
+        bld.set_not_before(&Asn1Time::from_unix(0).unwrap())
+            .unwrap();
+        bld.set_not_after(&Asn1Time::days_from_now(1).unwrap())
+            .unwrap();
+        let san = SubjectAlternativeName::new()
+            .dns("example.com")
+            .ip("127.0.0.1")
+            .ip("::1")
+            .build(&bld.x509v3_context(None, None))
+            .unwrap();
+        bld.append_extension(san).unwrap();
+        bld.sign(&pkey, MessageDigest::sha256()).unwrap();
+        let pem = String::from_utf8(bld.build().to_pem().unwrap()).unwrap();
+        let cert = Certificate::new(String::new(), pem);
+        let ips: Vec<IpAddr> = vec!["127.0.0.1".parse().unwrap(), "::1".parse().unwrap()];
+        assert_eq!(cert.ip_addresses()?, ips);
+        assert_eq!(cert.dns_names()?, ["example.com"]);
+        assert_eq!(cert.subject()?, "");
+        assert_eq!(cert.not_before()?, UNIX_EPOCH);
+        Ok(())
+    }
+
+    #[test]
+    fn test_create_csr_no_domains() {
+        let pkey = create_p256_key();
+        assert!(create_csr(&pkey, &[]).is_err());
+    }
+
+    #[test]
+    fn test_create_csr_alt_names() {
+        let pkey = create_p256_key();
+        let csr = create_csr(&pkey, &["example.com", "www.example.com"]).unwrap();
+        // each domain is a dNSName of its own in the SAN
+        let der = csr.to_der().unwrap();
+        for domain in &["example.com", "www.example.com"] {
+            let mut name = vec![0x82, domain.len() as u8];
+            name.extend_from_slice(domain.as_bytes());
+            assert!(der.windows(name.len()).any(|w| w == &name[..]));
+        }
     }
 }
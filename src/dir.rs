@@ -1,13 +1,15 @@
 //
 use std::sync::Arc;
 
-use crate::acc::AcmeKey;
+use crate::acc::{account_key_persist_key, AccountKeyAlgorithm, AcmeKey, ExternalAccountBinding};
 use crate::api::{ApiAccount, ApiDirectory};
-use crate::persist::{Persist, PersistKey, PersistKind};
-use crate::req::{req_expect_header, req_get, req_handle_error};
+use crate::http::{HttpClient, HttpRequest, UreqClient};
+use crate::persist::Persist;
+use crate::req::{req_expect_header, req_handle_error};
+use crate::signer::AccountSigner;
 use crate::trans::{NoncePool, Transport};
 use crate::util::read_json;
-use crate::{Account, Result};
+use crate::{Account, Error, OrderLedger, Result, RetryPolicy};
 
 const LETSENCRYPT: &str = "https://acme-v02.api.letsencrypt.org/directory";
 const LETSENCRYPT_STAGING: &str = "https://acme-staging-v02.api.letsencrypt.org/directory";
@@ -26,7 +28,7 @@ pub enum DirectoryUrl<'a> {
 }
 
 impl<'a> DirectoryUrl<'a> {
-    fn to_url(&self) -> &str {
+    pub(crate) fn to_url(&self) -> &str {
         match self {
             DirectoryUrl::LetsEncrypt => LETSENCRYPT,
             DirectoryUrl::LetsEncryptStaging => LETSENCRYPT_STAGING,
@@ -39,21 +41,40 @@ impl<'a> DirectoryUrl<'a> {
 #[derive(Clone)]
 pub struct Directory<P: Persist> {
     persist: P,
+    client: Arc<dyn HttpClient>,
     nonce_pool: Arc<NoncePool>,
     api_directory: ApiDirectory,
+    ledger: Option<OrderLedger>,
+    retry_policy: RetryPolicy,
 }
 
 impl<P: Persist> Directory<P> {
     /// Create a directory over a persistence implementation and directory url.
     pub fn from_url(persist: P, url: DirectoryUrl) -> Result<Directory<P>> {
+        Self::from_url_with_client(persist, url, Arc::new(UreqClient::new()))
+    }
+
+    /// Create a directory using the given [HTTP client] for all requests to the ACME API.
+    ///
+    /// The client is shared by all accounts and orders created from the directory.
+    ///
+    /// [HTTP client]: http/trait.HttpClient.html
+    pub fn from_url_with_client(
+        persist: P,
+        url: DirectoryUrl,
+        client: Arc<dyn HttpClient>,
+    ) -> Result<Directory<P>> {
         let dir_url = url.to_url();
-        let res = req_handle_error(req_get(&dir_url))?;
+        let res = req_handle_error(client.request(&HttpRequest::get(dir_url))?)?;
         let api_directory: ApiDirectory = read_json(res)?;
         let nonce_pool = Arc::new(NoncePool::new(&api_directory.newNonce));
         Ok(Directory {
             persist,
+            client,
             nonce_pool,
             api_directory,
+            ledger: None,
+            retry_policy: RetryPolicy::default(),
         })
     }
 
@@ -70,6 +91,12 @@ impl<P: Persist> Directory<P> {
     ///
     /// This is the same as calling
     /// `account_with_realm(contact_email, ["mailto: <contact_email>"]`)
+    ///
+    /// Creating an account this way agrees to the ACME API provider's terms of service
+    /// on behalf of the user. Use [`account_builder`] to present the terms and
+    /// explicitly agree to them.
+    ///
+    /// [`account_builder`]: struct.Directory.html#method.account_builder
     pub fn account(&self, contact_email: &str) -> Result<Account<P>> {
         // Contact email is the persistence realm when using this method.
         let contact = vec![format!("mailto:{}", contact_email)];
@@ -92,35 +119,147 @@ impl<P: Persist> Directory<P> {
     ///
     /// Either way the `newAccount` API endpoint is called and thereby ensures the
     /// account is active and working.
+    ///
+    /// Fails with [`Error::ExternalAccountRequired`] if a new account must be created
+    /// and the ACME API provider requires an external account binding. Use
+    /// [`account_with_eab`] for such providers.
+    ///
+    /// Like [`account`], this implicitly agrees to the terms of service.
+    ///
+    /// [`Error::ExternalAccountRequired`]: enum.Error.html#variant.ExternalAccountRequired
+    /// [`account_with_eab`]: struct.Directory.html#method.account_with_eab
+    /// [`account`]: struct.Directory.html#method.account
     pub fn account_with_realm(&self, realm: &str, contact: Vec<String>) -> Result<Account<P>> {
-        // key in persistence for acme account private key
-        let pem_key = PersistKey::new(realm, PersistKind::AccountPrivateKey, "acme_account");
-
-        // Get the key from a saved PEM, or from creating a new
-        let mut is_new = false;
-        let pem = self.persist().get(&pem_key)?;
-        let acme_key = if let Some(pem) = pem {
-            // we got a persisted private key. read it.
-            debug!("Read persisted acme account key");
-            AcmeKey::from_pem(&pem)?
-        } else {
-            // create a new key (and new account)
-            debug!("Create new acme account key");
-            is_new = true;
-            AcmeKey::new()
+        self.do_account(realm, new_account(contact), AccountOpts::default())
+    }
+
+    /// Access an account, binding it to an account with the ACME API provider
+    /// when it is created.
+    ///
+    /// Works like [`account_with_realm`], but if a new account is created, the
+    /// `newAccount` request carries an `externalAccountBinding` signed with the
+    /// credentials issued by the CA. Providers such as ZeroSSL, Google Trust Services
+    /// and Sectigo require this.
+    ///
+    /// The binding is only sent for new account keys. A persisted key is already
+    /// bound to its account.
+    ///
+    /// [`account_with_realm`]: struct.Directory.html#method.account_with_realm
+    pub fn account_with_eab(
+        &self,
+        realm: &str,
+        contact: Vec<String>,
+        eab: &ExternalAccountBinding,
+    ) -> Result<Account<P>> {
+        let opts = AccountOpts {
+            eab: Some(eab),
+            ..Default::default()
         };
+        self.do_account(realm, new_account(contact), opts)
+    }
 
-        // Prepare making a call to newAccount. This is fine to do both for
-        // new keys and existing. For existing the spec says to return a 200
-        // with the Location header set to the key id (kid).
-        let acc = ApiAccount {
-            contact,
-            termsOfServiceAgreed: Some(true),
+    /// Build access to an account under the persistence `realm`, with explicit
+    /// agreement to the terms of service.
+    ///
+    /// ```no_run
+    /// use acme_lib::{Error, Directory, DirectoryUrl};
+    /// use acme_lib::persist::FilePersist;
+    ///
+    /// fn create_account() -> Result<(), Error> {
+    ///   let dir = Directory::from_url(FilePersist::new("."), DirectoryUrl::LetsEncrypt)?;
+    ///   let builder = dir.account_builder("foo@bar.com");
+    ///   if let Some(url) = builder.terms_of_service() {
+    ///     // present the terms at `url` to the user, and only carry on if agreed.
+    ///   }
+    ///   let acc = builder
+    ///     .contact(vec!["mailto:foo@bar.com".into()])
+    ///     .agree_to_terms_of_service(true)
+    ///     .build()?;
+    ///   Ok(())
+    /// }
+    /// ```
+    pub fn account_builder(&self, realm: &str) -> AccountBuilder<'_, P> {
+        AccountBuilder {
+            dir: self,
+            realm: realm.to_string(),
+            contact: vec![],
+            terms_of_service_agreed: false,
+            eab: None,
+            key_algorithm: AccountKeyAlgorithm::default(),
+            signer: None,
+        }
+    }
+
+    /// Access an existing account using the private key persisted for the `realm`.
+    ///
+    /// Unlike [`account_with_realm`], this never creates an account. The `newAccount`
+    /// API endpoint is called with `onlyReturnExisting`, and if the ACME API provider
+    /// doesn't know the key, or there is no persisted key for the realm, this fails
+    /// with [`Error::AccountDoesNotExist`].
+    ///
+    /// [`account_with_realm`]: struct.Directory.html#method.account_with_realm
+    /// [`Error::AccountDoesNotExist`]: enum.Error.html#variant.AccountDoesNotExist
+    pub fn existing_account(&self, realm: &str) -> Result<Account<P>> {
+        self.do_account(realm, existing_account(), AccountOpts::default())
+    }
+
+    /// Recover an existing account from a PEM encoded private key.
+    ///
+    /// This looks up the account in the same way as [`existing_account`], but uses
+    /// the given key instead of the one in the persistence. Once the ACME API provider
+    /// has confirmed the account exists, the key is saved to the persistence under
+    /// the `realm`, replacing any previous key.
+    ///
+    /// [`existing_account`]: struct.Directory.html#method.existing_account
+    pub fn existing_account_from_pem(
+        &self,
+        realm: &str,
+        private_key_pem: &str,
+    ) -> Result<Account<P>> {
+        let acme_key = AcmeKey::from_pem(private_key_pem.as_bytes())?;
+        let opts = AccountOpts {
+            signer: Some(Arc::new(acme_key)),
             ..Default::default()
         };
+        self.do_account(realm, existing_account(), opts)
+    }
 
-        let mut transport = Transport::new(&self.nonce_pool, acme_key);
-        let res = transport.call_jwk(&self.api_directory.newAccount, &acc)?;
+    fn do_account(
+        &self,
+        realm: &str,
+        mut acc: ApiAccount,
+        opts: AccountOpts,
+    ) -> Result<Account<P>> {
+        let (signer, source) = account_signer(self.persist(), realm, &acc, &opts)?;
+
+        let mut transport =
+            Transport::new(&self.client, &self.nonce_pool, signer, &self.retry_policy);
+        let new_account_url = &self.api_directory.newAccount;
+
+        // a provided key might have an account already, which is then neither
+        // agreeing to the terms nor bound again.
+        let mut res = None;
+        if source == KeySource::Provided && !acc.onlyReturnExisting() {
+            match transport.call_jwk(new_account_url, &existing_account()) {
+                Ok(r) => res = Some(r),
+                Err(Error::AccountDoesNotExist) => {}
+                Err(e) => return Err(e),
+            }
+        }
+
+        // Make the call to newAccount. This is fine to do both for new keys and
+        // existing. For existing the spec says to return a 200 with the Location
+        // header set to the key id (kid).
+        let res = match res {
+            Some(res) => res,
+            None => {
+                if source.needs_binding(&acc) {
+                    let signer = transport.signer();
+                    new_account_binding(&self.api_directory, &mut acc, opts.eab, &*signer)?;
+                }
+                transport.call_jwk(new_account_url, &acc)?
+            }
+        };
         let kid = req_expect_header(&res, "location")?;
         debug!("Key id is: {}", kid);
         let api_account: ApiAccount = read_json(res)?;
@@ -128,11 +267,8 @@ impl<P: Persist> Directory<P> {
         // fill in the server returned key id
         transport.set_key_id(kid);
 
-        // If we did create a new key, save it back to the persistence.
-        if is_new {
-            debug!("Persist acme account key");
-            let pem = transport.acme_key().to_pem();
-            self.persist().put(&pem_key, &pem)?;
+        if source.save_key() {
+            save_account_key(self.persist(), realm, &*transport.signer())?;
         }
 
         // The finished account
@@ -142,9 +278,44 @@ impl<P: Persist> Directory<P> {
             realm,
             api_account,
             self.api_directory.clone(),
+            self.ledger.clone(),
         ))
     }
 
+    /// Keep track of new orders in the [ledger], refusing orders that would hit a
+    /// rate limit of the ACME API provider.
+    ///
+    /// Applies to accounts accessed after this call.
+    ///
+    /// [ledger]: struct.OrderLedger.html
+    pub fn with_order_ledger(mut self, ledger: OrderLedger) -> Self {
+        self.ledger = Some(ledger);
+        self
+    }
+
+    /// Retry calls failing with transient errors according to the [policy].
+    ///
+    /// Applies to accounts accessed after this call.
+    ///
+    /// [policy]: struct.RetryPolicy.html
+    pub fn with_retry_policy(mut self, retry_policy: RetryPolicy) -> Self {
+        self.retry_policy = retry_policy;
+        self
+    }
+
+    /// URL of the terms of service of the ACME API provider, if it has any.
+    pub fn terms_of_service(&self) -> Option<&str> {
+        self.api_directory.termsOfService()
+    }
+
+    /// Whether the ACME API provider requires an [external account binding]
+    /// to create new accounts.
+    ///
+    /// [external account binding]: struct.ExternalAccountBinding.html
+    pub fn external_account_required(&self) -> bool {
+        self.api_directory.externalAccountRequired()
+    }
+
     /// Access the underlying JSON object for debugging.
     pub fn api_directory(&self) -> &ApiDirectory {
         &self.api_directory
@@ -155,6 +326,225 @@ impl<P: Persist> Directory<P> {
     }
 }
 
+/// Builder for account access that requires explicit agreement to the terms of service.
+///
+/// Created using [`Directory::account_builder`]. If the ACME API provider has
+/// [terms of service], [`build`] fails to create a new account with
+/// [`Error::TermsOfServiceNotAgreed`] unless they have been agreed to with
+/// [`agree_to_terms_of_service`].
+///
+/// The account is otherwise accessed in the same way as [`Directory::account_with_realm`].
+///
+/// [`Directory::account_builder`]: struct.Directory.html#method.account_builder
+/// [terms of service]: struct.AccountBuilder.html#method.terms_of_service
+/// [`build`]: struct.AccountBuilder.html#method.build
+/// [`Error::TermsOfServiceNotAgreed`]: enum.Error.html#variant.TermsOfServiceNotAgreed
+/// [`agree_to_terms_of_service`]: struct.AccountBuilder.html#method.agree_to_terms_of_service
+/// [`Directory::account_with_realm`]: struct.Directory.html#method.account_with_realm
+pub struct AccountBuilder<'a, P: Persist> {
+    dir: &'a Directory<P>,
+    realm: String,
+    contact: Vec<String>,
+    terms_of_service_agreed: bool,
+    eab: Option<ExternalAccountBinding>,
+    key_algorithm: AccountKeyAlgorithm,
+    signer: Option<Arc<dyn AccountSigner>>,
+}
+
+impl<'a, P: Persist> AccountBuilder<'a, P> {
+    /// URL of the terms of service that must be agreed to, if the ACME API
+    /// provider has any.
+    pub fn terms_of_service(&self) -> Option<&str> {
+        self.dir.terms_of_service()
+    }
+
+    /// Set the contacts of the account, such as `mailto:foo@bar.com`.
+    pub fn contact(mut self, contact: Vec<String>) -> Self {
+        self.contact = contact;
+        self
+    }
+
+    /// Record whether the user agreed to the [terms of service].
+    ///
+    /// [terms of service]: struct.AccountBuilder.html#method.terms_of_service
+    pub fn agree_to_terms_of_service(mut self, agreed: bool) -> Self {
+        self.terms_of_service_agreed = agreed;
+        self
+    }
+
+    /// Bind a new account to an account with the ACME API provider, see
+    /// [`Directory::account_with_eab`].
+    ///
+    /// [`Directory::account_with_eab`]: struct.Directory.html#method.account_with_eab
+    pub fn external_account_binding(mut self, eab: ExternalAccountBinding) -> Self {
+        self.eab = Some(eab);
+        self
+    }
+
+    /// Algorithm of the account key, used if a new key is created. Defaults to `ES256`.
+    ///
+    /// A persisted key is used as is, whatever its algorithm.
+    pub fn key_algorithm(mut self, key_algorithm: AccountKeyAlgorithm) -> Self {
+        self.key_algorithm = key_algorithm;
+        self
+    }
+
+    /// Sign requests using the given [signer] instead of a key from the persistence.
+    ///
+    /// This is for account keys kept outside the library, such as in an HSM or a
+    /// cloud KMS. The signer's key is only saved to the persistence if the signer
+    /// exposes it as PEM.
+    ///
+    /// [signer]: signer/trait.AccountSigner.html
+    pub fn signer(mut self, signer: Arc<dyn AccountSigner>) -> Self {
+        self.signer = Some(signer);
+        self
+    }
+
+    /// Access the account, creating it if there is no persisted key for the realm.
+    ///
+    /// Agreement to the terms of service, and the external account binding, are only
+    /// required when the account is created. That is when there is no persisted key,
+    /// or the key of a given [signer] has no account yet.
+    ///
+    /// [signer]: struct.AccountBuilder.html#method.signer
+    pub fn build(self) -> Result<Account<P>> {
+        let acc = ApiAccount {
+            contact: self.contact,
+            termsOfServiceAgreed: Some(self.terms_of_service_agreed).filter(|a| *a),
+            ..Default::default()
+        };
+        let opts = AccountOpts {
+            eab: self.eab.as_ref(),
+            key_algorithm: self.key_algorithm,
+            signer: self.signer,
+        };
+        self.dir.do_account(&self.realm, acc, opts)
+    }
+}
+
+/// How to get hold of the account key, and bind new accounts.
+#[derive(Default)]
+pub(crate) struct AccountOpts<'a> {
+    pub eab: Option<&'a ExternalAccountBinding>,
+    pub signer: Option<Arc<dyn AccountSigner>>,
+    pub key_algorithm: AccountKeyAlgorithm,
+}
+
+/// Where the account key came from.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum KeySource {
+    /// Provided by the caller. It might or might not have an account already.
+    Provided,
+    /// Read from the persistence, and therefore has an account.
+    Persisted,
+    /// Newly created, without an account.
+    Generated,
+}
+
+impl KeySource {
+    /// Whether the `newAccount` request must be checked with [`new_account_binding`]
+    /// before it's sent, since it might create an account.
+    ///
+    /// A provided key is first looked up with [`existing_account`], and only
+    /// checked if there is no account for it.
+    pub fn needs_binding(self, acc: &ApiAccount) -> bool {
+        self != KeySource::Persisted && !acc.onlyReturnExisting()
+    }
+
+    /// Whether the key should be saved to the persistence once the account is confirmed.
+    pub fn save_key(self) -> bool {
+        self != KeySource::Persisted
+    }
+}
+
+/// Get the account key from the caller, a saved PEM, or from creating a new.
+pub(crate) fn account_signer<P: Persist>(
+    persist: &P,
+    realm: &str,
+    acc: &ApiAccount,
+    opts: &AccountOpts,
+) -> Result<(Arc<dyn AccountSigner>, KeySource)> {
+    // key in persistence for acme account private key
+    let pem_key = account_key_persist_key(realm);
+
+    if let Some(signer) = &opts.signer {
+        debug!("Use provided acme account signer");
+        Ok((signer.clone(), KeySource::Provided))
+    } else if let Some(pem) = persist.get(&pem_key)? {
+        // we got a persisted private key. read it.
+        debug!("Read persisted acme account key");
+        Ok((Arc::new(AcmeKey::from_pem(&pem)?), KeySource::Persisted))
+    } else {
+        // without a key, there's nothing to look up.
+        if acc.onlyReturnExisting() {
+            return Err(Error::AccountDoesNotExist);
+        }
+        // create a new key (and new account)
+        debug!("Create new acme account key: {:?}", opts.key_algorithm);
+        let key = AcmeKey::generate(opts.key_algorithm)?;
+        Ok((Arc::new(key), KeySource::Generated))
+    }
+}
+
+/// Check that a new account has agreed to the terms of service, and bind it to an
+/// external account if the provider requires it.
+pub(crate) fn new_account_binding(
+    api_directory: &ApiDirectory,
+    acc: &mut ApiAccount,
+    eab: Option<&ExternalAccountBinding>,
+    signer: &dyn AccountSigner,
+) -> Result<()> {
+    if let Some(url) = api_directory.termsOfService() {
+        if !acc.termsOfServiceAgreed() {
+            return Err(Error::TermsOfServiceNotAgreed(url.to_string()));
+        }
+    }
+    match eab {
+        Some(eab) => {
+            debug!("Bind new account to external account: {}", eab.key_id());
+            let jws = eab.to_jws(&api_directory.newAccount, signer)?;
+            acc.externalAccountBinding = Some(jws);
+        }
+        None if api_directory.externalAccountRequired() => {
+            return Err(Error::ExternalAccountRequired);
+        }
+        None => {}
+    }
+    Ok(())
+}
+
+/// Save a created (or imported) key back to the persistence. Signers keeping the
+/// key elsewhere don't expose it.
+pub(crate) fn save_account_key<P: Persist>(
+    persist: &P,
+    realm: &str,
+    signer: &dyn AccountSigner,
+) -> Result<()> {
+    if let Some(pem) = signer.private_key_pem() {
+        debug!("Persist acme account key");
+        persist.put(&account_key_persist_key(realm), &pem)?;
+    }
+    Ok(())
+}
+
+/// The `newAccount` request creating an account (unless the key already has one).
+pub(crate) fn new_account(contact: Vec<String>) -> ApiAccount {
+    ApiAccount {
+        contact,
+        termsOfServiceAgreed: Some(true),
+        ..Default::default()
+    }
+}
+
+/// The `newAccount` request only looking up an account.
+pub(crate) fn existing_account() -> ApiAccount {
+    ApiAccount {
+        onlyReturnExisting: Some(true),
+        ..Default::default()
+    }
+}
+
 #[cfg(test)]
 mod test {
     use super::*;
@@ -168,6 +558,46 @@ mod test {
         Ok(())
     }
 
+    #[test]
+    fn test_memory_client() -> Result<()> {
+        use crate::http::{HttpMethod, HttpResponse, MemoryClient};
+        let client = MemoryClient::new(|req| {
+            let res = match (req.method, &req.url[..]) {
+                (HttpMethod::Get, "mem:/directory") => HttpResponse::new(200).with_body(
+                    r#"{"newAccount":"mem:/new-acct","newNonce":"mem:/new-nonce",
+                    "newOrder":"mem:/new-order","revokeCert":"mem:/revoke-cert",
+                    "keyChange":"mem:/key-change"}"#,
+                ),
+                (HttpMethod::Head, "mem:/new-nonce") => {
+                    HttpResponse::new(200).with_header("Replay-Nonce", "nonce-1")
+                }
+                (HttpMethod::Post, "mem:/new-acct") => HttpResponse::new(201)
+                    .with_header("Location", "mem:/acct/1")
+                    .with_header("Replay-Nonce", "nonce-2")
+                    .with_body(r#"{"status":"valid"}"#),
+                _ => HttpResponse::new(404),
+            };
+            Ok(res)
+        });
+        let url = DirectoryUrl::Other("mem:/directory");
+        let dir =
+            Directory::from_url_with_client(MemoryPersist::new(), url, Arc::new(client.clone()))?;
+        let acc = dir.account("foo@bar.com")?;
+        assert_eq!(acc.account_url(), "mem:/acct/1");
+
+        let reqs = client.requests();
+        let methods: Vec<_> = reqs.iter().map(|r| r.method).collect();
+        assert_eq!(
+            methods,
+            [HttpMethod::Get, HttpMethod::Head, HttpMethod::Post]
+        );
+        assert_eq!(
+            reqs[2].header("content-type"),
+            Some("application/jose+json")
+        );
+        Ok(())
+    }
+
     #[test]
     fn test_create_acount() -> Result<()> {
         let server = crate::test::with_directory_server();
@@ -192,6 +622,178 @@ mod test {
         Ok(())
     }
 
+    #[test]
+    fn test_create_account_eab() -> Result<()> {
+        let server = crate::test::with_directory_server();
+        let url = format!("{}/eab", server.dir_url);
+        let persist = MemoryPersist::new();
+        let dir = Directory::from_url(persist, DirectoryUrl::Other(&url))?;
+        assert!(dir.external_account_required());
+        let contact = vec!["mailto:foo@bar.com".to_string()];
+        match dir.account_with_realm("foo@bar.com", contact.clone()) {
+            Err(Error::ExternalAccountRequired) => {}
+            x => panic!("Expected ExternalAccountRequired: {:?}", x.err()),
+        }
+        // also with a provided signer
+        let key = AcmeKey::generate(AccountKeyAlgorithm::ES256)?;
+        let res = dir
+            .account_builder("foo@bar.com")
+            .agree_to_terms_of_service(true)
+            .signer(Arc::new(key))
+            .build();
+        match res {
+            Err(Error::ExternalAccountRequired) => {}
+            x => panic!("Expected ExternalAccountRequired: {:?}", x.err()),
+        }
+        // the server checks the key id and the MAC
+        for (kid, key) in &[("kid-2", "c2VjcmV0"), ("kid-1", "b3RoZXI")] {
+            let eab = ExternalAccountBinding::new(kid, key)?;
+            let res = dir.account_with_eab("foo@bar.com", contact.clone(), &eab);
+            match res.map_err(|e| e.problem_kind()) {
+                Err(Some(crate::ProblemKind::Unauthorized)) => {}
+                x => panic!("Expected unauthorized: {:?}", x.err()),
+            }
+        }
+        let eab = ExternalAccountBinding::new("kid-1", "c2VjcmV0")?;
+        let _ = dir.account_with_eab("foo@bar.com", contact, &eab)?;
+        Ok(())
+    }
+
+    #[test]
+    fn test_reopen_signer_account_eab() -> Result<()> {
+        let server = crate::test::with_directory_server();
+        let url = format!("{}/eab", server.dir_url);
+        let dir = Directory::from_url(MemoryPersist::new(), DirectoryUrl::Other(&url))?;
+        let signer: Arc<dyn AccountSigner> =
+            Arc::new(AcmeKey::generate(AccountKeyAlgorithm::ES256)?);
+        let eab = ExternalAccountBinding::new("kid-1", "c2VjcmV0")?;
+        let acc1 = dir
+            .account_builder("foo@bar.com")
+            .agree_to_terms_of_service(true)
+            .external_account_binding(eab)
+            .signer(signer.clone())
+            .build()?;
+        // reopened without agreeing or binding again.
+        let acc2 = dir.account_builder("bar@bar.com").signer(signer).build()?;
+        assert_eq!(acc1.account_url(), acc2.account_url());
+        Ok(())
+    }
+
+    #[test]
+    fn test_existing_account() -> Result<()> {
+        let server = crate::test::with_directory_server();
+        let url = DirectoryUrl::Other(&server.dir_url);
+        let persist = MemoryPersist::new();
+        let dir = Directory::from_url(persist, url)?;
+        match dir.existing_account("foo@bar.com") {
+            Err(Error::AccountDoesNotExist) => {}
+            x => panic!("Expected AccountDoesNotExist: {:?}", x.err()),
+        }
+        let acc1 = dir.account("foo@bar.com")?;
+        let acc2 = dir.existing_account("foo@bar.com")?;
+        assert_eq!(acc1.acme_private_key_pem(), acc2.acme_private_key_pem());
+        Ok(())
+    }
+
+    #[test]
+    fn test_existing_account_from_pem() -> Result<()> {
+        let server = crate::test::with_directory_server();
+        let url = DirectoryUrl::Other(&server.dir_url);
+        let dir1 = Directory::from_url(MemoryPersist::new(), url.clone())?;
+        let pem = dir1.account("foo@bar.com")?.acme_private_key_pem();
+
+        // unknown to the ACME API
+        let dir2 = Directory::from_url(MemoryPersist::new(), url)?;
+        let unknown = AcmeKey::generate(AccountKeyAlgorithm::ES256)?.to_pem();
+        let unknown = String::from_utf8(unknown).unwrap();
+        match dir2.existing_account_from_pem("foo@bar.com", &unknown) {
+            Err(Error::AccountDoesNotExist) => {}
+            x => panic!("Expected AccountDoesNotExist: {:?}", x.err()),
+        }
+
+        // recovered and persisted
+        let acc = dir2.existing_account_from_pem("foo@bar.com", &pem)?;
+        assert_eq!(pem, acc.acme_private_key_pem());
+        let acc = dir2.existing_account("foo@bar.com")?;
+        assert_eq!(pem, acc.acme_private_key_pem());
+        Ok(())
+    }
+
+    #[test]
+    fn test_account_builder_terms_of_service() -> Result<()> {
+        let server = crate::test::with_directory_server();
+        let url = DirectoryUrl::Other(&server.dir_url);
+        let persist = MemoryPersist::new();
+        let dir = Directory::from_url(persist, url)?;
+        let tos = dir.terms_of_service().unwrap().to_string();
+        assert!(tos.ends_with("/terms"));
+        match dir.account_builder("foo@bar.com").build() {
+            Err(Error::TermsOfServiceNotAgreed(url)) => assert_eq!(url, tos),
+            x => panic!("Expected TermsOfServiceNotAgreed: {:?}", x.err()),
+        }
+        let acc = dir
+            .account_builder("foo@bar.com")
+            .contact(vec!["mailto:foo@bar.com".into()])
+            .agree_to_terms_of_service(true)
+            .build()?;
+        assert!(acc.api_account().is_status_valid());
+        // the existing account doesn't need to agree again.
+        let acc2 = dir.account_builder("foo@bar.com").build()?;
+        assert_eq!(acc.account_url(), acc2.account_url());
+        Ok(())
+    }
+
+    #[test]
+    fn test_account_key_algorithms() -> Result<()> {
+        let server = crate::test::with_directory_server();
+        let url = DirectoryUrl::Other(&server.dir_url);
+        let persist = MemoryPersist::new();
+        let dir = Directory::from_url(persist, url)?;
+        let algs = [
+            AccountKeyAlgorithm::ES384,
+            AccountKeyAlgorithm::RS256(2048),
+            AccountKeyAlgorithm::EdDSA,
+        ];
+        for alg in &algs {
+            let realm = format!("{:?}", alg);
+            let acc = dir
+                .account_builder(&realm)
+                .agree_to_terms_of_service(true)
+                .key_algorithm(*alg)
+                .build()?;
+            let _ = acc.new_order("acmetest.example.com", &[])?;
+            // the persisted key is read back with the same algorithm
+            let acc2 = dir.existing_account(&realm)?;
+            assert_eq!(acc.acme_private_key_pem(), acc2.acme_private_key_pem());
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn test_account_signer() -> Result<()> {
+        use crate::signer::RecordingSigner;
+        let server = crate::test::with_directory_server();
+        let url = DirectoryUrl::Other(&server.dir_url);
+        let persist = MemoryPersist::new();
+        let dir = Directory::from_url(persist.clone(), url)?;
+        let key = AcmeKey::generate(AccountKeyAlgorithm::ES256)?;
+        let signer = RecordingSigner::new(Arc::new(key));
+        let acc = dir
+            .account_builder("foo@bar.com")
+            .agree_to_terms_of_service(true)
+            .signer(Arc::new(signer.clone()))
+            .build()?;
+        // looked up first, then created.
+        assert_eq!(signer.signatures().len(), 2);
+        let _ = acc.new_order("acmetest.example.com", &[])?;
+        assert_eq!(signer.signatures().len(), 3);
+        // the key isn't exposed, and therefore not persisted.
+        assert!(acc.try_acme_private_key_pem().is_err());
+        let pem_key = account_key_persist_key("foo@bar.com");
+        assert!(persist.get(&pem_key)?.is_none());
+        Ok(())
+    }
+
     // #[test]
     // fn test_the_whole_hog() -> Result<()> {
     //     std::env::set_var("RUST_LOG", "acme_lib=trace");
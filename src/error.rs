@@ -1,6 +1,7 @@
 //
 use std::fmt;
 use std::io;
+use std::time::{SystemTime, UNIX_EPOCH};
 
 use crate::api::ApiProblem;
 
@@ -10,7 +11,10 @@ pub type Result<T> = std::result::Result<T, Error>;
 /// acme-lib errors.
 #[derive(Debug)]
 pub enum Error {
-    /// An API call failed.
+    /// An API call failed, or a challenge failed validation, with the problem
+    /// reported by the ACME API. See [`Error::problem_kind`].
+    ///
+    /// [`Error::problem_kind`]: enum.Error.html#method.problem_kind
     ApiProblem(ApiProblem),
     /// An API call failed.
     Call(String),
@@ -20,6 +24,45 @@ pub enum Error {
     Json(serde_json::Error),
     /// std::io error.
     Io(io::Error),
+    /// The ACME API provider requires an [external account binding] to create
+    /// new accounts, and none was provided.
+    ///
+    /// [external account binding]: struct.ExternalAccountBinding.html
+    ExternalAccountRequired,
+    /// Looking up an existing account found no account for the key.
+    AccountDoesNotExist,
+    /// The ACME API provider has terms of service (at the given URL) which
+    /// have not been agreed to.
+    TermsOfServiceNotAgreed(String),
+    /// The ACME API provider requires the user to take some action before the
+    /// account can be used, typically to agree to changed terms of service.
+    ///
+    /// The `instance` of the problem is a URL for the user to visit. Changed terms
+    /// of service can be agreed to with [`Account::agree_to_terms_of_service`].
+    ///
+    /// [`Account::agree_to_terms_of_service`]: struct.Account.html#method.agree_to_terms_of_service
+    UserActionRequired(ApiProblem),
+    /// The ACME API provider refused the request because a rate limit was hit, or the
+    /// [`OrderLedger`] refused a new order that would hit one.
+    ///
+    /// `retry_at` is the earliest time to try again, when known.
+    ///
+    /// [`OrderLedger`]: struct.OrderLedger.html
+    RateLimited {
+        problem: ApiProblem,
+        retry_at: Option<SystemTime>,
+    },
+    /// A call to the ACME API kept failing with transient errors, such as bad nonces,
+    /// server errors or connection errors, until the [`RetryPolicy`] gave up. Holds the
+    /// error of every attempt, in order.
+    ///
+    /// [`RetryPolicy`]: struct.RetryPolicy.html
+    RetriesExhausted(Vec<Error>),
+    /// Polling an order or authorization didn't reach a final status before
+    /// the deadline of the [`PollPolicy`].
+    ///
+    /// [`PollPolicy`]: struct.PollPolicy.html
+    Timeout(String),
     /// Some other error. Notice that `Error` is
     /// `From<String>` and `From<&str>` and it becomes `Other`.
     Other(String),
@@ -34,14 +77,213 @@ impl fmt::Display for Error {
             Error::Base64Decode(e) => write!(f, "{}", e),
             Error::Json(e) => write!(f, "{}", e),
             Error::Io(e) => write!(f, "{}", e),
+            Error::ExternalAccountRequired => {
+                write!(f, "ACME API provider requires an external account binding")
+            }
+            Error::AccountDoesNotExist => write!(f, "No account exists for the key"),
+            Error::TermsOfServiceNotAgreed(url) => {
+                write!(f, "Terms of service not agreed to: {}", url)
+            }
+            Error::UserActionRequired(a) => match &a.instance {
+                Some(instance) => write!(f, "{} (see {})", a, instance),
+                None => write!(f, "{}", a),
+            },
+            Error::RateLimited { problem, retry_at } => match retry_at {
+                Some(retry_at) => {
+                    let secs = retry_at.duration_since(UNIX_EPOCH).unwrap_or_default();
+                    let tm = time::at_utc(time::Timespec::new(secs.as_secs() as i64, 0));
+                    write!(f, "{} (retry after {})", problem, tm.rfc3339())
+                }
+                None => write!(f, "{}", problem),
+            },
+            Error::RetriesExhausted(errors) => {
+                write!(f, "Gave up after {} attempts", errors.len())?;
+                if let Some(last) = errors.last() {
+                    write!(f, ", last error: {}", last)?;
+                }
+                Ok(())
+            }
+            Error::Timeout(s) => write!(f, "{}", s),
             Error::Other(s) => write!(f, "{}", s),
         }
     }
 }
 
+impl Error {
+    /// The problem reported by the ACME API, if the error is one. For
+    /// `RetriesExhausted` it is the problem of the last attempt.
+    pub fn problem(&self) -> Option<&ApiProblem> {
+        match self {
+            Error::ApiProblem(p) => Some(p),
+            Error::UserActionRequired(p) => Some(p),
+            Error::RateLimited { problem, .. } => Some(problem),
+            Error::RetriesExhausted(errors) => errors.last().and_then(|e| e.problem()),
+            _ => None,
+        }
+    }
+
+    /// The kind of problem reported by the ACME API, if the error is one.
+    ///
+    /// ```
+    /// use acme_lib::{Error, ProblemKind};
+    ///
+    /// fn worth_retrying_later(err: &Error) -> bool {
+    ///     match err.problem_kind() {
+    ///         Some(ProblemKind::Dns) | Some(ProblemKind::Connection) => true,
+    ///         Some(ProblemKind::RateLimited) | Some(ProblemKind::ServerInternal) => true,
+    ///         _ => false,
+    ///     }
+    /// }
+    /// ```
+    pub fn problem_kind(&self) -> Option<ProblemKind> {
+        match self {
+            Error::AccountDoesNotExist => Some(ProblemKind::AccountDoesNotExist),
+            _ => self.problem().map(|p| p.kind()),
+        }
+    }
+}
+
+/// The kinds of [problems] the ACME API reports, as registered in RFC 8555.
+///
+/// Problem types are URNs such as `urn:ietf:params:acme:error:badCSR`. Older ACME
+/// APIs use the `urn:acme:error:` prefix, which maps to the same kinds.
+///
+/// [problems]: https://tools.ietf.org/html/rfc8555#section-6.7
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub enum ProblemKind {
+    /// The request specified an account that does not exist.
+    AccountDoesNotExist,
+    /// The request specified a certificate to be revoked that has already been revoked.
+    AlreadyRevoked,
+    /// The CSR is unacceptable, e.g. due to a short key.
+    BadCsr,
+    /// The client sent an unacceptable anti-replay nonce.
+    BadNonce,
+    /// The JWS was signed by a public key the server does not support.
+    BadPublicKey,
+    /// The revocation reason provided is not allowed by the server.
+    BadRevocationReason,
+    /// The JWS was signed with an algorithm the server does not support.
+    BadSignatureAlgorithm,
+    /// Certification Authority Authorization (CAA) records forbid the CA from issuing
+    /// a certificate.
+    Caa,
+    /// Specific error conditions are indicated in the subproblems.
+    Compound,
+    /// The server could not connect to the validation target.
+    Connection,
+    /// There was a problem with a DNS query during identifier validation.
+    Dns,
+    /// The request must include a value for the `externalAccountBinding` field.
+    ExternalAccountRequired,
+    /// Response received didn't match the challenge's requirements.
+    IncorrectResponse,
+    /// A contact URL for an account was invalid.
+    InvalidContact,
+    /// The request message was malformed.
+    Malformed,
+    /// The request attempted to finalize an order that is not ready to be finalized.
+    OrderNotReady,
+    /// The request exceeds a rate limit.
+    RateLimited,
+    /// The server will not issue certificates for the identifier.
+    RejectedIdentifier,
+    /// The server experienced an internal error.
+    ServerInternal,
+    /// The server received a TLS error during validation.
+    Tls,
+    /// The client lacks sufficient authorization.
+    Unauthorized,
+    /// A contact URL for an account used an unsupported protocol scheme.
+    UnsupportedContact,
+    /// An identifier is of an unsupported type.
+    UnsupportedIdentifier,
+    /// Visit the `instance` URL and take actions specified there.
+    UserActionRequired,
+    /// Any other problem type, kept as is.
+    Other(String),
+}
+
+const ACME_ERROR: &str = "urn:ietf:params:acme:error:";
+const ACME_ERROR_OLD: &str = "urn:acme:error:";
+
+const PROBLEM_KINDS: &[(&str, ProblemKind)] = &[
+    ("accountDoesNotExist", ProblemKind::AccountDoesNotExist),
+    ("alreadyRevoked", ProblemKind::AlreadyRevoked),
+    ("badCSR", ProblemKind::BadCsr),
+    ("badNonce", ProblemKind::BadNonce),
+    ("badPublicKey", ProblemKind::BadPublicKey),
+    ("badRevocationReason", ProblemKind::BadRevocationReason),
+    ("badSignatureAlgorithm", ProblemKind::BadSignatureAlgorithm),
+    ("caa", ProblemKind::Caa),
+    ("compound", ProblemKind::Compound),
+    ("connection", ProblemKind::Connection),
+    ("dns", ProblemKind::Dns),
+    (
+        "externalAccountRequired",
+        ProblemKind::ExternalAccountRequired,
+    ),
+    ("incorrectResponse", ProblemKind::IncorrectResponse),
+    ("invalidContact", ProblemKind::InvalidContact),
+    ("malformed", ProblemKind::Malformed),
+    ("orderNotReady", ProblemKind::OrderNotReady),
+    ("rateLimited", ProblemKind::RateLimited),
+    ("rejectedIdentifier", ProblemKind::RejectedIdentifier),
+    ("serverInternal", ProblemKind::ServerInternal),
+    ("tls", ProblemKind::Tls),
+    ("unauthorized", ProblemKind::Unauthorized),
+    ("unsupportedContact", ProblemKind::UnsupportedContact),
+    ("unsupportedIdentifier", ProblemKind::UnsupportedIdentifier),
+    ("userActionRequired", ProblemKind::UserActionRequired),
+];
+
+impl ProblemKind {
+    /// The kind of a problem type, with either URN prefix.
+    pub fn from_type(_type: &str) -> ProblemKind {
+        let name = _type
+            .strip_prefix(ACME_ERROR)
+            .or_else(|| _type.strip_prefix(ACME_ERROR_OLD))
+            .unwrap_or(_type);
+        PROBLEM_KINDS
+            .iter()
+            .find(|(n, _)| *n == name)
+            .map(|(_, k)| k.clone())
+            .unwrap_or_else(|| ProblemKind::Other(_type.to_string()))
+    }
+
+    /// The problem type URN.
+    pub fn to_type(&self) -> String {
+        match self {
+            ProblemKind::Other(t) => t.clone(),
+            _ => {
+                let name = PROBLEM_KINDS
+                    .iter()
+                    .find(|(_, k)| k == self)
+                    .map(|(n, _)| *n)
+                    .expect("problem kind name");
+                format!("{}{}", ACME_ERROR, name)
+            }
+        }
+    }
+}
+
+impl fmt::Display for ProblemKind {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.to_type())
+    }
+}
+
 impl From<ApiProblem> for Error {
     fn from(e: ApiProblem) -> Self {
-        Error::ApiProblem(e)
+        match e.kind() {
+            ProblemKind::AccountDoesNotExist => Error::AccountDoesNotExist,
+            ProblemKind::UserActionRequired => Error::UserActionRequired(e),
+            ProblemKind::RateLimited => Error::RateLimited {
+                problem: e,
+                retry_at: None,
+            },
+            _ => Error::ApiProblem(e),
+        }
     }
 }
 
@@ -68,3 +310,46 @@ impl From<&str> for Error {
         Error::Other(s.to_string())
     }
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_problem_kind() {
+        let kind = ProblemKind::from_type("urn:ietf:params:acme:error:badCSR");
+        assert_eq!(kind, ProblemKind::BadCsr);
+        assert_eq!(ProblemKind::from_type("urn:acme:error:badCSR"), kind);
+        assert_eq!(kind.to_string(), "urn:ietf:params:acme:error:badCSR");
+        for (name, kind) in PROBLEM_KINDS {
+            assert_eq!(&ProblemKind::from_type(&kind.to_type()), kind);
+            assert!(kind.to_type().ends_with(name));
+        }
+        let other = ProblemKind::from_type("urn:ietf:params:acme:error:somethingNew");
+        assert_eq!(
+            other,
+            ProblemKind::Other("urn:ietf:params:acme:error:somethingNew".into())
+        );
+        assert_eq!(other.to_type(), "urn:ietf:params:acme:error:somethingNew");
+    }
+
+    #[test]
+    fn test_error_problem_kind() {
+        let problem = |t: &str| ApiProblem {
+            _type: t.into(),
+            ..Default::default()
+        };
+        let err: Error = problem("urn:ietf:params:acme:error:rateLimited").into();
+        assert!(matches!(err, Error::RateLimited { .. }));
+        assert_eq!(err.problem_kind(), Some(ProblemKind::RateLimited));
+        let err: Error = problem("urn:acme:error:accountDoesNotExist").into();
+        assert!(matches!(err, Error::AccountDoesNotExist));
+        assert_eq!(err.problem_kind(), Some(ProblemKind::AccountDoesNotExist));
+        let err = Error::RetriesExhausted(vec![
+            problem("urn:ietf:params:acme:error:badNonce").into(),
+            problem("urn:ietf:params:acme:error:serverInternal").into(),
+        ]);
+        assert_eq!(err.problem_kind(), Some(ProblemKind::ServerInternal));
+        assert_eq!(Error::Other("x".into()).problem_kind(), None);
+    }
+}
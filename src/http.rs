@@ -0,0 +1,536 @@
+//! Pluggable HTTP client used for all requests to the ACME API.
+//!
+//! By default requests are made with [`UreqClient`], which can be configured with a
+//! proxy, extra root certificates for private ACME servers, and a client certificate.
+//! Anything else can be plugged in by implementing [`HttpClient`] and creating the
+//! directory using [`Directory::from_url_with_client`].
+//!
+//! [`MemoryClient`] answers requests in-process, for unit tests that shouldn't touch
+//! the network.
+//!
+//! [`UreqClient`]: struct.UreqClient.html
+//! [`HttpClient`]: trait.HttpClient.html
+//! [`Directory::from_url_with_client`]: ../struct.Directory.html#method.from_url_with_client
+//! [`MemoryClient`]: struct.MemoryClient.html
+use openssl::pkey::PKey;
+use openssl::x509::X509;
+use std::collections::HashSet;
+use std::io::Read;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use crate::{Error, Result};
+
+/// HTTP method of a request.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum HttpMethod {
+    /// `GET`, for the directory.
+    Get,
+    /// `HEAD`, for new nonces.
+    Head,
+    /// `POST`, for everything else.
+    Post,
+}
+
+impl HttpMethod {
+    /// The method name, such as `"POST"`.
+    pub fn as_str(self) -> &'static str {
+        match self {
+            HttpMethod::Get => "GET",
+            HttpMethod::Head => "HEAD",
+            HttpMethod::Post => "POST",
+        }
+    }
+}
+
+/// A request to the ACME API.
+#[derive(Debug, Clone)]
+pub struct HttpRequest {
+    /// The method.
+    pub method: HttpMethod,
+    /// The full URL.
+    pub url: String,
+    /// Headers as name and value.
+    pub headers: Vec<(String, String)>,
+    /// The body, which is empty for all but `POST`.
+    pub body: Vec<u8>,
+}
+
+impl HttpRequest {
+    pub(crate) fn get(url: &str) -> Self {
+        Self::new(HttpMethod::Get, url)
+    }
+
+    pub(crate) fn head(url: &str) -> Self {
+        Self::new(HttpMethod::Head, url)
+    }
+
+    pub(crate) fn post(url: &str, body: &str) -> Self {
+        let mut req = Self::new(HttpMethod::Post, url);
+        req.headers
+            .push(("content-type".into(), "application/jose+json".into()));
+        req.body = body.as_bytes().to_vec();
+        req
+    }
+
+    fn new(method: HttpMethod, url: &str) -> Self {
+        HttpRequest {
+            method,
+            url: url.into(),
+            headers: vec![],
+            body: vec![],
+        }
+    }
+
+    /// Get a header value. The name is case insensitive.
+    pub fn header(&self, name: &str) -> Option<&str> {
+        find_header(&self.headers, name)
+    }
+}
+
+/// A response from the ACME API, with the body fully read.
+#[derive(Debug, Clone, Default)]
+pub struct HttpResponse {
+    /// The status code.
+    pub status: u16,
+    /// Headers as name and value.
+    pub headers: Vec<(String, String)>,
+    /// The body.
+    pub body: Vec<u8>,
+}
+
+impl HttpResponse {
+    /// Create a response with the status code, no headers and an empty body.
+    pub fn new(status: u16) -> Self {
+        HttpResponse {
+            status,
+            ..Default::default()
+        }
+    }
+
+    /// Add a header.
+    pub fn with_header(mut self, name: &str, value: &str) -> Self {
+        self.headers.push((name.into(), value.into()));
+        self
+    }
+
+    /// Set the body.
+    pub fn with_body<B: Into<Vec<u8>>>(mut self, body: B) -> Self {
+        self.body = body.into();
+        self
+    }
+
+    /// Get a header value. The name is case insensitive.
+    pub fn header(&self, name: &str) -> Option<&str> {
+        find_header(&self.headers, name)
+    }
+
+    /// The content type without parameters such as `charset`.
+    pub fn content_type(&self) -> &str {
+        self.header("content-type")
+            .and_then(|v| v.split(';').next())
+            .map(|v| v.trim())
+            .unwrap_or("")
+    }
+
+    /// Whether the status is 2xx.
+    pub fn ok(&self) -> bool {
+        self.status >= 200 && self.status < 300
+    }
+
+    /// The body as text, replacing invalid UTF-8.
+    pub fn body_str(&self) -> String {
+        String::from_utf8_lossy(&self.body).into_owned()
+    }
+
+    /// How long the server asks us to wait before polling again, from the
+    /// `Retry-After` header in either of its forms, seconds or an HTTP date.
+    ///
+    /// A date in the past is a zero duration.
+    pub fn retry_after(&self) -> Option<Duration> {
+        let value = self.header("retry-after")?.trim();
+        if let Ok(secs) = value.parse::<u64>() {
+            return Some(Duration::from_secs(secs));
+        }
+        // Wed, 21 Oct 2015 07:28:00 GMT
+        let date = time::strptime(value, "%a, %d %b %Y %H:%M:%S GMT").ok()?;
+        let secs = (date.to_timespec() - time::get_time()).num_seconds();
+        Some(Duration::from_secs(secs.max(0) as u64))
+    }
+
+    /// The URLs of the `Link` headers with the relation, such as the `alternate`
+    /// certificate chains. Links may be in separate headers or comma separated.
+    pub fn links(&self, rel: &str) -> Vec<String> {
+        self.headers
+            .iter()
+            .filter(|(n, _)| n.eq_ignore_ascii_case("link"))
+            .flat_map(|(_, v)| v.split(','))
+            .filter_map(|link| {
+                let mut parts = link.split(';');
+                let url = parts.next()?.trim();
+                let url = url.strip_prefix('<')?.strip_suffix('>')?;
+                let has_rel = parts.any(|p| {
+                    let (name, value) = p.split_once('=').unwrap_or((p, ""));
+                    let value = value.trim().trim_matches('"');
+                    name.trim().eq_ignore_ascii_case("rel")
+                        && value
+                            .split_whitespace()
+                            .any(|r| r.eq_ignore_ascii_case(rel))
+                });
+                Some(url.to_string()).filter(|_| has_rel)
+            })
+            .collect()
+    }
+}
+
+fn find_header<'a>(headers: &'a [(String, String)], name: &str) -> Option<&'a str> {
+    headers
+        .iter()
+        .find(|(n, _)| n.eq_ignore_ascii_case(name))
+        .map(|(_, v)| v.as_str())
+}
+
+/// Client making the HTTP requests to the ACME API.
+///
+/// The client is shared by the directory, the accounts and orders created from it,
+/// and must therefore be thread safe.
+pub trait HttpClient: std::fmt::Debug + Send + Sync {
+    /// Make the request and read the whole response.
+    ///
+    /// Responses with error statuses (4xx, 5xx) are returned as `Ok`, since the ACME
+    /// API explains its errors in the body. `Err` is only for failing to get a response
+    /// at all, such as connection errors and timeouts.
+    fn request(&self, req: &HttpRequest) -> Result<HttpResponse>;
+}
+
+/// The default client, using [ureq].
+///
+/// ```no_run
+/// use acme_lib::http::UreqClient;
+/// use acme_lib::persist::MemoryPersist;
+/// use acme_lib::{Directory, DirectoryUrl, Error};
+/// use std::sync::Arc;
+/// use std::time::Duration;
+///
+/// fn private_acme() -> Result<(), Error> {
+///   let ca_pem = std::fs::read("private-ca.pem")?;
+///   let client = UreqClient::new()
+///     .with_timeout(Duration::from_secs(10))
+///     .with_proxy("proxy.example.com:3128")?
+///     .with_root_certificate(&ca_pem)?;
+///   let url = DirectoryUrl::Other("https://acme.example.com/directory");
+///   let dir = Directory::from_url_with_client(MemoryPersist::new(), url, Arc::new(client))?;
+///   Ok(())
+/// }
+/// ```
+///
+/// [ureq]: https://docs.rs/ureq
+#[derive(Clone)]
+pub struct UreqClient {
+    agent: ureq::Agent,
+    timeout: Duration,
+    tls_config: Option<rustls::ClientConfig>,
+}
+
+impl UreqClient {
+    /// Client with 30 second timeouts, no proxy and the webpki root certificates.
+    pub fn new() -> Self {
+        UreqClient {
+            agent: ureq::agent(),
+            timeout: Duration::from_secs(30),
+            tls_config: None,
+        }
+    }
+
+    /// Set the connect, read and write timeouts.
+    pub fn with_timeout(mut self, timeout: Duration) -> Self {
+        self.timeout = timeout;
+        self
+    }
+
+    /// Send all requests through a proxy, given as `user:password@host:port`, with
+    /// the user and password being optional.
+    pub fn with_proxy(mut self, proxy: &str) -> Result<Self> {
+        let proxy = ureq::Proxy::new(proxy).map_err(|e| format!("Bad proxy: {}", e))?;
+        self.agent.set_proxy(proxy);
+        Ok(self)
+    }
+
+    /// Trust the PEM encoded root certificates in addition to the webpki roots.
+    pub fn with_root_certificate(mut self, pem: &[u8]) -> Result<Self> {
+        let certs = X509::stack_from_pem(pem).map_err(|e| format!("Bad root PEM: {}", e))?;
+        let config = self.tls_config_mut();
+        for cert in certs {
+            let der = cert.to_der().map_err(|e| e.to_string())?;
+            config
+                .root_store
+                .add(&rustls::Certificate(der))
+                .map_err(|e| format!("Bad root certificate: {:?}", e))?;
+        }
+        Ok(self)
+    }
+
+    /// Authenticate using a client certificate (mutual TLS). The chain is PEM encoded
+    /// with the client certificate first, followed by the PEM encoded private key.
+    pub fn with_client_certificate(mut self, chain_pem: &[u8], key_pem: &[u8]) -> Result<Self> {
+        let chain = X509::stack_from_pem(chain_pem)
+            .and_then(|certs| {
+                certs
+                    .iter()
+                    .map(|c| c.to_der())
+                    .collect::<std::result::Result<Vec<_>, _>>()
+            })
+            .map_err(|e| format!("Bad client certificate PEM: {}", e))?;
+        let key = PKey::private_key_from_pem(key_pem)
+            .and_then(|k| k.private_key_to_pkcs8())
+            .map_err(|e| format!("Bad client key PEM: {}", e))?;
+        let chain = chain.into_iter().map(rustls::Certificate).collect();
+        self.tls_config_mut()
+            .set_single_client_cert(chain, rustls::PrivateKey(key))
+            .map_err(|e| format!("Bad client certificate: {}", e))?;
+        Ok(self)
+    }
+
+    /// Use the given TLS configuration, replacing the default and any
+    /// certificates added before.
+    pub fn with_tls_config(mut self, config: rustls::ClientConfig) -> Self {
+        self.tls_config = Some(config);
+        self
+    }
+
+    fn tls_config_mut(&mut self) -> &mut rustls::ClientConfig {
+        self.tls_config.get_or_insert_with(|| {
+            let mut config = rustls::ClientConfig::new();
+            config
+                .root_store
+                .add_server_trust_anchors(&webpki_roots::TLS_SERVER_ROOTS);
+            config
+        })
+    }
+}
+
+impl Default for UreqClient {
+    fn default() -> Self {
+        UreqClient::new()
+    }
+}
+
+impl std::fmt::Debug for UreqClient {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        f.debug_struct("UreqClient")
+            .field("timeout", &self.timeout)
+            .field("custom_tls", &self.tls_config.is_some())
+            .finish()
+    }
+}
+
+impl HttpClient for UreqClient {
+    fn request(&self, req: &HttpRequest) -> Result<HttpResponse> {
+        let mut ureq_req = self.agent.request(req.method.as_str(), &req.url);
+        for (name, value) in &req.headers {
+            ureq_req.set(name, value);
+        }
+        let millis = self.timeout.as_millis() as u64;
+        ureq_req.timeout_connect(millis);
+        ureq_req.timeout_read(millis);
+        ureq_req.timeout_write(millis);
+        if let Some(config) = &self.tls_config {
+            ureq_req.set_tls_config(Arc::new(config.clone()));
+        }
+        trace!("{:?}", ureq_req);
+
+        let res = if req.method == HttpMethod::Post {
+            ureq_req.send_bytes(&req.body)
+        } else {
+            ureq_req.call()
+        };
+
+        if let Some(err) = res.synthetic_error() {
+            return Err(Error::Call(format!(
+                "{} {}: {}",
+                req.method.as_str(),
+                req.url,
+                err
+            )));
+        }
+
+        let status = res.status();
+        // repeated headers, such as Link, are listed once per value.
+        let mut names = res.headers_names();
+        let mut seen = HashSet::new();
+        names.retain(|n| seen.insert(n.to_ascii_lowercase()));
+        let headers = names
+            .iter()
+            .flat_map(|n| {
+                res.all(n)
+                    .into_iter()
+                    .map(move |v| (n.clone(), v.to_string()))
+            })
+            .collect();
+        let mut body = vec![];
+        // letsencrypt sometimes closes the TLS abruptly causing io error
+        // even though we did capture the body.
+        res.into_reader().read_to_end(&mut body).ok();
+
+        Ok(HttpResponse {
+            status,
+            headers,
+            body,
+        })
+    }
+}
+
+type Handler = dyn Fn(&HttpRequest) -> Result<HttpResponse> + Send + Sync;
+
+/// In-memory client for tests, answering requests using a function.
+///
+/// All requests are recorded, and answered in the order they are made, which makes
+/// tests deterministic.
+///
+/// ```
+/// use acme_lib::http::{HttpClient, HttpMethod, HttpResponse, MemoryClient};
+///
+/// let client = MemoryClient::new(|req| {
+///   assert_eq!(req.method, HttpMethod::Head);
+///   Ok(HttpResponse::new(200).with_header("Replay-Nonce", "abc"))
+/// });
+/// # let req = acme_lib::http::HttpRequest {
+/// #   method: HttpMethod::Head, url: "https://example.com/new-nonce".into(),
+/// #   headers: vec![], body: vec![],
+/// # };
+/// # let res = client.request(&req).unwrap();
+/// # assert_eq!(res.header("replay-nonce"), Some("abc"));
+/// # assert_eq!(client.requests().len(), 1);
+/// ```
+#[derive(Clone)]
+pub struct MemoryClient {
+    handler: Arc<Handler>,
+    requests: Arc<Mutex<Vec<HttpRequest>>>,
+}
+
+impl MemoryClient {
+    /// Answer requests using the function.
+    pub fn new<F>(handler: F) -> Self
+    where
+        F: Fn(&HttpRequest) -> Result<HttpResponse> + Send + Sync + 'static,
+    {
+        MemoryClient {
+            handler: Arc::new(handler),
+            requests: Arc::new(Mutex::new(vec![])),
+        }
+    }
+
+    /// All requests made so far. Clones of the client share the requests.
+    pub fn requests(&self) -> Vec<HttpRequest> {
+        self.requests.lock().unwrap().clone()
+    }
+}
+
+impl std::fmt::Debug for MemoryClient {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        f.debug_struct("MemoryClient")
+            .field("requests", &self.requests.lock().unwrap().len())
+            .finish()
+    }
+}
+
+impl HttpClient for MemoryClient {
+    fn request(&self, req: &HttpRequest) -> Result<HttpResponse> {
+        self.requests.lock().unwrap().push(req.clone());
+        (self.handler)(req)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_response_headers() {
+        let res = HttpResponse::new(201)
+            .with_header("Content-Type", "application/json; charset=utf-8")
+            .with_header("Location", "https://example.com/acct/1");
+        assert!(res.ok());
+        assert_eq!(res.content_type(), "application/json");
+        assert_eq!(res.header("location"), Some("https://example.com/acct/1"));
+        assert_eq!(res.header("replay-nonce"), None);
+        assert_eq!(res.retry_after(), None);
+    }
+
+    #[test]
+    fn test_retry_after() {
+        let res = HttpResponse::new(200).with_header("Retry-After", "120");
+        assert_eq!(res.retry_after(), Some(Duration::from_secs(120)));
+
+        let res =
+            HttpResponse::new(200).with_header("Retry-After", "Wed, 21 Oct 2015 07:28:00 GMT");
+        assert_eq!(res.retry_after(), Some(Duration::from_secs(0)));
+
+        let future = time::now_utc() + time::Duration::seconds(3600);
+        let date = time::strftime("%a, %d %b %Y %H:%M:%S GMT", &future).unwrap();
+        let res = HttpResponse::new(200).with_header("Retry-After", &date);
+        let secs = res.retry_after().unwrap().as_secs();
+        assert!(secs > 3590 && secs <= 3600, "{}", secs);
+
+        let res = HttpResponse::new(200).with_header("Retry-After", "soon");
+        assert_eq!(res.retry_after(), None);
+    }
+
+    #[test]
+    fn test_links() {
+        let res = HttpResponse::new(200)
+            .with_header("Link", r#"<https://example.com/cert/1/1>;rel="alternate""#)
+            .with_header(
+                "link",
+                r#"<https://example.com/dir>; rel=index, <https://example.com/cert/1/2>; rel="alternate""#,
+            );
+        assert_eq!(
+            res.links("alternate"),
+            [
+                "https://example.com/cert/1/1",
+                "https://example.com/cert/1/2"
+            ]
+        );
+        assert_eq!(res.links("index"), ["https://example.com/dir"]);
+        assert!(res.links("up").is_empty());
+    }
+
+    #[test]
+    fn test_ureq_client() -> Result<()> {
+        let server = crate::test::with_directory_server();
+        let client = UreqClient::new().with_timeout(Duration::from_secs(5));
+        let res = client.request(&HttpRequest::get(&server.dir_url))?;
+        assert!(res.ok());
+        assert!(res.body_str().contains("newNonce"));
+
+        // nothing listening
+        let res = client.request(&HttpRequest::get("http://127.0.0.1:1/directory"));
+        assert!(res.is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_ureq_client_repeated_headers() -> Result<()> {
+        use std::io::Write;
+        let listener = std::net::TcpListener::bind("127.0.0.1:0")?;
+        let url = format!("http://{}/cert", listener.local_addr()?);
+        std::thread::spawn(move || {
+            let (mut stream, _) = listener.accept().unwrap();
+            let mut buf = [0; 1024];
+            let _ = stream.read(&mut buf).unwrap();
+            let res = "HTTP/1.1 200 OK\r\nLink: <mem:/1>;rel=\"alternate\"\r\n\
+                Replay-Nonce: nonce\r\nLink: <mem:/2>;rel=\"alternate\"\r\n\
+                Content-Length: 0\r\nConnection: close\r\n\r\n";
+            stream.write_all(res.as_bytes()).unwrap();
+        });
+        let res = UreqClient::new().request(&HttpRequest::get(&url))?;
+        assert_eq!(res.links("alternate"), ["mem:/1", "mem:/2"]);
+        let nonces: Vec<_> = res
+            .headers
+            .iter()
+            .filter(|(n, _)| n.eq_ignore_ascii_case("replay-nonce"))
+            .collect();
+        assert_eq!(nonces.len(), 1);
+        Ok(())
+    }
+}
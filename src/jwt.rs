@@ -1,14 +1,18 @@
+use openssl::bn::{BigNum, BigNumContext};
+use openssl::pkey::{HasPublic, PKeyRef};
+use openssl::sha::sha256;
 use serde::{Deserialize, Serialize};
 
-use crate::acc::AcmeKey;
-use crate::cert::EC_GROUP_P256;
+use crate::acc::AccountKeyAlgorithm;
 use crate::util::base64url;
+use crate::Result;
 
 #[derive(Debug, Serialize, Deserialize, Default)]
 pub(crate) struct JwsProtected {
     alg: String,
     url: String,
-    nonce: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    nonce: Option<String>,
     #[serde(skip_serializing_if = "Option::is_none")]
     jwk: Option<Jwk>,
     #[serde(skip_serializing_if = "Option::is_none")]
@@ -18,88 +22,300 @@ pub(crate) struct JwsProtected {
 impl JwsProtected {
     pub(crate) fn new_jwk(jwk: Jwk, url: &str, nonce: String) -> Self {
         JwsProtected {
-            alg: "ES256".into(),
+            alg: jwk.alg.clone(),
             url: url.into(),
-            nonce,
+            nonce: Some(nonce),
             jwk: Some(jwk),
             ..Default::default()
         }
     }
-    pub(crate) fn new_kid(kid: &str, url: &str, nonce: String) -> Self {
+    pub(crate) fn new_kid(alg: &str, kid: &str, url: &str, nonce: String) -> Self {
         JwsProtected {
-            alg: "ES256".into(),
+            alg: alg.into(),
+            url: url.into(),
+            nonce: Some(nonce),
+            kid: Some(kid.into()),
+            ..Default::default()
+        }
+    }
+    /// The inner JWS of a key change is signed by the new key and has no nonce.
+    pub(crate) fn new_key_change(jwk: Jwk, url: &str) -> Self {
+        JwsProtected {
+            alg: jwk.alg.clone(),
+            url: url.into(),
+            jwk: Some(jwk),
+            ..Default::default()
+        }
+    }
+    /// External account binding uses a MAC and has no nonce.
+    pub(crate) fn new_eab(alg: &str, kid: &str, url: &str) -> Self {
+        JwsProtected {
+            alg: alg.into(),
             url: url.into(),
-            nonce,
             kid: Some(kid.into()),
             ..Default::default()
         }
     }
 }
 
-#[derive(Debug, Serialize, Deserialize, Clone)]
-pub(crate) struct Jwk {
+/// Public key of an account, as a JSON Web Key ([RFC 7517]).
+///
+/// Which members are set depends on the key type (`kty`).
+///
+/// [RFC 7517]: https://tools.ietf.org/html/rfc7517
+#[derive(Debug, Serialize, Deserialize, Clone, Default, PartialEq, Eq)]
+pub struct Jwk {
     alg: String,
-    crv: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    crv: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    e: Option<String>,
     kty: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    n: Option<String>,
     #[serde(rename = "use")]
     _use: String,
-    x: String,
-    y: String,
-}
-
-#[derive(Debug, Serialize, Deserialize, Clone)]
-// LEXICAL ORDER OF FIELDS MATTER!
-pub(crate) struct JwkThumb {
-    crv: String,
-    kty: String,
-    x: String,
-    y: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    x: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    y: Option<String>,
 }
 
-impl From<&AcmeKey> for Jwk {
-    fn from(a: &AcmeKey) -> Self {
-        let mut ctx = openssl::bn::BigNumContext::new().expect("BigNumContext");
-        let mut x = openssl::bn::BigNum::new().expect("BigNum");
-        let mut y = openssl::bn::BigNum::new().expect("BigNum");
-        a.private_key()
-            .public_key()
-            .affine_coordinates_gfp(&*EC_GROUP_P256, &mut x, &mut y, &mut ctx)
-            .expect("affine_coordinates_gfp");
+impl Jwk {
+    /// An elliptic curve key from the (unpadded) affine coordinates.
+    ///
+    /// The coordinates are padded to the size of the curve.
+    pub fn new_ec(algorithm: AccountKeyAlgorithm, x: &[u8], y: &[u8]) -> Jwk {
+        let (crv, size) = match algorithm {
+            AccountKeyAlgorithm::ES384 => ("P-384", 48),
+            _ => ("P-256", 32),
+        };
         Jwk {
-            alg: "ES256".into(),
+            alg: algorithm.jws_alg().into(),
+            crv: Some(crv.into()),
             kty: "EC".into(),
-            crv: "P-256".into(),
             _use: "sig".into(),
-            x: base64url(&x.to_vec()),
-            y: base64url(&y.to_vec()),
+            x: Some(base64url(&pad(x, size))),
+            y: Some(base64url(&pad(y, size))),
+            ..Default::default()
         }
     }
+
+    /// An RSA key from the modulus and public exponent, both big-endian.
+    pub fn new_rsa(n: &[u8], e: &[u8]) -> Jwk {
+        Jwk {
+            alg: AccountKeyAlgorithm::RS256(0).jws_alg().into(),
+            e: Some(base64url(e)),
+            kty: "RSA".into(),
+            n: Some(base64url(n)),
+            _use: "sig".into(),
+            ..Default::default()
+        }
+    }
+
+    /// An Ed25519 key from the raw public key.
+    pub fn new_ed25519(x: &[u8]) -> Jwk {
+        Jwk {
+            alg: AccountKeyAlgorithm::EdDSA.jws_alg().into(),
+            crv: Some("Ed25519".into()),
+            kty: "OKP".into(),
+            _use: "sig".into(),
+            x: Some(base64url(x)),
+            ..Default::default()
+        }
+    }
+
+    /// Produce the JWK for an openssl public key of the given algorithm.
+    pub fn from_public_key<T: HasPublic>(
+        algorithm: AccountKeyAlgorithm,
+        key: &PKeyRef<T>,
+    ) -> Result<Jwk> {
+        let jwk = match algorithm {
+            AccountKeyAlgorithm::ES256 | AccountKeyAlgorithm::ES384 => {
+                let ec_key = key.ec_key().map_err(|e| e.to_string())?;
+                let mut ctx = BigNumContext::new().map_err(|e| e.to_string())?;
+                let mut x = BigNum::new().map_err(|e| e.to_string())?;
+                let mut y = BigNum::new().map_err(|e| e.to_string())?;
+                ec_key
+                    .public_key()
+                    .affine_coordinates(ec_key.group(), &mut x, &mut y, &mut ctx)
+                    .map_err(|e| e.to_string())?;
+                Jwk::new_ec(algorithm, &x.to_vec(), &y.to_vec())
+            }
+            AccountKeyAlgorithm::RS256(_) => {
+                let rsa = key.rsa().map_err(|e| e.to_string())?;
+                Jwk::new_rsa(&rsa.n().to_vec(), &rsa.e().to_vec())
+            }
+            AccountKeyAlgorithm::EdDSA => {
+                let x = key.raw_public_key().map_err(|e| e.to_string())?;
+                Jwk::new_ed25519(&x)
+            }
+        };
+        Ok(jwk)
+    }
+
+    /// The JWS algorithm of the key.
+    pub fn alg(&self) -> &str {
+        &self.alg
+    }
+
+    /// The base64url encoded thumbprint of the key ([RFC 7638]).
+    ///
+    /// [RFC 7638]: https://tools.ietf.org/html/rfc7638
+    pub(crate) fn thumbprint(&self) -> String {
+        let thumb: JwkThumb = self.into();
+        let json = serde_json::to_string(&thumb).expect("jwk_thumb");
+        base64url(&sha256(json.as_bytes()))
+    }
+
+    /// The openssl public key of the JWK, to verify signatures with.
+    #[cfg(any(test, feature = "test-server"))]
+    pub(crate) fn public_key(&self) -> Result<openssl::pkey::PKey<openssl::pkey::Public>> {
+        use crate::cert::{EC_GROUP_P256, EC_GROUP_P384};
+        use openssl::ec::EcKey;
+        use openssl::pkey::{Id, PKey};
+        use openssl::rsa::Rsa;
+
+        let member = |m: &Option<String>| -> Result<Vec<u8>> {
+            let m = m.as_deref().ok_or("Incomplete JWK")?;
+            let v = base64::decode_config(m, base64::URL_SAFE_NO_PAD)
+                .map_err(crate::Error::Base64Decode)?;
+            Ok(v)
+        };
+        let err = |e: openssl::error::ErrorStack| format!("Bad JWK: {}", e);
+        let key = match (&self.kty[..], self.crv.as_deref()) {
+            ("EC", Some(crv)) if crv == "P-256" || crv == "P-384" => {
+                let group = if crv == "P-256" {
+                    &*EC_GROUP_P256
+                } else {
+                    &*EC_GROUP_P384
+                };
+                let x = BigNum::from_slice(&member(&self.x)?).map_err(err)?;
+                let y = BigNum::from_slice(&member(&self.y)?).map_err(err)?;
+                EcKey::from_public_key_affine_coordinates(group, &x, &y).and_then(PKey::from_ec_key)
+            }
+            ("RSA", _) => {
+                let n = BigNum::from_slice(&member(&self.n)?).map_err(err)?;
+                let e = BigNum::from_slice(&member(&self.e)?).map_err(err)?;
+                Rsa::from_public_components(n, e).and_then(PKey::from_rsa)
+            }
+            ("OKP", Some("Ed25519")) => {
+                PKey::public_key_from_raw_bytes(&member(&self.x)?, Id::ED25519)
+            }
+            (kty, crv) => return Err(format!("Unsupported JWK: {} {:?}", kty, crv).into()),
+        };
+        Ok(key.map_err(err)?)
+    }
+}
+
+/// Left pad with zeroes to the given size.
+fn pad(v: &[u8], size: usize) -> Vec<u8> {
+    let mut padded = vec![0; size.saturating_sub(v.len())];
+    padded.extend_from_slice(v);
+    padded
+}
+
+/// Payload of the inner JWS when rolling over the account key.
+#[derive(Debug, Serialize, Deserialize)]
+pub(crate) struct JwkKeyChange {
+    pub account: String,
+    #[serde(rename = "oldKey")]
+    pub old_key: Jwk,
+}
+
+/// The required members of the JWK, used to compute the thumbprint (RFC 7638).
+#[derive(Debug, Serialize, Clone)]
+#[serde(untagged)]
+// LEXICAL ORDER OF FIELDS MATTER!
+pub(crate) enum JwkThumb {
+    Ec {
+        crv: String,
+        kty: String,
+        x: String,
+        y: String,
+    },
+    Rsa {
+        e: String,
+        kty: String,
+        n: String,
+    },
+    Okp {
+        crv: String,
+        kty: String,
+        x: String,
+    },
 }
 
 impl From<&Jwk> for JwkThumb {
     fn from(a: &Jwk) -> Self {
-        JwkThumb {
-            crv: a.crv.clone(),
-            kty: a.kty.clone(),
-            x: a.x.clone(),
-            y: a.y.clone(),
+        let member = |m: &Option<String>| m.clone().unwrap_or_default();
+        let kty = a.kty.clone();
+        match &kty[..] {
+            "RSA" => JwkThumb::Rsa {
+                e: member(&a.e),
+                kty,
+                n: member(&a.n),
+            },
+            "OKP" => JwkThumb::Okp {
+                crv: member(&a.crv),
+                kty,
+                x: member(&a.x),
+            },
+            _ => JwkThumb::Ec {
+                crv: member(&a.crv),
+                kty,
+                x: member(&a.x),
+                y: member(&a.y),
+            },
         }
     }
 }
 
-#[derive(Debug, Serialize, Deserialize)]
-pub(crate) struct Jws {
-    protected: String,
-    payload: String,
-    signature: String,
-}
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_jwk_thumb_rsa() {
+        // RFC 7638 section 3.1
+        let jwk = Jwk {
+            alg: "RS256".into(),
+            e: Some("AQAB".into()),
+            kty: "RSA".into(),
+            n: Some("0vx7agoebGcQSuuPiLJXZptN9nndrQmbXEps2aiAFbWhM78LhWx4cbbfAAtVT86zwu1RK7aPFFxuhDR1L6tSoc_BJECPebWKRXjBZCiFV4n3oknjhMstn64tZ_2W-5JsGY4Hc5n9yBXArwl93lqt7_RN5w6Cf0h4QyQ5v-65YGjQR0_FDW2QvzqY368QQMicAtaSqzs8KJZgnYb9c7d0zgdAZHzu6qMQvRL5hajrn1n91CbOpbISD08qNLyrdkt-bFTWhAI4vMQFh6WeZu0fM4lFd2NcRwr3XPksINHaQ-G_xBniIqbw0Ls1jF44-csFCur-kEgU8awapJzKnqDKgw".into()),
+            _use: "sig".into(),
+            ..Default::default()
+        };
+        assert_eq!(
+            jwk.thumbprint(),
+            "NzbLsXh8uDCcd-6MNwXF4W_7noWXFZAfHkxZsRGC9Xs"
+        );
+    }
+
+    #[test]
+    fn test_public_key() {
+        use crate::acc::AcmeKey;
+        use crate::signer::AccountSigner;
+        use openssl::sign::Verifier;
 
-impl Jws {
-    pub(crate) fn new(protected: String, payload: String, signature: String) -> Self {
-        Jws {
-            protected,
-            payload,
-            signature,
+        let algorithms = [
+            AccountKeyAlgorithm::ES256,
+            AccountKeyAlgorithm::ES384,
+            AccountKeyAlgorithm::RS256(2048),
+            AccountKeyAlgorithm::EdDSA,
+        ];
+        for algorithm in &algorithms {
+            let key = AcmeKey::generate(*algorithm).unwrap();
+            let public_key = key.jwk().public_key().unwrap();
+            let jwk = Jwk::from_public_key(*algorithm, &public_key).unwrap();
+            assert_eq!(jwk, key.jwk());
+            if *algorithm == AccountKeyAlgorithm::RS256(2048) {
+                let signature = key.sign(b"data").unwrap();
+                let md = openssl::hash::MessageDigest::sha256();
+                let mut verifier = Verifier::new(md, &public_key).unwrap();
+                verifier.update(b"data").unwrap();
+                assert!(verifier.verify(&signature).unwrap());
+            }
         }
     }
 }
@@ -0,0 +1,242 @@
+//
+use std::collections::{HashMap, VecDeque};
+use std::net::IpAddr;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, SystemTime};
+
+use crate::api::ApiProblem;
+use crate::{Error, Result};
+
+/// Local ledger of recent orders per registered domain.
+///
+/// ACME API providers limit how many certificates can be issued for a registered
+/// domain in a time window. Let's Encrypt for instance allows [50 per week]. With a
+/// ledger set on the [`Directory`], new orders that would obviously hit the limit are
+/// refused locally with [`Error::RateLimited`] instead of being sent to the ACME API.
+/// The ledger also remembers the `Retry-After` of rate limits the ACME API reports,
+/// and refuses orders for the same registered domains until then.
+///
+/// The ledger is kept in memory. Clones share the same record, which means one ledger
+/// can be used for several directories and accounts.
+///
+/// The registered domain of a name is the label in front of its public suffix. Without
+/// the full public suffix list, the last label is taken as the suffix. Suffixes of more
+/// labels, such as `co.uk`, must be added with [`with_public_suffix`].
+///
+/// ```
+/// use acme_lib::OrderLedger;
+///
+/// let ledger = OrderLedger::lets_encrypt().with_public_suffix("co.uk");
+/// assert_eq!(ledger.registered_domain("www.example.co.uk"), "example.co.uk");
+/// assert_eq!(ledger.registered_domain("*.foo.example.com"), "example.com");
+/// ```
+///
+/// [50 per week]: https://letsencrypt.org/docs/rate-limits/
+/// [`Directory`]: struct.Directory.html
+/// [`Error::RateLimited`]: enum.Error.html#variant.RateLimited
+/// [`with_public_suffix`]: struct.OrderLedger.html#method.with_public_suffix
+#[derive(Debug, Clone)]
+pub struct OrderLedger {
+    max_orders: usize,
+    window: Duration,
+    public_suffixes: Vec<String>,
+    record: Arc<Mutex<Record>>,
+}
+
+#[derive(Debug, Default)]
+struct Record {
+    /// Time of recent orders per registered domain, oldest first.
+    orders: HashMap<String, VecDeque<SystemTime>>,
+    /// Registered domains the ACME API rate limited, until when.
+    blocked: HashMap<String, SystemTime>,
+}
+
+impl OrderLedger {
+    /// Ledger allowing `max_orders` per registered domain within the `window`.
+    pub fn new(max_orders: usize, window: Duration) -> Self {
+        OrderLedger {
+            max_orders,
+            window,
+            public_suffixes: vec![],
+            record: Arc::new(Mutex::new(Record::default())),
+        }
+    }
+
+    /// Ledger for the Let's Encrypt limit of 50 certificates per registered domain
+    /// and week.
+    pub fn lets_encrypt() -> Self {
+        OrderLedger::new(50, Duration::from_secs(7 * 24 * 3600))
+    }
+
+    /// Add a public suffix of more than one label, such as `co.uk`.
+    pub fn with_public_suffix(mut self, suffix: &str) -> Self {
+        self.public_suffixes.push(normalize(suffix));
+        self
+    }
+
+    /// The registered domain that the name is counted for. IP addresses count
+    /// on their own.
+    pub fn registered_domain(&self, name: &str) -> String {
+        let name = normalize(name);
+        if let Ok(ip) = name.parse::<IpAddr>() {
+            return ip.to_string();
+        }
+        let suffix_labels = self
+            .public_suffixes
+            .iter()
+            .filter(|s| name.ends_with(&format!(".{}", s)))
+            .map(|s| s.split('.').count())
+            .max()
+            .unwrap_or(1);
+        let labels: Vec<_> = name.split('.').collect();
+        let start = labels.len().saturating_sub(suffix_labels + 1);
+        labels[start..].join(".")
+    }
+
+    /// Number of orders within the window for the registered domain of the name.
+    pub fn recent_orders(&self, name: &str) -> usize {
+        let domain = self.registered_domain(name);
+        let now = SystemTime::now();
+        let mut record = self.record.lock().unwrap();
+        record.prune(&domain, now, self.window);
+        record.orders.get(&domain).map(|o| o.len()).unwrap_or(0)
+    }
+
+    /// Refuse an order for the names if it would hit a rate limit.
+    pub(crate) fn check(&self, names: &[&str]) -> Result<()> {
+        let now = SystemTime::now();
+        let mut record = self.record.lock().unwrap();
+        for domain in self.registered_domains(names) {
+            if let Some(until) = record.blocked.get(&domain) {
+                if *until > now {
+                    let detail = format!("{} is rate limited by the ACME API", domain);
+                    return Err(refused(detail, *until));
+                }
+            }
+            record.prune(&domain, now, self.window);
+            if let Some(orders) = record.orders.get(&domain) {
+                if orders.len() >= self.max_orders {
+                    let detail = format!(
+                        "{} orders for {} in the last {} seconds",
+                        orders.len(),
+                        domain,
+                        self.window.as_secs()
+                    );
+                    return Err(refused(detail, orders[0] + self.window));
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Record the outcome of asking the ACME API for a new order for the names.
+    pub(crate) fn record<T>(&self, names: &[&str], result: &Result<T>) {
+        let now = SystemTime::now();
+        let mut record = self.record.lock().unwrap();
+        for domain in self.registered_domains(names) {
+            match result {
+                Ok(_) => record.orders.entry(domain).or_default().push_back(now),
+                Err(Error::RateLimited {
+                    retry_at: Some(until),
+                    ..
+                }) => {
+                    record.blocked.insert(domain, *until);
+                }
+                Err(_) => {}
+            }
+        }
+    }
+
+    fn registered_domains(&self, names: &[&str]) -> Vec<String> {
+        let mut domains: Vec<_> = names.iter().map(|n| self.registered_domain(n)).collect();
+        domains.sort();
+        domains.dedup();
+        domains
+    }
+}
+
+impl Record {
+    fn prune(&mut self, domain: &str, now: SystemTime, window: Duration) {
+        if let Some(orders) = self.orders.get_mut(domain) {
+            while orders.front().map(|t| *t + window <= now).unwrap_or(false) {
+                orders.pop_front();
+            }
+        }
+    }
+}
+
+fn normalize(name: &str) -> String {
+    name.trim_start_matches("*.")
+        .trim_end_matches('.')
+        .to_ascii_lowercase()
+}
+
+fn refused(detail: String, retry_at: SystemTime) -> Error {
+    Error::RateLimited {
+        problem: ApiProblem {
+            _type: "urn:ietf:params:acme:error:rateLimited".into(),
+            detail: Some(format!("Refused by the order ledger: {}", detail)),
+            ..Default::default()
+        },
+        retry_at: Some(retry_at),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_registered_domain() {
+        let ledger = OrderLedger::lets_encrypt().with_public_suffix("CO.UK");
+        assert_eq!(ledger.registered_domain("example.com"), "example.com");
+        assert_eq!(ledger.registered_domain("a.b.Example.com."), "example.com");
+        assert_eq!(ledger.registered_domain("*.example.com"), "example.com");
+        assert_eq!(
+            ledger.registered_domain("www.example.co.uk"),
+            "example.co.uk"
+        );
+        assert_eq!(ledger.registered_domain("co.uk"), "co.uk");
+        assert_eq!(ledger.registered_domain("localhost"), "localhost");
+        assert_eq!(ledger.registered_domain("192.0.2.1"), "192.0.2.1");
+        assert_eq!(ledger.registered_domain("2001:DB8::1"), "2001:db8::1");
+    }
+
+    #[test]
+    fn test_max_orders() {
+        let ledger = OrderLedger::new(2, Duration::from_secs(3600));
+        let ok: Result<()> = Ok(());
+        // one order for two names of the same registered domain counts once
+        ledger.record(&["example.com", "www.example.com"], &ok);
+        assert_eq!(ledger.recent_orders("foo.example.com"), 1);
+        ledger.check(&["example.com"]).unwrap();
+        ledger.record(&["example.com"], &ok);
+        ledger.check(&["other.com"]).unwrap();
+        match ledger.check(&["other.com", "api.example.com"]) {
+            Err(Error::RateLimited {
+                retry_at: Some(t), ..
+            }) => assert!(t > SystemTime::now() + Duration::from_secs(3500)),
+            x => panic!("Expected RateLimited: {:?}", x),
+        }
+        // orders out of the window are forgotten
+        let ledger = OrderLedger::new(1, Duration::from_secs(0));
+        ledger.record(&["example.com"], &ok);
+        ledger.check(&["example.com"]).unwrap();
+    }
+
+    #[test]
+    fn test_blocked() {
+        let ledger = OrderLedger::lets_encrypt();
+        let until = SystemTime::now() + Duration::from_secs(60);
+        let limited: Result<()> = Err(Error::RateLimited {
+            problem: ApiProblem::default(),
+            retry_at: Some(until),
+        });
+        ledger.record(&["www.example.com"], &limited);
+        assert_eq!(ledger.recent_orders("example.com"), 0);
+        match ledger.check(&["example.com"]) {
+            Err(Error::RateLimited { retry_at, .. }) => assert_eq!(retry_at, Some(until)),
+            x => panic!("Expected RateLimited: {:?}", x),
+        }
+    }
+}
@@ -53,7 +53,7 @@
 //!     // certificate for:
 //!     //
 //!     // http://mydomain.io/.well-known/acme-challenge/<token>
-//!     let chall = auths[0].http_challenge();
+//!     let chall = auths[0].http_challenge().ok_or("No http challenge")?;
 //!
 //!     // The token is the filename.
 //!     let token = chall.http_token();
@@ -109,7 +109,8 @@
 //! To use this library, there are points in the flow where you would need to modify either
 //! the web server or DNS server before progressing to get the certificate.
 //!
-//! See [`http_challenge`] and [`dns_challenge`].
+//! See [`http_challenge`] and [`dns_challenge`]. The [`solver`] module has helpers
+//! that do this during the validation.
 //!
 //! ### Multiple domains
 //!
@@ -120,13 +121,23 @@
 //! [`http_challenge`]: https://docs.rs/acme-lib/latest/acme_lib/order/struct.Auth.html#method.http_challenge
 //! [`dns_challenge`]: https://docs.rs/acme-lib/latest/acme_lib/order/struct.Auth.html#method.dns_challenge
 //! [`authorizations`]: https://docs.rs/acme-lib/latest/acme_lib/order/struct.NewOrder.html#method.authorizations
+//! [`solver`]: solver/index.html
 //!
 //! ## Rate limits
 //!
 //! The ACME API provider Let's Encrypt uses [rate limits] to ensure the API i not being
 //! abused. It might be tempting to put the `delay_millis` really low in some of this
 //! libraries' polling calls, but balance this against the real risk of having access
-//! cut off.
+//! cut off. The polling backs off between polls and waits for any `Retry-After` the API
+//! sends, see [`PollPolicy`].
+//!
+//! Hitting a rate limit fails with [`Error::RateLimited`], with the earliest time to retry.
+//! An [`OrderLedger`] can keep track of recent orders to not even try orders that would
+//! hit the limit on certificates per registered domain.
+//!
+//! [`PollPolicy`]: struct.PollPolicy.html
+//! [`Error::RateLimited`]: enum.Error.html#variant.RateLimited
+//! [`OrderLedger`]: struct.OrderLedger.html
 //!
 //! [rate limits]: https://letsencrypt.org/docs/rate-limits/
 //!
@@ -157,18 +168,36 @@ mod cert;
 mod dir;
 mod error;
 mod jwt;
+mod ledger;
+mod poll;
 mod req;
+mod retry;
 mod trans;
 mod util;
 
 pub mod api;
+#[cfg(feature = "async")]
+pub mod asynch;
+pub mod http;
 pub mod order;
 pub mod persist;
+pub mod signer;
+pub mod solver;
+#[cfg(any(test, feature = "test-server"))]
+pub mod testing;
 
 #[cfg(test)]
 mod test;
 
-pub use crate::acc::{Account, RevocationReason};
-pub use crate::cert::{create_p256_key, create_p384_key, create_rsa_key, Certificate};
-pub use crate::dir::{Directory, DirectoryUrl};
-pub use crate::error::{Error, Result};
+pub use crate::acc::{
+    Account, AccountKeyAlgorithm, EabAlgorithm, ExternalAccountBinding, RevocationReason,
+};
+pub use crate::cert::{
+    create_p256_key, create_p384_key, create_rsa_key, create_tls_alpn_certificate, Certificate,
+    KeyType,
+};
+pub use crate::dir::{AccountBuilder, Directory, DirectoryUrl};
+pub use crate::error::{Error, ProblemKind, Result};
+pub use crate::ledger::OrderLedger;
+pub use crate::poll::PollPolicy;
+pub use crate::retry::RetryPolicy;
@@ -2,15 +2,16 @@
 use openssl::sha::sha256;
 use std::sync::Arc;
 use std::thread;
-use std::time::Duration;
 
 use crate::acc::AccountInner;
-use crate::acc::AcmeKey;
 use crate::api::{ApiAuth, ApiChallenge, ApiEmptyObject, ApiEmptyString};
-use crate::jwt::*;
+use crate::cert::create_tls_alpn_certificate;
 use crate::persist::Persist;
+use crate::poll::{timeout_error, Poller};
+use crate::signer::AccountSigner;
+use crate::solver::{cleanup_after, DnsProvider, HttpSolver, TlsAlpnSolver};
 use crate::util::{base64url, read_json};
-use crate::Result;
+use crate::{Certificate, Error, PollPolicy, Result};
 
 /// An authorization ([ownership proof]) for a domain name.
 ///
@@ -25,9 +26,14 @@ use crate::Result;
 /// * In a text file served using [HTTP] from a web server of the domain being authorized.
 /// * A `TXT` [DNS] record under the domain being authorized.
 ///
+/// All challenges the ACME API offers are listed by [`challenges`], and
+/// [`select_challenge`] picks one by order of preference.
+///
 /// [ownership proof]: ../index.html#domain-ownership
 /// [HTTP]: #method.http_challenge
 /// [DNS]: #method.dns_challenge
+/// [`challenges`]: #method.challenges
+/// [`select_challenge`]: #method.select_challenge
 #[derive(Debug)]
 pub struct Auth<P: Persist> {
     inner: Arc<AccountInner<P>>,
@@ -55,7 +61,7 @@ impl<P: Persist> Auth<P> {
         !self.api_auth.is_status_valid()
     }
 
-    /// Get the http challenge.
+    /// Get the http challenge, if the ACME API offers one for this authorization.
     ///
     /// The http challenge must be placed so it is accessible under:
     ///
@@ -73,7 +79,7 @@ impl<P: Persist> Auth<P> {
     /// use std::io::Write;
     ///
     /// fn web_authorize<P: Persist>(auth: &Auth<P>) -> Result<(), Error> {
-    ///   let challenge = auth.http_challenge();
+    ///   let challenge = auth.http_challenge().ok_or("No http challenge")?;
     ///   // Assuming our web server's root is under /var/www
     ///   let path = {
     ///     let token = challenge.http_token();
@@ -85,14 +91,13 @@ impl<P: Persist> Auth<P> {
     ///   Ok(())
     /// }
     /// ```
-    pub fn http_challenge(&self) -> Challenge<P, Http> {
+    pub fn http_challenge(&self) -> Option<Challenge<P, Http>> {
         self.api_auth
             .http_challenge()
-            .map(|c| Challenge::new(&self.inner, c.clone(), &self.auth_url))
-            .expect("http-challenge")
+            .map(|c| Challenge::new(&self.inner, c.clone(), self.domain_name(), &self.auth_url))
     }
 
-    /// Get the dns challenge.
+    /// Get the dns challenge, if the ACME API offers one for this authorization.
     ///
     /// The dns challenge is a `TXT` record that must put created under:
     ///
@@ -108,7 +113,7 @@ impl<P: Persist> Auth<P> {
     /// use acme_lib::Error;
     ///
     /// fn dns_authorize<P: Persist>(auth: &Auth<P>) -> Result<(), Error> {
-    ///   let challenge = auth.dns_challenge();
+    ///   let challenge = auth.dns_challenge().ok_or("No dns challenge")?;
     ///   let record = format!("_acme-challenge.{}.", auth.domain_name());
     ///   // route_53_set_record(&record, "TXT", challenge.dns_proof());
     ///   challenge.validate(5000)?;
@@ -117,25 +122,59 @@ impl<P: Persist> Auth<P> {
     /// ```
     ///
     /// The dns proof is not the same as the http proof.
-    pub fn dns_challenge(&self) -> Challenge<P, Dns> {
+    pub fn dns_challenge(&self) -> Option<Challenge<P, Dns>> {
         self.api_auth
             .dns_challenge()
-            .map(|c| Challenge::new(&self.inner, c.clone(), &self.auth_url))
-            .expect("dns-challenge")
+            .map(|c| Challenge::new(&self.inner, c.clone(), self.domain_name(), &self.auth_url))
     }
 
-    /// Get the TLS ALPN challenge.
+    /// Get the TLS ALPN challenge, if the ACME API offers one for this authorization.
     ///
     /// The TLS ALPN challenge is a certificate that must be served when a
     /// request is made for the ALPN protocol "tls-alpn-01". The certificate
     /// must contain a single dNSName SAN containing the domain being
     /// validated, as well as an ACME extension containing the SHA256 of the
     /// key authorization.
-    pub fn tls_alpn_challenge(&self) -> Challenge<P, TlsAlpn> {
+    pub fn tls_alpn_challenge(&self) -> Option<Challenge<P, TlsAlpn>> {
         self.api_auth
             .tls_alpn_challenge()
-            .map(|c| Challenge::new(&self.inner, c.clone(), &self.auth_url))
-            .expect("tls-alpn-challenge")
+            .map(|c| Challenge::new(&self.inner, c.clone(), self.domain_name(), &self.auth_url))
+    }
+
+    /// All challenges the ACME API offers for this authorization, in the order the
+    /// ACME API lists them.
+    ///
+    /// Wildcard authorizations for instance only offer a dns challenge.
+    pub fn challenges(&self) -> Vec<AnyChallenge<P>> {
+        self.api_auth
+            .challenges
+            .iter()
+            .map(|c| AnyChallenge::new(&self.inner, c.clone(), self.domain_name(), &self.auth_url))
+            .collect()
+    }
+
+    /// The first challenge type in `preference` that the ACME API offers for this
+    /// authorization. Invalid challenges are skipped.
+    ///
+    /// ```no_run
+    /// use acme_lib::persist::Persist;
+    /// use acme_lib::order::{AnyChallenge, Auth, ChallengeType};
+    /// use acme_lib::Error;
+    ///
+    /// fn authorize<P: Persist>(auth: &Auth<P>) -> Result<(), Error> {
+    ///   let preference = [ChallengeType::TlsAlpn01, ChallengeType::Http01, ChallengeType::Dns01];
+    ///   match auth.select_challenge(&preference) {
+    ///     Some(AnyChallenge::TlsAlpn(challenge)) => { /* serve the certificate */ }
+    ///     Some(AnyChallenge::Http(challenge)) => { /* write the file */ }
+    ///     Some(AnyChallenge::Dns(challenge)) => { /* create the TXT record */ }
+    ///     _ => return Err("No challenge we can do".into()),
+    ///   }
+    ///   Ok(())
+    /// }
+    /// ```
+    pub fn select_challenge(&self, preference: &[ChallengeType]) -> Option<AnyChallenge<P>> {
+        select_api_challenge(&self.api_auth, preference)
+            .map(|c| AnyChallenge::new(&self.inner, c.clone(), self.domain_name(), &self.auth_url))
     }
 
     /// Access the underlying JSON object for debugging. We don't
@@ -158,12 +197,140 @@ pub struct Dns;
 #[doc(hidden)]
 pub struct TlsAlpn;
 
+/// Marker type for challenges of other types.
+#[doc(hidden)]
+pub struct Unknown;
+
+/// Type of a challenge, as in the `type` of the challenge in the ACME API.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub enum ChallengeType {
+    /// `http-01`
+    Http01,
+    /// `dns-01`
+    Dns01,
+    /// `tls-alpn-01`
+    TlsAlpn01,
+    /// Any other type.
+    Other(String),
+}
+
+impl ChallengeType {
+    /// The challenge type of the `type` string.
+    pub fn from_type(_type: &str) -> Self {
+        match _type {
+            "http-01" => ChallengeType::Http01,
+            "dns-01" => ChallengeType::Dns01,
+            "tls-alpn-01" => ChallengeType::TlsAlpn01,
+            _ => ChallengeType::Other(_type.into()),
+        }
+    }
+
+    /// The `type` string of the challenge type.
+    pub fn as_str(&self) -> &str {
+        match self {
+            ChallengeType::Http01 => "http-01",
+            ChallengeType::Dns01 => "dns-01",
+            ChallengeType::TlsAlpn01 => "tls-alpn-01",
+            ChallengeType::Other(s) => s,
+        }
+    }
+}
+
+impl std::fmt::Display for ChallengeType {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "{}", self.as_str())
+    }
+}
+
+/// Declare the `AnyChallenge` enum over the `Challenge` type in scope, which is
+/// shared by the blocking and async API.
+macro_rules! any_challenge {
+    ($(#[$doc:meta])*) => {
+        $(#[$doc])*
+        pub enum AnyChallenge<P: Persist> {
+            /// An `http-01` challenge.
+            Http(Challenge<P, Http>),
+            /// A `dns-01` challenge.
+            Dns(Challenge<P, Dns>),
+            /// A `tls-alpn-01` challenge.
+            TlsAlpn(Challenge<P, TlsAlpn>),
+            /// A challenge of a type this library doesn't know the proof of.
+            Unknown(Challenge<P, Unknown>),
+        }
+
+        impl<P: Persist> AnyChallenge<P> {
+            fn new(
+                inner: &Arc<AccountInner<P>>,
+                api_challenge: ApiChallenge,
+                domain: &str,
+                auth_url: &str,
+            ) -> Self {
+                match ChallengeType::from_type(&api_challenge._type) {
+                    ChallengeType::Http01 => {
+                        AnyChallenge::Http(Challenge::new(inner, api_challenge, domain, auth_url))
+                    }
+                    ChallengeType::Dns01 => {
+                        AnyChallenge::Dns(Challenge::new(inner, api_challenge, domain, auth_url))
+                    }
+                    ChallengeType::TlsAlpn01 => AnyChallenge::TlsAlpn(Challenge::new(
+                        inner,
+                        api_challenge,
+                        domain,
+                        auth_url,
+                    )),
+                    ChallengeType::Other(_) => AnyChallenge::Unknown(Challenge::new(
+                        inner,
+                        api_challenge,
+                        domain,
+                        auth_url,
+                    )),
+                }
+            }
+
+            /// Type of the challenge.
+            pub fn challenge_type(&self) -> ChallengeType {
+                ChallengeType::from_type(&self.api_challenge()._type)
+            }
+
+            /// Status of the challenge, `pending`, `processing`, `valid` or `invalid`.
+            pub fn status(&self) -> &str {
+                &self.api_challenge().status
+            }
+
+            /// Check whether this challenge still needs validation.
+            pub fn need_validate(&self) -> bool {
+                self.api_challenge().is_status_pending()
+            }
+
+            /// Access the underlying JSON object for debugging.
+            pub fn api_challenge(&self) -> &ApiChallenge {
+                match self {
+                    AnyChallenge::Http(c) => c.api_challenge(),
+                    AnyChallenge::Dns(c) => c.api_challenge(),
+                    AnyChallenge::TlsAlpn(c) => c.api_challenge(),
+                    AnyChallenge::Unknown(c) => c.api_challenge(),
+                }
+            }
+        }
+    };
+}
+
+#[cfg(feature = "async")]
+pub(crate) use any_challenge;
+
+any_challenge! {
+    /// Any of the challenges of an [`Auth`], by type.
+    ///
+    /// [`Auth`]: struct.Auth.html
+}
+
 /// A DNS, HTTP, or TLS-ALPN challenge as obtained from the [`Auth`].
 ///
 /// [`Auth`]: struct.Auth.html
 pub struct Challenge<P: Persist, A> {
     inner: Arc<AccountInner<P>>,
     api_challenge: ApiChallenge,
+    domain: String,
     auth_url: String,
     _ph: std::marker::PhantomData<A>,
 }
@@ -181,8 +348,23 @@ impl<P: Persist> Challenge<P, Http> {
 
     /// The `proof` is some text content that is placed in the file named by `token`.
     pub fn http_proof(&self) -> String {
-        let acme_key = self.inner.transport.acme_key();
-        key_authorization(&self.api_challenge.token, acme_key, false)
+        let signer = self.inner.transport.signer();
+        key_authorization(&self.api_challenge.token, &*signer, false)
+    }
+
+    /// Like [`validate_with_policy`], with the solver putting the proof in place
+    /// before the validation, and removing it after, also when the validation fails.
+    ///
+    /// [`validate_with_policy`]: struct.Challenge.html#method.validate_with_policy
+    pub fn validate_with_solver<S>(self, solver: &S, policy: &PollPolicy) -> Result<()>
+    where
+        S: HttpSolver + ?Sized,
+    {
+        let domain = self.domain.clone();
+        let token = self.api_challenge.token.clone();
+        solver.present(&domain, &token, &self.http_proof())?;
+        let res = self.validate_with_policy(policy);
+        cleanup_after(res, solver.cleanup(&domain, &token))
     }
 }
 
@@ -193,8 +375,34 @@ impl<P: Persist> Challenge<P, Dns> {
     /// _acme-challenge.<domain-to-be-proven>.  TXT  <proof>
     /// ```
     pub fn dns_proof(&self) -> String {
-        let acme_key = self.inner.transport.acme_key();
-        key_authorization(&self.api_challenge.token, acme_key, true)
+        let signer = self.inner.transport.signer();
+        key_authorization(&self.api_challenge.token, &*signer, true)
+    }
+
+    /// Name of the `TXT` record, `_acme-challenge.<domain-to-be-proven>`.
+    pub fn dns_name(&self) -> String {
+        format!("_acme-challenge.{}", self.domain.trim_start_matches("*."))
+    }
+
+    /// Like [`validate_with_policy`], with the provider publishing the `TXT` record
+    /// before the validation, and removing it after, also when the validation fails.
+    ///
+    /// Wrap the provider in a [`PropagationCheck`] to only validate once the record is
+    /// visible at the authoritative name servers, and in a [`CnameDelegation`] when the
+    /// name is delegated to another zone with a CNAME.
+    ///
+    /// [`validate_with_policy`]: struct.Challenge.html#method.validate_with_policy
+    /// [`PropagationCheck`]: ../solver/struct.PropagationCheck.html
+    /// [`CnameDelegation`]: ../solver/struct.CnameDelegation.html
+    pub fn validate_with_solver<S>(self, provider: &S, policy: &PollPolicy) -> Result<()>
+    where
+        S: DnsProvider + ?Sized,
+    {
+        let name = self.dns_name();
+        let proof = self.dns_proof();
+        provider.present(&name, &proof)?;
+        let res = self.validate_with_policy(policy);
+        cleanup_after(res, provider.cleanup(&name, &proof))
     }
 }
 
@@ -202,21 +410,65 @@ impl<P: Persist> Challenge<P, TlsAlpn> {
     /// The `proof` is the contents of the ACME extension to be placed in the
     /// certificate used for validation.
     pub fn tls_alpn_proof(&self) -> [u8; 32] {
-        let acme_key = self.inner.transport.acme_key();
-        sha256(key_authorization(&self.api_challenge.token, acme_key, false).as_bytes())
+        let signer = self.inner.transport.signer();
+        sha256(key_authorization(&self.api_challenge.token, &*signer, false).as_bytes())
+    }
+
+    /// The self-signed certificate with the proof, to serve for the domain.
+    ///
+    /// See [`create_tls_alpn_certificate`].
+    ///
+    /// [`create_tls_alpn_certificate`]: ../fn.create_tls_alpn_certificate.html
+    pub fn tls_alpn_certificate(&self) -> Result<Certificate> {
+        create_tls_alpn_certificate(&self.domain, &self.tls_alpn_proof())
+    }
+
+    /// Like [`validate_with_policy`], with the solver serving the validation certificate
+    /// during the validation, and removing it after, also when the validation fails.
+    ///
+    /// [`validate_with_policy`]: struct.Challenge.html#method.validate_with_policy
+    pub fn validate_with_solver<S>(self, solver: &S, policy: &PollPolicy) -> Result<()>
+    where
+        S: TlsAlpnSolver + ?Sized,
+    {
+        let domain = self.domain.clone();
+        solver.present(&domain, &self.tls_alpn_proof())?;
+        let res = self.validate_with_policy(policy);
+        cleanup_after(res, solver.cleanup(&domain))
     }
 }
 
 impl<P: Persist, A> Challenge<P, A> {
-    fn new(inner: &Arc<AccountInner<P>>, api_challenge: ApiChallenge, auth_url: &str) -> Self {
+    fn new(
+        inner: &Arc<AccountInner<P>>,
+        api_challenge: ApiChallenge,
+        domain: &str,
+        auth_url: &str,
+    ) -> Self {
         Challenge {
             inner: inner.clone(),
             api_challenge,
+            domain: domain.into(),
             auth_url: auth_url.into(),
             _ph: std::marker::PhantomData,
         }
     }
 
+    /// Domain name of the authorization this challenge is for.
+    pub fn domain_name(&self) -> &str {
+        &self.domain
+    }
+
+    /// Type of the challenge.
+    pub fn challenge_type(&self) -> ChallengeType {
+        ChallengeType::from_type(&self.api_challenge._type)
+    }
+
+    /// Status of the challenge, `pending`, `processing`, `valid` or `invalid`.
+    pub fn status(&self) -> &str {
+        &self.api_challenge.status
+    }
+
     /// Check whether this challlenge really need validation. It might already been
     /// done in a previous order for the same account.
     pub fn need_validate(&self) -> bool {
@@ -227,31 +479,37 @@ impl<P: Persist, A> Challenge<P, A> {
     ///
     /// The user must first update the DNS record or HTTP web server depending
     /// on the type challenge being validated.
+    ///
+    /// A failed validation is an [`Error::ApiProblem`] with the problem the ACME API
+    /// reports for the challenge, such as `dns` or `caa`.
+    ///
+    /// The `delay_millis` is the amount of time to wait between each poll of the
+    /// authorization status, see [`PollPolicy::from_millis`].
+    ///
+    /// [`Error::ApiProblem`]: ../enum.Error.html#variant.ApiProblem
+    /// [`PollPolicy::from_millis`]: ../struct.PollPolicy.html#method.from_millis
     pub fn validate(self, delay_millis: u64) -> Result<()> {
+        self.validate_with_policy(&PollPolicy::from_millis(delay_millis))
+    }
+
+    /// Like [`validate`], polling the pending authorization according to the policy.
+    ///
+    /// Fails with [`Error::Timeout`] if the authorization is still pending at the
+    /// deadline of the policy.
+    ///
+    /// [`validate`]: struct.Challenge.html#method.validate
+    /// [`Error::Timeout`]: ../enum.Error.html#variant.Timeout
+    pub fn validate_with_policy(self, policy: &PollPolicy) -> Result<()> {
         let url_chall = &self.api_challenge.url;
         let res = self.inner.transport.call(url_chall, &ApiEmptyObject)?;
         let _: ApiChallenge = read_json(res)?;
 
-        let auth = wait_for_auth_status(&self.inner, &self.auth_url, delay_millis)?;
-
-        if !auth.is_status_valid() {
-            let error = auth
-                .challenges
-                .iter()
-                .filter_map(|c| c.error.as_ref())
-                .nth(0);
-            let reason = if let Some(error) = error {
-                format!(
-                    "Failed: {}",
-                    error.detail.clone().unwrap_or_else(|| error._type.clone())
-                )
-            } else {
-                "Validation failed and no error found".into()
-            };
-            return Err(reason.into());
-        }
+        let auth = wait_for_auth_status(&self.inner, &self.auth_url, policy)?;
 
-        Ok(())
+        match auth_error(&auth) {
+            Some(err) => Err(err),
+            None => Ok(()),
+        }
     }
 
     /// Access the underlying JSON object for debugging.
@@ -260,12 +518,12 @@ impl<P: Persist, A> Challenge<P, A> {
     }
 }
 
-fn key_authorization(token: &str, key: &AcmeKey, extra_sha256: bool) -> String {
-    let jwk: Jwk = key.into();
-    let jwk_thumb: JwkThumb = (&jwk).into();
-    let jwk_json = serde_json::to_string(&jwk_thumb).expect("jwk_thumb");
-    let digest = base64url(&sha256(jwk_json.as_bytes()));
-    let key_auth = format!("{}.{}", token, digest);
+pub(crate) fn key_authorization(
+    token: &str,
+    signer: &dyn AccountSigner,
+    extra_sha256: bool,
+) -> String {
+    let key_auth = format!("{}.{}", token, signer.jwk().thumbprint());
     if extra_sha256 {
         base64url(&sha256(key_auth.as_bytes()))
     } else {
@@ -273,24 +531,60 @@ fn key_authorization(token: &str, key: &AcmeKey, extra_sha256: bool) -> String {
     }
 }
 
+/// The first challenge of the authorization by the preference, that isn't invalid.
+pub(crate) fn select_api_challenge<'a>(
+    auth: &'a ApiAuth,
+    preference: &[ChallengeType],
+) -> Option<&'a ApiChallenge> {
+    preference.iter().find_map(|t| {
+        auth.challenges
+            .iter()
+            .find(|c| c._type == t.as_str() && !c.is_status_invalid())
+    })
+}
+
+/// The error of an authorization that didn't become valid.
+pub(crate) fn auth_error(auth: &ApiAuth) -> Option<Error> {
+    if auth.is_status_valid() {
+        return None;
+    }
+    let error = auth
+        .challenges
+        .iter()
+        .filter_map(|c| c.error.as_ref())
+        .next();
+    match error {
+        // keep the problem, for its kind and subproblems.
+        Some(error) => Some(error.clone().into()),
+        None => Some("Validation failed and no error found".into()),
+    }
+}
+
 fn wait_for_auth_status<P: Persist>(
     inner: &Arc<AccountInner<P>>,
     auth_url: &str,
-    delay_millis: u64,
+    policy: &PollPolicy,
 ) -> Result<ApiAuth> {
+    let mut poller = Poller::new(policy);
     let auth = loop {
         let res = inner.transport.call(auth_url, &ApiEmptyString)?;
+        let retry_after = res.retry_after();
         let auth: ApiAuth = read_json(res)?;
         if !auth.is_status_pending() {
             break auth;
         }
-        thread::sleep(Duration::from_millis(delay_millis));
+        let status = auth.status.as_deref();
+        let delay = poller
+            .next_delay(retry_after)
+            .ok_or_else(|| timeout_error(auth_url, status, poller.elapsed()))?;
+        thread::sleep(delay);
     };
     Ok(auth)
 }
 
 #[cfg(test)]
 mod test {
+    use crate::order::ChallengeType;
     use crate::persist::*;
     use crate::*;
 
@@ -306,13 +600,72 @@ mod test {
         assert!(authz.len() == 1);
         let auth = &authz[0];
         {
-            let http = auth.http_challenge();
+            let http = auth.http_challenge().unwrap();
             assert!(http.need_validate());
         }
         {
-            let dns = auth.dns_challenge();
+            let dns = auth.dns_challenge().unwrap();
             assert!(dns.need_validate());
         }
+        {
+            let tls_alpn = auth.tls_alpn_challenge().unwrap();
+            let cert = tls_alpn.tls_alpn_certificate()?;
+            assert!(cert
+                .certificate()
+                .starts_with("-----BEGIN CERTIFICATE-----"));
+        }
+        let types: Vec<_> = auth
+            .challenges()
+            .iter()
+            .map(|c| (c.challenge_type(), c.status().to_string()))
+            .collect();
+        assert_eq!(types.len(), 3);
+        assert!(types.iter().all(|(_, status)| status == "pending"));
+        let pref = [ChallengeType::TlsAlpn01, ChallengeType::Http01];
+        match auth.select_challenge(&pref) {
+            Some(order::AnyChallenge::TlsAlpn(c)) => assert!(c.need_validate()),
+            _ => panic!("Expected the tls alpn challenge"),
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn test_select_challenge() -> Result<()> {
+        // a wildcard authorization, with only a dns challenge. And one unknown.
+        let ord = crate::test::memory_order("*.example.com", |req| match &req.url[..] {
+            "mem:/authz/1" => Some(
+                r#"{"identifier": {"type": "dns", "value": "example.com"},
+                "status": "pending", "wildcard": true, "challenges": [
+                {"type": "foo-01", "url": "mem:/chall/1", "status": "pending", "token": "x"},
+                {"type": "dns-01", "url": "mem:/chall/2", "status": "pending", "token": "y"}
+                ]}"#,
+            ),
+            _ => None,
+        })?;
+        let auth = &ord.authorizations()?[0];
+        assert!(auth.http_challenge().is_none());
+        assert!(auth.tls_alpn_challenge().is_none());
+        let types: Vec<_> = auth
+            .challenges()
+            .iter()
+            .map(|c| c.challenge_type())
+            .collect();
+        let foo = ChallengeType::Other("foo-01".into());
+        assert_eq!(types, vec![foo.clone(), ChallengeType::Dns01]);
+        let pref = [
+            ChallengeType::TlsAlpn01,
+            ChallengeType::Http01,
+            ChallengeType::Dns01,
+        ];
+        match auth.select_challenge(&pref) {
+            Some(order::AnyChallenge::Dns(c)) => assert_eq!(c.api_challenge().token, "y"),
+            _ => panic!("Expected the dns challenge"),
+        }
+        assert!(auth.select_challenge(&pref[..2]).is_none());
+        match auth.select_challenge(&[foo]) {
+            Some(order::AnyChallenge::Unknown(c)) => assert_eq!(c.status(), "pending"),
+            _ => panic!("Expected the unknown challenge"),
+        }
         Ok(())
     }
 }
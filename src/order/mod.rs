@@ -26,12 +26,17 @@ use crate::acc::AccountInner;
 use crate::api::{ApiAuth, ApiEmptyString, ApiFinalize, ApiOrder};
 use crate::cert::{create_csr, Certificate};
 use crate::persist::{Persist, PersistKey, PersistKind};
+use crate::poll::{timeout_error, Poller};
 use crate::util::{base64url, read_json};
-use crate::Result;
+use crate::{PollPolicy, Result};
 
 mod auth;
 
-pub use self::auth::{Auth, Challenge};
+#[cfg(feature = "async")]
+pub(crate) use self::auth::{
+    any_challenge, auth_error, key_authorization, select_api_challenge, Dns, Http, TlsAlpn, Unknown,
+};
+pub use self::auth::{AnyChallenge, Auth, Challenge, ChallengeType};
 
 /// The order wrapped with an outer façade.
 pub(crate) struct Order<P: Persist> {
@@ -54,35 +59,26 @@ impl<P: Persist> Order<P> {
 pub(crate) fn refresh_order<P: Persist>(
     inner: &Arc<AccountInner<P>>,
     url: String,
-    want_status: &'static str,
 ) -> Result<Order<P>> {
+    poll_order(inner, url).map(|(order, _)| order)
+}
+
+/// Refresh the order, also giving the `Retry-After` of the response.
+fn poll_order<P: Persist>(
+    inner: &Arc<AccountInner<P>>,
+    url: String,
+) -> Result<(Order<P>, Option<Duration>)> {
     let res = inner.transport.call(&url, &ApiEmptyString)?;
+    let retry_after = res.retry_after();
 
-    // our test rig requires the order to be in `want_status`.
-    // api_order_of is different for test compilation
-    let api_order = api_order_of(res, want_status)?;
+    let api_order: ApiOrder = read_json(res)?;
 
-    Ok(Order {
+    let order = Order {
         inner: inner.clone(),
         api_order,
         url,
-    })
-}
-
-#[cfg(not(test))]
-fn api_order_of(res: ureq::Response, _want_status: &str) -> Result<ApiOrder> {
-    read_json(res)
-}
-
-#[cfg(test)]
-// our test rig requires the order to be in `want_status`
-fn api_order_of(res: ureq::Response, want_status: &str) -> Result<ApiOrder> {
-    let s = res.into_string()?;
-    #[allow(clippy::trivial_regex)]
-    let re = regex::Regex::new("<STATUS>").unwrap();
-    let b = re.replace_all(&s, want_status).to_string();
-    let api_order: ApiOrder = serde_json::from_str(&b)?;
-    Ok(api_order)
+    };
+    Ok((order, retry_after))
 }
 
 /// A new order created by [`Account::new_order`].
@@ -142,7 +138,7 @@ impl<P: Persist> NewOrder<P> {
     ///
     /// The specification calls this a "POST-as-GET" against the order URL.
     pub fn refresh(&mut self) -> Result<()> {
-        let order = refresh_order(&self.order.inner, self.order.url.clone(), "ready")?;
+        let order = refresh_order(&self.order.inner, self.order.url.clone())?;
         self.order = order;
         Ok(())
     }
@@ -217,23 +213,31 @@ impl<P: Persist> CsrOrder<P> {
     ///
     /// Once the CSR has been submitted, the order goes into a `processing` status,
     /// where we must poll until the status changes. The `delay_millis` is the
-    /// amount of time to wait between each poll attempt.
+    /// amount of time to wait between each poll, see [`PollPolicy::from_millis`].
+    ///
+    /// [`PollPolicy::from_millis`]: ../struct.PollPolicy.html#method.from_millis
     pub fn finalize_pkey(
         self,
         private_key: PKey<pkey::Private>,
         delay_millis: u64,
     ) -> Result<CertOrder<P>> {
-        //
-        // the domains that we have authorized
-        let domains = self.order.api_order.domains();
-
-        // csr from private key and authorized domains.
-        let csr = create_csr(&private_key, &domains)?;
+        self.finalize_pkey_with_policy(private_key, &PollPolicy::from_millis(delay_millis))
+    }
 
-        // this is not the same as PEM.
-        let csr_der = csr.to_der().expect("to_der()");
-        let csr_enc = base64url(&csr_der);
-        let finalize = ApiFinalize { csr: csr_enc };
+    /// Like [`finalize_pkey`], polling the `processing` order according to the policy.
+    ///
+    /// Fails with [`Error::Timeout`] if the order is still processing at the deadline
+    /// of the policy.
+    ///
+    /// [`finalize_pkey`]: struct.CsrOrder.html#method.finalize_pkey
+    /// [`Error::Timeout`]: ../enum.Error.html#variant.Timeout
+    pub fn finalize_pkey_with_policy(
+        self,
+        private_key: PKey<pkey::Private>,
+        policy: &PollPolicy,
+    ) -> Result<CertOrder<P>> {
+        //
+        let finalize = finalize_request(&self.order.api_order, &private_key)?;
 
         let inner = self.order.inner;
         let order_url = self.order.url;
@@ -246,11 +250,8 @@ impl<P: Persist> CsrOrder<P> {
         // wait for the status to not be processing.
         // valid -> cert is issued
         // invalid -> the whole thing is off
-        let order = wait_for_order_status(&inner, &order_url, delay_millis)?;
-
-        if !order.api_order.is_status_valid() {
-            return Err(format!("Order is in status: {:?}", order.api_order.status).into());
-        }
+        let order = wait_for_order_status(&inner, &order_url, policy)?;
+        check_order_valid(&order.api_order)?;
 
         Ok(CertOrder { private_key, order })
     }
@@ -264,17 +265,95 @@ impl<P: Persist> CsrOrder<P> {
 fn wait_for_order_status<P: Persist>(
     inner: &Arc<AccountInner<P>>,
     url: &str,
-    delay_millis: u64,
+    policy: &PollPolicy,
 ) -> Result<Order<P>> {
+    let mut poller = Poller::new(policy);
     loop {
-        let order = refresh_order(inner, url.to_string(), "valid")?;
+        let (order, retry_after) = poll_order(inner, url.to_string())?;
         if !order.api_order.is_status_processing() {
             return Ok(order);
         }
-        thread::sleep(Duration::from_millis(delay_millis));
+        let status = order.api_order.status.as_deref();
+        let delay = poller
+            .next_delay(retry_after)
+            .ok_or_else(|| timeout_error(url, status, poller.elapsed()))?;
+        thread::sleep(delay);
     }
 }
 
+/// The finalize request with a CSR for the domains in the order.
+pub(crate) fn finalize_request(
+    api_order: &ApiOrder,
+    private_key: &PKey<pkey::Private>,
+) -> Result<ApiFinalize> {
+    // the domains that we have authorized
+    let domains = api_order.domains();
+
+    // csr from private key and authorized domains.
+    let csr = create_csr(private_key, &domains)?;
+
+    // this is not the same as PEM.
+    let csr_der = csr
+        .to_der()
+        .map_err(|e| format!("Error converting CSR to DER: {}", e))?;
+    let csr_enc = base64url(&csr_der);
+    Ok(ApiFinalize { csr: csr_enc })
+}
+
+/// The first domain of the order, which the certificate is persisted under.
+pub(crate) fn primary_domain(api_order: &ApiOrder) -> Result<&str> {
+    let domains = api_order.domains();
+    let primary = domains.first().ok_or("Order has no identifiers")?;
+    Ok(primary)
+}
+
+/// Once processing is done, the order must be valid for the certificate to be issued.
+pub(crate) fn check_order_valid(api_order: &ApiOrder) -> Result<()> {
+    if !api_order.is_status_valid() {
+        return Err(format!("Order is in status: {:?}", api_order.status).into());
+    }
+    Ok(())
+}
+
+/// The chain whose topmost certificate is issued by the common name, or else the
+/// first, default, chain.
+pub(crate) fn select_chain(mut chains: Vec<String>, issuer_cn: &str) -> String {
+    let preferred = chains.iter().position(|chain| {
+        let cert = Certificate::new(String::new(), chain.clone());
+        cert.issuer_common_name().ok().flatten().as_deref() == Some(issuer_cn)
+    });
+    debug!("Preferred chain by issuer {:?}: {:?}", issuer_cn, preferred);
+    chains.swap_remove(preferred.unwrap_or(0))
+}
+
+/// The certificate private key as PKCS#8 PEM, the way it's persisted.
+pub(crate) fn private_key_pem(private_key: &PKey<pkey::Private>) -> Result<String> {
+    let pem = private_key
+        .private_key_to_pem_pkcs8()
+        .map_err(|e| format!("Error converting private key to PEM: {}", e))?;
+    Ok(String::from_utf8_lossy(&pem).into_owned())
+}
+
+/// Save the key and downloaded cert into the persistence.
+pub(crate) fn save_certificate<P: Persist>(
+    persist: &P,
+    realm: &str,
+    primary_name: &str,
+    private_key: &PKey<pkey::Private>,
+    cert: String,
+) -> Result<Certificate> {
+    let pk_key = PersistKey::new(realm, PersistKind::PrivateKey, primary_name);
+    let pkey_pem = private_key_pem(private_key)?;
+    debug!("Save private key: {}", pk_key);
+    persist.put(&pk_key, pkey_pem.as_bytes())?;
+
+    let pk_crt = PersistKey::new(realm, PersistKind::Certificate, primary_name);
+    debug!("Save certificate: {}", pk_crt);
+    persist.put(&pk_crt, cert.as_bytes())?;
+
+    Ok(Certificate::new(pkey_pem, cert))
+}
+
 /// Order for an issued certificate that is ready to download.
 pub struct CertOrder<P: Persist> {
     private_key: PKey<pkey::Private>,
@@ -287,30 +366,70 @@ impl<P: Persist> CertOrder<P> {
     /// When downloaded, the certificate and key will be saved in the
     /// persistence. They can later be retreived using [`Account::certificate`].
     ///
+    /// The certificate comes with the default chain of the ACME API. See
+    /// [`download_and_save_preferred_chain`] to pick another one.
+    ///
     /// [`Account::certificate`]: ../struct.Account.html#method.certificate
+    /// [`download_and_save_preferred_chain`]: struct.CertOrder.html#method.download_and_save_preferred_chain
     pub fn download_and_save_cert(self) -> Result<Certificate> {
         //
-        let primary_name = self.order.api_order.domains()[0].to_string();
-        let url = self.order.api_order.certificate.expect("certificate url");
-        let inner = self.order.inner;
-        let realm = &inner.realm[..];
+        let url = self.certificate_url()?;
+        let res = self.order.inner.transport.call(url, &ApiEmptyString)?;
+        self.save(res.body_str())
+    }
 
-        let res = inner.transport.call(&url, &ApiEmptyString)?;
+    /// Download the certificate with each of the chains offered by the ACME API,
+    /// the default chain first. Nothing is saved in the persistence.
+    ///
+    /// The alternate chains are the `Link: rel="alternate"` of the certificate URL.
+    pub fn download_chains(&self) -> Result<Vec<Certificate>> {
+        let private_key = private_key_pem(&self.private_key)?;
+        let chains = self.chains()?;
+        let certs = chains
+            .into_iter()
+            .map(|chain| Certificate::new(private_key.clone(), chain))
+            .collect();
+        Ok(certs)
+    }
 
-        // save key and cert into persistence
-        let persist = &inner.persist;
-        let pk_key = PersistKey::new(realm, PersistKind::PrivateKey, &primary_name);
-        let pkey_pem_bytes = self.private_key.private_key_to_pem_pkcs8().expect("to_pem");
-        let pkey_pem = String::from_utf8_lossy(&pkey_pem_bytes);
-        debug!("Save private key: {}", pk_key);
-        persist.put(&pk_key, &pkey_pem_bytes)?;
+    /// Like [`download_and_save_cert`], saving the chain whose topmost certificate is
+    /// issued by the common name, such as `ISRG Root X1`.
+    ///
+    /// The default chain is saved if none of the offered chains matches.
+    ///
+    /// [`download_and_save_cert`]: struct.CertOrder.html#method.download_and_save_cert
+    pub fn download_and_save_preferred_chain(self, issuer_cn: &str) -> Result<Certificate> {
+        let chains = self.chains()?;
+        self.save(select_chain(chains, issuer_cn))
+    }
+
+    fn certificate_url(&self) -> Result<&str> {
+        let url = self.order.api_order.certificate.as_deref();
+        Ok(url.ok_or("Order is valid, but has no certificate url")?)
+    }
 
-        let cert = res.into_string()?;
-        let pk_crt = PersistKey::new(realm, PersistKind::Certificate, &primary_name);
-        debug!("Save certificate: {}", pk_crt);
-        persist.put(&pk_crt, cert.as_bytes())?;
+    /// The PEM of the default chain, followed by the alternates.
+    fn chains(&self) -> Result<Vec<String>> {
+        let transport = &self.order.inner.transport;
+        let res = transport.call(self.certificate_url()?, &ApiEmptyString)?;
+        let mut chains = vec![res.body_str()];
+        for url in res.links("alternate") {
+            let res = transport.call(&url, &ApiEmptyString)?;
+            chains.push(res.body_str());
+        }
+        Ok(chains)
+    }
 
-        Ok(Certificate::new(pkey_pem.to_string(), cert))
+    fn save(self, chain: String) -> Result<Certificate> {
+        let primary_name = primary_domain(&self.order.api_order)?;
+        let inner = &self.order.inner;
+        save_certificate(
+            &inner.persist,
+            &inner.realm,
+            primary_name,
+            &self.private_key,
+            chain,
+        )
     }
 
     /// Access the underlying JSON object for debugging.
@@ -337,6 +456,13 @@ mod test {
         Ok(())
     }
 
+    /// Validate the http challenge of the order, which the test server makes ready.
+    fn validate_http<P: Persist>(ord: &mut NewOrder<P>) -> Result<()> {
+        let auths = ord.authorizations()?;
+        auths[0].http_challenge().unwrap().validate(1)?;
+        ord.refresh()
+    }
+
     #[test]
     fn test_finalize() -> Result<()> {
         let server = crate::test::with_directory_server();
@@ -344,11 +470,29 @@ mod test {
         let persist = MemoryPersist::new();
         let dir = Directory::from_url(persist, url)?;
         let acc = dir.account("foo@bar.com")?;
-        let ord = acc.new_order("acmetest.example.com", &[])?;
-        // shortcut auth
-        let ord = CsrOrder { order: ord.order };
+        let mut ord = acc.new_order("acmetest.example.com", &[])?;
+        assert!(ord.confirm_validations().is_none());
+
+        // the server refuses to finalize before the authorization is valid
+        let early = CsrOrder {
+            order: Order::new(
+                &ord.order.inner,
+                ord.order.api_order.clone(),
+                ord.order.url.clone(),
+            ),
+        };
+        let err = early.finalize_pkey(cert::create_p256_key(), 1).err();
+        assert_eq!(
+            err.and_then(|e| e.problem_kind()),
+            Some(ProblemKind::OrderNotReady)
+        );
+
+        validate_http(&mut ord)?;
+        assert_eq!(ord.api_order().status.as_deref(), Some("ready"));
+        let ord = ord.confirm_validations().unwrap();
         let pkey = cert::create_p256_key();
-        let _ord = ord.finalize_pkey(pkey, 1)?;
+        let ord = ord.finalize_pkey(pkey, 1)?;
+        assert_eq!(ord.api_order().status.as_deref(), Some("valid"));
         Ok(())
     }
 
@@ -359,23 +503,113 @@ mod test {
         let persist = MemoryPersist::new();
         let dir = Directory::from_url(persist, url)?;
         let acc = dir.account("foo@bar.com")?;
-        let ord = acc.new_order("acmetest.example.com", &[])?;
-
-        // shortcut auth
-        let ord = CsrOrder { order: ord.order };
+        let mut ord = acc.new_order("acmetest.example.com", &[])?;
+        validate_http(&mut ord)?;
+        let ord = ord.confirm_validations().unwrap();
         let pkey = cert::create_p256_key();
         let ord = ord.finalize_pkey(pkey, 1)?;
 
         let cert = ord.download_and_save_cert()?;
-        assert_eq!("CERT HERE", cert.certificate());
+        assert!(cert
+            .certificate()
+            .starts_with("-----BEGIN CERTIFICATE-----"));
         assert!(!cert.private_key().is_empty());
 
         // check that the keys have been persisted
         let cert2 = acc.certificate("acmetest.example.com")?.unwrap();
         assert_eq!(cert.private_key(), cert2.private_key());
         assert_eq!(cert.certificate(), cert2.certificate());
-        assert_eq!(cert.valid_days_left(), 89);
+        // issued for 90 days, of which 89 whole days are left
+        assert_eq!(cert.valid_days_left()?, 89);
 
         Ok(())
     }
+
+    #[test]
+    fn test_preferred_chain() -> Result<()> {
+        use crate::testing::AcmeServer;
+        let server = AcmeServer::new()?;
+        let url = DirectoryUrl::Other(server.directory_url());
+        let client = Arc::new(server.clone());
+        let dir = Directory::from_url_with_client(MemoryPersist::new(), url, client)?;
+        let acc = dir.account("foo@bar.com")?;
+        let order = || -> Result<CertOrder<MemoryPersist>> {
+            let mut ord = acc.new_order("example.com", &[])?;
+            validate_http(&mut ord)?;
+            let ord = ord.confirm_validations().unwrap();
+            ord.finalize_pkey(cert::create_p256_key(), 1)
+        };
+
+        let chains = order()?.download_chains()?;
+        let issuers: Vec<_> = chains
+            .iter()
+            .map(|c| c.issuer_common_name().unwrap().unwrap())
+            .collect();
+        assert_eq!(
+            issuers,
+            ["acme-lib test root", "acme-lib test alternate root"]
+        );
+        assert_eq!(chains[0].leaf_certificate()?, chains[1].leaf_certificate()?);
+        assert!(acc.certificate("example.com")?.is_none());
+
+        let cert = order()?.download_and_save_preferred_chain("acme-lib test alternate root")?;
+        assert_eq!(
+            cert.issuer_common_name()?.as_deref(),
+            Some("acme-lib test alternate root")
+        );
+        assert_eq!(acc.certificate("example.com")?, Some(cert));
+
+        // the default chain when none matches
+        let cert = order()?.download_and_save_preferred_chain("ISRG Root X1")?;
+        assert_eq!(
+            cert.issuer_common_name()?.as_deref(),
+            Some("acme-lib test root")
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_finalize_timeout() -> Result<()> {
+        use crate::http::HttpResponse;
+        use std::time::Duration;
+        let client = crate::test::memory_client(|req| match &req.url[..] {
+            "mem:/new-order" => Some(
+                HttpResponse::new(201)
+                    .with_header("Location", "mem:/order/1")
+                    .with_body(
+                        r#"{"status":"ready","finalize":"mem:/order/1/finalize",
+                        "identifiers":[{"type":"dns","value":"acmetest.example.com"}]}"#,
+                    ),
+            ),
+            // stuck processing, asking to be polled again right away
+            _ => Some(
+                HttpResponse::new(200)
+                    .with_header("Retry-After", "0")
+                    .with_body(
+                        r#"{"status":"processing","finalize":"mem:/order/1/finalize",
+                "identifiers":[{"type":"dns","value":"acmetest.example.com"}]}"#,
+                    ),
+            ),
+        });
+        let url = DirectoryUrl::Other("mem:/directory");
+        let persist = MemoryPersist::new();
+        let dir = Directory::from_url_with_client(persist, url, Arc::new(client.clone()))?;
+        let acc = dir.account("foo@bar.com")?;
+        let ord = acc.new_order("acmetest.example.com", &[])?;
+        let ord = ord.confirm_validations().unwrap();
+
+        let policy = PollPolicy::new().with_deadline(Duration::from_millis(200));
+        match ord.finalize_pkey_with_policy(cert::create_p256_key(), &policy) {
+            Err(Error::Timeout(s)) => assert!(s.contains("processing"), "{}", s),
+            x => panic!("Expected Timeout: {:?}", x.err()),
+        }
+        // polled at the Retry-After, not the 1 second initial delay
+        let polls = client
+            .requests()
+            .iter()
+            .filter(|r| r.url == "mem:/order/1")
+            .count();
+        assert!(polls > 2, "{}", polls);
+        Ok(())
+    }
 }
@@ -117,7 +117,7 @@ impl Persist for MemoryPersist {
 
 /// Simple file persistence.
 ///
-/// Each key is saved under a unique filename.
+/// Each key is saved under a unique filename. Values are replaced atomically.
 #[derive(Clone)]
 pub struct FilePersist {
     dir: PathBuf,
@@ -136,12 +136,17 @@ impl FilePersist {
 
 impl Persist for FilePersist {
     fn put(&self, key: &PersistKey, value: &[u8]) -> Result<()> {
-        let f_name = file_name_of(&self.dir, &key);
-        fs::write(f_name, value).map_err(Error::from)
+        let f_name = file_name_of(&self.dir, key);
+        // write to a temporary file and rename it in place, so a reader never
+        // sees a partially written value (or loses the previous one).
+        let mut tmp_name = f_name.clone();
+        tmp_name.set_extension(format!("{}.tmp", key.kind.name()));
+        fs::write(&tmp_name, value)?;
+        fs::rename(tmp_name, f_name).map_err(Error::from)
     }
 
     fn get(&self, key: &PersistKey) -> Result<Option<Vec<u8>>> {
-        let f_name = file_name_of(&self.dir, &key);
+        let f_name = file_name_of(&self.dir, key);
         let ret = if let Ok(mut file) = fs::File::open(f_name) {
             let mut v = vec![];
             file.read_to_end(&mut v)?;
@@ -153,7 +158,7 @@ impl Persist for FilePersist {
     }
 }
 
-fn file_name_of(dir: &PathBuf, key: &PersistKey) -> PathBuf {
+fn file_name_of(dir: &Path, key: &PersistKey) -> PathBuf {
     let mut f_name = dir.join(key.to_string());
     f_name.set_extension(key.kind.name());
     f_name
@@ -0,0 +1,222 @@
+//
+use std::time::{Duration, Instant};
+
+use crate::Error;
+
+/// How to poll the ACME API while an order or authorization is being processed.
+///
+/// Between polls we wait for the `Retry-After` the ACME API sends, if any. Otherwise
+/// the delay starts at `initial_delay` and is multiplied for every poll up to the
+/// `max_delay`, with some random jitter so that many workers don't poll in lockstep.
+///
+/// Polling gives up with [`Error::Timeout`] once the next poll would be after the
+/// `deadline`, counted from when the polling started.
+///
+/// ```
+/// use acme_lib::PollPolicy;
+/// use std::time::Duration;
+///
+/// let policy = PollPolicy::new()
+///     .with_initial_delay(Duration::from_secs(2))
+///     .with_max_delay(Duration::from_secs(20))
+///     .with_deadline(Duration::from_secs(180));
+/// ```
+///
+/// [`Error::Timeout`]: enum.Error.html#variant.Timeout
+#[derive(Debug, Clone)]
+pub struct PollPolicy {
+    initial_delay: Duration,
+    max_delay: Duration,
+    multiplier: f64,
+    jitter: f64,
+    deadline: Duration,
+}
+
+impl PollPolicy {
+    /// Default policy. Starts at 1 second, doubling up to 30 seconds between polls,
+    /// with 20% jitter and a deadline of 10 minutes.
+    pub fn new() -> Self {
+        PollPolicy {
+            initial_delay: Duration::from_secs(1),
+            max_delay: Duration::from_secs(30),
+            multiplier: 2.0,
+            jitter: 0.2,
+            deadline: Duration::from_secs(600),
+        }
+    }
+
+    /// Poll at a fixed delay of `delay_millis` without jitter or deadline, apart
+    /// from honoring `Retry-After`. This is what the polling calls taking a
+    /// `delay_millis` use.
+    pub fn from_millis(delay_millis: u64) -> Self {
+        let delay = Duration::from_millis(delay_millis);
+        PollPolicy {
+            initial_delay: delay,
+            max_delay: delay,
+            multiplier: 1.0,
+            jitter: 0.0,
+            deadline: Duration::MAX,
+        }
+    }
+
+    /// Delay before the second poll.
+    pub fn with_initial_delay(mut self, delay: Duration) -> Self {
+        self.initial_delay = delay;
+        self
+    }
+
+    /// Upper bound of the delay between polls, not counting `Retry-After`.
+    pub fn with_max_delay(mut self, delay: Duration) -> Self {
+        self.max_delay = delay;
+        self
+    }
+
+    /// Factor to increase the delay with for each poll. `1.0` polls at a fixed delay.
+    pub fn with_multiplier(mut self, multiplier: f64) -> Self {
+        self.multiplier = multiplier.max(1.0);
+        self
+    }
+
+    /// Fraction of the delay, `0.0` to `1.0`, that is randomly taken off it.
+    /// Values that aren't finite, such as `NaN`, turn the jitter off.
+    pub fn with_jitter(mut self, jitter: f64) -> Self {
+        self.jitter = if jitter.is_finite() {
+            jitter.clamp(0.0, 1.0)
+        } else {
+            0.0
+        };
+        self
+    }
+
+    /// Give up polling after this long.
+    pub fn with_deadline(mut self, deadline: Duration) -> Self {
+        self.deadline = deadline;
+        self
+    }
+
+    /// The delay before poll number `attempt + 1` without jitter.
+    fn backoff(&self, attempt: u32) -> Duration {
+        let factor = self.multiplier.powi(attempt.min(64) as i32);
+        let millis = self.initial_delay.as_millis() as f64 * factor;
+        let max = self.max_delay.as_millis() as f64;
+        Duration::from_millis(millis.min(max) as u64)
+    }
+}
+
+impl Default for PollPolicy {
+    fn default() -> Self {
+        PollPolicy::new()
+    }
+}
+
+/// State of one polling loop.
+pub(crate) struct Poller {
+    policy: PollPolicy,
+    started: Instant,
+    attempt: u32,
+}
+
+impl Poller {
+    pub fn new(policy: &PollPolicy) -> Self {
+        Poller {
+            policy: policy.clone(),
+            started: Instant::now(),
+            attempt: 0,
+        }
+    }
+
+    /// The delay to wait before polling again, preferring the server's `Retry-After`.
+    ///
+    /// `None` if the next poll would be past the deadline.
+    pub fn next_delay(&mut self, retry_after: Option<Duration>) -> Option<Duration> {
+        let delay = match retry_after {
+            Some(d) => d,
+            None => {
+                let backoff = self.policy.backoff(self.attempt);
+                backoff - backoff.mul_f64(self.policy.jitter * random_fraction())
+            }
+        };
+        self.attempt += 1;
+        match self.elapsed().checked_add(delay) {
+            Some(at) if at <= self.policy.deadline => Some(delay),
+            _ => None,
+        }
+    }
+
+    /// Time since the polling started.
+    pub fn elapsed(&self) -> Duration {
+        self.started.elapsed()
+    }
+}
+
+/// The error for giving up polling `url`, still in `status`.
+pub(crate) fn timeout_error(url: &str, status: Option<&str>, elapsed: Duration) -> Error {
+    Error::Timeout(format!(
+        "Gave up polling {} after {} seconds in status: {}",
+        url,
+        elapsed.as_secs(),
+        status.unwrap_or("unknown")
+    ))
+}
+
+/// Random number in `[0, 1)`.
+pub(crate) fn random_fraction() -> f64 {
+    let mut buf = [0_u8; 4];
+    openssl::rand::rand_bytes(&mut buf).expect("rand_bytes");
+    u32::from_be_bytes(buf) as f64 / (u32::MAX as f64 + 1.0)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_backoff() {
+        let policy = PollPolicy::new()
+            .with_initial_delay(Duration::from_millis(100))
+            .with_max_delay(Duration::from_millis(500))
+            .with_jitter(0.0);
+        let mut poller = Poller::new(&policy);
+        let delays: Vec<_> = (0..5)
+            .map(|_| poller.next_delay(None).unwrap().as_millis())
+            .collect();
+        assert_eq!(delays, vec![100, 200, 400, 500, 500]);
+    }
+
+    #[test]
+    fn test_jitter() {
+        let policy = PollPolicy::new()
+            .with_initial_delay(Duration::from_millis(1000))
+            .with_jitter(0.5);
+        for _ in 0..20 {
+            let delay = Poller::new(&policy).next_delay(None).unwrap();
+            assert!(delay <= Duration::from_millis(1000));
+            assert!(delay >= Duration::from_millis(500));
+        }
+        // not a number is no jitter
+        let policy = policy.with_jitter(f64::NAN);
+        let delay = Poller::new(&policy).next_delay(None).unwrap();
+        assert_eq!(delay, Duration::from_millis(1000));
+    }
+
+    #[test]
+    fn test_fixed_delay() {
+        let mut poller = Poller::new(&PollPolicy::from_millis(100));
+        let delays: Vec<_> = (0..3)
+            .map(|_| poller.next_delay(None).unwrap().as_millis())
+            .collect();
+        assert_eq!(delays, vec![100, 100, 100]);
+    }
+
+    #[test]
+    fn test_retry_after_and_deadline() {
+        let policy = PollPolicy::from_millis(10).with_deadline(Duration::from_secs(60));
+        let mut poller = Poller::new(&policy);
+        let retry_after = Some(Duration::from_secs(30));
+        assert_eq!(
+            poller.next_delay(retry_after),
+            Some(Duration::from_secs(30))
+        );
+        assert_eq!(poller.next_delay(Some(Duration::from_secs(90))), None);
+    }
+}
@@ -0,0 +1,173 @@
+//
+use std::mem;
+use std::time::Duration;
+
+use crate::poll::random_fraction;
+use crate::Error;
+
+/// How to retry calls to the ACME API that fail with transient errors.
+///
+/// Transient errors are bad nonces, JWS verification errors, server errors (5xx)
+/// and failing to get a response at all. The last two are only retried for calls
+/// that are safe to repeat, such as fetching an order or authorization, and not for
+/// creating orders, finalizing, revoking or changing keys, which the API might have
+/// carried out without us getting the answer. A call is attempted at most `max_attempts`
+/// times, with a delay between attempts that starts at `initial_delay` and doubles
+/// up to `max_delay`, with some random jitter. When the attempts run out, the call
+/// fails with [`Error::RetriesExhausted`] holding the error of every attempt.
+///
+/// ```
+/// use acme_lib::RetryPolicy;
+/// use std::time::Duration;
+///
+/// let policy = RetryPolicy::new()
+///     .with_max_attempts(3)
+///     .with_max_delay(Duration::from_secs(2));
+/// ```
+///
+/// [`Error::RetriesExhausted`]: enum.Error.html#variant.RetriesExhausted
+#[derive(Debug, Clone)]
+pub struct RetryPolicy {
+    max_attempts: u32,
+    initial_delay: Duration,
+    max_delay: Duration,
+}
+
+impl RetryPolicy {
+    /// Default policy. 5 attempts, waiting from 100 milliseconds up to 5 seconds
+    /// between them.
+    pub fn new() -> Self {
+        RetryPolicy {
+            max_attempts: 5,
+            initial_delay: Duration::from_millis(100),
+            max_delay: Duration::from_secs(5),
+        }
+    }
+
+    /// Max number of attempts of a call, including the first. At least 1.
+    pub fn with_max_attempts(mut self, max_attempts: u32) -> Self {
+        self.max_attempts = max_attempts.max(1);
+        self
+    }
+
+    /// Delay after the first failed attempt.
+    pub fn with_initial_delay(mut self, delay: Duration) -> Self {
+        self.initial_delay = delay;
+        self
+    }
+
+    /// Upper bound of the delay between attempts.
+    pub fn with_max_delay(mut self, delay: Duration) -> Self {
+        self.max_delay = delay;
+        self
+    }
+
+    /// The delay after failed attempt number `failed`, counting from 1.
+    fn delay(&self, failed: usize) -> Duration {
+        let factor = 2_u32.saturating_pow(failed.saturating_sub(1).min(31) as u32);
+        let delay = self
+            .initial_delay
+            .saturating_mul(factor)
+            .min(self.max_delay);
+        // up to 20% off, so that clients don't retry in lockstep.
+        delay - delay.mul_f64(0.2 * random_fraction())
+    }
+}
+
+impl Default for RetryPolicy {
+    fn default() -> Self {
+        RetryPolicy::new()
+    }
+}
+
+/// Result of one attempt of a call.
+pub(crate) type Attempt<T> = std::result::Result<T, Failed>;
+
+/// A failed attempt of a call.
+#[derive(Debug)]
+pub(crate) struct Failed {
+    // boxed, since the error is large compared to the responses.
+    pub error: Box<Error>,
+    /// Whether another attempt might succeed.
+    pub transient: bool,
+}
+
+impl Failed {
+    pub fn new(error: Error, transient: bool) -> Self {
+        Failed {
+            error: Box::new(error),
+            transient,
+        }
+    }
+
+    pub fn transient(error: Error) -> Self {
+        Failed::new(error, true)
+    }
+
+    pub fn fatal(error: Error) -> Self {
+        Failed::new(error, false)
+    }
+}
+
+/// The attempts of one call.
+pub(crate) struct Retries {
+    policy: RetryPolicy,
+    errors: Vec<Error>,
+}
+
+impl Retries {
+    pub fn new(policy: &RetryPolicy) -> Self {
+        Retries {
+            policy: policy.clone(),
+            errors: vec![],
+        }
+    }
+
+    /// Take a failed attempt. Gives the delay before the next attempt, or the
+    /// error to fail the call with.
+    pub fn failed(&mut self, failed: Failed) -> Result<Duration, Error> {
+        if !failed.transient {
+            return Err(*failed.error);
+        }
+        debug!("Attempt {} failed: {}", self.errors.len() + 1, failed.error);
+        self.errors.push(*failed.error);
+        if self.errors.len() >= self.policy.max_attempts as usize {
+            return Err(Error::RetriesExhausted(mem::take(&mut self.errors)));
+        }
+        Ok(self.policy.delay(self.errors.len()))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_retries() {
+        let policy = RetryPolicy::new()
+            .with_max_attempts(3)
+            .with_initial_delay(Duration::from_millis(100));
+        let mut retries = Retries::new(&policy);
+        let delay = retries.failed(Failed::transient("one".into())).unwrap();
+        assert!(delay <= Duration::from_millis(100) && delay >= Duration::from_millis(80));
+        let delay = retries.failed(Failed::transient("two".into())).unwrap();
+        assert!(delay <= Duration::from_millis(200) && delay >= Duration::from_millis(160));
+        match retries.failed(Failed::transient("three".into())) {
+            Err(Error::RetriesExhausted(errors)) => {
+                let errors: Vec<_> = errors.iter().map(|e| e.to_string()).collect();
+                assert_eq!(errors, ["one", "two", "three"]);
+            }
+            x => panic!("Expected RetriesExhausted: {:?}", x),
+        }
+    }
+
+    #[test]
+    fn test_fatal() {
+        let mut retries = Retries::new(&RetryPolicy::new());
+        retries.failed(Failed::transient("one".into())).unwrap();
+        match retries.failed(Failed::fatal("two".into())) {
+            Err(Error::Other(s)) => assert_eq!(s, "two"),
+            x => panic!("Expected Other: {:?}", x),
+        }
+    }
+}
@@ -0,0 +1,90 @@
+//! Pluggable signing of requests to the ACME API.
+//!
+//! Every request to the ACME API is a JWS signed by the account key. By default the
+//! account key is an [`AcmeKey`] held in memory and kept as PEM in the persistence.
+//!
+//! Implementing [`AccountSigner`] allows the key to stay elsewhere, such as in a
+//! PKCS#11 token or a cloud KMS, where the library only ever sees the public key and
+//! the signatures. Such a signer is used with [`AccountBuilder::signer`].
+//!
+//! [`AcmeKey`]: struct.AcmeKey.html
+//! [`AccountSigner`]: trait.AccountSigner.html
+//! [`AccountBuilder::signer`]: ../struct.AccountBuilder.html#method.signer
+use std::sync::{Arc, Mutex};
+
+use crate::Result;
+
+pub use crate::acc::AcmeKey;
+pub use crate::jwt::Jwk;
+pub use crate::AccountKeyAlgorithm;
+
+/// Signer of requests to the ACME API using the account key.
+///
+/// Implementations must be thread safe, since the same signer is used by the account
+/// and all orders created from it.
+pub trait AccountSigner: std::fmt::Debug + Send + Sync {
+    /// The algorithm of the key, which decides the JWS `alg`.
+    fn algorithm(&self) -> AccountKeyAlgorithm;
+
+    /// The public key as JWK. This is called for every request needing the
+    /// key, and should be cheap.
+    fn jwk(&self) -> Jwk;
+
+    /// Sign the data (the JWS signing input).
+    ///
+    /// The signature must be encoded as JWS requires for the algorithm. For ECDSA
+    /// that is the `r` and `s` values concatenated, each padded to the size of the
+    /// curve, and not DER.
+    fn sign(&self, data: &[u8]) -> Result<Vec<u8>>;
+
+    /// The private key as PEM, for keys that can be saved to the persistence.
+    ///
+    /// Defaults to `None`, meaning the key is never persisted.
+    fn private_key_pem(&self) -> Option<Vec<u8>> {
+        None
+    }
+}
+
+/// Pairs of signed data and signature.
+type Signatures = Vec<(Vec<u8>, Vec<u8>)>;
+
+/// Test double that delegates to another signer and records every signature.
+#[derive(Debug, Clone)]
+pub struct RecordingSigner {
+    signer: Arc<dyn AccountSigner>,
+    signatures: Arc<Mutex<Signatures>>,
+}
+
+impl RecordingSigner {
+    /// Record the signatures made by the given signer.
+    pub fn new(signer: Arc<dyn AccountSigner>) -> Self {
+        RecordingSigner {
+            signer,
+            signatures: Arc::new(Mutex::new(vec![])),
+        }
+    }
+
+    /// All signatures made so far, as pairs of signed data and signature.
+    ///
+    /// Clones of the recording signer share the recorded signatures.
+    pub fn signatures(&self) -> Signatures {
+        self.signatures.lock().unwrap().clone()
+    }
+}
+
+impl AccountSigner for RecordingSigner {
+    fn algorithm(&self) -> AccountKeyAlgorithm {
+        self.signer.algorithm()
+    }
+
+    fn jwk(&self) -> Jwk {
+        self.signer.jwk()
+    }
+
+    fn sign(&self, data: &[u8]) -> Result<Vec<u8>> {
+        let signature = self.signer.sign(data)?;
+        let mut lock = self.signatures.lock().unwrap();
+        lock.push((data.to_vec(), signature.clone()));
+        Ok(signature)
+    }
+}
@@ -0,0 +1,165 @@
+//
+use std::net::SocketAddr;
+use std::time::Duration;
+
+use crate::solver::dns::{resolve, system_resolvers, TYPE_CNAME, TYPE_TXT};
+use crate::solver::DnsProvider;
+use crate::Result;
+
+/// Most CNAMEs to follow from the challenge name.
+const MAX_CNAMES: usize = 8;
+
+/// Follows a CNAME of the `TXT` record to publish, handing the target of the CNAME to
+/// another [`DnsProvider`].
+///
+/// The ACME API follows CNAMEs when looking up the `TXT` record, which means the
+/// `_acme-challenge` name can be delegated to a zone the provider can update:
+///
+/// ```text
+/// _acme-challenge.example.com. CNAME _acme-challenge.example.com.acme.example.net.
+/// ```
+///
+/// The chain of CNAMEs is resolved with the resolvers, which default to those in
+/// `/etc/resolv.conf`. Without a CNAME, the provider is handed the name itself.
+///
+/// To wait for the delegated record to propagate, wrap the provider, not the
+/// delegation, in a [`PropagationCheck`]:
+///
+/// ```no_run
+/// use acme_lib::solver::{CnameDelegation, PropagationCheck, Rfc2136};
+///
+/// let provider = Rfc2136::new("ns1.example.net:53", "acme.example.net")?;
+/// let provider = CnameDelegation::new(PropagationCheck::new(provider));
+/// # Ok::<(), acme_lib::Error>(())
+/// ```
+///
+/// [`DnsProvider`]: trait.DnsProvider.html
+/// [`PropagationCheck`]: struct.PropagationCheck.html
+#[derive(Debug, Clone)]
+pub struct CnameDelegation<D> {
+    provider: D,
+    resolvers: Vec<SocketAddr>,
+    query_timeout: Duration,
+}
+
+impl<D: DnsProvider> CnameDelegation<D> {
+    /// Hand the targets of CNAMEs to the provider.
+    pub fn new(provider: D) -> Self {
+        CnameDelegation {
+            provider,
+            resolvers: system_resolvers(),
+            query_timeout: Duration::from_secs(5),
+        }
+    }
+
+    /// Resolvers to follow the CNAMEs with.
+    pub fn with_resolvers(mut self, resolvers: &[SocketAddr]) -> Self {
+        self.resolvers = resolvers.to_vec();
+        self
+    }
+
+    /// How long to wait for the answer to one DNS query.
+    pub fn with_query_timeout(mut self, timeout: Duration) -> Self {
+        self.query_timeout = timeout;
+        self
+    }
+
+    /// The name at the end of the chain of CNAMEs starting at the name.
+    pub fn target(&self, name: &str) -> Result<String> {
+        let mut target = name.trim_end_matches('.').to_string();
+        let mut followed = 0;
+        loop {
+            let res = resolve(&self.resolvers, &target, TYPE_TXT, self.query_timeout)?;
+            // the resolver follows the chain as far as it can, but might stop at a
+            // target in another zone.
+            let before = followed;
+            while let Some(cname) = res
+                .answers
+                .iter()
+                .find(|r| r.rtype == TYPE_CNAME && r.name.eq_ignore_ascii_case(&target))
+            {
+                followed += 1;
+                if followed > MAX_CNAMES {
+                    return Err(format!("Too many CNAMEs from: {}", name).into());
+                }
+                target = cname.name_value()?;
+            }
+            if followed == before {
+                break;
+            }
+        }
+        if followed > 0 {
+            debug!("{} is delegated to {}", name, target);
+        }
+        Ok(target)
+    }
+}
+
+impl<D: DnsProvider> DnsProvider for CnameDelegation<D> {
+    fn present(&self, name: &str, value: &str) -> Result<()> {
+        self.provider.present(&self.target(name)?, value)
+    }
+
+    fn cleanup(&self, name: &str, value: &str) -> Result<()> {
+        self.provider.cleanup(&self.target(name)?, value)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::solver::dns::{put_name, Record, CLASS_IN};
+    use crate::solver::Rfc2136;
+    use crate::test::dns::with_dns_server;
+
+    const NAME: &str = "_acme-challenge.example.com";
+
+    fn cname(name: &str, target: &str) -> Record {
+        let mut rdata = vec![];
+        put_name(&mut rdata, target).unwrap();
+        Record {
+            name: name.into(),
+            rtype: TYPE_CNAME,
+            class: CLASS_IN,
+            ttl: 300,
+            rdata,
+        }
+    }
+
+    #[test]
+    fn test_target() -> Result<()> {
+        let com = with_dns_server("example.com", None);
+        let net = with_dns_server("example.net", None);
+        let provider = Rfc2136::new(net.addr, "example.net")?;
+        let delegation = CnameDelegation::new(provider).with_resolvers(&[com.addr, net.addr]);
+        // not delegated
+        assert_eq!(delegation.target(NAME)?, NAME);
+
+        // within the zone, then to another zone, and on in that zone.
+        com.add(cname(NAME, "_acme-challenge.acme.example.com"));
+        com.add(cname("_acme-challenge.acme.example.com", "a.example.net"));
+        net.add(cname("a.example.net", "b.example.net"));
+        assert_eq!(delegation.target(NAME)?, "b.example.net");
+
+        net.add(cname("b.example.net", "a.example.net"));
+        assert!(delegation.target(NAME).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_present_delegated() -> Result<()> {
+        let com = with_dns_server("example.com", None);
+        let net = with_dns_server("example.net", None);
+        let target = "_acme-challenge.example.com.acme.example.net";
+        com.add(cname(NAME, target));
+
+        let provider = Rfc2136::new(net.addr, "example.net")?;
+        let delegation = CnameDelegation::new(provider).with_resolvers(&[com.addr, net.addr]);
+        delegation.present(NAME, "proof")?;
+        assert_eq!(net.txt(target), ["proof"]);
+        assert!(com.txt(NAME).is_empty());
+        delegation.cleanup(NAME, "proof")?;
+        assert!(net.txt(target).is_empty());
+        Ok(())
+    }
+}
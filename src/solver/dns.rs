@@ -0,0 +1,507 @@
+//! Just enough of the DNS wire format (RFC 1035) for dynamic updates and the lookups
+//! of dns challenges.
+use std::convert::TryFrom;
+use std::fs;
+use std::io::{Read, Write};
+use std::net::{IpAddr, SocketAddr, TcpStream, UdpSocket};
+use std::time::Duration;
+
+use crate::Result;
+
+pub const TYPE_A: u16 = 1;
+pub const TYPE_NS: u16 = 2;
+pub const TYPE_CNAME: u16 = 5;
+pub const TYPE_SOA: u16 = 6;
+pub const TYPE_TXT: u16 = 16;
+pub const TYPE_AAAA: u16 = 28;
+pub const TYPE_TSIG: u16 = 250;
+
+pub const CLASS_IN: u16 = 1;
+pub const CLASS_NONE: u16 = 254;
+pub const CLASS_ANY: u16 = 255;
+
+pub const OPCODE_QUERY: u16 = 0;
+pub const OPCODE_UPDATE: u16 = 5;
+
+const FLAG_QR: u16 = 0x8000;
+const FLAG_TC: u16 = 0x0200;
+const FLAG_RD: u16 = 0x0100;
+
+/// A resource record. Names in the rdata are kept uncompressed.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Record {
+    pub name: String,
+    pub rtype: u16,
+    pub class: u16,
+    pub ttl: u32,
+    pub rdata: Vec<u8>,
+}
+
+impl Record {
+    pub fn txt(name: &str, class: u16, ttl: u32, value: &str) -> Self {
+        let mut rdata = vec![];
+        for chunk in value.as_bytes().chunks(255) {
+            rdata.push(chunk.len() as u8);
+            rdata.extend_from_slice(chunk);
+        }
+        Record {
+            name: name.into(),
+            rtype: TYPE_TXT,
+            class,
+            ttl,
+            rdata,
+        }
+    }
+
+    /// The strings of a TXT record, joined.
+    pub fn txt_value(&self) -> Result<String> {
+        let mut value = vec![];
+        let mut pos = 0;
+        while pos < self.rdata.len() {
+            let len = self.rdata[pos] as usize;
+            let s = self
+                .rdata
+                .get(pos + 1..pos + 1 + len)
+                .ok_or("Truncated TXT record")?;
+            value.extend_from_slice(s);
+            pos += 1 + len;
+        }
+        Ok(String::from_utf8_lossy(&value).into_owned())
+    }
+
+    /// The name of a CNAME or NS record.
+    pub fn name_value(&self) -> Result<String> {
+        Ok(read_name(&self.rdata, 0)?.0)
+    }
+
+    /// The address of an A or AAAA record.
+    pub fn ip_value(&self) -> Option<IpAddr> {
+        match self.rdata.len() {
+            4 => Some(<[u8; 4]>::try_from(&self.rdata[..]).ok()?.into()),
+            16 => Some(<[u8; 16]>::try_from(&self.rdata[..]).ok()?.into()),
+            _ => None,
+        }
+    }
+}
+
+/// A question, or the zone of an update.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Question {
+    pub name: String,
+    pub qtype: u16,
+    pub qclass: u16,
+}
+
+/// A DNS message. For updates the sections are zone, prerequisite, update and
+/// additional.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Message {
+    pub id: u16,
+    pub flags: u16,
+    pub questions: Vec<Question>,
+    pub answers: Vec<Record>,
+    pub authority: Vec<Record>,
+    pub additional: Vec<Record>,
+    /// Offset of the TSIG record, when parsed from a message ending with one.
+    pub tsig_offset: Option<usize>,
+}
+
+impl Message {
+    /// A query, asking for recursion or not.
+    pub fn query(name: &str, qtype: u16, recursion: bool) -> Self {
+        Message {
+            id: random_id(),
+            flags: OPCODE_QUERY << 11 | if recursion { FLAG_RD } else { 0 },
+            questions: vec![Question {
+                name: name.into(),
+                qtype,
+                qclass: CLASS_IN,
+            }],
+            ..Default::default()
+        }
+    }
+
+    pub fn update(zone: &str) -> Self {
+        Message {
+            id: random_id(),
+            flags: OPCODE_UPDATE << 11,
+            questions: vec![Question {
+                name: zone.into(),
+                qtype: TYPE_SOA,
+                qclass: CLASS_IN,
+            }],
+            ..Default::default()
+        }
+    }
+
+    pub fn rcode(&self) -> u16 {
+        self.flags & 0xf
+    }
+
+    pub fn is_response(&self) -> bool {
+        self.flags & FLAG_QR != 0
+    }
+
+    pub fn is_truncated(&self) -> bool {
+        self.flags & FLAG_TC != 0
+    }
+
+    pub fn to_bytes(&self) -> Result<Vec<u8>> {
+        let mut buf = vec![];
+        put_u16(&mut buf, self.id);
+        put_u16(&mut buf, self.flags);
+        put_u16(&mut buf, self.questions.len() as u16);
+        put_u16(&mut buf, self.answers.len() as u16);
+        put_u16(&mut buf, self.authority.len() as u16);
+        put_u16(&mut buf, self.additional.len() as u16);
+        for q in &self.questions {
+            put_name(&mut buf, &q.name)?;
+            put_u16(&mut buf, q.qtype);
+            put_u16(&mut buf, q.qclass);
+        }
+        let records = self.answers.iter();
+        let records = records.chain(&self.authority).chain(&self.additional);
+        for r in records {
+            put_name(&mut buf, &r.name)?;
+            put_u16(&mut buf, r.rtype);
+            put_u16(&mut buf, r.class);
+            buf.extend_from_slice(&r.ttl.to_be_bytes());
+            put_u16(&mut buf, r.rdata.len() as u16);
+            buf.extend_from_slice(&r.rdata);
+        }
+        Ok(buf)
+    }
+
+    pub fn from_bytes(buf: &[u8]) -> Result<Self> {
+        let mut msg = Message {
+            id: get_u16(buf, 0)?,
+            flags: get_u16(buf, 2)?,
+            ..Default::default()
+        };
+        let counts = [
+            get_u16(buf, 4)?,
+            get_u16(buf, 6)?,
+            get_u16(buf, 8)?,
+            get_u16(buf, 10)?,
+        ];
+        let mut pos = 12;
+        for _ in 0..counts[0] {
+            let (name, p) = read_name(buf, pos)?;
+            msg.questions.push(Question {
+                name,
+                qtype: get_u16(buf, p)?,
+                qclass: get_u16(buf, p + 2)?,
+            });
+            pos = p + 4;
+        }
+        for (i, count) in counts[1..].iter().enumerate() {
+            for _ in 0..*count {
+                let start = pos;
+                let (record, p) = read_record(buf, pos)?;
+                pos = p;
+                if record.rtype == TYPE_TSIG {
+                    msg.tsig_offset = Some(start);
+                }
+                match i {
+                    0 => msg.answers.push(record),
+                    1 => msg.authority.push(record),
+                    _ => msg.additional.push(record),
+                }
+            }
+        }
+        Ok(msg)
+    }
+}
+
+fn random_id() -> u16 {
+    let mut buf = [0_u8; 2];
+    openssl::rand::rand_bytes(&mut buf).expect("rand_bytes");
+    u16::from_be_bytes(buf)
+}
+
+pub fn put_u16(buf: &mut Vec<u8>, v: u16) {
+    buf.extend_from_slice(&v.to_be_bytes());
+}
+
+pub fn get_u16(buf: &[u8], pos: usize) -> Result<u16> {
+    let b = buf.get(pos..pos + 2).ok_or("Truncated DNS message")?;
+    Ok(u16::from_be_bytes([b[0], b[1]]))
+}
+
+/// Put the name uncompressed.
+pub fn put_name(buf: &mut Vec<u8>, name: &str) -> Result<()> {
+    for label in name.split('.').filter(|l| !l.is_empty()) {
+        if label.len() > 63 {
+            return Err(format!("DNS label too long: {}", label).into());
+        }
+        buf.push(label.len() as u8);
+        buf.extend_from_slice(label.as_bytes());
+    }
+    buf.push(0);
+    Ok(())
+}
+
+/// Read the possibly compressed name at `pos`, giving the name and the position after it.
+pub fn read_name(buf: &[u8], mut pos: usize) -> Result<(String, usize)> {
+    let mut labels: Vec<String> = vec![];
+    let mut end = None;
+    // bound the pointers followed, so a loop of pointers can't hang us.
+    for _ in 0..128 {
+        let len = *buf.get(pos).ok_or("Truncated DNS name")? as usize;
+        if len & 0xc0 == 0xc0 {
+            let ptr = get_u16(buf, pos)? as usize & 0x3fff;
+            end.get_or_insert(pos + 2);
+            pos = ptr;
+        } else if len == 0 {
+            return Ok((labels.join("."), end.unwrap_or(pos + 1)));
+        } else {
+            let label = buf
+                .get(pos + 1..pos + 1 + len)
+                .ok_or("Truncated DNS name")?;
+            labels.push(String::from_utf8_lossy(label).into_owned());
+            pos += 1 + len;
+        }
+    }
+    Err("Bad DNS name compression".into())
+}
+
+fn read_record(buf: &[u8], pos: usize) -> Result<(Record, usize)> {
+    let (name, pos) = read_name(buf, pos)?;
+    let rtype = get_u16(buf, pos)?;
+    let class = get_u16(buf, pos + 2)?;
+    let ttl = buf.get(pos + 4..pos + 8).ok_or("Truncated DNS record")?;
+    let ttl = u32::from_be_bytes([ttl[0], ttl[1], ttl[2], ttl[3]]);
+    let len = get_u16(buf, pos + 8)? as usize;
+    let start = pos + 10;
+    let raw = buf.get(start..start + len).ok_or("Truncated DNS record")?;
+    // names in rdata might point anywhere in the message, so decompress them.
+    let rdata = match rtype {
+        TYPE_NS | TYPE_CNAME => {
+            let mut rdata = vec![];
+            put_name(&mut rdata, &read_name(buf, start)?.0)?;
+            rdata
+        }
+        TYPE_SOA => {
+            let mut rdata = vec![];
+            let (mname, p) = read_name(buf, start)?;
+            let (rname, p) = read_name(buf, p)?;
+            put_name(&mut rdata, &mname)?;
+            put_name(&mut rdata, &rname)?;
+            let rest = buf.get(p..start + len).ok_or("Truncated SOA record")?;
+            rdata.extend_from_slice(rest);
+            rdata
+        }
+        _ => raw.to_vec(),
+    };
+    let record = Record {
+        name,
+        rtype,
+        class,
+        ttl,
+        rdata,
+    };
+    Ok((record, start + len))
+}
+
+/// Send the message to the server and read the response, over UDP and falling back
+/// to TCP when the response is truncated.
+pub fn exchange(server: SocketAddr, msg: &[u8], timeout: Duration) -> Result<Vec<u8>> {
+    let id = get_u16(msg, 0)?;
+    let res = exchange_udp(server, id, msg, timeout)?;
+    if Message::from_bytes(&res)?.is_truncated() {
+        return exchange_tcp(server, msg, timeout);
+    }
+    Ok(res)
+}
+
+fn exchange_udp(server: SocketAddr, id: u16, msg: &[u8], timeout: Duration) -> Result<Vec<u8>> {
+    let bind: SocketAddr = if server.is_ipv4() {
+        ([0, 0, 0, 0], 0).into()
+    } else {
+        ([0_u16; 8], 0).into()
+    };
+    let socket = UdpSocket::bind(bind)?;
+    socket.set_read_timeout(Some(timeout))?;
+    socket.connect(server)?;
+    socket.send(msg)?;
+    let mut buf = [0_u8; 4096];
+    loop {
+        let n = socket
+            .recv(&mut buf)
+            .map_err(|e| format!("No DNS response from {}: {}", server, e))?;
+        // skip anything that isn't the response to this message.
+        if n >= 12 && get_u16(&buf, 0)? == id {
+            return Ok(buf[..n].to_vec());
+        }
+    }
+}
+
+fn exchange_tcp(server: SocketAddr, msg: &[u8], timeout: Duration) -> Result<Vec<u8>> {
+    let mut stream = TcpStream::connect_timeout(&server, timeout)?;
+    stream.set_read_timeout(Some(timeout))?;
+    stream.set_write_timeout(Some(timeout))?;
+    let mut req = (msg.len() as u16).to_be_bytes().to_vec();
+    req.extend_from_slice(msg);
+    stream.write_all(&req)?;
+    let mut len = [0_u8; 2];
+    stream.read_exact(&mut len)?;
+    let mut res = vec![0_u8; u16::from_be_bytes(len) as usize];
+    stream.read_exact(&mut res)?;
+    Ok(res)
+}
+
+/// Ask the server the question, giving the response.
+pub fn query(
+    server: SocketAddr,
+    name: &str,
+    qtype: u16,
+    recursion: bool,
+    timeout: Duration,
+) -> Result<Message> {
+    let msg = Message::query(name, qtype, recursion);
+    let res = Message::from_bytes(&exchange(server, &msg.to_bytes()?, timeout)?)?;
+    if !res.is_response() || res.id != msg.id {
+        return Err(format!("Bad DNS response from {}", server).into());
+    }
+    Ok(res)
+}
+
+/// Ask the resolvers in turn, until one answers with NOERROR or NXDOMAIN.
+pub fn resolve(
+    resolvers: &[SocketAddr],
+    name: &str,
+    qtype: u16,
+    timeout: Duration,
+) -> Result<Message> {
+    let mut last_err = None;
+    for resolver in resolvers {
+        match query(*resolver, name, qtype, true, timeout) {
+            Ok(res) if res.rcode() == 0 || res.rcode() == 3 => return Ok(res),
+            Ok(res) => {
+                let rcode = rcode_name(res.rcode());
+                let err = format!("DNS query of {} at {} failed: {}", name, resolver, rcode);
+                last_err = Some(err.into());
+            }
+            Err(e) => last_err = Some(e),
+        }
+    }
+    Err(last_err.unwrap_or_else(|| "No DNS resolvers to ask".into()))
+}
+
+/// The name servers in `/etc/resolv.conf`.
+pub fn system_resolvers() -> Vec<SocketAddr> {
+    let conf = fs::read_to_string("/etc/resolv.conf").unwrap_or_default();
+    parse_resolv_conf(&conf)
+}
+
+fn parse_resolv_conf(conf: &str) -> Vec<SocketAddr> {
+    conf.lines()
+        .filter_map(|l| {
+            let mut words = l.split_whitespace();
+            match words.next() {
+                Some("nameserver") => words.next(),
+                _ => None,
+            }
+        })
+        // drop any zone index of link local addresses.
+        .filter_map(|ip| ip.split('%').next()?.parse::<IpAddr>().ok())
+        .map(|ip| SocketAddr::new(ip, 53))
+        .collect()
+}
+
+/// Name of the rcode of a response.
+pub fn rcode_name(rcode: u16) -> String {
+    let name = match rcode {
+        0 => "NOERROR",
+        1 => "FORMERR",
+        2 => "SERVFAIL",
+        3 => "NXDOMAIN",
+        4 => "NOTIMP",
+        5 => "REFUSED",
+        6 => "YXDOMAIN",
+        7 => "YXRRSET",
+        8 => "NXRRSET",
+        9 => "NOTAUTH",
+        10 => "NOTZONE",
+        _ => return format!("RCODE{}", rcode),
+    };
+    name.into()
+}
+
+/// Whether the name is the zone or in it.
+pub fn in_zone(name: &str, zone: &str) -> bool {
+    let name = name.trim_end_matches('.').to_ascii_lowercase();
+    let zone = zone.trim_end_matches('.').to_ascii_lowercase();
+    zone.is_empty() || name == zone || name.ends_with(&format!(".{}", zone))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_roundtrip() {
+        let mut msg = Message::update("example.com.");
+        msg.authority.push(Record::txt(
+            "_acme-challenge.example.com",
+            CLASS_IN,
+            60,
+            "proof",
+        ));
+        let bytes = msg.to_bytes().unwrap();
+        let parsed = Message::from_bytes(&bytes).unwrap();
+        assert_eq!(parsed.flags >> 11, OPCODE_UPDATE);
+        assert_eq!(parsed.questions[0].name, "example.com");
+        assert_eq!(parsed.authority, msg.authority);
+        assert_eq!(parsed.authority[0].txt_value().unwrap(), "proof");
+
+        let long = "x".repeat(300);
+        let txt = Record::txt("a", CLASS_IN, 0, &long);
+        assert_eq!(txt.rdata.len(), 302);
+        assert_eq!(txt.txt_value().unwrap(), long);
+        assert!(put_name(&mut vec![], &"x".repeat(64)).is_err());
+    }
+
+    #[test]
+    fn test_compression() {
+        // a response for example.com CNAME target.example.com, with pointers.
+        let mut buf = vec![0x12, 0x34, 0x81, 0x80, 0, 1, 0, 1, 0, 0, 0, 0];
+        put_name(&mut buf, "example.com").unwrap();
+        buf.extend_from_slice(&[0, 5, 0, 1]);
+        // answer name points to the question name at 12
+        buf.extend_from_slice(&[0xc0, 12, 0, 5, 0, 1, 0, 0, 0, 60, 0, 9]);
+        // "target" and a pointer to "com" at 12 + 8
+        buf.extend_from_slice(&[6, b't', b'a', b'r', b'g', b'e', b't', 0xc0, 20]);
+        let msg = Message::from_bytes(&buf).unwrap();
+        assert!(msg.is_response());
+        assert_eq!(msg.answers[0].name, "example.com");
+        let (target, _) = read_name(&msg.answers[0].rdata, 0).unwrap();
+        assert_eq!(target, "target.com");
+
+        // a pointer to itself
+        let mut bad = buf[..12].to_vec();
+        bad.extend_from_slice(&[0xc0, 12, 0, 5, 0, 1]);
+        assert!(Message::from_bytes(&bad).is_err());
+        // truncated
+        assert!(Message::from_bytes(&buf[..buf.len() - 3]).is_err());
+    }
+
+    #[test]
+    fn test_in_zone() {
+        assert!(in_zone("_acme-challenge.Example.com.", "example.com"));
+        assert!(in_zone("example.com", "example.com."));
+        assert!(!in_zone("example.com", "ample.com"));
+        assert!(!in_zone("example.org", "example.com"));
+    }
+
+    #[test]
+    fn test_parse_resolv_conf() {
+        let conf = "# comment\nsearch example.com\nnameserver 10.0.0.1\n\
+                    nameserver fe80::1%eth0\noptions ndots:1\n";
+        let expected: Vec<SocketAddr> = vec![
+            "10.0.0.1:53".parse().unwrap(),
+            "[fe80::1]:53".parse().unwrap(),
+        ];
+        assert_eq!(parse_resolv_conf(conf), expected);
+    }
+}
@@ -0,0 +1,251 @@
+//
+use std::collections::HashMap;
+use std::io::{self, Read, Write};
+use std::net::{SocketAddr, TcpListener, TcpStream, ToSocketAddrs};
+use std::sync::{Arc, Mutex};
+
+use crate::solver::{HttpSolver, Server};
+use crate::Result;
+
+const CHALLENGE_PATH: &str = "/.well-known/acme-challenge/";
+
+/// Max size of the request line and headers we read.
+const MAX_HEAD: usize = 8192;
+
+/// Standalone web server answering http challenges.
+///
+/// The responder serves the proof of every registered token under
+/// `/.well-known/acme-challenge/<token>` and answers anything else with a 404. Tokens are
+/// registered around the validation by [`validate_with_solver`], or by hand with
+/// [`add_token`].
+///
+/// The ACME API validates on port 80, so in production the responder must be bound
+/// to port 80, or port 80 must be forwarded to it.
+///
+/// Clones share the same server, which means one responder can serve concurrent
+/// orders. The server stops when the last clone is dropped.
+///
+/// ```no_run
+/// use acme_lib::solver::HttpResponder;
+///
+/// let responder = HttpResponder::bind("0.0.0.0:80")?;
+/// # Ok::<(), acme_lib::Error>(())
+/// ```
+///
+/// [`validate_with_solver`]: ../order/struct.Challenge.html#method.validate_with_solver
+/// [`add_token`]: struct.HttpResponder.html#method.add_token
+#[derive(Debug, Clone)]
+pub struct HttpResponder {
+    inner: Arc<Inner>,
+}
+
+#[derive(Debug)]
+struct Inner {
+    server: Server,
+    proofs: Arc<Mutex<HashMap<String, String>>>,
+}
+
+impl HttpResponder {
+    /// Bind the responder to the address and start serving in a background thread.
+    pub fn bind<A: ToSocketAddrs>(addr: A) -> Result<HttpResponder> {
+        let listener = TcpListener::bind(addr)?;
+        let proofs = Arc::new(Mutex::new(HashMap::new()));
+        let server = {
+            let proofs = proofs.clone();
+            Server::start(listener, "http-responder", move |stream| {
+                respond(stream, &proofs)
+            })?
+        };
+        Ok(HttpResponder {
+            inner: Arc::new(Inner { server, proofs }),
+        })
+    }
+
+    /// The address the responder is bound to. Useful when bound to port 0.
+    pub fn local_addr(&self) -> SocketAddr {
+        self.inner.server.local_addr()
+    }
+
+    /// Serve the proof for the token.
+    pub fn add_token(&self, token: &str, proof: &str) {
+        let mut proofs = self.inner.proofs.lock().unwrap();
+        proofs.insert(token.into(), proof.into());
+    }
+
+    /// Stop serving the proof for the token.
+    pub fn remove_token(&self, token: &str) {
+        self.inner.proofs.lock().unwrap().remove(token);
+    }
+}
+
+impl HttpSolver for HttpResponder {
+    fn present(&self, _domain: &str, token: &str, proof: &str) -> Result<()> {
+        self.add_token(token, proof);
+        Ok(())
+    }
+
+    fn cleanup(&self, _domain: &str, token: &str) -> Result<()> {
+        self.remove_token(token);
+        Ok(())
+    }
+}
+
+fn respond(mut stream: TcpStream, proofs: &Mutex<HashMap<String, String>>) -> io::Result<()> {
+    let head = read_head(&mut stream)?;
+    let mut request_line = head.lines().next().unwrap_or("").split_whitespace();
+    let method = request_line.next().unwrap_or("");
+    let path = request_line.next().unwrap_or("");
+    let path = path.split('?').next().unwrap_or("");
+
+    let proof = if method == "GET" || method == "HEAD" {
+        path.strip_prefix(CHALLENGE_PATH)
+            .and_then(|token| proofs.lock().unwrap().get(token).cloned())
+    } else {
+        None
+    };
+    trace!(
+        "HTTP responder {} {} found: {}",
+        method,
+        path,
+        proof.is_some()
+    );
+
+    let (status, body) = match proof {
+        Some(proof) => ("200 OK", proof),
+        None => ("404 Not Found", "Not found".to_string()),
+    };
+    let mut res = format!(
+        "HTTP/1.1 {}\r\nContent-Type: text/plain\r\nContent-Length: {}\r\nConnection: close\r\n\r\n",
+        status,
+        body.len()
+    );
+    if method != "HEAD" {
+        res.push_str(&body);
+    }
+    stream.write_all(res.as_bytes())?;
+    stream.flush()
+}
+
+/// Read the request line and headers, up to the empty line.
+fn read_head(stream: &mut TcpStream) -> io::Result<String> {
+    let mut head = Vec::new();
+    let mut buf = [0_u8; 1024];
+    while !head.ends_with(b"\r\n\r\n") && head.len() < MAX_HEAD {
+        let n = stream.read(&mut buf)?;
+        if n == 0 {
+            break;
+        }
+        head.extend_from_slice(&buf[..n]);
+        if head.windows(4).any(|w| w == b"\r\n\r\n") {
+            break;
+        }
+    }
+    Ok(String::from_utf8_lossy(&head).into_owned())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    /// Request the path from the responder, giving the status code and body.
+    fn get(addr: SocketAddr, method: &str, path: &str) -> (u16, String) {
+        let mut stream = TcpStream::connect(addr).unwrap();
+        let req = format!("{} {} HTTP/1.1\r\nHost: example.com\r\n\r\n", method, path);
+        stream.write_all(req.as_bytes()).unwrap();
+        let mut res = String::new();
+        stream.read_to_string(&mut res).unwrap();
+        let status = res[9..12].parse().unwrap();
+        let body = res.split_once("\r\n\r\n").unwrap().1.to_string();
+        (status, body)
+    }
+
+    #[test]
+    fn test_responder() -> Result<()> {
+        let responder = HttpResponder::bind("127.0.0.1:0")?;
+        let addr = responder.local_addr();
+        let path = "/.well-known/acme-challenge/tok";
+        assert_eq!(get(addr, "GET", path).0, 404);
+
+        // a clone serves the same tokens
+        responder
+            .clone()
+            .present("example.com", "tok", "tok.proof")?;
+        assert_eq!(get(addr, "GET", path), (200, "tok.proof".to_string()));
+        assert_eq!(get(addr, "HEAD", path), (200, "".to_string()));
+        assert_eq!(get(addr, "POST", path).0, 404);
+        assert_eq!(get(addr, "GET", "/tok").0, 404);
+        assert_eq!(get(addr, "GET", "/.well-known/acme-challenge/other").0, 404);
+
+        responder.cleanup("example.com", "tok")?;
+        assert_eq!(get(addr, "GET", path).0, 404);
+        Ok(())
+    }
+
+    #[test]
+    fn test_validate_with_responder() -> Result<()> {
+        use crate::{Error, PollPolicy};
+
+        let responder = HttpResponder::bind("127.0.0.1:0")?;
+        let addr = responder.local_addr();
+        let fetched = Arc::new(Mutex::new(None));
+        let ord = {
+            let fetched = fetched.clone();
+            crate::test::memory_order("example.com", move |req| {
+                let path = "/.well-known/acme-challenge/tok";
+                let body = match &req.url[..] {
+                    // like the ACME API, fetch the proof when asked to validate.
+                    "mem:/chall/1" => {
+                        *fetched.lock().unwrap() = Some(get(addr, "GET", path));
+                        r#"{"type": "http-01", "url": "mem:/chall/1", "status": "processing",
+                        "token": "tok"}"#
+                    }
+                    "mem:/authz/1" => match &*fetched.lock().unwrap() {
+                        Some((200, proof)) if proof.starts_with("tok.") => {
+                            r#"{"identifier": {"type": "dns", "value": "example.com"},
+                            "status": "valid", "challenges": []}"#
+                        }
+                        Some(_) => {
+                            r#"{"identifier": {"type": "dns", "value": "example.com"},
+                            "status": "invalid", "challenges": [
+                            {"type": "http-01", "url": "mem:/chall/1", "status": "invalid",
+                            "token": "tok", "error": {
+                                "type": "urn:ietf:params:acme:error:unauthorized",
+                                "detail": "Invalid response"}}]}"#
+                        }
+                        None => {
+                            r#"{"identifier": {"type": "dns", "value": "example.com"},
+                            "status": "pending", "challenges": [
+                            {"type": "http-01", "url": "mem:/chall/1", "status": "pending",
+                            "token": "tok"}]}"#
+                        }
+                    },
+                    _ => return None,
+                };
+                Some(body)
+            })?
+        };
+        let policy = PollPolicy::from_millis(1);
+
+        let auth = &ord.authorizations()?[0];
+        let chall = auth.http_challenge().ok_or("No http challenge")?;
+        assert_eq!(chall.domain_name(), "example.com");
+        chall.validate_with_solver(&responder, &policy)?;
+        // cleaned up after
+        assert_eq!(get(addr, "GET", "/.well-known/acme-challenge/tok").0, 404);
+
+        // the ACME API fails to fetch the proof from some other responder.
+        *fetched.lock().unwrap() = None;
+        let other = HttpResponder::bind("127.0.0.1:0")?;
+        let auth = &ord.authorizations()?[0];
+        let chall = auth.http_challenge().ok_or("No http challenge")?;
+        match chall.validate_with_solver(&other, &policy) {
+            Err(Error::ApiProblem(p)) => assert_eq!(p.detail.as_deref(), Some("Invalid response")),
+            x => panic!("Expected ApiProblem: {:?}", x),
+        }
+        assert_eq!(
+            get(other.local_addr(), "GET", "/.well-known/acme-challenge/tok").0,
+            404
+        );
+        Ok(())
+    }
+}
@@ -0,0 +1,225 @@
+//! Solvers that put the proof of a challenge in place while it's being validated.
+//!
+//! Instead of placing the proof by hand before calling [`validate`], a solver is handed
+//! to [`validate_with_solver`]. The solver is asked to present the proof, the challenge
+//! is validated, and the solver is asked to clean up again, also when the validation
+//! fails.
+//!
+//! For http challenges, [`HttpResponder`] is a small standalone web server answering
+//! requests for `/.well-known/acme-challenge/<token>`, and [`Webroot`] writes the proof
+//! into the document root of a web server that is already running.
+//!
+//! For TLS ALPN challenges, [`TlsAlpnResponder`] is a small standalone TLS server
+//! answering `acme-tls/1` handshakes with the validation certificate.
+//!
+//! For dns challenges, a [`DnsProvider`] publishes the `TXT` record. [`Rfc2136`] does
+//! so with dynamic updates of the zone at its name server. Wrapped in a
+//! [`PropagationCheck`], validation waits until the record is visible at all the
+//! authoritative name servers of the zone. A [`CnameDelegation`] publishes the record
+//! at the target of a CNAME of the `_acme-challenge` name instead.
+//!
+//! ```no_run
+//! use acme_lib::persist::Persist;
+//! use acme_lib::order::Auth;
+//! use acme_lib::solver::HttpResponder;
+//! use acme_lib::{Error, PollPolicy};
+//!
+//! fn authorize<P: Persist>(auth: &Auth<P>, responder: &HttpResponder) -> Result<(), Error> {
+//!   let challenge = auth.http_challenge().ok_or("No http challenge")?;
+//!   challenge.validate_with_solver(responder, &PollPolicy::new())?;
+//!   Ok(())
+//! }
+//! ```
+//!
+//! [`validate`]: ../order/struct.Challenge.html#method.validate
+//! [`validate_with_solver`]: ../order/struct.Challenge.html#method.validate_with_solver
+//! [`HttpResponder`]: struct.HttpResponder.html
+//! [`Webroot`]: struct.Webroot.html
+//! [`TlsAlpnResponder`]: struct.TlsAlpnResponder.html
+//! [`DnsProvider`]: trait.DnsProvider.html
+//! [`Rfc2136`]: struct.Rfc2136.html
+//! [`PropagationCheck`]: struct.PropagationCheck.html
+//! [`CnameDelegation`]: struct.CnameDelegation.html
+use std::io;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr, TcpListener, TcpStream};
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::thread;
+use std::time::Duration;
+
+use crate::Result;
+
+mod delegation;
+pub(crate) mod dns;
+mod http;
+mod propagation;
+mod rfc2136;
+mod tls_alpn;
+mod tsig;
+mod webroot;
+
+pub use self::delegation::CnameDelegation;
+pub use self::http::HttpResponder;
+pub use self::propagation::PropagationCheck;
+pub use self::rfc2136::Rfc2136;
+pub use self::tls_alpn::TlsAlpnResponder;
+pub use self::tsig::{TsigAlgorithm, TsigKey};
+pub use self::webroot::Webroot;
+
+/// Puts the proof of http challenges in place.
+///
+/// The proof must be served as the body of:
+///
+/// ```text
+/// http://<domain>/.well-known/acme-challenge/<token>
+/// ```
+pub trait HttpSolver: Send + Sync {
+    /// Make the proof accessible for the token of the domain.
+    fn present(&self, domain: &str, token: &str, proof: &str) -> Result<()>;
+
+    /// Remove the proof for the token of the domain.
+    fn cleanup(&self, domain: &str, token: &str) -> Result<()>;
+}
+
+/// Puts the validation certificate of TLS ALPN challenges in place.
+///
+/// The certificate, made with [`create_tls_alpn_certificate`], must be served in
+/// TLS handshakes for the domain negotiating the `acme-tls/1` ALPN protocol.
+///
+/// [`create_tls_alpn_certificate`]: ../fn.create_tls_alpn_certificate.html
+pub trait TlsAlpnSolver: Send + Sync {
+    /// Serve the validation certificate with the proof for the domain.
+    fn present(&self, domain: &str, proof: &[u8; 32]) -> Result<()>;
+
+    /// Stop serving the validation certificate for the domain.
+    fn cleanup(&self, domain: &str) -> Result<()>;
+}
+
+/// Publishes the `TXT` records of dns challenges.
+///
+/// The record is named `_acme-challenge.<domain>` and holds the proof:
+///
+/// ```text
+/// _acme-challenge.<domain>.  TXT  <proof>
+/// ```
+///
+/// Several records might be published under the same name at the same time, for
+/// instance for `example.com` and `*.example.com` in one order. Cleaning up one must
+/// leave the others.
+pub trait DnsProvider: Send + Sync {
+    /// Publish a `TXT` record of the name with the value.
+    fn present(&self, name: &str, value: &str) -> Result<()>;
+
+    /// Remove the `TXT` record of the name with the value.
+    fn cleanup(&self, name: &str, value: &str) -> Result<()>;
+}
+
+/// The result of a validation after cleaning up, or the cleanup error if only the
+/// cleanup failed.
+pub(crate) fn cleanup_after<T>(res: Result<T>, cleanup: Result<()>) -> Result<T> {
+    match (res, cleanup) {
+        (Ok(_), Err(e)) => Err(e),
+        (res, Err(e)) => {
+            debug!("Cleanup after failed validation failed: {}", e);
+            res
+        }
+        (res, Ok(())) => res,
+    }
+}
+
+/// Connections a [`Server`] handles at the same time. More are closed right away.
+const MAX_CONNECTIONS: usize = 16;
+
+/// Background thread accepting connections, each handled in a thread of its own, up
+/// to [`MAX_CONNECTIONS`] at a time.
+///
+/// Stops accepting when dropped.
+#[derive(Debug)]
+pub(crate) struct Server {
+    addr: SocketAddr,
+    stop: Arc<AtomicBool>,
+}
+
+impl Server {
+    pub fn start<F>(listener: TcpListener, name: &str, handle: F) -> Result<Server>
+    where
+        F: Fn(TcpStream) -> io::Result<()> + Send + Sync + 'static,
+    {
+        let addr = listener.local_addr()?;
+        let stop = Arc::new(AtomicBool::new(false));
+        let handle = Arc::new(handle);
+        let active = Arc::new(AtomicUsize::new(0));
+        {
+            let stop = stop.clone();
+            let name = name.to_string();
+            thread::Builder::new()
+                .name(format!("acme-{}", name))
+                .spawn(move || {
+                    for stream in listener.incoming() {
+                        if stop.load(Ordering::SeqCst) {
+                            break;
+                        }
+                        let stream = match stream {
+                            Ok(stream) => stream,
+                            Err(e) => {
+                                debug!("{} failed to accept: {}", name, e);
+                                continue;
+                            }
+                        };
+                        if active.fetch_add(1, Ordering::SeqCst) >= MAX_CONNECTIONS {
+                            active.fetch_sub(1, Ordering::SeqCst);
+                            debug!("{} too many connections, closing one", name);
+                            continue;
+                        }
+                        let active = Active(active.clone());
+                        let handle = handle.clone();
+                        let name = name.clone();
+                        thread::spawn(move || {
+                            let res = stream
+                                .set_read_timeout(Some(Duration::from_secs(10)))
+                                .and_then(|_| {
+                                    stream.set_write_timeout(Some(Duration::from_secs(10)))
+                                })
+                                .and_then(|_| handle(stream));
+                            if let Err(e) = res {
+                                debug!("{} failed to respond: {}", name, e);
+                            }
+                            drop(active);
+                        });
+                    }
+                    debug!("{} stopped", name);
+                })?;
+        }
+        debug!("{} listening on {}", name, addr);
+        Ok(Server { addr, stop })
+    }
+
+    /// The address the server is bound to.
+    pub fn local_addr(&self) -> SocketAddr {
+        self.addr
+    }
+}
+
+/// Counts a connection as handled until dropped, also if the handler panics.
+struct Active(Arc<AtomicUsize>);
+
+impl Drop for Active {
+    fn drop(&mut self) {
+        self.0.fetch_sub(1, Ordering::SeqCst);
+    }
+}
+
+impl Drop for Server {
+    fn drop(&mut self) {
+        self.stop.store(true, Ordering::SeqCst);
+        // wake up the accept loop, so it sees the stop.
+        let mut addr = self.addr;
+        if addr.ip().is_unspecified() {
+            addr.set_ip(match addr.ip() {
+                IpAddr::V4(_) => Ipv4Addr::LOCALHOST.into(),
+                IpAddr::V6(_) => Ipv6Addr::LOCALHOST.into(),
+            });
+        }
+        let _ = TcpStream::connect_timeout(&addr, Duration::from_secs(1));
+    }
+}
@@ -0,0 +1,244 @@
+//
+use std::net::{IpAddr, SocketAddr};
+use std::thread;
+use std::time::Duration;
+
+use crate::poll::{timeout_error, Poller};
+use crate::solver::dns::{query, resolve, system_resolvers, Message};
+use crate::solver::dns::{TYPE_A, TYPE_AAAA, TYPE_NS, TYPE_SOA, TYPE_TXT};
+use crate::solver::{cleanup_after, DnsProvider};
+use crate::{PollPolicy, Result};
+
+/// Waits for the published `TXT` record to be visible at the authoritative name servers
+/// of its zone, before the ACME API is asked to validate.
+///
+/// Validating too early, before all name servers of the zone have the record, fails the
+/// authorization. The check wraps another [`DnsProvider`]: after it has published the
+/// record, each authoritative name server is asked for the record until all of them
+/// answer with the value, or the deadline of the [`PollPolicy`] passes. In that case the
+/// record is removed again and [`Error::Timeout`] returned.
+///
+/// The authoritative name servers are found with the resolvers, which default to those
+/// in `/etc/resolv.conf`. They can also be given directly.
+///
+/// Waiting blocks the calling thread. The async API runs providers on a thread of
+/// their own.
+///
+/// ```no_run
+/// use acme_lib::solver::{PropagationCheck, Rfc2136};
+/// use acme_lib::PollPolicy;
+/// use std::time::Duration;
+///
+/// let provider = Rfc2136::new("ns1.example.com:53", "example.com")?;
+/// let provider = PropagationCheck::new(provider)
+///     .with_resolvers(&["1.1.1.1:53".parse().unwrap()])
+///     .with_policy(PollPolicy::new().with_deadline(Duration::from_secs(120)));
+/// # Ok::<(), acme_lib::Error>(())
+/// ```
+///
+/// [`DnsProvider`]: trait.DnsProvider.html
+/// [`PollPolicy`]: ../struct.PollPolicy.html
+/// [`Error::Timeout`]: ../enum.Error.html#variant.Timeout
+#[derive(Debug, Clone)]
+pub struct PropagationCheck<D> {
+    provider: D,
+    resolvers: Vec<SocketAddr>,
+    nameservers: Vec<SocketAddr>,
+    policy: PollPolicy,
+    query_timeout: Duration,
+}
+
+impl<D: DnsProvider> PropagationCheck<D> {
+    /// Check the records published by the provider. Polls from 2 seconds up to 15
+    /// seconds between checks, for at most 5 minutes.
+    pub fn new(provider: D) -> Self {
+        PropagationCheck {
+            provider,
+            resolvers: system_resolvers(),
+            nameservers: vec![],
+            policy: PollPolicy::new()
+                .with_initial_delay(Duration::from_secs(2))
+                .with_max_delay(Duration::from_secs(15))
+                .with_deadline(Duration::from_secs(300)),
+            query_timeout: Duration::from_secs(5),
+        }
+    }
+
+    /// Resolvers to find the authoritative name servers with.
+    pub fn with_resolvers(mut self, resolvers: &[SocketAddr]) -> Self {
+        self.resolvers = resolvers.to_vec();
+        self
+    }
+
+    /// Ask these name servers for the record, instead of finding the authoritative name
+    /// servers with the resolvers.
+    pub fn with_nameservers(mut self, nameservers: &[SocketAddr]) -> Self {
+        self.nameservers = nameservers.to_vec();
+        self
+    }
+
+    /// How long to wait between checks, and for how long in total.
+    pub fn with_policy(mut self, policy: PollPolicy) -> Self {
+        self.policy = policy;
+        self
+    }
+
+    /// How long to wait for the answer to one DNS query.
+    pub fn with_query_timeout(mut self, timeout: Duration) -> Self {
+        self.query_timeout = timeout;
+        self
+    }
+
+    /// Wait for the `TXT` record of the name with the value to be visible at all the
+    /// authoritative name servers.
+    pub fn wait_for(&self, name: &str, value: &str) -> Result<()> {
+        let nameservers = if self.nameservers.is_empty() {
+            self.authoritative_nameservers(name)?
+        } else {
+            self.nameservers.clone()
+        };
+        let mut poller = Poller::new(&self.policy);
+        let mut pending = nameservers;
+        loop {
+            pending.retain(|ns| !self.is_visible(*ns, name, value));
+            if pending.is_empty() {
+                debug!("TXT {} is visible at all name servers", name);
+                return Ok(());
+            }
+            trace!("TXT {} not yet visible at: {:?}", name, pending);
+            let status = format!("not visible at {:?}", pending);
+            let delay = poller
+                .next_delay(None)
+                .ok_or_else(|| timeout_error(name, Some(&status), poller.elapsed()))?;
+            thread::sleep(delay);
+        }
+    }
+
+    /// Whether the name server answers with the value. Failed queries count as not.
+    fn is_visible(&self, nameserver: SocketAddr, name: &str, value: &str) -> bool {
+        let res = match query(nameserver, name, TYPE_TXT, false, self.query_timeout) {
+            Ok(res) => res,
+            Err(e) => {
+                debug!("TXT query of {} at {} failed: {}", name, nameserver, e);
+                return false;
+            }
+        };
+        res.answers
+            .iter()
+            .filter(|r| r.rtype == TYPE_TXT)
+            .any(|r| r.txt_value().map(|v| v == value).unwrap_or(false))
+    }
+
+    /// The addresses of the authoritative name servers of the zone of the name.
+    fn authoritative_nameservers(&self, name: &str) -> Result<Vec<SocketAddr>> {
+        let res = self.resolve(name, TYPE_SOA)?;
+        // the SOA of the zone is the answer, or in the authority section if the name
+        // isn't the zone itself.
+        let zone = res
+            .answers
+            .iter()
+            .chain(&res.authority)
+            .find(|r| r.rtype == TYPE_SOA)
+            .map(|r| r.name.clone())
+            .ok_or_else(|| format!("No zone found for: {}", name))?;
+
+        let mut nameservers = vec![];
+        let res = self.resolve(&zone, TYPE_NS)?;
+        for ns in res.answers.iter().filter(|r| r.rtype == TYPE_NS) {
+            let ns = ns.name_value()?;
+            for qtype in &[TYPE_A, TYPE_AAAA] {
+                let res = self.resolve(&ns, *qtype)?;
+                let ips = res.answers.iter().filter(|r| r.rtype == *qtype);
+                let addrs = ips.filter_map(|r| r.ip_value());
+                nameservers.extend(addrs.map(|ip: IpAddr| SocketAddr::new(ip, 53)));
+            }
+        }
+        if nameservers.is_empty() {
+            return Err(format!("No name servers found for the zone: {}", zone).into());
+        }
+        debug!("Name servers of {}: {:?}", zone, nameservers);
+        Ok(nameservers)
+    }
+
+    fn resolve(&self, name: &str, qtype: u16) -> Result<Message> {
+        resolve(&self.resolvers, name, qtype, self.query_timeout)
+    }
+}
+
+impl<D: DnsProvider> DnsProvider for PropagationCheck<D> {
+    fn present(&self, name: &str, value: &str) -> Result<()> {
+        self.provider.present(name, value)?;
+        let res = self.wait_for(name, value);
+        if res.is_err() {
+            return cleanup_after(res, self.provider.cleanup(name, value));
+        }
+        res
+    }
+
+    fn cleanup(&self, name: &str, value: &str) -> Result<()> {
+        self.provider.cleanup(name, value)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::solver::dns::{Record, CLASS_IN};
+    use crate::solver::Rfc2136;
+    use crate::test::dns::with_dns_server;
+    use crate::Error;
+    use std::sync::Arc;
+
+    const NAME: &str = "_acme-challenge.example.com";
+
+    #[test]
+    fn test_authoritative_nameservers() -> Result<()> {
+        let server = with_dns_server("example.com", None);
+        let check = PropagationCheck::new(Rfc2136::new(server.addr, "example.com")?)
+            .with_resolvers(&[server.addr]);
+        let ns: SocketAddr = "127.0.0.1:53".parse().unwrap();
+        assert_eq!(check.authoritative_nameservers(NAME)?, [ns]);
+        assert_eq!(check.authoritative_nameservers("example.com")?, [ns]);
+        assert!(check.authoritative_nameservers("example.org").is_err());
+
+        let silent = std::net::UdpSocket::bind("127.0.0.1:0")?;
+        let check = check
+            .with_resolvers(&[silent.local_addr()?, server.addr])
+            .with_query_timeout(Duration::from_millis(100));
+        assert_eq!(check.authoritative_nameservers(NAME)?, [ns]);
+        assert!(check.with_resolvers(&[]).wait_for(NAME, "x").is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_wait_for() -> Result<()> {
+        let primary = with_dns_server("example.com", None);
+        let secondary = Arc::new(with_dns_server("example.com", None));
+        let policy = PollPolicy::from_millis(10).with_deadline(Duration::from_secs(5));
+        let check = PropagationCheck::new(Rfc2136::new(primary.addr, "example.com")?)
+            .with_nameservers(&[primary.addr, secondary.addr])
+            .with_policy(policy);
+
+        // the secondary lags behind.
+        let lagging = secondary.clone();
+        let transfer = thread::spawn(move || {
+            thread::sleep(Duration::from_millis(100));
+            lagging.add(Record::txt(NAME, CLASS_IN, 60, "proof"));
+        });
+        check.present(NAME, "proof")?;
+        assert_eq!(secondary.txt(NAME), ["proof"]);
+        transfer.join().unwrap();
+        check.cleanup(NAME, "proof")?;
+        assert!(primary.txt(NAME).is_empty());
+
+        // never reaching the secondary, the record is removed again.
+        let policy = PollPolicy::from_millis(10).with_deadline(Duration::from_millis(100));
+        let check = check.with_policy(policy);
+        match check.present(NAME, "other") {
+            Err(Error::Timeout(_)) => {}
+            r => panic!("Expected timeout: {:?}", r),
+        }
+        assert!(primary.txt(NAME).is_empty());
+        Ok(())
+    }
+}
@@ -0,0 +1,213 @@
+//
+use std::net::{SocketAddr, ToSocketAddrs};
+use std::time::Duration;
+
+use crate::solver::dns::{exchange, in_zone, rcode_name, Message, Record};
+use crate::solver::dns::{CLASS_IN, CLASS_NONE};
+use crate::solver::{DnsProvider, TsigKey};
+use crate::Result;
+
+/// Publishes the `TXT` records of dns challenges with [RFC 2136] dynamic updates, like
+/// `nsupdate` does.
+///
+/// The updates are sent to the primary name server of the zone, signed with a TSIG key
+/// when there is one. The name server must allow the key to update `TXT` records under
+/// `_acme-challenge` in the zone, for instance with a BIND `update-policy` of:
+///
+/// ```text
+/// grant acme-update wildcard _acme-challenge.*.example.com. TXT;
+/// grant acme-update name _acme-challenge.example.com. TXT;
+/// ```
+///
+/// ```no_run
+/// use acme_lib::solver::{Rfc2136, TsigAlgorithm, TsigKey};
+///
+/// let key = TsigKey::from_base64("acme-update", TsigAlgorithm::HmacSha256, "c2VjcmV0")?;
+/// let provider = Rfc2136::new("ns1.example.com:53", "example.com")?.with_tsig(key);
+/// # Ok::<(), acme_lib::Error>(())
+/// ```
+///
+/// [RFC 2136]: https://tools.ietf.org/html/rfc2136
+#[derive(Debug, Clone)]
+pub struct Rfc2136 {
+    server: SocketAddr,
+    zone: String,
+    key: Option<TsigKey>,
+    ttl: u32,
+    timeout: Duration,
+}
+
+impl Rfc2136 {
+    /// Update the zone at the name server. The records are published with a TTL of
+    /// 60 seconds.
+    pub fn new<A: ToSocketAddrs>(server: A, zone: &str) -> Result<Self> {
+        let server = server
+            .to_socket_addrs()?
+            .next()
+            .ok_or("No address for the DNS server")?;
+        Ok(Rfc2136 {
+            server,
+            zone: zone.trim_end_matches('.').to_ascii_lowercase(),
+            key: None,
+            ttl: 60,
+            timeout: Duration::from_secs(10),
+        })
+    }
+
+    /// Sign the updates with the key.
+    pub fn with_tsig(mut self, key: TsigKey) -> Self {
+        self.key = Some(key);
+        self
+    }
+
+    /// TTL of the published records.
+    pub fn with_ttl(mut self, ttl: u32) -> Self {
+        self.ttl = ttl;
+        self
+    }
+
+    /// How long to wait for the name server to respond to an update.
+    pub fn with_timeout(mut self, timeout: Duration) -> Self {
+        self.timeout = timeout;
+        self
+    }
+
+    fn update(&self, name: &str, record: Record) -> Result<()> {
+        if !in_zone(name, &self.zone) {
+            return Err(format!("{} is not in the zone {}", name, self.zone).into());
+        }
+        let mut msg = Message::update(&self.zone);
+        msg.authority.push(record);
+        let mac = match &self.key {
+            Some(key) => Some(key.sign(&mut msg, None)?),
+            None => None,
+        };
+        let bytes = exchange(self.server, &msg.to_bytes()?, self.timeout)?;
+        let res = Message::from_bytes(&bytes)?;
+        if !res.is_response() || res.id != msg.id {
+            return Err(format!("Bad response to DNS update from {}", self.server).into());
+        }
+        let verified = match &self.key {
+            Some(key) => key.verify(&bytes, mac.as_deref()).map(|_| ()),
+            None => Ok(()),
+        };
+        if res.rcode() != 0 {
+            let mut rcode = rcode_name(res.rcode());
+            if let Err(e) = verified {
+                // such as a NOTAUTH for a bad signature.
+                rcode = format!("{} ({})", rcode, e);
+            }
+            return Err(format!("DNS update of {} failed: {}", name, rcode).into());
+        }
+        verified
+    }
+}
+
+impl DnsProvider for Rfc2136 {
+    fn present(&self, name: &str, value: &str) -> Result<()> {
+        debug!("Add TXT {} {:?}", name, value);
+        self.update(name, Record::txt(name, CLASS_IN, self.ttl, value))
+    }
+
+    fn cleanup(&self, name: &str, value: &str) -> Result<()> {
+        debug!("Delete TXT {} {:?}", name, value);
+        // class NONE and TTL 0 deletes the record with this value only.
+        self.update(name, Record::txt(name, CLASS_NONE, 0, value))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::solver::TsigAlgorithm;
+    use crate::test::dns::with_dns_server;
+
+    fn key(secret: &[u8]) -> TsigKey {
+        TsigKey::new("acme-update", TsigAlgorithm::HmacSha256, secret)
+    }
+
+    #[test]
+    fn test_update() -> Result<()> {
+        let server = with_dns_server("example.com", Some(key(b"secret")));
+        let provider = Rfc2136::new(server.addr, "Example.com.")?.with_tsig(key(b"secret"));
+        let name = "_acme-challenge.example.com";
+        provider.present(name, "one")?;
+        provider.present(name, "two")?;
+        assert_eq!(server.txt(name), ["one", "two"]);
+        provider.cleanup(name, "one")?;
+        assert_eq!(server.txt(name), ["two"]);
+
+        // not in the zone
+        assert!(provider
+            .present("_acme-challenge.example.org", "x")
+            .is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_update_refused() -> Result<()> {
+        let server = with_dns_server("example.com", Some(key(b"secret")));
+        let name = "_acme-challenge.example.com";
+        let wrong_key = Rfc2136::new(server.addr, "example.com")?.with_tsig(key(b"wrong"));
+        let err = wrong_key.present(name, "x").unwrap_err();
+        assert!(err.to_string().contains("NOTAUTH"), "{}", err);
+        let no_key = Rfc2136::new(server.addr, "example.com")?;
+        assert!(no_key.present(name, "x").is_err());
+        assert!(server.txt(name).is_empty());
+
+        // the name server isn't authoritative for the zone
+        let provider = Rfc2136::new(server.addr, "other.com")?.with_tsig(key(b"secret"));
+        let err = provider
+            .present("_acme-challenge.other.com", "x")
+            .unwrap_err();
+        assert!(err.to_string().contains("NOTZONE"), "{}", err);
+
+        // nobody answering
+        let silent = std::net::UdpSocket::bind("127.0.0.1:0")?;
+        let provider = Rfc2136::new(silent.local_addr()?, "example.com")?
+            .with_timeout(Duration::from_millis(100));
+        assert!(provider.present(name, "x").is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_validate_with_rfc2136() -> Result<()> {
+        use crate::PollPolicy;
+        use std::sync::{Arc, Mutex};
+
+        let server = Arc::new(with_dns_server("example.com", Some(key(b"secret"))));
+        let seen = Arc::new(Mutex::new(vec![]));
+        let ord = {
+            let server = server.clone();
+            let seen = seen.clone();
+            crate::test::memory_order("*.example.com", move |req| {
+                let body = match &req.url[..] {
+                    // like the ACME API, look up the record when asked to validate.
+                    "mem:/chall/1" => {
+                        *seen.lock().unwrap() = server.txt("_acme-challenge.example.com");
+                        r#"{"type": "dns-01", "url": "mem:/chall/1", "status": "processing",
+                        "token": "tok"}"#
+                    }
+                    "mem:/authz/1" => {
+                        r#"{"identifier": {"type": "dns", "value": "example.com"},
+                        "status": "valid", "wildcard": true, "challenges": [
+                        {"type": "dns-01", "url": "mem:/chall/1", "status": "pending",
+                        "token": "tok"}]}"#
+                    }
+                    _ => return None,
+                };
+                Some(body)
+            })?
+        };
+        let auth = &ord.authorizations()?[0];
+        let chall = auth.dns_challenge().ok_or("No dns challenge")?;
+        assert_eq!(chall.dns_name(), "_acme-challenge.example.com");
+        let proof = chall.dns_proof();
+
+        let provider = Rfc2136::new(server.addr, server.zone())?.with_tsig(key(b"secret"));
+        chall.validate_with_solver(&provider, &PollPolicy::from_millis(1))?;
+        assert_eq!(*seen.lock().unwrap(), [proof]);
+        assert!(server.txt("_acme-challenge.example.com").is_empty());
+        Ok(())
+    }
+}
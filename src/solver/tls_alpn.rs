@@ -0,0 +1,201 @@
+//
+use openssl::pkey::{PKey, Private};
+use openssl::ssl::{
+    select_next_proto, AlpnError, NameType, SniError, Ssl, SslContext, SslMethod, SslVersion,
+};
+use openssl::x509::X509;
+use std::collections::HashMap;
+use std::net::{SocketAddr, TcpListener, TcpStream, ToSocketAddrs};
+use std::sync::{Arc, Mutex};
+
+use crate::cert::create_tls_alpn_certificate;
+use crate::solver::{Server, TlsAlpnSolver};
+use crate::{Certificate, Result};
+
+/// The ALPN protocol of TLS ALPN challenges, in wire format.
+const ACME_TLS_ALPN: &[u8] = b"\x0aacme-tls/1";
+
+type Certs = Arc<Mutex<HashMap<String, (PKey<Private>, X509)>>>;
+
+/// Standalone TLS server answering TLS ALPN challenges.
+///
+/// The responder only completes handshakes that negotiate the `acme-tls/1` ALPN
+/// protocol, for a server name it has a validation certificate for, and closes the
+/// connection right after. Certificates are registered around the validation by
+/// [`validate_with_solver`], or by hand with [`add_certificate`].
+///
+/// The ACME API validates on port 443, so in production the responder must be bound
+/// to port 443, or `acme-tls/1` connections to port 443 must be forwarded to it.
+///
+/// Clones share the same server, which means one responder can serve concurrent
+/// orders. The server stops when the last clone is dropped.
+///
+/// ```no_run
+/// use acme_lib::solver::TlsAlpnResponder;
+///
+/// let responder = TlsAlpnResponder::bind("0.0.0.0:443")?;
+/// # Ok::<(), acme_lib::Error>(())
+/// ```
+///
+/// [`validate_with_solver`]: ../order/struct.Challenge.html#method.validate_with_solver
+/// [`add_certificate`]: struct.TlsAlpnResponder.html#method.add_certificate
+#[derive(Debug, Clone)]
+pub struct TlsAlpnResponder {
+    inner: Arc<Inner>,
+}
+
+#[derive(Debug)]
+struct Inner {
+    server: Server,
+    certs: Certs,
+}
+
+impl TlsAlpnResponder {
+    /// Bind the responder to the address and start serving in a background thread.
+    pub fn bind<A: ToSocketAddrs>(addr: A) -> Result<TlsAlpnResponder> {
+        let certs: Certs = Arc::new(Mutex::new(HashMap::new()));
+        let ctx =
+            ssl_context(&certs).map_err(|e| format!("Failed to create TLS ALPN context: {}", e))?;
+        let listener = TcpListener::bind(addr)?;
+        let server = Server::start(listener, "tls-alpn-responder", move |stream| {
+            respond(&ctx, stream);
+            Ok(())
+        })?;
+        Ok(TlsAlpnResponder {
+            inner: Arc::new(Inner { server, certs }),
+        })
+    }
+
+    /// The address the responder is bound to. Useful when bound to port 0.
+    pub fn local_addr(&self) -> SocketAddr {
+        self.inner.server.local_addr()
+    }
+
+    /// Serve the validation certificate for the domain. See
+    /// [`create_tls_alpn_certificate`].
+    ///
+    /// [`create_tls_alpn_certificate`]: ../fn.create_tls_alpn_certificate.html
+    pub fn add_certificate(&self, domain: &str, cert: &Certificate) -> Result<()> {
+        let pkey = PKey::private_key_from_pem(cert.private_key().as_bytes())
+            .map_err(|e| format!("Error reading private key PEM: {}", e))?;
+        let x509 = X509::from_pem(cert.certificate().as_bytes())
+            .map_err(|e| format!("Error reading certificate PEM: {}", e))?;
+        let mut certs = self.inner.certs.lock().unwrap();
+        certs.insert(domain.to_ascii_lowercase(), (pkey, x509));
+        Ok(())
+    }
+
+    /// Stop serving the validation certificate for the domain.
+    pub fn remove_certificate(&self, domain: &str) {
+        let mut certs = self.inner.certs.lock().unwrap();
+        certs.remove(&domain.to_ascii_lowercase());
+    }
+}
+
+impl TlsAlpnSolver for TlsAlpnResponder {
+    fn present(&self, domain: &str, proof: &[u8; 32]) -> Result<()> {
+        let cert = create_tls_alpn_certificate(domain, proof)?;
+        self.add_certificate(domain, &cert)
+    }
+
+    fn cleanup(&self, domain: &str) -> Result<()> {
+        self.remove_certificate(domain);
+        Ok(())
+    }
+}
+
+fn ssl_context(certs: &Certs) -> std::result::Result<SslContext, openssl::error::ErrorStack> {
+    let mut bld = SslContext::builder(SslMethod::tls_server())?;
+    // RFC 8737 requires TLS 1.2 or later.
+    bld.set_min_proto_version(Some(SslVersion::TLS1_2))?;
+    let certs = certs.clone();
+    bld.set_servername_callback(move |ssl, _alert| {
+        let name = ssl
+            .servername(NameType::HOST_NAME)
+            .map(|n| n.to_ascii_lowercase());
+        let certs = certs.lock().unwrap();
+        let (pkey, x509) = match name.as_ref().and_then(|n| certs.get(n)) {
+            Some(c) => c,
+            None => {
+                debug!("No TLS ALPN certificate for: {:?}", name);
+                return Err(SniError::ALERT_FATAL);
+            }
+        };
+        ssl.set_certificate(x509)
+            .and_then(|_| ssl.set_private_key(pkey))
+            .map_err(|_| SniError::ALERT_FATAL)
+    });
+    bld.set_alpn_select_callback(|_, client| {
+        select_next_proto(ACME_TLS_ALPN, client).ok_or(AlpnError::ALERT_FATAL)
+    });
+    Ok(bld.build())
+}
+
+fn respond(ctx: &SslContext, stream: TcpStream) {
+    let ssl = match Ssl::new(ctx) {
+        Ok(ssl) => ssl,
+        Err(e) => {
+            debug!("Failed to create TLS ALPN session: {}", e);
+            return;
+        }
+    };
+    // the handshake is the validation. The connection is closed after it.
+    match ssl.accept(stream) {
+        Ok(mut stream) => {
+            trace!("TLS ALPN handshake done");
+            let _ = stream.shutdown();
+        }
+        Err(e) => debug!("TLS ALPN handshake failed: {}", e),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use openssl::ssl::{SslConnector, SslVerifyMode};
+
+    /// Handshake like the ACME API, giving the certificate of the responder.
+    fn handshake(addr: SocketAddr, domain: &str, alpn: &[u8]) -> Option<X509> {
+        let mut bld = SslConnector::builder(SslMethod::tls_client()).unwrap();
+        bld.set_verify(SslVerifyMode::NONE);
+        bld.set_alpn_protos(alpn).unwrap();
+        let conn = bld.build();
+        let stream = TcpStream::connect(addr).unwrap();
+        let stream = conn
+            .configure()
+            .unwrap()
+            .verify_hostname(false)
+            .connect(domain, stream)
+            .ok()?;
+        assert_eq!(
+            stream.ssl().selected_alpn_protocol(),
+            Some(&b"acme-tls/1"[..])
+        );
+        stream.ssl().peer_certificate()
+    }
+
+    #[test]
+    fn test_tls_alpn_responder() -> Result<()> {
+        let responder = TlsAlpnResponder::bind("127.0.0.1:0")?;
+        let addr = responder.local_addr();
+        assert!(handshake(addr, "example.com", ACME_TLS_ALPN).is_none());
+
+        responder.clone().present("Example.com", &[1; 32])?;
+        let x509 = handshake(addr, "example.com", ACME_TLS_ALPN).unwrap();
+        let sans: Vec<_> = x509
+            .subject_alt_names()
+            .unwrap()
+            .iter()
+            .filter_map(|n| n.dnsname().map(|s| s.to_string()))
+            .collect();
+        assert_eq!(sans, ["Example.com"]);
+
+        // other server names and protocols don't get it.
+        assert!(handshake(addr, "other.com", ACME_TLS_ALPN).is_none());
+        assert!(handshake(addr, "example.com", b"\x08http/1.1").is_none());
+
+        responder.cleanup("example.com")?;
+        assert!(handshake(addr, "example.com", ACME_TLS_ALPN).is_none());
+        Ok(())
+    }
+}
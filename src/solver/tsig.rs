@@ -0,0 +1,308 @@
+//
+use openssl::hash::MessageDigest;
+use openssl::memcmp;
+use openssl::pkey::PKey;
+use openssl::sign::Signer;
+use std::fmt;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use crate::solver::dns::{get_u16, put_name, put_u16, read_name, Message, Record};
+use crate::solver::dns::{CLASS_ANY, TYPE_TSIG};
+use crate::Result;
+
+/// Seconds of clock difference allowed between us and the name server.
+const FUDGE: u16 = 300;
+
+/// HMAC algorithm of a TSIG key.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TsigAlgorithm {
+    /// `hmac-sha256`
+    HmacSha256,
+    /// `hmac-sha384`
+    HmacSha384,
+    /// `hmac-sha512`
+    HmacSha512,
+}
+
+impl TsigAlgorithm {
+    /// The algorithm name, such as `hmac-sha256`.
+    pub fn as_str(self) -> &'static str {
+        match self {
+            TsigAlgorithm::HmacSha256 => "hmac-sha256",
+            TsigAlgorithm::HmacSha384 => "hmac-sha384",
+            TsigAlgorithm::HmacSha512 => "hmac-sha512",
+        }
+    }
+
+    fn digest(self) -> MessageDigest {
+        match self {
+            TsigAlgorithm::HmacSha256 => MessageDigest::sha256(),
+            TsigAlgorithm::HmacSha384 => MessageDigest::sha384(),
+            TsigAlgorithm::HmacSha512 => MessageDigest::sha512(),
+        }
+    }
+}
+
+/// Key to sign DNS messages with ([RFC 8945] TSIG).
+///
+/// The key is shared with the name server, for instance from a BIND `key` statement:
+///
+/// ```text
+/// key "acme-update" {
+///     algorithm hmac-sha256;
+///     secret "c2VjcmV0IGtleSBmb3IgdGVzdGluZw==";
+/// };
+/// ```
+///
+/// ```
+/// use acme_lib::solver::{TsigAlgorithm, TsigKey};
+///
+/// let key = TsigKey::from_base64(
+///     "acme-update",
+///     TsigAlgorithm::HmacSha256,
+///     "c2VjcmV0IGtleSBmb3IgdGVzdGluZw==",
+/// )?;
+/// # Ok::<(), acme_lib::Error>(())
+/// ```
+///
+/// [RFC 8945]: https://tools.ietf.org/html/rfc8945
+#[derive(Clone)]
+pub struct TsigKey {
+    name: String,
+    algorithm: TsigAlgorithm,
+    secret: Vec<u8>,
+}
+
+impl TsigKey {
+    /// Key of the name, algorithm and secret.
+    pub fn new(name: &str, algorithm: TsigAlgorithm, secret: &[u8]) -> Self {
+        TsigKey {
+            name: name.trim_end_matches('.').to_ascii_lowercase(),
+            algorithm,
+            secret: secret.to_vec(),
+        }
+    }
+
+    /// Key with the secret in base64, like in the key files of BIND.
+    pub fn from_base64(name: &str, algorithm: TsigAlgorithm, secret: &str) -> Result<Self> {
+        let secret = base64::decode(secret.trim())
+            .map_err(|e| format!("Bad TSIG secret for {}: {}", name, e))?;
+        Ok(TsigKey::new(name, algorithm, &secret))
+    }
+
+    /// Name of the key.
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    /// Sign the message, adding the TSIG record. Gives the MAC to verify the response
+    /// with.
+    pub(crate) fn sign(&self, msg: &mut Message, request_mac: Option<&[u8]>) -> Result<Vec<u8>> {
+        self.sign_at(msg, request_mac, now())
+    }
+
+    fn sign_at(&self, msg: &mut Message, request_mac: Option<&[u8]>, time: u64) -> Result<Vec<u8>> {
+        let mut data = vec![];
+        if let Some(mac) = request_mac {
+            put_u16(&mut data, mac.len() as u16);
+            data.extend_from_slice(mac);
+        }
+        data.extend_from_slice(&msg.to_bytes()?);
+        // no error and no other data
+        let tail = [0_u8; 4];
+        data.extend_from_slice(&self.variables(time, FUDGE, &tail)?);
+        let mac = self.hmac(&data)?;
+
+        let mut rdata = vec![];
+        put_name(&mut rdata, self.algorithm.as_str())?;
+        rdata.extend_from_slice(&time.to_be_bytes()[2..]);
+        put_u16(&mut rdata, FUDGE);
+        put_u16(&mut rdata, mac.len() as u16);
+        rdata.extend_from_slice(&mac);
+        put_u16(&mut rdata, msg.id);
+        rdata.extend_from_slice(&tail);
+        msg.additional.push(Record {
+            name: self.name.clone(),
+            rtype: TYPE_TSIG,
+            class: CLASS_ANY,
+            ttl: 0,
+            rdata,
+        });
+        Ok(mac)
+    }
+
+    /// Verify the TSIG record ending the message, as parsed from the bytes. Gives the
+    /// MAC of the message.
+    pub(crate) fn verify(&self, bytes: &[u8], request_mac: Option<&[u8]>) -> Result<Vec<u8>> {
+        self.verify_at(bytes, request_mac, now())
+    }
+
+    fn verify_at(&self, bytes: &[u8], request_mac: Option<&[u8]>, time: u64) -> Result<Vec<u8>> {
+        let msg = Message::from_bytes(bytes)?;
+        let (offset, tsig) = match (msg.tsig_offset, msg.additional.last()) {
+            (Some(offset), Some(tsig)) if tsig.rtype == TYPE_TSIG => (offset, tsig),
+            _ => return Err("DNS response is not signed".into()),
+        };
+        if !tsig.name.eq_ignore_ascii_case(&self.name) {
+            return Err(format!("DNS response signed with other key: {}", tsig.name).into());
+        }
+        let rdata = &tsig.rdata;
+        let (algorithm, pos) = read_name(rdata, 0)?;
+        if !algorithm.eq_ignore_ascii_case(self.algorithm.as_str()) {
+            return Err(format!("DNS response signed with other algorithm: {}", algorithm).into());
+        }
+        let signed = rdata.get(pos..pos + 6).ok_or("Truncated TSIG record")?;
+        let signed = signed.iter().fold(0_u64, |t, b| t << 8 | *b as u64);
+        let fudge = get_u16(rdata, pos + 6)?;
+        let mac_len = get_u16(rdata, pos + 8)? as usize;
+        let mac = rdata
+            .get(pos + 10..pos + 10 + mac_len)
+            .ok_or("Truncated TSIG record")?;
+        let pos = pos + 10 + mac_len;
+        let original_id = get_u16(rdata, pos)?;
+        let error = get_u16(rdata, pos + 2)?;
+        let tail = &rdata[pos + 2..];
+        if error != 0 {
+            let error = match error {
+                16 => "BADSIG".into(),
+                17 => "BADKEY".into(),
+                18 => "BADTIME".into(),
+                e => format!("error {}", e),
+            };
+            return Err(format!("TSIG of DNS message rejected: {}", error).into());
+        }
+
+        // the message as it was before the TSIG record was added.
+        let mut data = vec![];
+        if let Some(mac) = request_mac {
+            put_u16(&mut data, mac.len() as u16);
+            data.extend_from_slice(mac);
+        }
+        let mut unsigned = bytes[..offset].to_vec();
+        unsigned[0..2].copy_from_slice(&original_id.to_be_bytes());
+        let arcount = get_u16(&unsigned, 10)? - 1;
+        unsigned[10..12].copy_from_slice(&arcount.to_be_bytes());
+        data.extend_from_slice(&unsigned);
+        data.extend_from_slice(&self.variables(signed, fudge, tail)?);
+        let expected = self.hmac(&data)?;
+        if mac.len() != expected.len() || !memcmp::eq(mac, &expected) {
+            return Err("Bad TSIG signature of DNS message".into());
+        }
+        if time.max(signed) - time.min(signed) > fudge as u64 {
+            return Err("TSIG time of DNS message out of range".into());
+        }
+        Ok(expected)
+    }
+
+    /// The TSIG variables, with the `tail` of error, other length and other data.
+    fn variables(&self, time: u64, fudge: u16, tail: &[u8]) -> Result<Vec<u8>> {
+        let mut buf = vec![];
+        put_name(&mut buf, &self.name)?;
+        put_u16(&mut buf, CLASS_ANY);
+        buf.extend_from_slice(&0_u32.to_be_bytes());
+        put_name(&mut buf, self.algorithm.as_str())?;
+        buf.extend_from_slice(&time.to_be_bytes()[2..]);
+        put_u16(&mut buf, fudge);
+        buf.extend_from_slice(tail);
+        Ok(buf)
+    }
+
+    fn hmac(&self, data: &[u8]) -> Result<Vec<u8>> {
+        let err = |e| format!("Failed to sign DNS message: {}", e);
+        let key = PKey::hmac(&self.secret).map_err(err)?;
+        let mut signer = Signer::new(self.algorithm.digest(), &key).map_err(err)?;
+        signer.update(data).map_err(err)?;
+        let mac = signer.sign_to_vec().map_err(err)?;
+        Ok(mac)
+    }
+}
+
+impl fmt::Debug for TsigKey {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        // never log the secret.
+        f.debug_struct("TsigKey")
+            .field("name", &self.name)
+            .field("algorithm", &self.algorithm)
+            .finish()
+    }
+}
+
+fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or(0)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::solver::dns::{CLASS_IN, OPCODE_UPDATE};
+
+    fn key() -> TsigKey {
+        TsigKey::new(
+            "Acme-Update.",
+            TsigAlgorithm::HmacSha256,
+            b"secret key for testing",
+        )
+    }
+
+    fn update() -> Message {
+        let mut msg = Message::update("example.com");
+        msg.id = 0x1234;
+        let name = "_acme-challenge.example.com";
+        msg.authority.push(Record::txt(name, CLASS_IN, 60, "proof"));
+        msg
+    }
+
+    #[test]
+    fn test_sign() {
+        let mut msg = update();
+        let mac = key().sign_at(&mut msg, None, 1_600_000_000).unwrap();
+        // computed with another implementation of RFC 8945
+        let expected = "62f751f56a5f0e4b00385df1875db040ce28f3f2b308f0258fdda02f632483dd";
+        let hex: String = mac.iter().map(|b| format!("{:02x}", b)).collect();
+        assert_eq!(hex, expected);
+        let tsig = msg.additional.last().unwrap();
+        assert_eq!(tsig.name, "acme-update");
+        assert_eq!(tsig.rtype, TYPE_TSIG);
+    }
+
+    #[test]
+    fn test_verify() {
+        let key = key();
+        let mut req = update();
+        let req_mac = key.sign(&mut req, None).unwrap();
+        let bytes = req.to_bytes().unwrap();
+        assert_eq!(key.verify(&bytes, None).unwrap(), req_mac);
+
+        // the response is signed including the MAC of the request
+        let mut res = update();
+        res.flags = 0x8000 | OPCODE_UPDATE << 11;
+        res.authority.clear();
+        key.sign(&mut res, Some(&req_mac)).unwrap();
+        let bytes = res.to_bytes().unwrap();
+        key.verify(&bytes, Some(&req_mac)).unwrap();
+        assert!(key.verify(&bytes, None).is_err());
+
+        // tampered, other secret, unsigned, too old
+        let mut tampered = bytes.clone();
+        tampered[3] |= 0x05;
+        assert!(key.verify(&tampered, Some(&req_mac)).is_err());
+        let other = TsigKey::new("acme-update", TsigAlgorithm::HmacSha256, b"other");
+        assert!(other.verify(&bytes, Some(&req_mac)).is_err());
+        assert!(key.verify(&update().to_bytes().unwrap(), None).is_err());
+        let mut old = update();
+        key.sign_at(&mut old, None, 1_600_000_000).unwrap();
+        assert!(key.verify(&old.to_bytes().unwrap(), None).is_err());
+    }
+
+    #[test]
+    fn test_from_base64() {
+        let alg = TsigAlgorithm::HmacSha512;
+        let key = TsigKey::from_base64("k", alg, "c2VjcmV0IGtleSBmb3IgdGVzdGluZw==").unwrap();
+        assert_eq!(key.secret, b"secret key for testing");
+        assert!(!format!("{:?}", key).contains("secret:"));
+        assert!(TsigKey::from_base64("k", alg, "not base64!").is_err());
+    }
+}
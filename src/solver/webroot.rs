@@ -0,0 +1,195 @@
+//
+use std::collections::HashMap;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+
+use crate::solver::HttpSolver;
+use crate::{Error, Result};
+
+/// Solves http challenges by writing the proof into the document root of a web server
+/// that is already running, such as nginx.
+///
+/// The proof is written to `<webroot>/.well-known/acme-challenge/<token>`, readable by
+/// everyone so the web server can serve it. The `.well-known/acme-challenge` directories
+/// are created if missing, but the webroot itself must exist. The file is removed again
+/// after the validation, also when it fails. See [`validate_with_solver`].
+///
+/// Orders for several domains might span virtual hosts with different document roots,
+/// which are set per domain with [`with_domain`].
+///
+/// ```no_run
+/// use acme_lib::solver::Webroot;
+///
+/// let webroot = Webroot::new("/var/www/html")
+///     .with_domain("api.example.com", "/srv/api/public");
+/// ```
+///
+/// [`validate_with_solver`]: ../order/struct.Challenge.html#method.validate_with_solver
+/// [`with_domain`]: struct.Webroot.html#method.with_domain
+#[derive(Debug, Clone, Default)]
+pub struct Webroot {
+    default: Option<PathBuf>,
+    domains: HashMap<String, PathBuf>,
+}
+
+impl Webroot {
+    /// Webroot used for all domains not set with [`with_domain`].
+    ///
+    /// [`with_domain`]: struct.Webroot.html#method.with_domain
+    pub fn new<P: AsRef<Path>>(webroot: P) -> Self {
+        Webroot {
+            default: Some(webroot.as_ref().into()),
+            domains: HashMap::new(),
+        }
+    }
+
+    /// No webroot for any domain, other than the ones set with [`with_domain`].
+    ///
+    /// [`with_domain`]: struct.Webroot.html#method.with_domain
+    pub fn per_domain() -> Self {
+        Webroot::default()
+    }
+
+    /// Use the webroot for the domain.
+    pub fn with_domain<P: AsRef<Path>>(mut self, domain: &str, webroot: P) -> Self {
+        self.domains
+            .insert(domain.to_ascii_lowercase(), webroot.as_ref().into());
+        self
+    }
+
+    /// The webroot for the domain, if there is one.
+    pub fn webroot(&self, domain: &str) -> Option<&Path> {
+        self.domains
+            .get(&domain.to_ascii_lowercase())
+            .or(self.default.as_ref())
+            .map(|p| p.as_path())
+    }
+
+    /// The webroot for the domain, or an error if there is none.
+    fn webroot_of(&self, domain: &str) -> Result<&Path> {
+        self.webroot(domain)
+            .ok_or_else(|| Error::Other(format!("No webroot for domain: {}", domain)))
+    }
+
+    /// The challenge file of the token for the domain.
+    fn file_name(&self, domain: &str, token: &str) -> Result<PathBuf> {
+        // the token ends up in a path, don't let it escape the webroot.
+        let is_base64url = |c: char| c.is_ascii_alphanumeric() || c == '-' || c == '_';
+        if token.is_empty() || !token.chars().all(is_base64url) {
+            return Err(format!("Bad challenge token: {}", token).into());
+        }
+        let webroot = self.webroot_of(domain)?;
+        Ok(webroot.join(".well-known/acme-challenge").join(token))
+    }
+}
+
+impl HttpSolver for Webroot {
+    fn present(&self, domain: &str, token: &str, proof: &str) -> Result<()> {
+        let f_name = self.file_name(domain, token)?;
+        let well_known = self.webroot_of(domain)?.join(".well-known");
+        create_dir(&well_known)?;
+        create_dir(&well_known.join("acme-challenge"))?;
+        // write to a temporary file and rename it in place, so the web server never
+        // serves a partially written proof.
+        let tmp_name = f_name.with_extension("tmp");
+        fs::write(&tmp_name, proof)?;
+        set_mode(&tmp_name, 0o644)?;
+        fs::rename(tmp_name, &f_name)?;
+        debug!("Wrote http challenge: {}", f_name.display());
+        Ok(())
+    }
+
+    fn cleanup(&self, domain: &str, token: &str) -> Result<()> {
+        let f_name = self.file_name(domain, token)?;
+        match fs::remove_file(&f_name) {
+            Ok(()) => Ok(()),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(()),
+            Err(e) => Err(e.into()),
+        }
+    }
+}
+
+/// Create the directory, readable by everyone, unless it exists already.
+fn create_dir(dir: &Path) -> Result<()> {
+    match fs::create_dir(dir) {
+        Ok(()) => set_mode(dir, 0o755),
+        Err(e) if e.kind() == io::ErrorKind::AlreadyExists && dir.is_dir() => Ok(()),
+        Err(e) => Err(e.into()),
+    }
+}
+
+#[cfg(unix)]
+fn set_mode(path: &Path, mode: u32) -> Result<()> {
+    use std::os::unix::fs::PermissionsExt;
+    fs::set_permissions(path, fs::Permissions::from_mode(mode))?;
+    Ok(())
+}
+
+#[cfg(not(unix))]
+fn set_mode(_path: &Path, _mode: u32) -> Result<()> {
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn temp_dir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("acme-lib-{}-{}", name, std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    #[test]
+    fn test_webroot() -> Result<()> {
+        let root = temp_dir("webroot");
+        let api = temp_dir("webroot-api");
+        let webroot = Webroot::new(&root).with_domain("API.example.com", &api);
+        assert_eq!(webroot.webroot("example.com"), Some(root.as_path()));
+        assert_eq!(webroot.webroot("api.example.com"), Some(api.as_path()));
+
+        webroot.present("example.com", "tok-1_A", "tok-1_A.proof")?;
+        webroot.present("api.example.com", "tok2", "tok2.proof")?;
+        let file = root.join(".well-known/acme-challenge/tok-1_A");
+        assert_eq!(fs::read_to_string(&file)?, "tok-1_A.proof");
+        let api_file = api.join(".well-known/acme-challenge/tok2");
+        assert_eq!(fs::read_to_string(&api_file)?, "tok2.proof");
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt;
+            let mode = |p: &Path| fs::metadata(p).unwrap().permissions().mode() & 0o777;
+            assert_eq!(mode(&file), 0o644);
+            assert_eq!(mode(file.parent().unwrap()), 0o755);
+        }
+
+        webroot.cleanup("example.com", "tok-1_A")?;
+        assert!(!file.exists());
+        // cleaning up twice is fine
+        webroot.cleanup("example.com", "tok-1_A")?;
+        webroot.cleanup("api.example.com", "tok2")?;
+        assert!(!api_file.exists());
+
+        fs::remove_dir_all(root)?;
+        fs::remove_dir_all(api)?;
+        Ok(())
+    }
+
+    #[test]
+    fn test_webroot_errors() {
+        let root = temp_dir("webroot-errors");
+        let webroot = Webroot::per_domain().with_domain("example.com", &root);
+        assert!(webroot.present("other.com", "tok", "proof").is_err());
+        assert!(webroot
+            .present("example.com", "../../tok", "proof")
+            .is_err());
+        assert!(webroot.present("example.com", "", "proof").is_err());
+        assert!(!root.join(".well-known").exists());
+        // the webroot itself isn't created
+        let missing = Webroot::new(root.join("missing"));
+        assert!(missing.present("example.com", "tok", "proof").is_err());
+        assert!(!root.join("missing").exists());
+        fs::remove_dir_all(root).unwrap();
+    }
+}
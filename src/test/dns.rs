@@ -0,0 +1,197 @@
+//! Authoritative name server for the tests of dns challenges.
+use std::net::{SocketAddr, UdpSocket};
+use std::sync::{Arc, Mutex};
+use std::thread;
+
+use crate::solver::dns::{in_zone, OPCODE_QUERY, OPCODE_UPDATE};
+use crate::solver::dns::{put_name, Message, Record, CLASS_ANY, CLASS_IN, CLASS_NONE};
+use crate::solver::dns::{TYPE_A, TYPE_CNAME, TYPE_NS, TYPE_SOA, TYPE_TXT};
+use crate::solver::TsigKey;
+
+const NOERROR: u16 = 0;
+const FORMERR: u16 = 1;
+const NXDOMAIN: u16 = 3;
+const NOTIMP: u16 = 4;
+const REFUSED: u16 = 5;
+const NOTAUTH: u16 = 9;
+const NOTZONE: u16 = 10;
+
+pub struct DnsServer {
+    pub addr: SocketAddr,
+    zone: String,
+    records: Arc<Mutex<Vec<Record>>>,
+}
+
+impl DnsServer {
+    /// Values of the TXT records of the name.
+    pub fn txt(&self, name: &str) -> Vec<String> {
+        let records = self.records.lock().unwrap();
+        records
+            .iter()
+            .filter(|r| r.rtype == TYPE_TXT && r.name.eq_ignore_ascii_case(name))
+            .map(|r| r.txt_value().unwrap())
+            .collect()
+    }
+
+    pub fn zone(&self) -> &str {
+        &self.zone
+    }
+
+    /// Add the record, like a zone transfer to a secondary would.
+    pub fn add(&self, record: Record) {
+        self.records.lock().unwrap().push(record);
+    }
+}
+
+/// Serve the zone, taking updates signed with the key. The zone has the name server
+/// `ns1.<zone>` at 127.0.0.1.
+pub fn with_dns_server(zone: &str, key: Option<TsigKey>) -> DnsServer {
+    let socket = UdpSocket::bind("127.0.0.1:0").unwrap();
+    let addr = socket.local_addr().unwrap();
+    let records = Arc::new(Mutex::new(apex(zone)));
+    {
+        let zone = zone.to_string();
+        let records = records.clone();
+        thread::spawn(move || {
+            let mut buf = [0_u8; 4096];
+            while let Ok((n, from)) = socket.recv_from(&mut buf) {
+                let res = respond(&buf[..n], &zone, key.as_ref(), &records);
+                socket.send_to(&res, from).unwrap();
+            }
+        });
+    }
+    DnsServer {
+        addr,
+        zone: zone.into(),
+        records,
+    }
+}
+
+fn respond(req: &[u8], zone: &str, key: Option<&TsigKey>, records: &Mutex<Vec<Record>>) -> Vec<u8> {
+    let msg = match Message::from_bytes(req) {
+        Ok(msg) => msg,
+        Err(_) => return response(&Message::default(), FORMERR).to_bytes().unwrap(),
+    };
+    if msg.flags >> 11 & 0xf == OPCODE_QUERY {
+        return query(&msg, zone, records).to_bytes().unwrap();
+    }
+    let mac = match key.map(|k| k.verify(req, None)) {
+        Some(Ok(mac)) => Some(mac),
+        Some(Err(_)) => return response(&msg, NOTAUTH).to_bytes().unwrap(),
+        None => None,
+    };
+    let rcode = match msg.flags >> 11 & 0xf {
+        OPCODE_UPDATE => update(&msg, zone, records),
+        _ => NOTIMP,
+    };
+    let mut res = response(&msg, rcode);
+    if let (Some(key), Some(mac)) = (key, mac) {
+        key.sign(&mut res, Some(&mac)).unwrap();
+    }
+    res.to_bytes().unwrap()
+}
+
+fn response(req: &Message, rcode: u16) -> Message {
+    Message {
+        id: req.id,
+        flags: 0x8000 | (req.flags & 0x7800) | rcode,
+        questions: req.questions.clone(),
+        ..Default::default()
+    }
+}
+
+/// Answer from the records, following CNAMEs within the zone.
+fn query(msg: &Message, zone: &str, records: &Mutex<Vec<Record>>) -> Message {
+    let question = match &msg.questions[..] {
+        [q] if in_zone(&q.name, zone) => q,
+        [_] => return response(msg, REFUSED),
+        _ => return response(msg, FORMERR),
+    };
+    let records = records.lock().unwrap();
+    let mut res = response(msg, NOERROR);
+    let mut name = question.name.clone();
+    loop {
+        let at_name: Vec<_> = records
+            .iter()
+            .filter(|r| r.name.eq_ignore_ascii_case(&name))
+            .collect();
+        let answers: Vec<_> = at_name
+            .iter()
+            .filter(|r| r.rtype == question.qtype)
+            .map(|r| (*r).clone())
+            .collect();
+        if !answers.is_empty() {
+            res.answers.extend(answers);
+            return res;
+        }
+        match at_name.iter().find(|r| r.rtype == TYPE_CNAME) {
+            // following a loop of CNAMEs only so far.
+            Some(cname) if res.answers.len() > 16 => {
+                res.answers.push((*cname).clone());
+                return res;
+            }
+            Some(cname) if cname.name_value().map(|n| in_zone(&n, zone)).unwrap() => {
+                res.answers.push((*cname).clone());
+                name = cname.name_value().unwrap();
+            }
+            Some(cname) => {
+                res.answers.push((*cname).clone());
+                return res;
+            }
+            None => {
+                if at_name.is_empty() && res.answers.is_empty() {
+                    res.flags |= NXDOMAIN;
+                }
+                let soa = records.iter().find(|r| r.rtype == TYPE_SOA).unwrap();
+                res.authority.push(soa.clone());
+                return res;
+            }
+        }
+    }
+}
+
+/// The SOA and NS records of the zone, with the address of the name server.
+fn apex(zone: &str) -> Vec<Record> {
+    let ns = format!("ns1.{}", zone);
+    let record = |name: &str, rtype, rdata| Record {
+        name: name.into(),
+        rtype,
+        class: CLASS_IN,
+        ttl: 300,
+        rdata,
+    };
+    let mut soa = vec![];
+    put_name(&mut soa, &ns).unwrap();
+    put_name(&mut soa, &format!("hostmaster.{}", zone)).unwrap();
+    for v in &[1_u32, 3600, 600, 86400, 60] {
+        soa.extend_from_slice(&v.to_be_bytes());
+    }
+    let mut ns_name = vec![];
+    put_name(&mut ns_name, &ns).unwrap();
+    vec![
+        record(zone, TYPE_SOA, soa),
+        record(zone, TYPE_NS, ns_name),
+        record(&ns, TYPE_A, vec![127, 0, 0, 1]),
+    ]
+}
+
+fn update(msg: &Message, zone: &str, records: &Mutex<Vec<Record>>) -> u16 {
+    if msg.questions.len() != 1 || !msg.questions[0].name.eq_ignore_ascii_case(zone) {
+        return NOTZONE;
+    }
+    let mut records = records.lock().unwrap();
+    for r in &msg.authority {
+        let same = |o: &Record| o.name.eq_ignore_ascii_case(&r.name) && o.rtype == r.rtype;
+        match r.class {
+            CLASS_IN => {
+                if !records.iter().any(|o| same(o) && o.rdata == r.rdata) {
+                    records.push(r.clone());
+                }
+            }
+            CLASS_NONE => records.retain(|o| !(same(o) && o.rdata == r.rdata)),
+            CLASS_ANY => records.retain(|o| !same(o)),
+            _ => return FORMERR,
+        }
+    }
+    NOERROR
+}
@@ -1,11 +1,26 @@
 #![allow(clippy::trivial_regex)]
 
-use futures::Future;
-use hyper::{service::service_fn_ok, Body, Method, Request, Response, Server};
+use futures::{Future, Stream};
+use hyper::{service::service_fn, Body, Method, Request, Response, Server};
 use lazy_static::lazy_static;
+use openssl::x509::X509Req;
+use std::collections::HashSet;
 use std::net::TcpListener;
+use std::sync::{Arc, Mutex};
 use std::thread;
 
+use crate::api::ApiJws;
+use crate::http::{HttpMethod, HttpRequest, HttpResponse, MemoryClient};
+use crate::jwt::{Jwk, JwkKeyChange};
+use crate::order::NewOrder;
+use crate::persist::MemoryPersist;
+use crate::testing::ca::Ca;
+use crate::testing::jws::Jws;
+use crate::{Directory, DirectoryUrl, Result};
+
+pub mod dns;
+mod malformed;
+
 lazy_static! {
     static ref RE_URL: regex::Regex = regex::Regex::new("<URL>").unwrap();
 }
@@ -21,6 +36,19 @@ impl Drop for TestServer {
     }
 }
 
+/// What the test server remembers between requests.
+#[derive(Default)]
+struct State {
+    /// Public key (jwk) of accounts created so far, see `key_of`.
+    accounts: Mutex<HashSet<String>>,
+    /// Whether the (one) account has agreed to the terms of service.
+    tos_agreed: Mutex<bool>,
+    /// Status of the (one) order, `None` until it's created.
+    order_status: Mutex<Option<&'static str>>,
+    /// PEM chain issued when finalizing the order.
+    certificate: Mutex<Option<String>>,
+}
+
 fn get_directory(url: &str) -> Response<Body> {
     const BODY: &str = r#"{
     "keyChange": "<URL>/acme/key-change",
@@ -29,6 +57,7 @@ fn get_directory(url: &str) -> Response<Body> {
     "newOrder": "<URL>/acme/new-order",
     "revokeCert": "<URL>/acme/revoke-cert",
     "meta": {
+        "termsOfService": "<URL>/terms",
         "caaIdentities": [
         "testdir.org"
         ]
@@ -37,6 +66,25 @@ fn get_directory(url: &str) -> Response<Body> {
     Response::new(Body::from(RE_URL.replace_all(BODY, url)))
 }
 
+/// The external account the `/directory/eab` server binds new accounts to, with
+/// the MAC key `c2VjcmV0` as base64url.
+const EAB_KEY_ID: &str = "kid-1";
+const EAB_HMAC_KEY: &[u8] = b"secret";
+
+fn get_directory_eab(url: &str) -> Response<Body> {
+    const BODY: &str = r#"{
+    "keyChange": "<URL>/acme/key-change",
+    "newAccount": "<URL>/acme/new-acct-eab",
+    "newNonce": "<URL>/acme/new-nonce",
+    "newOrder": "<URL>/acme/new-order",
+    "revokeCert": "<URL>/acme/revoke-cert",
+    "meta": {
+        "externalAccountRequired": true
+    }
+    }"#;
+    Response::new(Body::from(RE_URL.replace_all(BODY, url)))
+}
+
 fn head_new_nonce() -> Response<Body> {
     Response::builder()
         .status(204)
@@ -48,7 +96,26 @@ fn head_new_nonce() -> Response<Body> {
         .unwrap()
 }
 
-fn post_new_acct(url: &str) -> Response<Body> {
+fn post_new_acct(url: &str, body: &[u8], state: &State) -> Response<Body> {
+    let key = jws_protected(body).and_then(|p| key_of(&p["jwk"]));
+    let only_existing = jws_payload(body)
+        .and_then(|p| p["onlyReturnExisting"].as_bool())
+        .unwrap_or(false);
+    let created = {
+        let mut accounts = state.accounts.lock().unwrap();
+        let key = key.unwrap_or_default();
+        if only_existing && !accounts.contains(&key) {
+            return problem(
+                400,
+                "urn:ietf:params:acme:error:accountDoesNotExist",
+                "No account exists with the provided key",
+            );
+        }
+        accounts.insert(key)
+    };
+    if let Some(agreed) = jws_payload(body).and_then(|p| p["termsOfServiceAgreed"].as_bool()) {
+        *state.tos_agreed.lock().unwrap() = agreed;
+    }
     const BODY: &str = r#"{
     "id": 7728515,
     "key": {
@@ -68,40 +135,139 @@ fn post_new_acct(url: &str) -> Response<Body> {
     }"#;
     let location: String = RE_URL.replace_all("<URL>/acme/acct/7728515", url).into();
     Response::builder()
-        .status(201)
+        .status(if created { 201 } else { 200 })
         .header("Location", location)
         .body(Body::from(BODY))
         .unwrap()
 }
 
-fn post_new_order(url: &str) -> Response<Body> {
-    const BODY: &str = r#"{
-    "status": "pending",
-    "expires": "2019-01-09T08:26:43.570360537Z",
-    "identifiers": [
-        {
-        "type": "dns",
-        "value": "acmetest.example.com"
+/// New accounts must be bound to the external account, which the binding proves by
+/// a MAC of the account key.
+fn post_new_acct_eab(url: &str, body: &[u8], state: &State) -> Response<Body> {
+    let unauthorized = |detail: &str| {
+        problem(
+            403,
+            "urn:ietf:params:acme:error:unauthorized",
+            &format!("Bad external account binding: {}", detail),
+        )
+    };
+    // existing accounts are returned as is, without binding.
+    let key = jws_protected(body).and_then(|p| key_of(&p["jwk"]));
+    let exists = match key {
+        Some(k) => state.accounts.lock().unwrap().contains(&k),
+        None => false,
+    };
+    let only_existing = jws_payload(body)
+        .and_then(|p| p["onlyReturnExisting"].as_bool())
+        .unwrap_or(false);
+    if exists || only_existing {
+        return post_new_acct(url, body, state);
+    }
+    let (outer, inner) = match Jws::parse(body).and_then(|outer| {
+        let eab: serde_json::Value = outer.payload()?;
+        let eab: ApiJws = serde_json::from_value(eab["externalAccountBinding"].clone())?;
+        let inner = Jws::from_api(&eab)?;
+        Ok((outer, inner))
+    }) {
+        Ok(jws) => jws,
+        Err(e) => return unauthorized(&e.to_string()),
+    };
+    if inner.protected.kid.as_deref() != Some(EAB_KEY_ID) {
+        return unauthorized("unknown key id");
+    }
+    if inner.protected.url != outer.protected.url {
+        return unauthorized("wrong url");
+    }
+    let jwk = outer.protected.jwk.as_ref().map(serde_json::to_value);
+    if inner.payload::<serde_json::Value>().ok() != jwk.and_then(|j| j.ok()) {
+        return unauthorized("not the account key");
+    }
+    if !inner.verify_hmac(EAB_HMAC_KEY) {
+        return unauthorized("wrong MAC");
+    }
+    post_new_acct(url, body, state)
+}
+
+fn problem(status: u16, _type: &str, detail: &str) -> Response<Body> {
+    let body = serde_json::json!({
+        "type": _type,
+        "detail": detail,
+        "instance": "https://example.com/acme/docs/problem",
+        "status": status,
+    });
+    Response::builder()
+        .status(status)
+        .header("Content-Type", "application/problem+json")
+        .body(Body::from(body.to_string()))
+        .unwrap()
+}
+
+/// Decode a base64url encoded JSON part of a JWS request body.
+fn jws_part(body: &[u8], part: &str) -> Option<serde_json::Value> {
+    let jws: serde_json::Value = serde_json::from_slice(body).ok()?;
+    let encoded = jws[part].as_str()?;
+    let decoded = base64::decode_config(encoded, base64::URL_SAFE_NO_PAD).ok()?;
+    serde_json::from_slice(&decoded).ok()
+}
+
+fn jws_protected(body: &[u8]) -> Option<serde_json::Value> {
+    jws_part(body, "protected")
+}
+
+/// The decoded payload of a JWS request body. `None` for POST-as-GET.
+fn jws_payload(body: &[u8]) -> Option<serde_json::Value> {
+    jws_part(body, "payload")
+}
+
+fn post_acct(body: &[u8], state: &State) -> Response<Body> {
+    let mut acct = serde_json::json!({
+        "contact": [
+            "mailto:foo@bar.com"
+        ],
+        "status": "valid"
+    });
+    if let Some(payload) = jws_payload(body) {
+        if let Some(contact) = payload.get("contact") {
+            acct["contact"] = contact.clone();
         }
-    ],
-    "authorizations": [
-        "<URL>/acme/authz/YTqpYUthlVfwBncUufE8IRWLMSRqcSs"
-    ],
-    "finalize": "<URL>/acme/finalize/7738992/18234324"
-    }"#;
+        if let Some(status) = payload.get("status") {
+            acct["status"] = status.clone();
+        }
+        if let Some(agreed) = payload["termsOfServiceAgreed"].as_bool() {
+            *state.tos_agreed.lock().unwrap() = agreed;
+        }
+    }
+    acct["termsOfServiceAgreed"] = (*state.tos_agreed.lock().unwrap()).into();
+    Response::builder()
+        .status(200)
+        .body(Body::from(acct.to_string()))
+        .unwrap()
+}
+
+fn post_new_order(url: &str, state: &State) -> Response<Body> {
+    if !*state.tos_agreed.lock().unwrap() {
+        return problem(
+            403,
+            "urn:ietf:params:acme:error:userActionRequired",
+            "Terms of service have changed",
+        );
+    }
+    *state.order_status.lock().unwrap() = Some("pending");
+    *state.certificate.lock().unwrap() = None;
     let location: String = RE_URL
         .replace_all("<URL>/acme/order/YTqpYUthlVfwBncUufE8", url)
         .into();
     Response::builder()
         .status(201)
         .header("Location", location)
-        .body(Body::from(RE_URL.replace_all(BODY, url)))
+        .body(Body::from(order_body(url, "pending")))
         .unwrap()
 }
 
-fn post_get_order(url: &str) -> Response<Body> {
+/// The order in the status, with the certificate url once it's valid.
+fn order_body(url: &str, status: &str) -> String {
     const BODY: &str = r#"{
-    "status": "<STATUS>",
+    "status": "pending",
     "expires": "2019-01-09T08:26:43.570360537Z",
     "identifiers": [
         {
@@ -112,14 +278,36 @@ fn post_get_order(url: &str) -> Response<Body> {
     "authorizations": [
         "<URL>/acme/authz/YTqpYUthlVfwBncUufE8IRWLMSRqcSs"
     ],
-    "finalize": "<URL>/acme/finalize/7738992/18234324",
-    "certificate": "<URL>/acme/cert/fae41c070f967713109028"
+    "finalize": "<URL>/acme/finalize/7738992/18234324"
     }"#;
-    let b = RE_URL.replace_all(BODY, url).to_string();
-    Response::builder().status(200).body(Body::from(b)).unwrap()
+    let mut order: serde_json::Value =
+        serde_json::from_str(&RE_URL.replace_all(BODY, url)).unwrap();
+    order["status"] = status.into();
+    if status == "valid" {
+        let cert_url = RE_URL.replace_all("<URL>/acme/cert/fae41c070f967713109028", url);
+        order["certificate"] = cert_url.into();
+    }
+    order.to_string()
 }
 
-fn post_authz(url: &str) -> Response<Body> {
+/// The order, which is processing for one poll after being finalized.
+fn post_get_order(url: &str, state: &State) -> Response<Body> {
+    let mut order_status = state.order_status.lock().unwrap();
+    let status = match *order_status {
+        Some(status) => status,
+        None => return Response::builder().status(404).body(Body::empty()).unwrap(),
+    };
+    if status == "processing" {
+        *order_status = Some("valid");
+    }
+    Response::builder()
+        .status(200)
+        .body(Body::from(order_body(url, status)))
+        .unwrap()
+}
+
+/// The authorization, which is valid by its http challenge once that is validated.
+fn post_authz(url: &str, state: &State) -> Response<Body> {
     const BODY: &str = r#"{
         "identifier": {
             "type": "dns",
@@ -148,33 +336,156 @@ fn post_authz(url: &str) -> Response<Body> {
         }
         ]
     }"#;
+    let mut authz: serde_json::Value =
+        serde_json::from_str(&RE_URL.replace_all(BODY, url)).unwrap();
+    if *state.order_status.lock().unwrap() != Some("pending") {
+        authz["status"] = "valid".into();
+        authz["challenges"][0]["status"] = "valid".into();
+    }
     Response::builder()
         .status(201)
-        .body(Body::from(RE_URL.replace_all(BODY, url)))
+        .body(Body::from(authz.to_string()))
         .unwrap()
 }
 
-fn post_finalize(_url: &str) -> Response<Body> {
+/// Validating the http challenge makes the order ready.
+fn post_http_challenge(url: &str, state: &State) -> Response<Body> {
+    let mut order_status = state.order_status.lock().unwrap();
+    if *order_status == Some("pending") {
+        *order_status = Some("ready");
+    }
+    let chall = serde_json::json!({
+        "type": "http-01",
+        "status": "valid",
+        "url": RE_URL.replace_all(
+            "<URL>/acme/challenge/YTqpYUthlVfwBncUufE8IRWLMSRqcSs/216789597",
+            url
+        ),
+        "token": "MUi-gqeOJdRkSb_YR2eaMxQBqf6al8dgt_dOttSWb0w"
+    });
+    Response::builder()
+        .status(200)
+        .body(Body::from(chall.to_string()))
+        .unwrap()
+}
+
+/// The jwk as the accounts remember it.
+fn key_of(jwk: &serde_json::Value) -> Option<String> {
+    let jwk: Jwk = serde_json::from_value(jwk.clone()).ok()?;
+    serde_json::to_string(&jwk).ok()
+}
+
+/// Roll over the account key, if the inner JWS is signed by the new key and names
+/// the account and its current key, which signs the outer JWS.
+fn post_key_change(body: &[u8], state: &State) -> Response<Body> {
+    let malformed = |detail: &str| {
+        problem(
+            400,
+            "urn:ietf:params:acme:error:malformed",
+            &format!("Bad key change: {}", detail),
+        )
+    };
+    let (outer, inner) = match Jws::parse(body).and_then(|outer| {
+        let inner = Jws::from_api(&outer.payload()?)?;
+        Ok((outer, inner))
+    }) {
+        Ok(jws) => jws,
+        Err(e) => return malformed(&e.to_string()),
+    };
+    let key_change: JwkKeyChange = match inner.payload() {
+        Ok(key_change) => key_change,
+        Err(e) => return malformed(&e.to_string()),
+    };
+    let new_key = match &inner.protected.jwk {
+        Some(jwk) if inner.verify(jwk) => jwk,
+        _ => return malformed("inner JWS not signed by the new key"),
+    };
+    if inner.protected.url != outer.protected.url {
+        return malformed("inner JWS for another url");
+    }
+    if outer.protected.kid.as_deref() != Some(&key_change.account[..]) {
+        return malformed("not the account of the outer JWS");
+    }
+    if !outer.verify(&key_change.old_key) {
+        return malformed("outer JWS not signed by the old key");
+    }
+    let mut accounts = state.accounts.lock().unwrap();
+    let old_key = serde_json::to_string(&key_change.old_key).unwrap();
+    if !accounts.remove(&old_key) {
+        return malformed("old key is not an account key");
+    }
+    accounts.insert(serde_json::to_string(new_key).unwrap());
     Response::builder().status(200).body(Body::empty()).unwrap()
 }
 
-fn post_certificate(_url: &str) -> Response<Body> {
+/// Issue a certificate for the CSR, if the order is ready for one.
+fn post_finalize(url: &str, body: &[u8], state: &State) -> Response<Body> {
+    let mut order_status = state.order_status.lock().unwrap();
+    if *order_status != Some("ready") {
+        return problem(
+            403,
+            "urn:ietf:params:acme:error:orderNotReady",
+            "The order is not ready",
+        );
+    }
+    let csr = jws_payload(body)
+        .and_then(|p| p["csr"].as_str().map(|s| s.to_string()))
+        .and_then(|csr| base64::decode_config(csr, base64::URL_SAFE_NO_PAD).ok())
+        .and_then(|der| X509Req::from_der(&der).ok());
+    let csr = match csr {
+        Some(csr) => csr,
+        None => return problem(400, "urn:ietf:params:acme:error:badCSR", "Bad CSR"),
+    };
+    let ca = Ca::new().unwrap();
+    let names = ["acmetest.example.com".to_string()];
+    let cert = match ca.issue(&csr, &names, 90) {
+        Ok(cert) => cert,
+        Err(e) => return problem(400, "urn:ietf:params:acme:error:badCSR", &e.to_string()),
+    };
+    let mut chain = cert.to_pem().unwrap();
+    chain.extend(ca.intermediate().to_pem().unwrap());
+    *state.certificate.lock().unwrap() = Some(String::from_utf8(chain).unwrap());
+    *order_status = Some("processing");
     Response::builder()
         .status(200)
-        .body("CERT HERE".into())
+        .body(Body::from(order_body(url, "processing")))
         .unwrap()
 }
 
-fn route_request(req: Request<Body>, url: &str) -> Response<Body> {
-    match (req.method(), req.uri().path()) {
+fn post_certificate(state: &State) -> Response<Body> {
+    match &*state.certificate.lock().unwrap() {
+        Some(chain) => Response::builder()
+            .status(200)
+            .header("Content-Type", "application/pem-certificate-chain")
+            .body(Body::from(chain.clone()))
+            .unwrap(),
+        None => Response::builder().status(404).body(Body::empty()).unwrap(),
+    }
+}
+
+fn route_request(
+    method: &Method,
+    path: &str,
+    body: &[u8],
+    url: &str,
+    state: &State,
+) -> Response<Body> {
+    match (method, path) {
         (&Method::GET, "/directory") => get_directory(url),
+        (&Method::GET, "/directory/eab") => get_directory_eab(url),
         (&Method::HEAD, "/acme/new-nonce") => head_new_nonce(),
-        (&Method::POST, "/acme/new-acct") => post_new_acct(url),
-        (&Method::POST, "/acme/new-order") => post_new_order(url),
-        (&Method::POST, "/acme/order/YTqpYUthlVfwBncUufE8") => post_get_order(url),
-        (&Method::POST, "/acme/authz/YTqpYUthlVfwBncUufE8IRWLMSRqcSs") => post_authz(url),
-        (&Method::POST, "/acme/finalize/7738992/18234324") => post_finalize(url),
-        (&Method::POST, "/acme/cert/fae41c070f967713109028") => post_certificate(url),
+        (&Method::POST, "/acme/new-acct") => post_new_acct(url, body, state),
+        (&Method::POST, "/acme/new-acct-eab") => post_new_acct_eab(url, body, state),
+        (&Method::POST, "/acme/acct/7728515") => post_acct(body, state),
+        (&Method::POST, "/acme/new-order") => post_new_order(url, state),
+        (&Method::POST, "/acme/key-change") => post_key_change(body, state),
+        (&Method::POST, "/acme/order/YTqpYUthlVfwBncUufE8") => post_get_order(url, state),
+        (&Method::POST, "/acme/authz/YTqpYUthlVfwBncUufE8IRWLMSRqcSs") => post_authz(url, state),
+        (&Method::POST, "/acme/challenge/YTqpYUthlVfwBncUufE8IRWLMSRqcSs/216789597") => {
+            post_http_challenge(url, state)
+        }
+        (&Method::POST, "/acme/finalize/7738992/18234324") => post_finalize(url, body, state),
+        (&Method::POST, "/acme/cert/fae41c070f967713109028") => post_certificate(state),
         (_, _) => Response::builder().status(404).body(Body::empty()).unwrap(),
     }
 }
@@ -186,9 +497,20 @@ pub fn with_directory_server() -> TestServer {
     let url = format!("http://127.0.0.1:{}", port);
     let dir_url = format!("{}/directory", url);
 
+    let state = Arc::new(State::default());
+
     let make_service = move || {
         let url2 = url.clone();
-        service_fn_ok(move |req| route_request(req, &url2))
+        let state2 = state.clone();
+        service_fn(move |req: Request<Body>| {
+            let url3 = url2.clone();
+            let state3 = state2.clone();
+            let (parts, body) = req.into_parts();
+            body.concat2().map(move |body| {
+                let path = parts.uri.path();
+                route_request(&parts.method, path, &body, &url3, &state3)
+            })
+        })
     };
     let server = Server::from_tcp(tcp).unwrap().serve(make_service);
 
@@ -208,6 +530,58 @@ pub fn with_directory_server() -> TestServer {
     }
 }
 
+/// Client for an in-memory ACME API at `mem:/directory`, answering the directory,
+/// nonce and account requests. Anything else is answered by `f`, or a 404.
+pub fn memory_client<F>(f: F) -> MemoryClient
+where
+    F: Fn(&HttpRequest) -> Option<HttpResponse> + Send + Sync + 'static,
+{
+    MemoryClient::new(move |req| {
+        let res = match (req.method, &req.url[..]) {
+            (HttpMethod::Get, "mem:/directory") => HttpResponse::new(200).with_body(
+                r#"{"newAccount":"mem:/new-acct","newNonce":"mem:/new-nonce",
+                "newOrder":"mem:/new-order","revokeCert":"mem:/revoke-cert",
+                "keyChange":"mem:/key-change"}"#,
+            ),
+            (HttpMethod::Head, "mem:/new-nonce") => {
+                HttpResponse::new(200).with_header("Replay-Nonce", "nonce")
+            }
+            (HttpMethod::Post, "mem:/new-acct") => HttpResponse::new(201)
+                .with_header("Location", "mem:/acct/1")
+                .with_body(r#"{"status":"valid"}"#),
+            _ => f(req).unwrap_or_else(|| HttpResponse::new(404)),
+        };
+        Ok(res)
+    })
+}
+
+/// A new order for `domain` with the single authorization `mem:/authz/1`, from an
+/// in-memory ACME API. The JSON of the authorization and its challenges is answered
+/// by `f`, or a 404.
+pub fn memory_order<F>(domain: &str, f: F) -> Result<NewOrder<MemoryPersist>>
+where
+    F: Fn(&HttpRequest) -> Option<&'static str> + Send + Sync + 'static,
+{
+    let order = format!(
+        r#"{{"status": "pending", "finalize": "mem:/order/1/finalize",
+        "identifiers": [{{"type": "dns", "value": "{}"}}],
+        "authorizations": ["mem:/authz/1"]}}"#,
+        domain
+    );
+    let client = memory_client(move |req| {
+        let res = match &req.url[..] {
+            "mem:/new-order" => HttpResponse::new(201)
+                .with_header("Location", "mem:/order/1")
+                .with_body(order.as_str()),
+            _ => HttpResponse::new(200).with_body(f(req)?),
+        };
+        Some(res.with_header("Content-Type", "application/json"))
+    });
+    let url = DirectoryUrl::Other("mem:/directory");
+    let dir = Directory::from_url_with_client(MemoryPersist::new(), url, Arc::new(client))?;
+    dir.account("foo@bar.com")?.new_order(domain, &[])
+}
+
 #[test]
 pub fn test_make_directory() {
     let server = with_directory_server();
@@ -0,0 +1,246 @@
+//
+use openssl::asn1::Asn1Time;
+use openssl::bn::{BigNum, MsbOption};
+use openssl::error::ErrorStack;
+use openssl::hash::MessageDigest;
+use openssl::nid::Nid;
+use openssl::pkey::{self, PKey, PKeyRef};
+use openssl::x509::extension::{
+    AuthorityKeyIdentifier, BasicConstraints, ExtendedKeyUsage, KeyUsage, SubjectAlternativeName,
+    SubjectKeyIdentifier,
+};
+use openssl::x509::{X509Builder, X509NameBuilder, X509Ref, X509ReqRef, X509};
+
+use crate::cert::create_p256_key;
+use crate::Result;
+
+/// Throwaway certificate authority, with a root and an intermediate issuing the
+/// certificates.
+///
+/// The intermediate is cross-signed by an alternate root, for an alternate chain.
+pub(crate) struct Ca {
+    root: X509,
+    key: PKey<pkey::Private>,
+    intermediate: X509,
+    alternate_root: X509,
+    alternate_intermediate: X509,
+}
+
+impl Ca {
+    pub fn new() -> Result<Ca> {
+        let err = |e: ErrorStack| format!("Failed to create test CA: {}", e);
+        let new_root = |cn: &str| -> std::result::Result<_, ErrorStack> {
+            let key = create_p256_key();
+            let mut bld = builder(cn, &key, None, 3650)?;
+            ca_extensions(&mut bld, None)?;
+            bld.sign(&key, MessageDigest::sha256())?;
+            Ok((bld.build(), key))
+        };
+        let (root, root_key) = new_root("acme-lib test root").map_err(err)?;
+        let (alternate_root, alternate_root_key) =
+            new_root("acme-lib test alternate root").map_err(err)?;
+        let key = create_p256_key();
+        let intermediate = |root: &X509, root_key: &PKey<pkey::Private>| {
+            let cn = "acme-lib test intermediate";
+            let mut bld = builder(cn, &key, Some(root), 1825)?;
+            ca_extensions(&mut bld, Some(root))?;
+            bld.sign(root_key, MessageDigest::sha256())?;
+            Ok(bld.build())
+        };
+        let alternate_intermediate =
+            intermediate(&alternate_root, &alternate_root_key).map_err(err)?;
+        let intermediate = intermediate(&root, &root_key).map_err(err)?;
+        Ok(Ca {
+            root,
+            key,
+            intermediate,
+            alternate_root,
+            alternate_intermediate,
+        })
+    }
+
+    pub fn root(&self) -> &X509 {
+        &self.root
+    }
+
+    pub fn intermediate(&self) -> &X509 {
+        &self.intermediate
+    }
+
+    pub fn alternate_root(&self) -> &X509 {
+        &self.alternate_root
+    }
+
+    /// The intermediate, as issued by the alternate root.
+    pub fn alternate_intermediate(&self) -> &X509 {
+        &self.alternate_intermediate
+    }
+
+    /// Issue a certificate for the CSR, which must be for the names exactly.
+    pub fn issue(&self, csr: &X509ReqRef, names: &[String], days: u32) -> Result<X509> {
+        let bad_csr = |e: ErrorStack| format!("Bad CSR: {}", e);
+        let pubkey = csr.public_key().map_err(bad_csr)?;
+        if !csr.verify(&pubkey).map_err(bad_csr)? {
+            return Err("Bad CSR: signature doesn't match the key".into());
+        }
+        let mut requested = csr_names(csr, &pubkey).map_err(bad_csr)?;
+        requested.sort();
+        requested.dedup();
+        let mut expected: Vec<_> = names.iter().map(|n| n.to_ascii_lowercase()).collect();
+        expected.sort();
+        expected.dedup();
+        if requested != expected {
+            let msg = format!("Bad CSR: names {:?} are not the order's", requested);
+            return Err(msg.into());
+        }
+
+        let err = |e: ErrorStack| format!("Failed to issue certificate: {}", e);
+        // a common name can't be longer than 64 characters.
+        let cn = names.first().filter(|n| n.len() <= 64).map(|n| &n[..]);
+        let issuer = Some(&*self.intermediate);
+        let mut bld = builder(cn.unwrap_or(""), &pubkey, issuer, days).map_err(err)?;
+        let ctx = bld.x509v3_context(Some(&self.intermediate), None);
+        let mut san = SubjectAlternativeName::new();
+        for name in names {
+            san.dns(name);
+        }
+        let san = san.build(&ctx).map_err(err)?;
+        let ski = SubjectKeyIdentifier::new().build(&ctx).map_err(err)?;
+        let aki = AuthorityKeyIdentifier::new()
+            .keyid(true)
+            .build(&ctx)
+            .map_err(err)?;
+        let extensions = vec![
+            BasicConstraints::new().critical().build().map_err(err)?,
+            KeyUsage::new()
+                .critical()
+                .digital_signature()
+                .key_encipherment()
+                .build()
+                .map_err(err)?,
+            ExtendedKeyUsage::new().server_auth().build().map_err(err)?,
+            san,
+            ski,
+            aki,
+        ];
+        for ext in extensions {
+            bld.append_extension(ext).map_err(err)?;
+        }
+        bld.sign(&self.key, MessageDigest::sha256()).map_err(err)?;
+        Ok(bld.build())
+    }
+}
+
+/// Builder of a certificate for the key, issued by the issuer or self-signed.
+fn builder<T: pkey::HasPublic>(
+    cn: &str,
+    key: &PKeyRef<T>,
+    issuer: Option<&X509Ref>,
+    days: u32,
+) -> std::result::Result<X509Builder, ErrorStack> {
+    let mut name = X509NameBuilder::new()?;
+    if !cn.is_empty() {
+        name.append_entry_by_nid(Nid::COMMONNAME, cn)?;
+    }
+    let name = name.build();
+
+    let mut serial = BigNum::new()?;
+    serial.rand(128, MsbOption::MAYBE_ZERO, false)?;
+    let serial = serial.to_asn1_integer()?;
+
+    let mut bld = X509Builder::new()?;
+    bld.set_version(2)?;
+    bld.set_serial_number(&serial)?;
+    bld.set_subject_name(&name)?;
+    match issuer {
+        Some(issuer) => bld.set_issuer_name(issuer.subject_name())?,
+        None => bld.set_issuer_name(&name)?,
+    }
+    bld.set_pubkey(key)?;
+    let not_before = Asn1Time::days_from_now(0)?;
+    let not_after = Asn1Time::days_from_now(days)?;
+    bld.set_not_before(&not_before)?;
+    bld.set_not_after(&not_after)?;
+    Ok(bld)
+}
+
+fn ca_extensions(
+    bld: &mut X509Builder,
+    issuer: Option<&X509Ref>,
+) -> std::result::Result<(), ErrorStack> {
+    let mut constraints = BasicConstraints::new();
+    constraints.critical().ca();
+    if issuer.is_some() {
+        constraints.pathlen(0);
+    }
+    bld.append_extension(constraints.build()?)?;
+    let usage = KeyUsage::new()
+        .critical()
+        .key_cert_sign()
+        .crl_sign()
+        .build()?;
+    bld.append_extension(usage)?;
+    let ski = SubjectKeyIdentifier::new().build(&bld.x509v3_context(issuer, None))?;
+    bld.append_extension(ski)?;
+    if issuer.is_some() {
+        let aki = AuthorityKeyIdentifier::new()
+            .keyid(true)
+            .build(&bld.x509v3_context(issuer, None))?;
+        bld.append_extension(aki)?;
+    }
+    Ok(())
+}
+
+/// The names a CSR asks for, in its common name and SANs, lowercased.
+fn csr_names(
+    csr: &X509ReqRef,
+    pubkey: &PKeyRef<pkey::Public>,
+) -> std::result::Result<Vec<String>, ErrorStack> {
+    let mut names = vec![];
+    for entry in csr.subject_name().entries_by_nid(Nid::COMMONNAME) {
+        names.push(String::from_utf8_lossy(entry.data().as_slice()).into_owned());
+    }
+    // openssl reads the SANs of certificates only, so put them in a (never signed) one.
+    let mut bld = X509Builder::new()?;
+    bld.set_pubkey(pubkey)?;
+    if let Ok(extensions) = csr.extensions() {
+        for ext in extensions {
+            bld.append_extension(ext)?;
+        }
+    }
+    let cert = bld.build();
+    if let Some(sans) = cert.subject_alt_names() {
+        names.extend(
+            sans.iter()
+                .filter_map(|n| n.dnsname().map(|d| d.to_string())),
+        );
+    }
+    Ok(names.into_iter().map(|n| n.to_ascii_lowercase()).collect())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::cert::create_csr;
+
+    #[test]
+    fn test_issue() -> Result<()> {
+        let ca = Ca::new()?;
+        let csr = create_csr(&create_p256_key(), &["example.com", "www.example.com"])?;
+        let names = ["www.example.com".to_string(), "Example.com".to_string()];
+        let cert = ca.issue(&csr, &names, 90)?;
+        let intermediate = ca.intermediate().public_key().unwrap();
+        assert!(cert.verify(&intermediate).unwrap());
+        let root = ca.root().public_key().unwrap();
+        assert!(ca.intermediate().verify(&root).unwrap());
+        let root = ca.alternate_root().public_key().unwrap();
+        assert!(ca.alternate_intermediate().verify(&root).unwrap());
+        assert!(cert
+            .verify(&ca.alternate_intermediate().public_key().unwrap())
+            .unwrap());
+
+        let err = ca.issue(&csr, &names[..1], 90).unwrap_err();
+        assert!(err.to_string().starts_with("Bad CSR"), "{}", err);
+        Ok(())
+    }
+}
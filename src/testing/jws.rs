@@ -0,0 +1,156 @@
+//
+use openssl::bn::BigNum;
+use openssl::ecdsa::EcdsaSig;
+use openssl::error::ErrorStack;
+use openssl::hash::{hash, MessageDigest};
+use openssl::memcmp;
+use openssl::pkey::PKey;
+use openssl::sign::{Signer, Verifier};
+use serde::de::DeserializeOwned;
+use serde::Deserialize;
+
+use crate::api::ApiJws;
+use crate::jwt::Jwk;
+use crate::Result;
+
+/// The protected header of a JWS.
+#[derive(Debug, Deserialize)]
+pub(crate) struct Protected {
+    pub alg: String,
+    pub url: String,
+    pub nonce: Option<String>,
+    pub jwk: Option<Jwk>,
+    pub kid: Option<String>,
+}
+
+/// A JWS in the flattened JSON serialization, as the requests are.
+pub(crate) struct Jws {
+    pub protected: Protected,
+    /// The decoded payload, which is empty for POST-as-GET.
+    pub payload: Vec<u8>,
+    signing_input: String,
+    signature: Vec<u8>,
+}
+
+impl Jws {
+    pub fn parse(body: &[u8]) -> Result<Jws> {
+        let jws: ApiJws = serde_json::from_slice(body)?;
+        Jws::from_api(&jws)
+    }
+
+    pub fn from_api(jws: &ApiJws) -> Result<Jws> {
+        let decode = |s: &str| {
+            base64::decode_config(s, base64::URL_SAFE_NO_PAD).map_err(crate::Error::Base64Decode)
+        };
+        let protected = serde_json::from_slice(&decode(&jws.protected)?)?;
+        Ok(Jws {
+            protected,
+            payload: decode(&jws.payload)?,
+            signing_input: format!("{}.{}", jws.protected, jws.payload),
+            signature: decode(&jws.signature)?,
+        })
+    }
+
+    pub fn is_post_as_get(&self) -> bool {
+        self.payload.is_empty()
+    }
+
+    pub fn payload<T: DeserializeOwned>(&self) -> Result<T> {
+        Ok(serde_json::from_slice(&self.payload)?)
+    }
+
+    /// Whether the JWS is signed by the key.
+    pub fn verify(&self, jwk: &Jwk) -> bool {
+        if jwk.alg() != self.protected.alg {
+            return false;
+        }
+        let key = match jwk.public_key() {
+            Ok(key) => key,
+            Err(_) => return false,
+        };
+        let data = self.signing_input.as_bytes();
+        let sig = &self.signature[..];
+        let verified = || -> std::result::Result<bool, ErrorStack> {
+            match &self.protected.alg[..] {
+                "ES256" | "ES384" => {
+                    let (md, size) = if self.protected.alg == "ES256" {
+                        (MessageDigest::sha256(), 32)
+                    } else {
+                        (MessageDigest::sha384(), 48)
+                    };
+                    if sig.len() != 2 * size {
+                        return Ok(false);
+                    }
+                    let r = BigNum::from_slice(&sig[..size])?;
+                    let s = BigNum::from_slice(&sig[size..])?;
+                    let ecdsa = EcdsaSig::from_private_components(r, s)?;
+                    ecdsa.verify(&hash(md, data)?, &*key.ec_key()?)
+                }
+                "RS256" => {
+                    let mut verifier = Verifier::new(MessageDigest::sha256(), &key)?;
+                    verifier.update(data)?;
+                    verifier.verify(sig)
+                }
+                "EdDSA" => Verifier::new_without_digest(&key)?.verify_oneshot(sig, data),
+                _ => Ok(false),
+            }
+        };
+        verified().unwrap_or(false)
+    }
+
+    /// Whether the JWS is signed with the MAC key, as external account bindings are.
+    pub fn verify_hmac(&self, hmac_key: &[u8]) -> bool {
+        let md = match &self.protected.alg[..] {
+            "HS256" => MessageDigest::sha256(),
+            "HS384" => MessageDigest::sha384(),
+            "HS512" => MessageDigest::sha512(),
+            _ => return false,
+        };
+        let mac = || -> std::result::Result<Vec<u8>, ErrorStack> {
+            let key = PKey::hmac(hmac_key)?;
+            let mut signer = Signer::new(md, &key)?;
+            signer.update(self.signing_input.as_bytes())?;
+            signer.sign_to_vec()
+        };
+        match mac() {
+            Ok(mac) => mac.len() == self.signature.len() && memcmp::eq(&mac, &self.signature),
+            Err(_) => false,
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::acc::{AccountKeyAlgorithm, AcmeKey};
+    use crate::signer::AccountSigner;
+    use crate::trans::JwsSigner;
+    use std::sync::Arc;
+
+    #[test]
+    fn test_verify() -> Result<()> {
+        let algorithms = [
+            AccountKeyAlgorithm::ES256,
+            AccountKeyAlgorithm::ES384,
+            AccountKeyAlgorithm::RS256(2048),
+            AccountKeyAlgorithm::EdDSA,
+        ];
+        let other = AcmeKey::generate(AccountKeyAlgorithm::ES256)?.jwk();
+        for algorithm in &algorithms {
+            let key = Arc::new(AcmeKey::generate(*algorithm)?);
+            let jwk = key.jwk();
+            let signer = JwsSigner::new(key);
+            let body = signer.jwk_body("https://acme.test/x", "nonce".into(), &[1, 2, 3])?;
+            let jws = Jws::parse(body.as_bytes())?;
+            assert_eq!(jws.protected.nonce.as_deref(), Some("nonce"));
+            assert_eq!(jws.payload::<Vec<u8>>()?, [1, 2, 3]);
+            assert!(jws.verify(&jwk), "{:?}", algorithm);
+            assert!(!jws.verify(&other), "{:?}", algorithm);
+
+            let mut tampered = jws;
+            tampered.signing_input.push('x');
+            assert!(!tampered.verify(&jwk), "{:?}", algorithm);
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,1412 @@
+//! In-process ACME API to test against, enabled with the `test-server` cargo feature.
+//!
+//! [`AcmeServer`] is an [`HttpClient`] that answers the requests to an ACME API at
+//! `https://acme.test` itself, without touching the network. Like a real ACME API
+//! provider it keeps track of accounts, orders and authorizations, verifies the
+//! signature and nonce of every request, and issues real certificates from a throwaway
+//! certificate authority, with an alternate chain to another root. Faults, such as
+//! bad nonces, server errors and rate limits, can be [injected] to see how they are
+//! handled.
+//!
+//! Challenges are valid as soon as they are validated, unless the server is told where
+//! to validate them: http challenges against a port on `127.0.0.1`, dns challenges
+//! against a name server, and TLS ALPN challenges against another port on `127.0.0.1`.
+//! Together with the [solvers], this tests the whole flow offline.
+//!
+//! ```
+//! use acme_lib::persist::MemoryPersist;
+//! use acme_lib::testing::AcmeServer;
+//! use acme_lib::{create_p256_key, Directory, DirectoryUrl};
+//! use std::sync::Arc;
+//!
+//! let server = AcmeServer::new()?;
+//! let url = DirectoryUrl::Other(server.directory_url());
+//! let dir = Directory::from_url_with_client(MemoryPersist::new(), url, Arc::new(server.clone()))?;
+//! let acc = dir.account("foo@bar.com")?;
+//!
+//! let mut ord_new = acc.new_order("example.com", &[])?;
+//! let ord_csr = loop {
+//!     if let Some(ord_csr) = ord_new.confirm_validations() {
+//!         break ord_csr;
+//!     }
+//!     for auth in ord_new.authorizations()? {
+//!         auth.http_challenge().ok_or("No http challenge")?.validate(10)?;
+//!     }
+//!     ord_new.refresh()?;
+//! };
+//! let ord_cert = ord_csr.finalize_pkey(create_p256_key(), 10)?;
+//! let cert = ord_cert.download_and_save_cert()?;
+//! assert!(cert.certificate().starts_with("-----BEGIN CERTIFICATE-----"));
+//! # Ok::<(), acme_lib::Error>(())
+//! ```
+//!
+//! [`AcmeServer`]: struct.AcmeServer.html
+//! [`HttpClient`]: ../http/trait.HttpClient.html
+//! [injected]: struct.AcmeServer.html#method.inject
+//! [solvers]: ../solver/index.html
+use openssl::rand::rand_bytes;
+use openssl::x509::X509Req;
+use serde::{Deserialize, Serialize};
+use std::collections::{HashMap, HashSet};
+use std::net::SocketAddr;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use crate::api::{
+    ApiAccount, ApiAuth, ApiChallenge, ApiDirectory, ApiDirectoryMeta, ApiFinalize, ApiIdentifier,
+    ApiJws, ApiOrder, ApiProblem, ApiRevocation,
+};
+use crate::http::{HttpClient, HttpMethod, HttpRequest, HttpResponse};
+use crate::jwt::{Jwk, JwkKeyChange};
+use crate::util::base64url;
+use crate::{Certificate, Error, ProblemKind, Result};
+
+pub(crate) mod ca;
+pub(crate) mod jws;
+mod validate;
+
+use self::ca::Ca;
+use self::jws::Jws;
+use self::validate::Validator;
+
+/// Where the API pretends to be.
+const BASE_URL: &str = "https://acme.test";
+
+/// How long issued certificates are valid.
+const CERTIFICATE_DAYS: u32 = 90;
+
+/// The endpoints of the ACME API, to inject faults at.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Endpoint {
+    /// `GET` of the directory.
+    Directory,
+    /// The `newNonce` URL.
+    NewNonce,
+    /// The `newAccount` URL.
+    NewAccount,
+    /// The `newOrder` URL.
+    NewOrder,
+    /// The `revokeCert` URL.
+    RevokeCert,
+    /// The `keyChange` URL.
+    KeyChange,
+    /// Account URLs.
+    Account,
+    /// Order URLs.
+    Order,
+    /// The `finalize` URLs of orders.
+    Finalize,
+    /// Authorization URLs.
+    Authorization,
+    /// Challenge URLs.
+    Challenge,
+    /// Certificate URLs.
+    Certificate,
+    /// All of the above.
+    Any,
+}
+
+/// A failure to inject, see [`AcmeServer::inject`].
+///
+/// [`AcmeServer::inject`]: struct.AcmeServer.html#method.inject
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Fault {
+    /// No response at all, as when the connection fails.
+    Connection,
+    /// A `badNonce` problem, as when the API has forgotten the nonces it handed out.
+    BadNonce,
+    /// A `serverInternal` problem with status 500.
+    ServerError,
+    /// A `rateLimited` problem with status 429, and a `Retry-After` of the duration.
+    RateLimited(Duration),
+    /// A problem of the kind, with the status.
+    Problem(u16, ProblemKind),
+    /// Validating the challenge fails with `incorrectResponse`, whatever the proof.
+    /// Only injected at [`Endpoint::Challenge`].
+    ///
+    /// [`Endpoint::Challenge`]: enum.Endpoint.html#variant.Challenge
+    InvalidChallenge,
+}
+
+/// An ACME API answering requests in-process. See the [module] documentation.
+///
+/// Clones share the same state, which means a clone can be handed to the directory
+/// as its client while the original is kept to configure the server and inject faults.
+/// Requests are answered one at a time, including the validation of challenges.
+///
+/// [module]: index.html
+#[derive(Clone)]
+pub struct AcmeServer {
+    state: Arc<Mutex<State>>,
+}
+
+impl AcmeServer {
+    /// Start with a new certificate authority, no accounts, and challenges being valid
+    /// without validating them.
+    pub fn new() -> Result<AcmeServer> {
+        let state = State {
+            ca: Ca::new()?,
+            validator: Validator {
+                timeout: Duration::from_secs(5),
+                ..Default::default()
+            },
+            terms_of_service: None,
+            external_account: None,
+            processing_polls: 0,
+            faults: vec![],
+            nonces: HashSet::new(),
+            next_id: 1,
+            accounts: HashMap::new(),
+            orders: HashMap::new(),
+            authzs: HashMap::new(),
+            certs: HashMap::new(),
+        };
+        Ok(AcmeServer {
+            state: Arc::new(Mutex::new(state)),
+        })
+    }
+
+    /// The URL of the directory.
+    pub fn directory_url(&self) -> &str {
+        "https://acme.test/directory"
+    }
+
+    /// Require new accounts to agree to the terms of service at the URL.
+    pub fn with_terms_of_service(self, url: &str) -> Self {
+        self.set_terms_of_service(url);
+        self
+    }
+
+    /// Change the terms of service. Accounts must agree to the new terms before they can
+    /// order certificates again.
+    pub fn set_terms_of_service(&self, url: &str) {
+        self.state().terms_of_service = Some(url.into());
+    }
+
+    /// Require new accounts to be bound to the external account with the key id and the
+    /// (raw) MAC key.
+    pub fn with_external_account(self, key_id: &str, hmac_key: &[u8]) -> Self {
+        self.state().external_account = Some((key_id.into(), hmac_key.to_vec()));
+        self
+    }
+
+    /// Validate http challenges by fetching the proof from the port on `127.0.0.1`.
+    pub fn with_http_port(self, port: u16) -> Self {
+        self.state().validator.http_port = Some(port);
+        self
+    }
+
+    /// Validate TLS ALPN challenges with a handshake with the port on `127.0.0.1`.
+    pub fn with_tls_alpn_port(self, port: u16) -> Self {
+        self.state().validator.tls_alpn_port = Some(port);
+        self
+    }
+
+    /// Validate dns challenges by looking up the `TXT` record at the name server.
+    pub fn with_dns_resolver(self, resolver: SocketAddr) -> Self {
+        self.state().validator.dns_resolver = Some(resolver);
+        self
+    }
+
+    /// How many times authorizations and orders are polled as still pending or
+    /// processing, before the result of the validation or the finalization shows.
+    /// Defaults to none.
+    pub fn with_processing_polls(self, polls: u32) -> Self {
+        self.state().processing_polls = polls;
+        self
+    }
+
+    /// Fail the next request to the endpoint with the fault. Faults are used once, in
+    /// the order they are injected.
+    pub fn inject(&self, endpoint: Endpoint, fault: Fault) {
+        self.state().faults.push((endpoint, fault));
+    }
+
+    /// The PEM encoded root certificate, which issued certificates chain up to.
+    pub fn root_certificate_pem(&self) -> String {
+        let pem = self.state().ca.root().to_pem().expect("root PEM");
+        String::from_utf8_lossy(&pem).into_owned()
+    }
+
+    /// The PEM encoded root of the alternate chain of issued certificates, which is
+    /// offered with a `Link: rel="alternate"` header.
+    pub fn alternate_root_certificate_pem(&self) -> String {
+        let pem = self.state().ca.alternate_root().to_pem().expect("root PEM");
+        String::from_utf8_lossy(&pem).into_owned()
+    }
+
+    /// Whether the certificate has been revoked.
+    pub fn is_revoked(&self, certificate: &Certificate) -> bool {
+        let der = match certificate.certificate_der() {
+            Ok(der) => der,
+            Err(_) => return false,
+        };
+        let state = self.state();
+        state.certs.values().any(|c| c.der == der && c.revoked)
+    }
+
+    fn state(&self) -> std::sync::MutexGuard<'_, State> {
+        self.state.lock().unwrap()
+    }
+}
+
+impl std::fmt::Debug for AcmeServer {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        f.debug_struct("AcmeServer")
+            .field("url", &BASE_URL)
+            .finish()
+    }
+}
+
+impl HttpClient for AcmeServer {
+    fn request(&self, req: &HttpRequest) -> Result<HttpResponse> {
+        let call_error = |msg: &str| {
+            let msg = format!("{} {}: {}", req.method.as_str(), req.url, msg);
+            Err(Error::Call(msg))
+        };
+        let path = match req.url.strip_prefix(BASE_URL) {
+            Some(path) if path.starts_with('/') => path,
+            _ => return call_error("Unknown host"),
+        };
+        let route = Route::parse(path);
+        let mut state = self.state();
+        let fault = route.and_then(|r| state.take_fault(r.endpoint()));
+        trace!("{} {} {:?}", req.method.as_str(), req.url, fault);
+
+        let res = match fault {
+            Some(Fault::Connection) => return call_error("Connection reset"),
+            Some(Fault::BadNonce) => problem_response(&bad_nonce()),
+            Some(Fault::ServerError) => {
+                let detail = "The server experienced an internal error";
+                problem_response(&problem(ProblemKind::ServerInternal, 500, detail))
+            }
+            Some(Fault::RateLimited(retry_after)) => {
+                let detail = "Too many requests";
+                problem_response(&problem(ProblemKind::RateLimited, 429, detail))
+                    .with_header("Retry-After", &retry_after.as_secs().to_string())
+            }
+            Some(Fault::Problem(status, kind)) => {
+                problem_response(&problem(kind, status, "Injected problem"))
+            }
+            Some(Fault::InvalidChallenge) | None => {
+                let fail_validation = fault.is_some();
+                let reply = match route {
+                    Some(route) => state.handle(req, route, fail_validation),
+                    None => Err(problem(ProblemKind::Malformed, 404, "Not found")),
+                };
+                reply.unwrap_or_else(|p| problem_response(&p))
+            }
+        };
+        // every response carries a fresh nonce.
+        let nonce = state.new_nonce();
+        Ok(res.with_header("Replay-Nonce", &nonce))
+    }
+}
+
+/// The URLs of the API, parsed.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Route {
+    Directory,
+    NewNonce,
+    NewAccount,
+    NewOrder,
+    RevokeCert,
+    KeyChange,
+    Account(u64),
+    Order(u64),
+    Finalize(u64),
+    Authorization(u64),
+    Challenge(u64, usize),
+    Certificate(u64, usize),
+}
+
+impl Route {
+    fn parse(path: &str) -> Option<Route> {
+        let parts: Vec<_> = path[1..].split('/').collect();
+        let id = |i: usize| parts.get(i).and_then(|p| p.parse::<u64>().ok());
+        let route = match (parts[0], parts.len()) {
+            ("directory", 1) => Route::Directory,
+            ("new-nonce", 1) => Route::NewNonce,
+            ("new-account", 1) => Route::NewAccount,
+            ("new-order", 1) => Route::NewOrder,
+            ("revoke-cert", 1) => Route::RevokeCert,
+            ("key-change", 1) => Route::KeyChange,
+            ("account", 2) => Route::Account(id(1)?),
+            ("order", 2) => Route::Order(id(1)?),
+            ("order", 3) if parts[2] == "finalize" => Route::Finalize(id(1)?),
+            ("authz", 2) => Route::Authorization(id(1)?),
+            ("challenge", 3) => Route::Challenge(id(1)?, id(2)? as usize),
+            ("cert", 2) => Route::Certificate(id(1)?, 0),
+            ("cert", 3) => Route::Certificate(id(1)?, id(2)? as usize),
+            _ => return None,
+        };
+        Some(route)
+    }
+
+    fn endpoint(self) -> Endpoint {
+        match self {
+            Route::Directory => Endpoint::Directory,
+            Route::NewNonce => Endpoint::NewNonce,
+            Route::NewAccount => Endpoint::NewAccount,
+            Route::NewOrder => Endpoint::NewOrder,
+            Route::RevokeCert => Endpoint::RevokeCert,
+            Route::KeyChange => Endpoint::KeyChange,
+            Route::Account(_) => Endpoint::Account,
+            Route::Order(_) => Endpoint::Order,
+            Route::Finalize(_) => Endpoint::Finalize,
+            Route::Authorization(_) => Endpoint::Authorization,
+            Route::Challenge(_, _) => Endpoint::Challenge,
+            Route::Certificate(_, _) => Endpoint::Certificate,
+        }
+    }
+}
+
+/// The response to a request, or the problem to answer with.
+type Reply = std::result::Result<HttpResponse, ApiProblem>;
+
+struct State {
+    ca: Ca,
+    validator: Validator,
+    terms_of_service: Option<String>,
+    /// Key id and MAC key of the external account new accounts must be bound to.
+    external_account: Option<(String, Vec<u8>)>,
+    processing_polls: u32,
+    faults: Vec<(Endpoint, Fault)>,
+    nonces: HashSet<String>,
+    /// Accounts, orders, authorizations and certificates are numbered in one sequence.
+    next_id: u64,
+    accounts: HashMap<u64, AccountState>,
+    orders: HashMap<u64, OrderState>,
+    authzs: HashMap<u64, AuthzState>,
+    certs: HashMap<u64, CertState>,
+}
+
+struct AccountState {
+    jwk: Jwk,
+    contact: Vec<String>,
+    /// The terms of service agreed to, which is `""` when there were none.
+    agreed_terms: Option<String>,
+    deactivated: bool,
+}
+
+struct OrderState {
+    account: u64,
+    identifiers: Vec<ApiIdentifier>,
+    authzs: Vec<u64>,
+    expires: String,
+    /// `processing` or `valid` once finalized, else the status follows the authorizations.
+    status: Option<&'static str>,
+    polls_left: u32,
+    cert: Option<u64>,
+}
+
+struct AuthzState {
+    account: u64,
+    api_auth: ApiAuth,
+    /// The challenge validated and the problem it failed with, until the polls are done.
+    result: Option<(usize, Option<ApiProblem>)>,
+    polls_left: u32,
+}
+
+impl AuthzState {
+    /// Let the result of the validation show.
+    fn settle(&mut self) {
+        if let Some((i, error)) = self.result.take() {
+            let status = if error.is_none() { "valid" } else { "invalid" };
+            let challenge = &mut self.api_auth.challenges[i];
+            challenge.status = status.into();
+            if error.is_none() {
+                challenge.validated = Some(timestamp(0));
+            }
+            challenge.error = error;
+            self.api_auth.status = Some(status.into());
+        }
+    }
+}
+
+struct CertState {
+    account: u64,
+    der: Vec<u8>,
+    /// The PEM of the certificate followed by the intermediate, for the default and
+    /// the alternate chain.
+    chains: Vec<String>,
+    revoked: bool,
+}
+
+/// The contacts of an account update, if they are updated at all.
+#[derive(Deserialize)]
+struct ContactUpdate {
+    contact: Option<Vec<String>>,
+}
+
+/// The `newOrder` request.
+#[derive(Deserialize)]
+struct NewOrderRequest {
+    identifiers: Vec<ApiIdentifier>,
+}
+
+impl State {
+    fn take_fault(&mut self, endpoint: Endpoint) -> Option<Fault> {
+        let pos = self.faults.iter().position(|(e, f)| {
+            let at = *e == endpoint || *e == Endpoint::Any;
+            at && (*f != Fault::InvalidChallenge || endpoint == Endpoint::Challenge)
+        })?;
+        Some(self.faults.remove(pos).1)
+    }
+
+    fn new_nonce(&mut self) -> String {
+        let nonce = random(16);
+        self.nonces.insert(nonce.clone());
+        nonce
+    }
+
+    fn next_id(&mut self) -> u64 {
+        self.next_id += 1;
+        self.next_id - 1
+    }
+
+    fn handle(&mut self, req: &HttpRequest, route: Route, fail_validation: bool) -> Reply {
+        match (route, req.method) {
+            (Route::Directory, HttpMethod::Get) => json(200, &self.directory()),
+            (Route::NewNonce, HttpMethod::Head) => Ok(HttpResponse::new(200)),
+            (Route::NewNonce, HttpMethod::Get) => Ok(HttpResponse::new(204)),
+            (Route::Directory, _) | (Route::NewNonce, _) => Err(method_not_allowed()),
+            (_, HttpMethod::Post) => self.post(req, route, fail_validation),
+            _ => Err(method_not_allowed()),
+        }
+    }
+
+    fn directory(&self) -> ApiDirectory {
+        ApiDirectory {
+            newNonce: url("/new-nonce"),
+            newAccount: url("/new-account"),
+            newOrder: url("/new-order"),
+            newAuthz: None,
+            revokeCert: url("/revoke-cert"),
+            keyChange: url("/key-change"),
+            meta: Some(ApiDirectoryMeta {
+                termsOfService: self.terms_of_service.clone(),
+                externalAccountRequired: Some(self.external_account.is_some()),
+                ..Default::default()
+            }),
+        }
+    }
+
+    /// Check the JWS of the request, and answer it.
+    fn post(&mut self, req: &HttpRequest, route: Route, fail_validation: bool) -> Reply {
+        let jws = Jws::parse(&req.body).map_err(|e| malformed(format!("Bad JWS: {}", e)))?;
+        if jws.protected.url != req.url {
+            let detail = "The url of the JWS is not the request url";
+            return Err(problem(ProblemKind::Unauthorized, 401, detail));
+        }
+        let nonce = jws.protected.nonce.as_deref().unwrap_or_default();
+        if !self.nonces.remove(nonce) {
+            return Err(bad_nonce());
+        }
+
+        if route == Route::NewAccount {
+            let jwk = match (&jws.protected.jwk, &jws.protected.kid) {
+                (Some(jwk), None) => jwk.clone(),
+                _ => return Err(malformed("newAccount must be signed with a jwk")),
+            };
+            verify(&jws, &jwk)?;
+            return self.new_account(&jws, jwk);
+        }
+
+        let account = match (&jws.protected.jwk, &jws.protected.kid) {
+            (None, Some(kid)) => self.account_of(kid)?,
+            _ => return Err(malformed("Requests must be signed with the account kid")),
+        };
+        verify(&jws, &self.accounts[&account].jwk)?;
+        if self.accounts[&account].deactivated {
+            let detail = "The account is deactivated";
+            return Err(problem(ProblemKind::Unauthorized, 401, detail));
+        }
+
+        match route {
+            Route::Account(id) => self.update_account(account, id, &jws),
+            Route::NewOrder => self.new_order(account, &jws),
+            Route::Order(id) => self.poll_order(account, id),
+            Route::Finalize(id) => self.finalize(account, id, &jws),
+            Route::Authorization(id) => self.poll_authz(account, id),
+            Route::Challenge(id, i) => self.validate(account, id, i, fail_validation),
+            Route::Certificate(id, chain) => self.certificate(account, id, chain),
+            Route::KeyChange => self.key_change(account, &jws),
+            Route::RevokeCert => self.revoke(account, &jws),
+            Route::Directory | Route::NewNonce | Route::NewAccount => Err(method_not_allowed()),
+        }
+    }
+
+    /// The account of the key id, which is the account URL.
+    fn account_of(&self, kid: &str) -> std::result::Result<u64, ApiProblem> {
+        kid.strip_prefix(&url("/account/"))
+            .and_then(|id| id.parse().ok())
+            .filter(|id| self.accounts.contains_key(id))
+            .ok_or_else(|| {
+                let detail = format!("No account exists for: {}", kid);
+                problem(ProblemKind::AccountDoesNotExist, 400, detail)
+            })
+    }
+
+    fn new_account(&mut self, jws: &Jws, jwk: Jwk) -> Reply {
+        let req: ApiAccount = payload(jws)?;
+        let thumbprint = jwk.thumbprint();
+        let existing = self
+            .accounts
+            .iter()
+            .find(|(_, a)| a.jwk.thumbprint() == thumbprint)
+            .map(|(id, a)| (*id, a.deactivated));
+        match existing {
+            Some((_, true)) => {
+                let detail = "The account is deactivated";
+                return Err(problem(ProblemKind::Unauthorized, 401, detail));
+            }
+            Some((id, false)) => return self.account_response(id, 200),
+            None => {}
+        }
+        if req.onlyReturnExisting() {
+            let detail = "No account exists for the key";
+            return Err(problem(ProblemKind::AccountDoesNotExist, 400, detail));
+        }
+        if let Some((key_id, hmac_key)) = &self.external_account {
+            let eab = req.externalAccountBinding.as_ref().ok_or_else(|| {
+                let detail = "New accounts must be bound to an external account";
+                problem(ProblemKind::ExternalAccountRequired, 400, detail)
+            })?;
+            check_binding(eab, key_id, hmac_key, &jwk)?;
+        }
+        if self.terms_of_service.is_some() && !req.termsOfServiceAgreed() {
+            return Err(malformed("Must agree to the terms of service"));
+        }
+
+        let id = self.next_id();
+        let agreed_terms = self.terms_of_service.clone().unwrap_or_default();
+        let account = AccountState {
+            jwk,
+            agreed_terms: Some(agreed_terms).filter(|_| req.termsOfServiceAgreed()),
+            contact: req.contact,
+            deactivated: false,
+        };
+        self.accounts.insert(id, account);
+        self.account_response(id, 201)
+    }
+
+    fn account_response(&self, id: u64, status: u16) -> Reply {
+        let account_url = url(&format!("/account/{}", id));
+        json(status, &self.api_account(id)).map(|res| res.with_header("Location", &account_url))
+    }
+
+    fn api_account(&self, id: u64) -> ApiAccount {
+        let account = &self.accounts[&id];
+        let status = if account.deactivated {
+            "deactivated"
+        } else {
+            "valid"
+        };
+        ApiAccount {
+            status: Some(status.into()),
+            contact: account.contact.clone(),
+            termsOfServiceAgreed: Some(self.has_agreed(account)),
+            ..Default::default()
+        }
+    }
+
+    fn has_agreed(&self, account: &AccountState) -> bool {
+        match &self.terms_of_service {
+            Some(terms) => account.agreed_terms.as_ref() == Some(terms),
+            None => account.agreed_terms.is_some(),
+        }
+    }
+
+    fn update_account(&mut self, account: u64, id: u64, jws: &Jws) -> Reply {
+        if account != id {
+            let detail = "The account is not the signing account";
+            return Err(problem(ProblemKind::Unauthorized, 403, detail));
+        }
+        if !jws.is_post_as_get() {
+            let req: ApiAccount = payload(jws)?;
+            let terms = self.terms_of_service.clone().unwrap_or_default();
+            let account = self.accounts.get_mut(&id).unwrap();
+            if req.termsOfServiceAgreed() {
+                account.agreed_terms = Some(terms);
+            }
+            // an empty list removes the contacts, unlike a missing one.
+            if let Some(contact) = payload::<ContactUpdate>(jws)?.contact {
+                account.contact = contact;
+            }
+            match req.status.as_deref() {
+                None | Some("valid") => {}
+                Some("deactivated") => account.deactivated = true,
+                Some(status) => return Err(malformed(format!("Bad account status: {}", status))),
+            }
+        }
+        json(200, &self.api_account(id))
+    }
+
+    fn new_order(&mut self, account: u64, jws: &Jws) -> Reply {
+        if let Some(terms) = &self.terms_of_service {
+            if !self.has_agreed(&self.accounts[&account]) {
+                let mut problem = problem(
+                    ProblemKind::UserActionRequired,
+                    403,
+                    "The terms of service have changed",
+                );
+                problem.instance = Some(terms.clone());
+                return Err(problem);
+            }
+        }
+        let req: NewOrderRequest = payload(jws)?;
+        if req.identifiers.is_empty() {
+            return Err(malformed("The order has no identifiers"));
+        }
+        let mut identifiers: Vec<ApiIdentifier> = vec![];
+        for identifier in req.identifiers {
+            if !identifier.is_type_dns() {
+                let detail = format!("Identifier type not supported: {}", identifier._type);
+                return Err(problem(ProblemKind::UnsupportedIdentifier, 400, detail));
+            }
+            let value = identifier.value.to_ascii_lowercase();
+            if !is_dns_name(&value) {
+                let detail = format!("Not a valid DNS name: {}", identifier.value);
+                return Err(problem(ProblemKind::RejectedIdentifier, 400, detail));
+            }
+            if !identifiers.iter().any(|i| i.value == value) {
+                identifiers.push(ApiIdentifier {
+                    _type: "dns".into(),
+                    value,
+                });
+            }
+        }
+
+        let authzs = identifiers
+            .iter()
+            .map(|i| self.authz_for(account, &i.value))
+            .collect();
+        let id = self.next_id();
+        let order = OrderState {
+            account,
+            identifiers,
+            authzs,
+            expires: timestamp(7),
+            status: None,
+            polls_left: 0,
+            cert: None,
+        };
+        self.orders.insert(id, order);
+        let order_url = url(&format!("/order/{}", id));
+        json(201, &self.api_order(id)).map(|res| res.with_header("Location", &order_url))
+    }
+
+    /// A valid authorization of the account for the name, or a new one.
+    fn authz_for(&mut self, account: u64, name: &str) -> u64 {
+        let wildcard = name.starts_with("*.");
+        let domain = name.trim_start_matches("*.");
+        let valid = self.authzs.iter().find(|(_, a)| {
+            a.account == account
+                && a.api_auth.identifier.value == domain
+                && a.api_auth.wildcard() == wildcard
+                && a.api_auth.is_status_valid()
+        });
+        if let Some((id, _)) = valid {
+            return *id;
+        }
+
+        let id = self.next_id();
+        // wildcards can only be validated using dns.
+        let types: &[&str] = if wildcard {
+            &["dns-01"]
+        } else {
+            &["http-01", "dns-01", "tls-alpn-01"]
+        };
+        let challenges = types
+            .iter()
+            .enumerate()
+            .map(|(i, t)| ApiChallenge {
+                url: url(&format!("/challenge/{}/{}", id, i)),
+                _type: t.to_string(),
+                status: "pending".into(),
+                token: random(32),
+                validated: None,
+                error: None,
+            })
+            .collect();
+        let api_auth = ApiAuth {
+            identifier: ApiIdentifier {
+                _type: "dns".into(),
+                value: domain.into(),
+            },
+            status: Some("pending".into()),
+            expires: Some(timestamp(7)),
+            challenges,
+            wildcard: Some(wildcard).filter(|w| *w),
+        };
+        let authz = AuthzState {
+            account,
+            api_auth,
+            result: None,
+            polls_left: 0,
+        };
+        self.authzs.insert(id, authz);
+        id
+    }
+
+    fn api_order(&self, id: u64) -> ApiOrder {
+        let order = &self.orders[&id];
+        let authzs: Vec<_> = order
+            .authzs
+            .iter()
+            .map(|a| &self.authzs[a].api_auth)
+            .collect();
+        let status = match order.status {
+            Some(status) => status,
+            None if authzs.iter().any(|a| a.is_status_invalid()) => "invalid",
+            None if authzs.iter().all(|a| a.is_status_valid()) => "ready",
+            None => "pending",
+        };
+        let error = authzs
+            .iter()
+            .flat_map(|a| a.challenges.iter())
+            .find_map(|c| c.error.clone());
+        ApiOrder {
+            status: Some(status.into()),
+            expires: Some(order.expires.clone()),
+            identifiers: order.identifiers.clone(),
+            notBefore: None,
+            notAfter: None,
+            error,
+            authorizations: Some(
+                order
+                    .authzs
+                    .iter()
+                    .map(|a| url(&format!("/authz/{}", a)))
+                    .collect(),
+            ),
+            finalize: url(&format!("/order/{}/finalize", id)),
+            certificate: order
+                .cert
+                .filter(|_| status == "valid")
+                .map(|c| url(&format!("/cert/{}", c))),
+        }
+    }
+
+    fn poll_order(&mut self, account: u64, id: u64) -> Reply {
+        check_owner(self.orders.get(&id).map(|o| o.account), account, "order")?;
+        let order = self.orders.get_mut(&id).unwrap();
+        if order.status == Some("processing") {
+            if order.polls_left > 0 {
+                order.polls_left -= 1;
+            } else {
+                order.status = Some("valid");
+            }
+        }
+        json(200, &self.api_order(id))
+    }
+
+    fn poll_authz(&mut self, account: u64, id: u64) -> Reply {
+        check_owner(
+            self.authzs.get(&id).map(|a| a.account),
+            account,
+            "authorization",
+        )?;
+        let authz = self.authzs.get_mut(&id).unwrap();
+        if authz.polls_left > 0 {
+            authz.polls_left -= 1;
+        } else {
+            authz.settle();
+        }
+        json(200, &authz.api_auth)
+    }
+
+    fn validate(&mut self, account: u64, id: u64, i: usize, fail_validation: bool) -> Reply {
+        check_owner(
+            self.authzs.get(&id).map(|a| a.account),
+            account,
+            "authorization",
+        )?;
+        let authz = &self.authzs[&id];
+        let challenge = authz
+            .api_auth
+            .challenges
+            .get(i)
+            .ok_or_else(|| problem(ProblemKind::Malformed, 404, "No such challenge"))?;
+        // validation starts once.
+        if !authz.api_auth.is_status_pending() || !challenge.is_status_pending() {
+            return json(200, challenge);
+        }
+
+        let key_auth = format!(
+            "{}.{}",
+            challenge.token,
+            self.accounts[&account].jwk.thumbprint()
+        );
+        let mut domain = authz.api_auth.identifier.value.clone();
+        if authz.api_auth.wildcard() {
+            domain = format!("*.{}", domain);
+        }
+        let result = if fail_validation {
+            let detail = "The proof doesn't match the challenge";
+            Err(problem(ProblemKind::IncorrectResponse, 403, detail))
+        } else {
+            let validator = &self.validator;
+            validator.validate(&challenge._type, &domain, &challenge.token, &key_auth)
+        };
+        debug!("Validated {} for {}: {:?}", challenge._type, domain, result);
+
+        let polls = self.processing_polls;
+        let authz = self.authzs.get_mut(&id).unwrap();
+        authz.api_auth.challenges[i].status = "processing".into();
+        authz.result = Some((i, result.err()));
+        authz.polls_left = polls;
+        if polls == 0 {
+            authz.settle();
+        }
+        json(200, &authz.api_auth.challenges[i])
+    }
+
+    fn finalize(&mut self, account: u64, id: u64, jws: &Jws) -> Reply {
+        check_owner(self.orders.get(&id).map(|o| o.account), account, "order")?;
+        let status = self.api_order(id).status.unwrap_or_default();
+        if status != "ready" {
+            let detail = format!("The order is {}, not ready", status);
+            return Err(problem(ProblemKind::OrderNotReady, 403, detail));
+        }
+        let req: ApiFinalize = payload(jws)?;
+        let bad_csr = |detail: String| problem(ProblemKind::BadCsr, 400, detail);
+        let der = base64::decode_config(&req.csr, base64::URL_SAFE_NO_PAD)
+            .map_err(|e| bad_csr(format!("Bad CSR: {}", e)))?;
+        let csr = X509Req::from_der(&der).map_err(|e| bad_csr(format!("Bad CSR: {}", e)))?;
+        let names: Vec<_> = self.orders[&id]
+            .identifiers
+            .iter()
+            .map(|i| i.value.clone())
+            .collect();
+        let cert = self
+            .ca
+            .issue(&csr, &names, CERTIFICATE_DAYS)
+            .map_err(|e| bad_csr(e.to_string()))?;
+
+        let pem = |c: &openssl::x509::X509Ref| {
+            let pem = c.to_pem().expect("certificate PEM");
+            String::from_utf8_lossy(&pem).into_owned()
+        };
+        let cert_state = CertState {
+            account,
+            der: cert.to_der().expect("certificate DER"),
+            chains: vec![
+                format!("{}{}", pem(&cert), pem(self.ca.intermediate())),
+                format!("{}{}", pem(&cert), pem(self.ca.alternate_intermediate())),
+            ],
+            revoked: false,
+        };
+        let cert_id = self.next_id();
+        self.certs.insert(cert_id, cert_state);
+
+        let polls = self.processing_polls;
+        let order = self.orders.get_mut(&id).unwrap();
+        order.cert = Some(cert_id);
+        order.status = Some(if polls == 0 { "valid" } else { "processing" });
+        order.polls_left = polls;
+        json(200, &self.api_order(id))
+    }
+
+    fn certificate(&self, account: u64, id: u64, chain: usize) -> Reply {
+        check_owner(
+            self.certs.get(&id).map(|c| c.account),
+            account,
+            "certificate",
+        )?;
+        let chains = &self.certs[&id].chains;
+        let pem = chains
+            .get(chain)
+            .ok_or_else(|| problem(ProblemKind::Malformed, 404, "Unknown certificate chain"))?;
+        let mut res =
+            HttpResponse::new(200).with_header("Content-Type", "application/pem-certificate-chain");
+        // each chain links to the others.
+        for other in (0..chains.len()).filter(|c| *c != chain) {
+            let link = match other {
+                0 => url(&format!("/cert/{}", id)),
+                _ => url(&format!("/cert/{}/{}", id, other)),
+            };
+            res = res.with_header("Link", &format!("<{}>;rel=\"alternate\"", link));
+        }
+        Ok(res.with_body(pem.clone()))
+    }
+
+    fn key_change(&mut self, account: u64, jws: &Jws) -> Reply {
+        let inner: ApiJws = payload(jws)?;
+        let inner = Jws::from_api(&inner).map_err(|e| malformed(format!("Bad JWS: {}", e)))?;
+        let new_jwk = match (&inner.protected.jwk, &inner.protected.kid) {
+            (Some(jwk), None) => jwk.clone(),
+            _ => return Err(malformed("The inner JWS must be signed with a jwk")),
+        };
+        if inner.protected.url != url("/key-change") || inner.protected.nonce.is_some() {
+            return Err(malformed(
+                "The inner JWS must be for the keyChange url, without nonce",
+            ));
+        }
+        verify(&inner, &new_jwk)?;
+        let change: JwkKeyChange = payload(&inner)?;
+        if change.account != url(&format!("/account/{}", account)) {
+            let detail = "The key change is for another account";
+            return Err(problem(ProblemKind::Unauthorized, 403, detail));
+        }
+        if change.old_key != self.accounts[&account].jwk {
+            return Err(malformed("The oldKey is not the account key"));
+        }
+        let thumbprint = new_jwk.thumbprint();
+        if self
+            .accounts
+            .values()
+            .any(|a| a.jwk.thumbprint() == thumbprint)
+        {
+            let detail = "The new key is already in use by an account";
+            return Err(problem(ProblemKind::Malformed, 409, detail));
+        }
+        self.accounts.get_mut(&account).unwrap().jwk = new_jwk;
+        json(200, &self.api_account(account))
+    }
+
+    fn revoke(&mut self, account: u64, jws: &Jws) -> Reply {
+        let req: ApiRevocation = payload(jws)?;
+        let der = base64::decode_config(&req.certificate, base64::URL_SAFE_NO_PAD)
+            .map_err(|e| malformed(format!("Bad certificate: {}", e)))?;
+        let cert = self
+            .certs
+            .values_mut()
+            .find(|c| c.der == der)
+            .ok_or_else(|| problem(ProblemKind::Malformed, 404, "Unknown certificate"))?;
+        if cert.account != account {
+            let detail = "The certificate was issued to another account";
+            return Err(problem(ProblemKind::Unauthorized, 403, detail));
+        }
+        // 7 is not used.
+        if req.reason == 7 || req.reason > 10 {
+            let detail = format!("Bad revocation reason: {}", req.reason);
+            return Err(problem(ProblemKind::BadRevocationReason, 400, detail));
+        }
+        if cert.revoked {
+            let detail = "The certificate is already revoked";
+            return Err(problem(ProblemKind::AlreadyRevoked, 400, detail));
+        }
+        cert.revoked = true;
+        Ok(HttpResponse::new(200))
+    }
+}
+
+/// The inner JWS of an external account binding must be for the key, MAC'ed with
+/// the key of the external account.
+fn check_binding(
+    eab: &ApiJws,
+    key_id: &str,
+    hmac_key: &[u8],
+    jwk: &Jwk,
+) -> std::result::Result<(), ApiProblem> {
+    let eab = Jws::from_api(eab).map_err(|e| malformed(format!("Bad binding: {}", e)))?;
+    if eab.protected.kid.as_deref() != Some(key_id) {
+        let detail = "Unknown external account";
+        return Err(problem(ProblemKind::Unauthorized, 401, detail));
+    }
+    if eab.protected.url != url("/new-account") || eab.protected.nonce.is_some() {
+        return Err(malformed(
+            "The binding must be for the newAccount url, without nonce",
+        ));
+    }
+    if !eab.verify_hmac(hmac_key) {
+        let detail = "The binding isn't signed by the external account";
+        return Err(problem(ProblemKind::Unauthorized, 401, detail));
+    }
+    let bound: Jwk = payload(&eab)?;
+    if bound != *jwk {
+        return Err(malformed("The binding is for another key"));
+    }
+    Ok(())
+}
+
+fn verify(jws: &Jws, jwk: &Jwk) -> std::result::Result<(), ApiProblem> {
+    if jws.verify(jwk) {
+        Ok(())
+    } else {
+        Err(malformed("JWS verification error"))
+    }
+}
+
+fn check_owner(
+    owner: Option<u64>,
+    account: u64,
+    what: &str,
+) -> std::result::Result<(), ApiProblem> {
+    match owner {
+        None => {
+            let detail = format!("No such {}", what);
+            Err(problem(ProblemKind::Malformed, 404, detail))
+        }
+        Some(owner) if owner != account => {
+            let detail = format!("The {} belongs to another account", what);
+            Err(problem(ProblemKind::Unauthorized, 403, detail))
+        }
+        Some(_) => Ok(()),
+    }
+}
+
+/// Letters, digits and hyphens, with an optional wildcard label first.
+fn is_dns_name(name: &str) -> bool {
+    let name = name.strip_prefix("*.").unwrap_or(name);
+    let label_ok = |l: &str| {
+        !l.is_empty()
+            && l.len() <= 63
+            && !l.starts_with('-')
+            && !l.ends_with('-')
+            && l.bytes().all(|b| b.is_ascii_alphanumeric() || b == b'-')
+    };
+    name.len() <= 253 && name.split('.').all(label_ok)
+}
+
+fn payload<T: serde::de::DeserializeOwned>(jws: &Jws) -> std::result::Result<T, ApiProblem> {
+    jws.payload()
+        .map_err(|e| malformed(format!("Bad payload: {}", e)))
+}
+
+fn url(path: &str) -> String {
+    format!("{}{}", BASE_URL, path)
+}
+
+/// Random base64url string of `len` bytes.
+fn random(len: usize) -> String {
+    let mut buf = vec![0; len];
+    rand_bytes(&mut buf).expect("rand_bytes");
+    base64url(&buf)
+}
+
+/// The time in `days`, as RFC 3339.
+fn timestamp(days: i64) -> String {
+    let tm = time::now_utc() + time::Duration::days(days);
+    tm.rfc3339().to_string()
+}
+
+fn json<T: Serialize + ?Sized>(status: u16, body: &T) -> Reply {
+    Ok(HttpResponse::new(status)
+        .with_header("Content-Type", "application/json")
+        .with_body(serde_json::to_vec(body).expect("json")))
+}
+
+fn problem<S: Into<String>>(kind: ProblemKind, status: u16, detail: S) -> ApiProblem {
+    ApiProblem {
+        _type: kind.to_type(),
+        detail: Some(detail.into()),
+        status: Some(status),
+        ..Default::default()
+    }
+}
+
+fn malformed<S: Into<String>>(detail: S) -> ApiProblem {
+    problem(ProblemKind::Malformed, 400, detail)
+}
+
+fn bad_nonce() -> ApiProblem {
+    let detail = "JWS has an invalid anti-replay nonce";
+    problem(ProblemKind::BadNonce, 400, detail)
+}
+
+fn method_not_allowed() -> ApiProblem {
+    problem(ProblemKind::Malformed, 405, "Method not allowed")
+}
+
+fn problem_response(problem: &ApiProblem) -> HttpResponse {
+    HttpResponse::new(problem.status.unwrap_or(400))
+        .with_header("Content-Type", "application/problem+json")
+        .with_body(serde_json::to_vec(problem).expect("json"))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::persist::MemoryPersist;
+    use crate::solver::{HttpResponder, Rfc2136, TlsAlpnResponder};
+    use crate::test::dns::with_dns_server;
+    use crate::{Directory, DirectoryUrl, ExternalAccountBinding, PollPolicy, RevocationReason};
+    use openssl::x509::X509;
+
+    fn directory(server: &AcmeServer) -> Result<Directory<MemoryPersist>> {
+        let url = DirectoryUrl::Other(server.directory_url());
+        Directory::from_url_with_client(MemoryPersist::new(), url, Arc::new(server.clone()))
+    }
+
+    fn policy() -> PollPolicy {
+        PollPolicy::from_millis(1).with_max_delay(Duration::from_millis(10))
+    }
+
+    /// Issue a certificate for the names, validating the challenges with `validate`.
+    fn issue<F>(dir: &Directory<MemoryPersist>, names: &[&str], validate: F) -> Result<Certificate>
+    where
+        F: Fn(&crate::order::Auth<MemoryPersist>) -> Result<()>,
+    {
+        let acc = dir.account("foo@bar.com")?;
+        let mut ord_new = acc.new_order(names[0], &names[1..])?;
+        let ord_csr = loop {
+            if let Some(ord_csr) = ord_new.confirm_validations() {
+                break ord_csr;
+            }
+            for auth in ord_new.authorizations()? {
+                if auth.need_challenge() {
+                    validate(&auth)?;
+                }
+            }
+            ord_new.refresh()?;
+        };
+        let pkey = crate::create_p256_key();
+        let ord_cert = ord_csr.finalize_pkey_with_policy(pkey, &policy())?;
+        ord_cert.download_and_save_cert()
+    }
+
+    /// The names in the leaf, which must chain up to the root of the server.
+    fn check_chain(server: &AcmeServer, cert: &Certificate) -> Vec<String> {
+        let chain = X509::stack_from_pem(cert.certificate().as_bytes()).unwrap();
+        let root = X509::from_pem(server.root_certificate_pem().as_bytes()).unwrap();
+        assert_eq!(chain.len(), 2);
+        assert!(chain[0].verify(&chain[1].public_key().unwrap()).unwrap());
+        assert!(chain[1].verify(&root.public_key().unwrap()).unwrap());
+        chain[0]
+            .subject_alt_names()
+            .unwrap()
+            .iter()
+            .map(|n| n.dnsname().unwrap().to_string())
+            .collect()
+    }
+
+    #[test]
+    fn test_http_flow() -> Result<()> {
+        let responder = HttpResponder::bind("127.0.0.1:0")?;
+        let server = AcmeServer::new()?
+            .with_http_port(responder.local_addr().port())
+            .with_processing_polls(2);
+        let dir = directory(&server)?;
+        let names = ["example.com", "www.example.com"];
+        let cert = issue(&dir, &names, |auth| {
+            let challenge = auth.http_challenge().unwrap();
+            challenge.validate_with_solver(&responder, &policy())
+        })?;
+        assert_eq!(check_chain(&server, &cert), names);
+        assert_eq!(cert.valid_days_left()?, 89);
+
+        // the authorizations are reused.
+        let acc = dir.account("foo@bar.com")?;
+        let ord = acc.new_order("example.com", &[])?;
+        assert!(ord.is_validated());
+
+        // without the proof in place, the validation fails.
+        let ord = acc.new_order("other.example.com", &[])?;
+        let auths = ord.authorizations()?;
+        let err = auths[0]
+            .http_challenge()
+            .unwrap()
+            .validate_with_policy(&policy());
+        assert_eq!(
+            err.unwrap_err().problem_kind(),
+            Some(ProblemKind::Unauthorized)
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_dns_flow() -> Result<()> {
+        let dns = with_dns_server("example.com", None);
+        let server = AcmeServer::new()?.with_dns_resolver(dns.addr);
+        let dir = directory(&server)?;
+        let provider = Rfc2136::new(dns.addr, "example.com")?;
+        let names = ["*.example.com", "example.com"];
+        let cert = issue(&dir, &names, |auth| {
+            let challenge = auth.dns_challenge().unwrap();
+            challenge.validate_with_solver(&provider, &policy())
+        })?;
+        assert_eq!(check_chain(&server, &cert), names);
+        assert!(dns.txt("_acme-challenge.example.com").is_empty());
+
+        // a wildcard can only be validated using dns.
+        let acc = dir.account("foo@bar.com")?;
+        let ord = acc.new_order("*.example.net", &[])?;
+        let auths = ord.authorizations()?;
+        assert!(auths[0].api_auth().wildcard());
+        assert!(auths[0].http_challenge().is_none());
+        let err = auths[0]
+            .dns_challenge()
+            .unwrap()
+            .validate_with_policy(&policy());
+        assert_eq!(err.unwrap_err().problem_kind(), Some(ProblemKind::Dns));
+        Ok(())
+    }
+
+    #[test]
+    fn test_tls_alpn_flow() -> Result<()> {
+        let responder = TlsAlpnResponder::bind("127.0.0.1:0")?;
+        let server = AcmeServer::new()?.with_tls_alpn_port(responder.local_addr().port());
+        let dir = directory(&server)?;
+        let cert = issue(&dir, &["example.com"], |auth| {
+            let challenge = auth.tls_alpn_challenge().unwrap();
+            challenge.validate_with_solver(&responder, &policy())
+        })?;
+        assert_eq!(check_chain(&server, &cert), ["example.com"]);
+        Ok(())
+    }
+
+    #[test]
+    fn test_faults() -> Result<()> {
+        let server = AcmeServer::new()?;
+        let dir = directory(&server)?;
+        let acc = dir.account("foo@bar.com")?;
+
+        // retried
+        server.inject(Endpoint::NewOrder, Fault::BadNonce);
+        let mut ord = acc.new_order("example.com", &[])?;
+        server.inject(Endpoint::Order, Fault::ServerError);
+        server.inject(Endpoint::Order, Fault::Connection);
+        ord.refresh()?;
+
+        // not resent, since the server might have acted on them.
+        server.inject(Endpoint::NewOrder, Fault::ServerError);
+        let err = acc.new_order("example.com", &[]).err().unwrap();
+        assert_eq!(err.problem_kind(), Some(ProblemKind::ServerInternal));
+        server.inject(Endpoint::NewOrder, Fault::Connection);
+        assert!(acc.new_order("example.com", &[]).is_err());
+
+        let limit = Duration::from_secs(60);
+        server.inject(Endpoint::NewOrder, Fault::RateLimited(limit));
+        match acc.new_order("example.com", &[]) {
+            Err(Error::RateLimited { retry_at, .. }) => assert!(retry_at.is_some()),
+            x => panic!("Expected RateLimited: {:?}", x.err()),
+        }
+
+        let kind = ProblemKind::RejectedIdentifier;
+        server.inject(Endpoint::NewOrder, Fault::Problem(400, kind.clone()));
+        let err = acc.new_order("example.com", &[]).err().unwrap();
+        assert_eq!(err.problem_kind(), Some(kind));
+
+        // only at the challenge.
+        server.inject(Endpoint::Any, Fault::InvalidChallenge);
+        let ord = acc.new_order("example.com", &[])?;
+        let auths = ord.authorizations()?;
+        let err = auths[0]
+            .http_challenge()
+            .unwrap()
+            .validate(1)
+            .err()
+            .unwrap();
+        assert_eq!(err.problem_kind(), Some(ProblemKind::IncorrectResponse));
+        let mut ord = ord;
+        ord.refresh()?;
+        assert!(ord.api_order().is_status_invalid());
+        Ok(())
+    }
+
+    #[test]
+    fn test_nonces_and_signatures() -> Result<()> {
+        let server = AcmeServer::new()?;
+        let client = crate::http::MemoryClient::new({
+            let server = server.clone();
+            move |req| server.request(req)
+        });
+        let dir_url = DirectoryUrl::Other(server.directory_url());
+        let client = Arc::new(client);
+        let dir = Directory::from_url_with_client(MemoryPersist::new(), dir_url, client.clone())?;
+        dir.account("foo@bar.com")?;
+
+        // replayed
+        let req = client.requests().pop().unwrap();
+        assert_eq!(req.url, url("/new-account"));
+        let res = server.request(&req)?;
+        assert_eq!(res.status, 400);
+        assert!(res.body_str().contains("badNonce"));
+
+        // tampered, with a fresh nonce.
+        let res = server.request(&HttpRequest::head(&url("/new-nonce")))?;
+        let mut jws: ApiJws = serde_json::from_slice(&req.body)?;
+        let protected = base64::decode_config(&jws.protected, base64::URL_SAFE_NO_PAD).unwrap();
+        let mut protected: serde_json::Value = serde_json::from_slice(&protected)?;
+        protected["nonce"] = res.header("replay-nonce").unwrap().into();
+        jws.protected = base64url(protected.to_string().as_bytes());
+        let mut req = req;
+        req.body = serde_json::to_vec(&jws)?;
+        let res = server.request(&req)?;
+        assert_eq!(res.status, 400);
+        assert!(res.body_str().contains("JWS verification error"));
+
+        // elsewhere
+        assert!(server
+            .request(&HttpRequest::get("https://example.com/"))
+            .is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_external_account() -> Result<()> {
+        let server = AcmeServer::new()?.with_external_account("kid-1", b"secret");
+        let dir = directory(&server)?;
+        assert!(dir.external_account_required());
+
+        let eab = ExternalAccountBinding::from_raw_key("kid-1", b"wrong");
+        let err = dir
+            .account_with_eab("foo@bar.com", vec![], &eab)
+            .err()
+            .unwrap();
+        assert_eq!(err.problem_kind(), Some(ProblemKind::Unauthorized));
+
+        let eab = ExternalAccountBinding::from_raw_key("kid-1", b"secret");
+        dir.account_with_eab("foo@bar.com", vec![], &eab)?;
+        // existing accounts aren't bound again.
+        dir.account("foo@bar.com")?;
+        Ok(())
+    }
+
+    #[test]
+    fn test_terms_of_service() -> Result<()> {
+        let server = AcmeServer::new()?.with_terms_of_service("https://acme.test/terms/1");
+        let dir = directory(&server)?;
+        let mut acc = dir.account("foo@bar.com")?;
+        acc.new_order("example.com", &[])?;
+
+        server.set_terms_of_service("https://acme.test/terms/2");
+        match acc.new_order("example.com", &[]) {
+            Err(Error::UserActionRequired(p)) => {
+                assert_eq!(p.instance.as_deref(), Some("https://acme.test/terms/2"))
+            }
+            x => panic!("Expected UserActionRequired: {:?}", x.err()),
+        }
+        acc.agree_to_terms_of_service()?;
+        assert!(acc.api_account().termsOfServiceAgreed());
+        acc.new_order("example.com", &[])?;
+        Ok(())
+    }
+
+    #[test]
+    fn test_key_change_revoke_and_deactivate() -> Result<()> {
+        let server = AcmeServer::new()?;
+        let dir = directory(&server)?;
+        let cert = issue(&dir, &["example.com"], |auth| {
+            auth.http_challenge().unwrap().validate(1)
+        })?;
+
+        let mut acc = dir.account("foo@bar.com")?;
+        let old_pem = acc.acme_private_key_pem();
+        acc.change_key()?;
+        acc.new_order("example.com", &[])?;
+        // the old key has no account.
+        let old = dir.existing_account_from_pem("old", &old_pem);
+        assert_eq!(
+            old.err().unwrap().problem_kind(),
+            Some(ProblemKind::AccountDoesNotExist)
+        );
+
+        assert!(!server.is_revoked(&cert));
+        acc.revoke_certificate(&cert, RevocationReason::Superseded)?;
+        assert!(server.is_revoked(&cert));
+        let err = acc.revoke_certificate(&cert, RevocationReason::Superseded);
+        assert_eq!(
+            err.err().unwrap().problem_kind(),
+            Some(ProblemKind::AlreadyRevoked)
+        );
+
+        acc.deactivate()?;
+        let err = acc.new_order("example.com", &[]).err().unwrap();
+        assert_eq!(err.problem_kind(), Some(ProblemKind::Unauthorized));
+        Ok(())
+    }
+}
@@ -0,0 +1,181 @@
+//
+use openssl::sha::sha256;
+use openssl::ssl::{SslContext, SslMethod, SslVerifyMode, SslVersion};
+use std::io::{Read, Write};
+use std::net::{Ipv4Addr, SocketAddr, TcpStream};
+use std::time::Duration;
+
+use crate::api::ApiProblem;
+use crate::solver::dns::{rcode_name, resolve, TYPE_TXT};
+use crate::util::base64url;
+use crate::ProblemKind;
+
+use super::problem;
+
+/// The ALPN protocol of TLS ALPN challenges, in wire format.
+const ACME_TLS_ALPN: &[u8] = b"\x0aacme-tls/1";
+
+/// DER of the acmeIdentifier extension OID, 1.3.6.1.5.5.7.1.31.
+const ACME_IDENTIFIER_OID: &[u8] = &[0x06, 0x08, 0x2b, 0x06, 0x01, 0x05, 0x05, 0x07, 0x01, 0x1f];
+
+/// Where to validate challenges. Challenge types without a place are always valid.
+#[derive(Debug, Clone, Default)]
+pub(crate) struct Validator {
+    pub http_port: Option<u16>,
+    pub tls_alpn_port: Option<u16>,
+    pub dns_resolver: Option<SocketAddr>,
+    pub timeout: Duration,
+}
+
+impl Validator {
+    /// Validate the challenge of the type for the domain, with the key authorization.
+    pub fn validate(&self, _type: &str, domain: &str, token: &str, key_auth: &str) -> Reply {
+        match _type {
+            "http-01" => match self.http_port {
+                Some(port) => self.http(port, domain, token, key_auth),
+                None => Ok(()),
+            },
+            "dns-01" => match self.dns_resolver {
+                Some(resolver) => self.dns(resolver, domain, key_auth),
+                None => Ok(()),
+            },
+            "tls-alpn-01" => match self.tls_alpn_port {
+                Some(port) => self.tls_alpn(port, domain, key_auth),
+                None => Ok(()),
+            },
+            _ => Err(problem(
+                ProblemKind::Malformed,
+                400,
+                format!("Unknown challenge type: {}", _type),
+            )),
+        }
+    }
+
+    fn connect(&self, port: u16, domain: &str) -> Result<TcpStream, ApiProblem> {
+        let addr = SocketAddr::from((Ipv4Addr::LOCALHOST, port));
+        let connected = TcpStream::connect_timeout(&addr, self.timeout).and_then(|stream| {
+            stream.set_read_timeout(Some(self.timeout))?;
+            stream.set_write_timeout(Some(self.timeout))?;
+            Ok(stream)
+        });
+        connected.map_err(|e| {
+            let detail = format!("Connecting to {} at {}: {}", domain, addr, e);
+            problem(ProblemKind::Connection, 400, detail)
+        })
+    }
+
+    fn http(&self, port: u16, domain: &str, token: &str, key_auth: &str) -> Reply {
+        let mut stream = self.connect(port, domain)?;
+        let url = format!("http://{}/.well-known/acme-challenge/{}", domain, token);
+        let req = format!(
+            "GET /.well-known/acme-challenge/{} HTTP/1.1\r\nHost: {}\r\nConnection: close\r\n\r\n",
+            token, domain
+        );
+        let mut res = vec![];
+        stream
+            .write_all(req.as_bytes())
+            .and_then(|_| stream.read_to_end(&mut res))
+            .map_err(|e| {
+                let detail = format!("Fetching {}: {}", url, e);
+                problem(ProblemKind::Connection, 400, detail)
+            })?;
+        let res = String::from_utf8_lossy(&res);
+        let (head, body) = res.split_once("\r\n\r\n").unwrap_or((&res, ""));
+        let status = head.split_whitespace().nth(1).unwrap_or("");
+        if status != "200" {
+            let detail = format!("Invalid response from {}: {}", url, status);
+            return Err(problem(ProblemKind::Unauthorized, 403, detail));
+        }
+        if body.trim_end() != key_auth {
+            let detail = format!("The key authorization at {} doesn't match", url);
+            return Err(problem(ProblemKind::IncorrectResponse, 403, detail));
+        }
+        Ok(())
+    }
+
+    fn dns(&self, resolver: SocketAddr, domain: &str, key_auth: &str) -> Reply {
+        let name = format!("_acme-challenge.{}", domain.trim_start_matches("*."));
+        let dns_problem = |detail: String| problem(ProblemKind::Dns, 400, detail);
+        let res = resolve(&[resolver], &name, TYPE_TXT, self.timeout)
+            .map_err(|e| dns_problem(format!("Looking up TXT for {}: {}", name, e)))?;
+        if res.rcode() != 0 {
+            let rcode = rcode_name(res.rcode());
+            return Err(dns_problem(format!(
+                "{} looking up TXT for {}",
+                rcode, name
+            )));
+        }
+        let values: Vec<_> = res
+            .answers
+            .iter()
+            .filter(|r| r.rtype == TYPE_TXT)
+            .filter_map(|r| r.txt_value().ok())
+            .collect();
+        let expected = base64url(&sha256(key_auth.as_bytes()));
+        if !values.contains(&expected) {
+            let detail = match values.first() {
+                Some(found) => format!("Incorrect TXT record {:?} found at {}", found, name),
+                None => format!("No TXT record found at {}", name),
+            };
+            return Err(problem(ProblemKind::IncorrectResponse, 403, detail));
+        }
+        Ok(())
+    }
+
+    fn tls_alpn(&self, port: u16, domain: &str, key_auth: &str) -> Reply {
+        let stream = self.connect(port, domain)?;
+        let tls_problem = |e: String| {
+            let detail = format!("TLS ALPN handshake with {}: {}", domain, e);
+            problem(ProblemKind::Tls, 400, detail)
+        };
+        let ssl = (|| {
+            let mut bld = SslContext::builder(SslMethod::tls_client())?;
+            bld.set_min_proto_version(Some(SslVersion::TLS1_2))?;
+            bld.set_alpn_protos(ACME_TLS_ALPN)?;
+            // the validation certificate is self-signed.
+            bld.set_verify(SslVerifyMode::NONE);
+            let mut ssl = openssl::ssl::Ssl::new(&bld.build())?;
+            ssl.set_hostname(domain)?;
+            Ok(ssl)
+        })()
+        .map_err(|e: openssl::error::ErrorStack| tls_problem(e.to_string()))?;
+        let stream = ssl
+            .connect(stream)
+            .map_err(|e| tls_problem(e.to_string()))?;
+
+        let incorrect = |detail: &str| {
+            let detail = format!("{} of {}", detail, domain);
+            Err(problem(ProblemKind::IncorrectResponse, 403, detail))
+        };
+        if stream.ssl().selected_alpn_protocol() != Some(&ACME_TLS_ALPN[1..]) {
+            return incorrect("acme-tls/1 not negotiated in the TLS ALPN handshake");
+        }
+        let cert = match stream.ssl().peer_certificate() {
+            Some(cert) => cert,
+            None => return incorrect("No certificate in the TLS ALPN handshake"),
+        };
+        let names: Vec<_> = cert
+            .subject_alt_names()
+            .map(|sans| {
+                sans.iter()
+                    .filter_map(|n| n.dnsname().map(|d| d.to_ascii_lowercase()))
+                    .collect()
+            })
+            .unwrap_or_default();
+        if names != [domain.to_ascii_lowercase()] {
+            return incorrect("The SAN is not (only) the domain in the validation certificate");
+        }
+        // the extension is the OID, the critical flag, and the octet string with the
+        // octet string of the hash.
+        let der = cert.to_der().map_err(|e| tls_problem(e.to_string()))?;
+        let mut proof = vec![0x04, 0x20];
+        proof.extend_from_slice(&sha256(key_auth.as_bytes()));
+        let has = |needle: &[u8]| der.windows(needle.len()).any(|w| w == needle);
+        if !has(ACME_IDENTIFIER_OID) || !has(&proof) {
+            return incorrect("The acmeIdentifier doesn't match in the validation certificate");
+        }
+        Ok(())
+    }
+}
+
+type Reply = Result<(), ApiProblem>;
@@ -1,14 +1,17 @@
-use openssl::ecdsa::EcdsaSig;
-use openssl::sha::sha256;
 use serde::Serialize;
 use std::collections::VecDeque;
-use std::sync::{Arc, Mutex};
+use std::sync::{Arc, Mutex, RwLock};
+use std::thread;
+use std::time::{Duration, SystemTime};
 
-use crate::acc::AcmeKey;
+use crate::api::{ApiJws, ApiProblem};
+use crate::http::{HttpClient, HttpRequest, HttpResponse};
 use crate::jwt::*;
-use crate::req::{req_expect_header, req_handle_error, req_head, req_post};
+use crate::req::{req_expect_header, req_handle_error};
+use crate::retry::{Attempt, Failed, Retries};
+use crate::signer::AccountSigner;
 use crate::util::base64url;
-use crate::Result;
+use crate::{Error, Result, RetryPolicy};
 
 /// JWS payload and nonce handling for requests to the API.
 ///
@@ -18,87 +21,240 @@ use crate::Result;
 /// 2. `call_jwk()` against newAccount url
 /// 3. `set_key_id` from the returned `Location` header.
 /// 4. `call()` for all calls after that.
-#[derive(Clone, Debug)]
+#[derive(Debug)]
 pub(crate) struct Transport {
-    acme_key: AcmeKey,
+    client: Arc<dyn HttpClient>,
     nonce_pool: Arc<NoncePool>,
+    jws: JwsSigner,
+    retry_policy: RetryPolicy,
 }
 
 impl Transport {
-    pub fn new(nonce_pool: &Arc<NoncePool>, acme_key: AcmeKey) -> Self {
+    pub fn new(
+        client: &Arc<dyn HttpClient>,
+        nonce_pool: &Arc<NoncePool>,
+        signer: Arc<dyn AccountSigner>,
+        retry_policy: &RetryPolicy,
+    ) -> Self {
         Transport {
-            acme_key,
+            client: client.clone(),
             nonce_pool: nonce_pool.clone(),
+            jws: JwsSigner::new(signer),
+            retry_policy: retry_policy.clone(),
         }
     }
 
     /// Update the key id once it is known (part of setting up the transport).
     pub fn set_key_id(&mut self, kid: String) {
-        self.acme_key.set_key_id(kid);
+        self.jws.set_key_id(kid);
     }
 
-    /// The key used in the transport
-    pub fn acme_key(&self) -> &AcmeKey {
-        &self.acme_key
+    /// The key id, which is the account url.
+    pub fn key_id(&self) -> &str {
+        self.jws.key_id()
+    }
+
+    /// The signer used in the transport
+    pub fn signer(&self) -> Arc<dyn AccountSigner> {
+        self.jws.signer()
     }
 
     /// Make call using the full jwk. Only for the first newAccount request.
-    pub fn call_jwk<T: Serialize + ?Sized>(&self, url: &str, body: &T) -> Result<ureq::Response> {
-        self.do_call(url, body, jws_with_jwk)
+    pub fn call_jwk<T: Serialize + ?Sized>(&self, url: &str, body: &T) -> Result<HttpResponse> {
+        // newAccount for a key that already has an account gives back that account.
+        self.do_call(url, body, JwsSigner::jwk_body, true)
     }
 
     /// Make call using the key id
-    pub fn call<T: Serialize + ?Sized>(&self, url: &str, body: &T) -> Result<ureq::Response> {
-        self.do_call(url, body, jws_with_kid)
+    pub fn call<T: Serialize + ?Sized>(&self, url: &str, body: &T) -> Result<HttpResponse> {
+        self.do_call(url, body, JwsSigner::kid_body, is_post_as_get(body))
     }
 
-    fn do_call<T: Serialize + ?Sized, F: Fn(&str, String, &AcmeKey, &T) -> Result<String>>(
+    /// Roll over to a new key against the keyChange url. On success, the new signer
+    /// replaces the current for all subsequent calls.
+    pub fn change_key(&self, url: &str, new_signer: Arc<dyn AccountSigner>) -> Result<()> {
+        let inner = self.jws.key_change(url, &*new_signer)?;
+        self.call(url, &inner)?;
+        self.jws.replace_signer(new_signer);
+        Ok(())
+    }
+
+    fn do_call<T: Serialize + ?Sized>(
         &self,
         url: &str,
         body: &T,
-        make_body: F,
-    ) -> Result<ureq::Response> {
-        // The ACME API may at any point invalidate all nonces. If we detect such an
-        // error, we loop until the server accepts the nonce.
+        make_body: fn(&JwsSigner, &str, String, &T) -> Result<String>,
+        idempotent: bool,
+    ) -> Result<HttpResponse> {
+        // The ACME API may at any point invalidate all nonces, and there might be
+        // server or connection trouble. Such calls are retried according to the policy,
+        // though server and connection trouble only for idempotent calls, since
+        // the server might have acted on a request we got no answer for.
+        let mut retries = Retries::new(&self.retry_policy);
         loop {
-            // Either get a new nonce, or reuse one from a previous request.
-            let nonce = self.nonce_pool.get_nonce()?;
+            match self.attempt(url, body, make_body, idempotent) {
+                Ok(response) => return Ok(response),
+                Err(failed) => thread::sleep(retries.failed(failed)?),
+            }
+        }
+    }
 
-            // Sign the body.
-            let body = make_body(url, nonce, &self.acme_key, body)?;
+    fn attempt<T: Serialize + ?Sized>(
+        &self,
+        url: &str,
+        body: &T,
+        make_body: fn(&JwsSigner, &str, String, &T) -> Result<String>,
+        idempotent: bool,
+    ) -> Attempt<HttpResponse> {
+        // Either get a new nonce, or reuse one from a previous request.
+        let nonce = match self.nonce_pool.pop_nonce() {
+            Some(nonce) => nonce,
+            None => {
+                let req = self.nonce_pool.new_nonce_request();
+                NoncePool::read_new_nonce(self.client.request(&req))?
+            }
+        };
 
-            debug!("Call endpoint {}", url);
+        // Sign the body.
+        let body = make_body(&self.jws, url, nonce, body).map_err(Failed::fatal)?;
 
-            // Post it to the URL
-            let response = req_post(url, &body);
+        debug!("Call endpoint {}", url);
 
-            // Regardless of the request being a success or not, there might be
-            // a nonce in the response.
-            self.nonce_pool.extract_nonce(&response);
+        // Post it to the URL
+        let response = self.client.request(&HttpRequest::post(url, &body));
 
-            // Turn errors into ApiProblem.
-            let result = req_handle_error(response);
+        read_response(&self.nonce_pool, response, idempotent)
+    }
+}
 
-            if let Err(problem) = &result {
-                if problem.is_bad_nonce() {
-                    // retry the request with a new nonce.
-                    debug!("Retrying on bad nonce");
-                    continue;
-                }
-                // it seems we sometimes make bad JWTs. Why?!
-                if problem.is_jwt_verification_error() {
-                    debug!("Retrying on: {}", problem);
-                    continue;
-                }
-            }
+/// Whether the body is that of a POST-as-GET, which is safe to send again.
+pub(crate) fn is_post_as_get<T: Serialize + ?Sized>(body: &T) -> bool {
+    // ApiEmptyString is the only payload serializing to an empty JSON string.
+    match serde_json::to_string(body) {
+        Ok(json) => json == "\"\"",
+        Err(_) => false,
+    }
+}
+
+/// Read the response of an attempt. Errors are turned into ApiProblem, unless there
+/// was no response at all. Connection errors and server errors are only transient
+/// for `idempotent` requests.
+pub(crate) fn read_response(
+    nonce_pool: &NoncePool,
+    response: Result<HttpResponse>,
+    idempotent: bool,
+) -> Attempt<HttpResponse> {
+    let response = response.map_err(|e| Failed::new(e, idempotent))?;
 
-            return Ok(result?);
+    // Regardless of the request being a success or not, there might be
+    // a nonce in the response.
+    nonce_pool.extract_nonce(&response);
+
+    let retry_after = response.retry_after();
+    let server_error = response.status >= 500;
+
+    req_handle_error(response).map_err(|problem| {
+        let transient = (idempotent && server_error) || is_retry_problem(&problem);
+        Failed::new(call_error(problem, retry_after), transient)
+    })
+}
+
+/// The error of a failed call. Rate limits get the earliest retry time from the
+/// `Retry-After` of the response.
+pub(crate) fn call_error(problem: ApiProblem, retry_after: Option<Duration>) -> Error {
+    if problem.is_rate_limited() {
+        let retry_at = retry_after.map(|d| SystemTime::now() + d);
+        return Error::RateLimited { problem, retry_at };
+    }
+    problem.into()
+}
+
+/// Whether a call failing with the problem should be retried with a new nonce.
+pub(crate) fn is_retry_problem(problem: &ApiProblem) -> bool {
+    if problem.is_bad_nonce() {
+        // retry the request with a new nonce.
+        debug!("Retrying on bad nonce");
+        return true;
+    }
+    // it seems we sometimes make bad JWTs. Why?!
+    if problem.is_jwt_verification_error() {
+        debug!("Retrying on: {}", problem);
+        return true;
+    }
+    false
+}
+
+/// The account key and key id, signing request bodies.
+///
+/// This is the part of the transport that doesn't do any I/O.
+#[derive(Debug)]
+pub(crate) struct JwsSigner {
+    signer: RwLock<Arc<dyn AccountSigner>>,
+    key_id: Option<String>,
+}
+
+impl JwsSigner {
+    pub fn new(signer: Arc<dyn AccountSigner>) -> Self {
+        JwsSigner {
+            signer: RwLock::new(signer),
+            key_id: None,
         }
     }
+
+    pub fn set_key_id(&mut self, kid: String) {
+        self.key_id = Some(kid);
+    }
+
+    pub fn key_id(&self) -> &str {
+        self.key_id.as_deref().expect("key_id")
+    }
+
+    pub fn signer(&self) -> Arc<dyn AccountSigner> {
+        self.signer.read().unwrap().clone()
+    }
+
+    /// Body signed with the full jwk. Only for the newAccount request.
+    pub fn jwk_body<T: Serialize + ?Sized>(
+        &self,
+        url: &str,
+        nonce: String,
+        payload: &T,
+    ) -> Result<String> {
+        jws_with_jwk(url, nonce, &*self.signer(), payload)
+    }
+
+    /// Body signed using the key id.
+    pub fn kid_body<T: Serialize + ?Sized>(
+        &self,
+        url: &str,
+        nonce: String,
+        payload: &T,
+    ) -> Result<String> {
+        jws_with_kid(url, self.key_id(), nonce, &*self.signer(), payload)
+    }
+
+    /// The inner JWS of a keyChange request.
+    ///
+    /// The inner JWS is signed by the new key and proves possession of it. The
+    /// outer is signed by the old key (kid) as for any other call.
+    pub fn key_change(&self, url: &str, new_signer: &dyn AccountSigner) -> Result<ApiJws> {
+        let key_change = JwkKeyChange {
+            account: self.key_id().to_string(),
+            old_key: self.signer().jwk(),
+        };
+        let protected = JwsProtected::new_key_change(new_signer.jwk(), url);
+        jws_with(protected, new_signer, &key_change)
+    }
+
+    /// Replace the signer once the keyChange is accepted. The account (and thereby
+    /// the key id) is unchanged.
+    pub fn replace_signer(&self, new_signer: Arc<dyn AccountSigner>) {
+        *self.signer.write().unwrap() = new_signer;
+    }
 }
 
 /// Shared pool of nonces.
-#[derive(Default, Debug)]
+#[derive(Debug)]
 pub(crate) struct NoncePool {
     nonce_url: String,
     pool: Mutex<VecDeque<String>>,
@@ -108,11 +264,11 @@ impl NoncePool {
     pub fn new(nonce_url: &str) -> Self {
         NoncePool {
             nonce_url: nonce_url.into(),
-            ..Default::default()
+            pool: Mutex::new(VecDeque::new()),
         }
     }
 
-    fn extract_nonce(&self, res: &ureq::Response) {
+    pub fn extract_nonce(&self, res: &HttpResponse) {
         if let Some(nonce) = res.header("replay-nonce") {
             trace!("Extract nonce");
             let mut pool = self.pool.lock().unwrap();
@@ -123,46 +279,61 @@ impl NoncePool {
         }
     }
 
-    fn get_nonce(&self) -> Result<String> {
-        {
-            let mut pool = self.pool.lock().unwrap();
-            if let Some(nonce) = pool.pop_front() {
-                trace!("Use previous nonce");
-                return Ok(nonce);
-            }
+    /// Reuse a nonce from a previous response.
+    pub fn pop_nonce(&self) -> Option<String> {
+        let nonce = self.pool.lock().unwrap().pop_front();
+        if nonce.is_some() {
+            trace!("Use previous nonce");
         }
+        nonce
+    }
+
+    /// Request for a new nonce, when there are none to reuse.
+    pub fn new_nonce_request(&self) -> HttpRequest {
         debug!("Request new nonce");
-        let res = req_head(&self.nonce_url);
-        Ok(req_expect_header(&res, "replay-nonce")?)
+        HttpRequest::head(&self.nonce_url)
+    }
+
+    /// Read the nonce from the response to the new nonce request.
+    pub fn read_new_nonce(res: Result<HttpResponse>) -> Attempt<String> {
+        let res = res.map_err(Failed::transient)?;
+        let transient = res.status >= 500;
+        let res = req_handle_error(res).map_err(|p| Failed::new(p.into(), transient))?;
+        req_expect_header(&res, "replay-nonce").map_err(|p| Failed::fatal(p.into()))
     }
 }
 
 fn jws_with_kid<T: Serialize + ?Sized>(
     url: &str,
+    key_id: &str,
     nonce: String,
-    key: &AcmeKey,
+    signer: &dyn AccountSigner,
     payload: &T,
 ) -> Result<String> {
-    let protected = JwsProtected::new_kid(key.key_id(), url, nonce);
-    jws_with(protected, key, payload)
+    let alg = signer.algorithm().jws_alg();
+    let protected = JwsProtected::new_kid(alg, key_id, url, nonce);
+    Ok(serde_json::to_string(&jws_with(
+        protected, signer, payload,
+    )?)?)
 }
 
 fn jws_with_jwk<T: Serialize + ?Sized>(
     url: &str,
     nonce: String,
-    key: &AcmeKey,
+    signer: &dyn AccountSigner,
     payload: &T,
 ) -> Result<String> {
-    let jwk: Jwk = key.into();
-    let protected = JwsProtected::new_jwk(jwk, url, nonce);
-    jws_with(protected, key, payload)
+    let protected = JwsProtected::new_jwk(signer.jwk(), url, nonce);
+    Ok(serde_json::to_string(&jws_with(
+        protected, signer, payload,
+    )?)?)
 }
 
 fn jws_with<T: Serialize + ?Sized>(
     protected: JwsProtected,
-    key: &AcmeKey,
+    signer: &dyn AccountSigner,
     payload: &T,
-) -> Result<String> {
+) -> Result<ApiJws> {
     let protected = {
         let pro_json = serde_json::to_string(&protected)?;
         base64url(pro_json.as_bytes())
@@ -179,17 +350,116 @@ fn jws_with<T: Serialize + ?Sized>(
     };
 
     let to_sign = format!("{}.{}", protected, payload);
-    let digest = sha256(to_sign.as_bytes());
-    let sig = EcdsaSig::sign(&digest, key.private_key()).expect("EcdsaSig::sign");
-    let r = sig.r().to_vec();
-    let s = sig.s().to_vec();
+    let signature = base64url(&signer.sign(to_sign.as_bytes())?);
+
+    Ok(ApiJws {
+        protected,
+        payload,
+        signature,
+    })
+}
+
+#[cfg(test)]
+mod test {
+    use crate::http::HttpResponse;
+    use crate::persist::MemoryPersist;
+    use crate::*;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::Arc;
+    use std::time::Duration;
+
+    fn bad_nonce() -> HttpResponse {
+        HttpResponse::new(400)
+            .with_header("Content-Type", "application/problem+json")
+            .with_header("Replay-Nonce", "nonce")
+            .with_body(r#"{"type":"badNonce","detail":"JWS has an invalid anti-replay nonce"}"#)
+    }
+
+    #[test]
+    fn test_retries_exhausted() -> Result<()> {
+        let client = crate::test::memory_client(|req| match &req.url[..] {
+            "mem:/acct/1" => Some(bad_nonce()),
+            _ => None,
+        });
+        let url = DirectoryUrl::Other("mem:/directory");
+        let policy = RetryPolicy::new()
+            .with_max_attempts(3)
+            .with_initial_delay(Duration::from_millis(1));
+        let dir = Directory::from_url_with_client(MemoryPersist::new(), url, Arc::new(client))?
+            .with_retry_policy(policy);
+        let mut acc = dir.account("foo@bar.com")?;
+        match acc.refresh() {
+            Err(Error::RetriesExhausted(errors)) => {
+                assert_eq!(errors.len(), 3);
+                for e in errors {
+                    match e {
+                        Error::ApiProblem(p) => {
+                            assert_eq!(p.kind(), ProblemKind::BadNonce);
+                            assert_eq!(p.status, Some(400));
+                        }
+                        e => panic!("Expected ApiProblem: {:?}", e),
+                    }
+                }
+            }
+            x => panic!("Expected RetriesExhausted: {:?}", x.err()),
+        }
+        Ok(())
+    }
 
-    let mut v = Vec::with_capacity(r.len() + s.len());
-    v.extend_from_slice(&r);
-    v.extend_from_slice(&s);
-    let signature = base64url(&v);
+    #[test]
+    fn test_retry_transient() -> Result<()> {
+        let count = Arc::new(AtomicUsize::new(0));
+        let count2 = count.clone();
+        let client = crate::test::memory_client(move |req| match &req.url[..] {
+            "mem:/acct/1" => Some(match count2.fetch_add(1, Ordering::SeqCst) {
+                0 => HttpResponse::new(503).with_body("unavailable"),
+                1 => bad_nonce(),
+                3 => HttpResponse::new(403)
+                    .with_header("Content-Type", "application/problem+json")
+                    .with_body(r#"{"type":"urn:ietf:params:acme:error:unauthorized"}"#),
+                _ => HttpResponse::new(200).with_body(r#"{"status":"valid"}"#),
+            }),
+            _ => None,
+        });
+        let url = DirectoryUrl::Other("mem:/directory");
+        let policy = RetryPolicy::new().with_initial_delay(Duration::from_millis(1));
+        let dir = Directory::from_url_with_client(MemoryPersist::new(), url, Arc::new(client))?
+            .with_retry_policy(policy);
+        let mut acc = dir.account("foo@bar.com")?;
+        acc.refresh()?;
+        assert_eq!(count.load(Ordering::SeqCst), 3);
 
-    let jws = Jws::new(protected, payload, signature);
+        // errors that aren't transient fail right away
+        match acc.refresh() {
+            Err(Error::ApiProblem(p)) => assert!(p._type.ends_with("unauthorized")),
+            x => panic!("Expected ApiProblem: {:?}", x.err()),
+        }
+        assert_eq!(count.load(Ordering::SeqCst), 4);
+        Ok(())
+    }
 
-    Ok(serde_json::to_string(&jws)?)
+    #[test]
+    fn test_no_resend_new_order() -> Result<()> {
+        let count = Arc::new(AtomicUsize::new(0));
+        let count2 = count.clone();
+        let client = crate::test::memory_client(move |req| match &req.url[..] {
+            "mem:/new-order" => {
+                count2.fetch_add(1, Ordering::SeqCst);
+                Some(HttpResponse::new(503).with_body("unavailable"))
+            }
+            _ => None,
+        });
+        let url = DirectoryUrl::Other("mem:/directory");
+        let policy = RetryPolicy::new().with_initial_delay(Duration::from_millis(1));
+        let dir = Directory::from_url_with_client(MemoryPersist::new(), url, Arc::new(client))?
+            .with_retry_policy(policy);
+        let acc = dir.account("foo@bar.com")?;
+        // the server might have created the order, so it must not be sent again.
+        match acc.new_order("acmetest.example.com", &[]) {
+            Err(Error::ApiProblem(p)) => assert_eq!(p.status, Some(503)),
+            x => panic!("Expected ApiProblem: {:?}", x.err()),
+        }
+        assert_eq!(count.load(Ordering::SeqCst), 1);
+        Ok(())
+    }
 }
@@ -1,7 +1,7 @@
 use lazy_static::lazy_static;
 use serde::de::DeserializeOwned;
 
-use crate::req::req_safe_read_body;
+use crate::http::HttpResponse;
 use crate::Result;
 
 lazy_static! {
@@ -13,8 +13,8 @@ pub(crate) fn base64url<T: ?Sized + AsRef<[u8]>>(input: &T) -> String {
     base64::encode_config(input, *BASE64_CONFIG)
 }
 
-pub(crate) fn read_json<T: DeserializeOwned>(res: ureq::Response) -> Result<T> {
-    let res_body = req_safe_read_body(res);
+pub(crate) fn read_json<T: DeserializeOwned>(res: HttpResponse) -> Result<T> {
+    let res_body = res.body_str();
     debug!("{}", res_body);
     Ok(serde_json::from_str(&res_body)?)
 }